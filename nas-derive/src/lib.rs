@@ -0,0 +1,173 @@
+/*
+    `#[derive(RemoteStruct)]`: declares the layout of a structure in the game process,
+    every field gets a `#[remote(offset = ...)]` attribute. The generated code reads the
+    whole structure with a single read and follows the fields marked as pointers.
+    See `nas::modules::remote_struct` for the trait and the supported field types.
+*/
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, GenericArgument, LitInt, PathArguments, Type};
+
+/// How a field is stored at its offset
+enum FieldKind {
+    /// The value itself, decoded from the bytes of the structure
+    Inline,
+    /// Pointer to a remote structure, `Option<T>` fields accept null pointers
+    Pointer,
+    /// Pointer to NUL-terminated UTF-16 characters, read up to the given length
+    Utf16(TokenStream2),
+}
+
+struct RemoteField {
+    ident: syn::Ident,
+    ty: Type,
+    offset: LitInt,
+    kind: FieldKind,
+}
+
+#[proc_macro_derive(RemoteStruct, attributes(remote))]
+pub fn derive_remote_struct(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input).unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new_spanned(&input.ident, "RemoteStruct can only be derived for structs"));
+    };
+    let Fields::Named(fields) = &data.fields else {
+        return Err(syn::Error::new_spanned(&input.ident, "RemoteStruct needs named fields"));
+    };
+    let fields = fields.named.iter().map(parse_field).collect::<syn::Result<Vec<_>>>()?;
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let remote_struct = quote!(::nas::modules::remote_struct::RemoteStruct);
+
+    let ends = fields.iter().map(|field| {
+        let offset = &field.offset;
+        let ty = &field.ty;
+        match field.kind {
+            FieldKind::Inline => quote!(#offset + <#ty as #remote_struct>::SIZE),
+            FieldKind::Pointer | FieldKind::Utf16(_) => quote!(#offset + 8),
+        }
+    });
+
+    let values = fields.iter().map(|field| {
+        let ident = &field.ident;
+        let offset = &field.offset;
+        let ty = &field.ty;
+        let field_name = format!("{}.{}", name, ident);
+        let pointer = quote! {
+            usize::from_le_bytes(bytes[#offset..#offset + 8].try_into().unwrap())
+        };
+        let null_error = quote! {
+            format!("{} is a null pointer", #field_name)
+        };
+
+        let value = match (&field.kind, option_inner(ty)) {
+            (FieldKind::Inline, _) => quote! {
+                <#ty as #remote_struct>::from_remote_bytes(memory, &bytes[#offset..#offset + <#ty as #remote_struct>::SIZE])?
+            },
+            (FieldKind::Pointer, Some(inner)) => quote! {
+                match #pointer {
+                    0 => None,
+                    pointer => Some(<#inner as #remote_struct>::read(memory, pointer)?),
+                }
+            },
+            (FieldKind::Pointer, None) => quote! {
+                match #pointer {
+                    0 => return Err(#null_error.into()),
+                    pointer => <#ty as #remote_struct>::read(memory, pointer)?,
+                }
+            },
+            (FieldKind::Utf16(max_chars), Some(_)) => quote! {
+                match #pointer {
+                    0 => None,
+                    pointer => Some(memory.read_utf16_string(pointer, #max_chars)?),
+                }
+            },
+            (FieldKind::Utf16(max_chars), None) => quote! {
+                match #pointer {
+                    0 => return Err(#null_error.into()),
+                    pointer => memory.read_utf16_string(pointer, #max_chars)?,
+                }
+            },
+        };
+        quote!(#ident: #value)
+    });
+
+    Ok(quote! {
+        impl #impl_generics #remote_struct for #name #ty_generics #where_clause {
+            const SIZE: usize = {
+                let mut size = 0usize;
+                #(
+                    let end = #ends;
+                    if end > size {
+                        size = end;
+                    }
+                )*
+                size
+            };
+
+            fn from_remote_bytes(
+                memory: &dyn ::nas::utils::process_memory::ProcessMemory,
+                bytes: &[u8],
+            ) -> ::std::result::Result<Self, ::std::boxed::Box<dyn ::std::error::Error>> {
+                Ok(Self {
+                    #(#values,)*
+                })
+            }
+        }
+    })
+}
+
+fn parse_field(field: &syn::Field) -> syn::Result<RemoteField> {
+    let ident = field.ident.clone().unwrap();
+    let mut offset = None;
+    let mut kind = FieldKind::Inline;
+
+    for attr in field.attrs.iter().filter(|attr| attr.path().is_ident("remote")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("offset") {
+                offset = Some(meta.value()?.parse::<LitInt>()?);
+            } else if meta.path.is_ident("pointer") {
+                kind = FieldKind::Pointer;
+            } else if meta.path.is_ident("utf16") {
+                let max_chars = if meta.input.peek(syn::Token![=]) {
+                    let max_chars = meta.value()?.parse::<LitInt>()?;
+                    quote!(#max_chars)
+                } else {
+                    quote!(0x1000)
+                };
+                kind = FieldKind::Utf16(max_chars);
+            } else {
+                return Err(meta.error("expected `offset = ...`, `pointer` or `utf16`"));
+            }
+            Ok(())
+        })?;
+    }
+
+    let offset = offset.ok_or_else(|| syn::Error::new_spanned(field, "missing #[remote(offset = ...)]"))?;
+    Ok(RemoteField { ident, ty: field.ty.clone(), offset, kind })
+}
+
+/// `T` for a field of type `Option<T>`
+fn option_inner(ty: &Type) -> Option<&Type> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    if segment.ident != "Option" {
+        return None;
+    }
+    let PathArguments::AngleBracketed(arguments) = &segment.arguments else {
+        return None;
+    };
+    match arguments.args.first()? {
+        GenericArgument::Type(inner) => Some(inner),
+        _ => None,
+    }
+}
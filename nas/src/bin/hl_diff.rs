@@ -0,0 +1,35 @@
+/*
+    Offline check to run after a game patch:
+    `cargo run --bin hl_diff -- <old hlboot.dat> <new hlboot.dat>`
+    Lists every function the modules depend on and whether it changed.
+*/
+
+use nas::modules::bytecode_diff::{diff_dependencies, format_report, FUNCTION_DEPENDENCIES};
+use hlbc::Bytecode;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 {
+        eprintln!("Usage: {} <old hlboot.dat> <new hlboot.dat>", args[0]);
+        return ExitCode::from(2);
+    }
+
+    let load = |path: &str| Bytecode::from_file(path).map_err(|e| format!("Failed to load {}: {}", path, e));
+    let (old, new) = match (load(&args[1]), load(&args[2])) {
+        (Ok(old), Ok(new)) => (old, new),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("{}", e);
+            return ExitCode::from(2);
+        }
+    };
+
+    let diffs = diff_dependencies(&old, &new, FUNCTION_DEPENDENCIES);
+    print!("{}", format_report(&diffs));
+
+    if diffs.iter().any(|diff| diff.needs_attention()) {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
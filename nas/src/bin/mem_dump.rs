@@ -0,0 +1,47 @@
+/*
+    Capture the memory of the running game for offline analysis:
+    `cargo run --bin mem_dump -- <pid> <output> [all|image|private|executable|writable]`
+    The archive is loaded back with `DumpMemory::open`.
+*/
+
+use nas::utils::memory_dump::dump_regions;
+use nas::utils::process_memory::*;
+use std::path::Path;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 || args.len() > 4 {
+        eprintln!("Usage: {} <pid> <output> [all|image|private|executable|writable]", args[0]);
+        return ExitCode::from(2);
+    }
+
+    let Ok(pid) = args[1].parse::<u32>() else {
+        eprintln!("Invalid pid: {}", args[1]);
+        return ExitCode::from(2);
+    };
+    let filter: fn(&MemoryRegion) -> bool = match args.get(3).map_or("all", String::as_str) {
+        "all" => |_| true,
+        "image" => |region| region.type_ == MEM_IMAGE,
+        "private" => |region| region.type_ == MEM_PRIVATE,
+        "executable" => |region| is_executable(region.protect),
+        "writable" => |region| is_writable(region.protect),
+        other => {
+            eprintln!("Unknown region filter: {}", other);
+            return ExitCode::from(2);
+        }
+    };
+
+    let result = open_process_memory(pid)
+        .and_then(|memory| dump_regions(memory.as_ref(), pid, Path::new(&args[2]), filter));
+    match result {
+        Ok(index) => {
+            println!("Dumped {} regions, {:#x} bytes", index.regions.len(), index.total_size());
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
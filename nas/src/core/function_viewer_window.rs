@@ -0,0 +1,273 @@
+/*
+    Developer window to browse hashlink functions: decompiled Haxe source, bytecode and JIT address.
+    Toggled with Ctrl+Shift+D, `hlboot.dat` is parsed in the background the first time the window is opened.
+*/
+
+use hudhook::*;
+use hlbc::fmt::EnhancedFmt;
+use hlbc::types::Function;
+use hlbc::Bytecode;
+use hlbc_decompiler::decompile_function;
+use hlbc_decompiler::fmt::FormatOptions;
+use imgui::{Condition, Key};
+use crate::modules::hashlink::Hashlink;
+use std::fmt::Write;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Maximum number of search results shown in the list
+const MAX_RESULTS: usize = 500;
+
+/// Delay before loading again while Hashlink is not initialized
+const LOAD_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+struct FunctionEntry {
+    path: String,
+    /// Lowercased `path`, matched by the search filter
+    lowercase_path: String,
+    findex: usize,
+    address: usize,
+}
+
+enum LoadError {
+    /// Hashlink is not initialized yet, tried again later
+    NotReady,
+    Failed(String),
+}
+
+type Loaded = (Bytecode, Vec<FunctionEntry>);
+
+struct SelectedFunction {
+    findex: usize,
+    address: usize,
+    header: String,
+    decompiled: String,
+    opcodes: String,
+}
+
+pub struct FunctionViewerWindow {
+    pub window_visible: bool,
+    pid: u32,
+    bytecode: Option<Bytecode>,
+    functions: Vec<FunctionEntry>,
+    search: String,
+    selected: Option<SelectedFunction>,
+    load_error: Option<String>,
+    loading: Option<JoinHandle<Result<Loaded, LoadError>>>,
+    retry_at: Option<Instant>,
+}
+
+impl FunctionViewerWindow {
+    pub fn new(pid: u32) -> Self {
+        Self {
+            window_visible: false,
+            pid,
+            bytecode: None,
+            functions: Vec::new(),
+            search: String::new(),
+            selected: None,
+            load_error: None,
+            loading: None,
+            retry_at: None,
+        }
+    }
+
+    pub fn toggle_visibility(&mut self) {
+        self.window_visible = !self.window_visible;
+    }
+
+    /// Start the background load, or take its result once finished. Only failures to parse the bytecode are kept
+    fn load(&mut self) {
+        if self.bytecode.is_some() || self.load_error.is_some() {
+            return;
+        }
+
+        match &self.loading {
+            Some(loading) if !loading.is_finished() => {}
+            Some(_) => {
+                let result = self.loading.take().unwrap().join()
+                    .unwrap_or_else(|_| Err(LoadError::Failed("Loader panicked".to_string())));
+                match result {
+                    Ok((bytecode, functions)) => {
+                        tracing::info!("Function viewer loaded {} functions", functions.len());
+                        self.functions = functions;
+                        self.bytecode = Some(bytecode);
+                        self.retry_at = None;
+                    }
+                    Err(LoadError::NotReady) => {
+                        self.retry_at = Some(Instant::now() + LOAD_RETRY_DELAY);
+                    }
+                    Err(LoadError::Failed(e)) => {
+                        tracing::error!("Function viewer failed to load bytecode: {}", e);
+                        self.load_error = Some(e);
+                    }
+                }
+            }
+            None if self.retry_at.is_some_and(|at| Instant::now() < at) => {}
+            None => {
+                let pid = self.pid;
+                self.loading = Some(std::thread::spawn(move || load_functions(pid)));
+            }
+        }
+    }
+
+    fn select(&mut self, findex: usize, address: usize) {
+        let Some(bytecode) = &self.bytecode else {
+            return;
+        };
+        let Some(function) = bytecode.functions.iter().find(|f| f.findex.0 == findex) else {
+            return;
+        };
+
+        self.selected = Some(SelectedFunction {
+            findex,
+            address,
+            header: function.display_header::<EnhancedFmt>(bytecode).to_string(),
+            decompiled: decompile(bytecode, function),
+            opcodes: disassemble(bytecode, function),
+        });
+    }
+}
+
+/// Copy the resolved functions under the Hashlink lock, then parse `hlboot.dat` without it
+fn load_functions(pid: u32) -> Result<Loaded, LoadError> {
+    let resolved: Vec<(String, usize, usize)> = {
+        let guard = Hashlink::instance(pid).lock().unwrap();
+        let Some(hashlink) = guard.as_ref() else {
+            return Err(LoadError::NotReady);
+        };
+        hashlink.functions
+            .iter()
+            .map(|function| (function.path(), function.findex, function.address))
+            .collect()
+    };
+
+    let bytecode = Hashlink::load_bytecode(pid).map_err(|e| LoadError::Failed(e.to_string()))?;
+    let functions = resolved
+        .into_iter()
+        .map(|(path, findex, address)| FunctionEntry {
+            lowercase_path: path.to_lowercase(),
+            path,
+            findex,
+            address,
+        })
+        .collect();
+
+    Ok((bytecode, functions))
+}
+
+/// Decompile to Haxe source, the decompiler can panic on unusual control flow
+fn decompile(bytecode: &Bytecode, function: &Function) -> String {
+    catch_unwind(AssertUnwindSafe(|| {
+        decompile_function(bytecode, function)
+            .display(bytecode, &FormatOptions::new(2))
+            .to_string()
+    }))
+    .unwrap_or_else(|_| "// Decompilation failed".to_string())
+}
+
+fn disassemble(bytecode: &Bytecode, function: &Function) -> String {
+    let mut text = String::new();
+    for (i, reg) in function.regs.iter().enumerate() {
+        let _ = writeln!(text, "reg{:<3} {}", i, bytecode[*reg].display::<EnhancedFmt>(bytecode));
+    }
+    text.push('\n');
+    for (i, op) in function.ops.iter().enumerate() {
+        let _ = writeln!(text, "{:>4}: {}", i, op.display(bytecode, function, i as i32, 11));
+    }
+    text
+}
+
+impl ImguiRenderLoop for FunctionViewerWindow {
+    fn render(&mut self, ui: &mut imgui::Ui) {
+        if ui.is_key_down(Key::LeftCtrl) &&
+           ui.is_key_down(Key::LeftShift) &&
+           ui.is_key_pressed(Key::D)
+        {
+            self.toggle_visibility();
+            tracing::info!("Function viewer visibility toggled: {}", self.window_visible);
+        }
+
+        if !self.window_visible {
+            return;
+        }
+        self.load();
+
+        let mut clicked = None;
+        let mut window_visible = self.window_visible;
+        ui.window("Function Viewer")
+            .size([900.0, 600.0], Condition::FirstUseEver)
+            .position([340.0, 16.0], Condition::FirstUseEver)
+            .opened(&mut window_visible)
+            .build(|| {
+                if let Some(error) = &self.load_error {
+                    ui.text_colored([1.0, 0.3, 0.3, 1.0], format!("Failed to load functions: {}", error));
+                    return;
+                }
+                if self.bytecode.is_none() {
+                    ui.text_disabled("Loading functions...");
+                    return;
+                }
+
+                ui.input_text("Search", &mut self.search)
+                    .hint("ui.win.EndGame.init or findex")
+                    .build();
+
+                ui.child_window("function_list")
+                    .size([300.0, 0.0])
+                    .border(true)
+                    .build(|| {
+                        let search = self.search.to_lowercase();
+                        let findex = search.parse::<usize>().ok();
+                        let results = self.functions
+                            .iter()
+                            .filter(|f| search.is_empty()
+                                || f.lowercase_path.contains(&search)
+                                || findex == Some(f.findex))
+                            .take(MAX_RESULTS);
+
+                        for function in results {
+                            let is_selected = self.selected.as_ref().is_some_and(|s| s.findex == function.findex);
+                            let label = format!("{}@{}", function.path, function.findex);
+                            if ui.selectable_config(&label).selected(is_selected).build() {
+                                clicked = Some((function.findex, function.address));
+                            }
+                        }
+                    });
+
+                ui.same_line();
+
+                ui.child_window("function_details")
+                    .border(true)
+                    .build(|| {
+                        let Some(selected) = &self.selected else {
+                            ui.text_disabled("Select a function");
+                            return;
+                        };
+
+                        ui.text(&selected.header);
+                        ui.text(format!("JIT address: {:#016x}", selected.address));
+                        ui.same_line();
+                        if ui.small_button("Copy") {
+                            ui.set_clipboard_text(format!("{:#x}", selected.address));
+                        }
+                        ui.separator();
+
+                        if let Some(_tab_bar) = ui.tab_bar("function_tabs") {
+                            if let Some(_tab) = ui.tab_item("Decompiled") {
+                                ui.child_window("decompiled").build(|| ui.text(&selected.decompiled));
+                            }
+                            if let Some(_tab) = ui.tab_item("Bytecode") {
+                                ui.child_window("opcodes").build(|| ui.text(&selected.opcodes));
+                            }
+                        }
+                    });
+            });
+        self.window_visible = window_visible;
+
+        if let Some((findex, address)) = clicked {
+            self.select(findex, address);
+        }
+    }
+}
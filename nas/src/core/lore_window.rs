@@ -1,6 +1,6 @@
 use hudhook::*;
 use imgui::{Condition, WindowFlags};
-use crate::modules::game_common::GameCommon;
+use crate::modules::game_common::{GameCommon, WindowSize};
 use std::sync::Arc;
 
 pub struct LoreWindow {
@@ -36,7 +36,7 @@ impl LoreWindow {
 
     fn update_dimensions(&mut self) {
         if let Some(game_common) = &self.game_common {
-            if let (Ok(width), Ok(height)) = (game_common.get_window_width(), game_common.get_window_height()) {
+            if let Ok(WindowSize { width, height }) = game_common.get_window_size() {
                 if width != self.current_width || height != self.current_height {
                     self.current_width = width;
                     self.current_height = height;
@@ -1,7 +1,9 @@
 pub mod building_window;
+pub mod function_viewer_window;
 pub mod lore_window;
 pub mod warband_window;
 
 pub use building_window::*;
+pub use function_viewer_window::*;
 pub use lore_window::*;
 pub use warband_window::*;
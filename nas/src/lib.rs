@@ -1,15 +1,23 @@
+// Lets `#[derive(RemoteStruct)]` refer to `::nas` inside this crate too
+extern crate self as nas;
+
 pub mod modules;
 pub mod utils;
+// Overlay windows, they need hudhook and only build for Windows
+#[cfg(windows)]
 pub mod core;
 
 // Re-export commonly used items
 pub use modules::*;
 pub use utils::*;
 
+#[cfg(windows)]
 use hudhook::*;
 
+#[cfg(windows)]
 mod main_window;
 
+#[cfg(windows)]
 #[no_mangle]
 pub unsafe extern "system" fn DllMain(
     hmodule: hudhook::windows::Win32::Foundation::HINSTANCE,
@@ -30,6 +38,9 @@ pub unsafe extern "system" fn DllMain(
                 hudhook::eject();
             }
         });
+    } else if reason == hudhook::windows::Win32::System::SystemServices::DLL_PROCESS_DETACH {
+        // Runs under the loader lock, hooks and memory were already released by the eject menu
+        hudhook::tracing::trace!("DllMain() detach");
     }
 }
 
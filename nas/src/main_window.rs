@@ -15,8 +15,13 @@ use crate::modules::auto_lockin::AutoLockin;
 use crate::modules::game_common::GameCommon;
 use crate::modules::build_guide::{BuildGuideManager};
 use crate::modules::winrate_tracker::WinrateTracker;
-use crate::modules::{callback_system, winrate_tracker};
+use crate::modules::{callback_system, eject, hook_monitor, winrate_tracker};
+use crate::modules::hashlink::Hashlink;
+use crate::modules::patch_map::PatchMap;
+use crate::modules::symbol_export::ExportFormat;
+use crate::utils::process_memory::open_process_memory;
 use crate::core::building_window::BuildingWindow;
+use crate::core::function_viewer_window::FunctionViewerWindow;
 use crate::core::lore_window::LoreWindow;
 use crate::core::warband_window::WarbandWindow;
 
@@ -80,10 +85,15 @@ pub struct MainWindow {
     building_window: Option<BuildingWindow>,
     lore_window: Option<LoreWindow>,
     warband_window: Option<WarbandWindow>,
+    function_viewer_window: Option<FunctionViewerWindow>,
     build_guide_manager: Option<BuildGuideManager>,
     selected_guide: Option<String>,
     winrate_tracker: Option<WinrateTracker>,
     winrate_enabled: bool,
+    /// Set by the menu entry, handled once the frame is built
+    eject_requested: bool,
+    /// Modules with a hook disabled by the hook monitor, turned off on the next frame
+    disabled_modules: Arc<Mutex<Vec<String>>>,
 }
 
 impl MainWindow {
@@ -103,13 +113,53 @@ impl MainWindow {
             building_window: None,
             lore_window: None,
             warband_window: None,
+            function_viewer_window: None,
             build_guide_manager: None,
             selected_guide: None,
             winrate_tracker: None,
             winrate_enabled: false,
+            eject_requested: false,
+            disabled_modules: Arc::new(Mutex::new(Vec::new())),
         }
     }
 
+    /// Disable every module, restore the game code, free our memory and unload the DLL.
+    /// Stays loaded when something could not be restored, unloading would leave the game
+    /// jumping into freed code
+    fn eject(&mut self) {
+        tracing::info!("Ejecting");
+        hook_monitor::instance().stop();
+
+        self.checkbox_auto_accept = false;
+        self.lobby_members_enabled = false;
+        self.winrate_enabled = false;
+        self.selected_clan = None;
+        self.selected_color = None;
+
+        // Each module restores its injections and frees its variables when dropped
+        self.auto_accept = None;
+        self.auto_lockin = None;
+        self.winrate_tracker = None;
+        self.lobby_members = None;
+        self.lore_window = None;
+        self.game_common = None;
+        self.command_context = None;
+
+        let report = eject::eject();
+        for name in &report.undone {
+            tracing::warn!("Injection {} was still applied, restored", name);
+        }
+        if !report.is_clean() {
+            for (name, e) in &report.failed {
+                tracing::error!("Cannot eject, {} not restored: {}", name, e);
+            }
+            return;
+        }
+
+        tracing::info!("Released {} cave pages, unloading", report.released_pages);
+        hudhook::eject();
+    }
+
     fn update_pid(&mut self) {
         self.pid = std::process::id();
     }
@@ -126,6 +176,41 @@ impl MainWindow {
             _ => None,
         }
     }
+
+    /// Turn off the modules whose hooks the hook monitor disabled, their other hooks are removed
+    /// and the checkboxes match the game code again
+    fn turn_off_disabled_modules(&mut self) {
+        let mut modules = std::mem::take(&mut *self.disabled_modules.lock().unwrap());
+        modules.sort();
+        modules.dedup();
+
+        for module in modules {
+            let result = match module.as_str() {
+                "AutoAccept" => {
+                    self.checkbox_auto_accept = false;
+                    self.auto_accept.as_mut().map_or(Ok(()), |auto_accept| auto_accept.auto_accept_apply(false))
+                }
+                "AutoLockin" => {
+                    self.selected_clan = None;
+                    self.selected_color = None;
+                    self.auto_lockin.as_mut().map_or(Ok(()), |auto_lockin| auto_lockin.disable())
+                }
+                "LobbyMembers" => {
+                    self.lobby_members_enabled = false;
+                    self.lobby_members.as_ref().map_or(Ok(()), |lobby| lobby.disable())
+                }
+                "WinrateTracker" => {
+                    self.winrate_enabled = false;
+                    self.winrate_tracker.as_mut().map_or(Ok(()), |wrt| wrt.apply(false))
+                }
+                _ => continue,
+            };
+            match result {
+                Ok(()) => tracing::warn!("{} turned off, one of its hooks was disabled", module),
+                Err(e) => tracing::error!("Failed to turn off {}: {}", module, e),
+            }
+        }
+    }
 }
 
 const FONT_DATA: &[u8] = include_bytes!("../assets/Microsoft Yahei.ttf");
@@ -184,8 +269,11 @@ impl ImguiRenderLoop for MainWindow {
         
         ctx.fonts().build_rgba32_texture();
 
+        // Every module accesses the game through the same process memory backend
+        let memory = open_process_memory(self.pid).unwrap();
+
         // Initialize command context
-        self.command_context = Some(CommandContext::new(self.pid).unwrap());
+        self.command_context = Some(CommandContext::new(self.pid, memory.clone()).unwrap());
         
         // Initialize AutoAccept with new pattern
         let mut auto_accept = AutoAccept::new();
@@ -195,8 +283,8 @@ impl ImguiRenderLoop for MainWindow {
         self.auto_accept = Some(auto_accept);
         
         // Keep AutoLockin with old pattern for now
-        self.auto_lockin = Some(AutoLockin::new(self.pid).unwrap());
-        match GameCommon::new(self.pid) {
+        self.auto_lockin = Some(AutoLockin::new(self.pid, memory.clone()).unwrap());
+        match GameCommon::new(self.pid, memory.clone()) {
             Ok(mut game_common) => {
                 if let Err(e) = game_common.game_common_apply(true) {
                     tracing::error!("Failed to apply game common: {}", e);
@@ -221,11 +309,12 @@ impl ImguiRenderLoop for MainWindow {
                 self.lore_window = Some(LoreWindow::new());
             }
         }
-        self.lobby_members = Some(LobbyMembers::new(self.pid).unwrap());
+        self.lobby_members = Some(LobbyMembers::new(self.pid, memory.clone()).unwrap());
         self.building_window = Some(BuildingWindow::new());
         self.warband_window = Some(WarbandWindow::new());
+        self.function_viewer_window = Some(FunctionViewerWindow::new(self.pid));
         
-        match WinrateTracker::new(self.pid) {
+        match WinrateTracker::new(self.pid, memory) {
             Ok(wrt) => {
                 self.winrate_tracker = Some(wrt);
                 tracing::info!("Successfully initialized WinrateTracker");
@@ -239,6 +328,23 @@ impl ImguiRenderLoop for MainWindow {
             }
         }
 
+        // Watch the applied hooks, sites overwritten by the game or another tool are re-applied
+        let disabled_modules = self.disabled_modules.clone();
+        callback_system::instance().register(move |event: &hook_monitor::HookTamperEvent| {
+            for hook in &event.hooks {
+                match &hook.action {
+                    hook_monitor::TamperAction::Failed(e) => {
+                        tracing::error!("Failed to restore hook {} at 0x{:X}: {}", hook.name, hook.address, e);
+                    }
+                    hook_monitor::TamperAction::Disabled => {
+                        disabled_modules.lock().unwrap().push(hook.module().to_string());
+                    }
+                    _ => {}
+                }
+            }
+        });
+        hook_monitor::instance().start(std::time::Duration::from_secs(2));
+
     }
 
     fn render(&mut self, ui: &mut imgui::Ui) {
@@ -250,6 +356,13 @@ impl ImguiRenderLoop for MainWindow {
             tracing::info!("Window visibility toggled: {}", self.window_visible);
         }
 
+        if ui.is_key_down(Key::LeftCtrl) && 
+           ui.is_key_down(Key::LeftShift) && 
+           ui.is_key_pressed(Key::E) 
+        {
+            self.eject_requested = true;
+        }
+
         if self.winrate_enabled {
             if let Some(event) = winrate_tracker::take_pending_endgame() {
                 callback_system::instance().emit(event);
@@ -258,6 +371,7 @@ impl ImguiRenderLoop for MainWindow {
 
 
         callback_system::instance().update();
+        self.turn_off_disabled_modules();
 
         if self.window_visible {
             ui.window("Northgard Assistant")
@@ -586,6 +700,56 @@ impl ImguiRenderLoop for MainWindow {
                     }
 
                     ui.separator();
+
+                    if ui.collapsing_header("Export Functions", imgui::TreeNodeFlags::empty()) {
+                        for format in ExportFormat::ALL {
+                            if ui.button(format.name()) {
+                                let path = Hashlink::exports_directory().join(format.file_name());
+                                let guard = Hashlink::instance(self.pid).lock().unwrap();
+                                match guard.as_ref() {
+                                    Some(hashlink) => {
+                                        if let Err(e) = hashlink.export_functions(format, &path) {
+                                            tracing::error!("Failed to export functions: {}", e);
+                                        }
+                                    }
+                                    None => tracing::error!("Hashlink instance not found"),
+                                }
+                            }
+                        }
+                        ui.text_disabled(Hashlink::exports_directory().to_string_lossy());
+                    }
+
+                    ui.separator();
+
+                    if ui.collapsing_header("Active Patches", imgui::TreeNodeFlags::empty()) {
+                        let patches: Vec<_> = PatchMap::all().iter().flat_map(|map| map.patches()).collect();
+                        if patches.is_empty() {
+                            ui.text_disabled("No patch applied");
+                        }
+                        for patch in patches {
+                            ui.text(format!("0x{:X}  {:>2} bytes  {}", patch.address, patch.len, patch.owner));
+                            if !patch.chain.is_empty() {
+                                ui.text_disabled(format!("    {}", patch.chain.join(" > ")));
+                            }
+                        }
+                        if let Some(auto_accept) = &self.auto_accept {
+                            match auto_accept.preview() {
+                                Ok(Some(preview)) => {
+                                    if let Some(_node) = ui.tree_node("AutoAccept setCheckedJoin") {
+                                        ui.text(preview.to_string());
+                                    }
+                                }
+                                Ok(None) => {}
+                                Err(e) => ui.text_disabled(format!("AutoAccept preview failed: {}", e)),
+                            }
+                        }
+                    }
+
+                    ui.separator();
+
+                    if ui.button("Eject (Ctrl+Shift+E)") {
+                        self.eject_requested = true;
+                    }
                     
                 });
         }
@@ -601,5 +765,13 @@ impl ImguiRenderLoop for MainWindow {
         if let Some(warband) = &mut self.warband_window {
             warband.render(ui);
         }
+
+        if let Some(function_viewer) = &mut self.function_viewer_window {
+            function_viewer.render(ui);
+        }
+
+        if std::mem::take(&mut self.eject_requested) {
+            self.eject();
+        }
     }
 }
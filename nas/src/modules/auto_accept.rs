@@ -1,11 +1,12 @@
 use crate::modules::base::{Command, InjectionManager};
-use iced_x86::code_asm::*;
+use crate::modules::context_hook::{ContextHandler, HookAction};
+use crate::modules::libmem_injection::{PatchMode, PatchPreview};
 use std::error::Error;
 
 pub struct AutoAccept {
     address_setcheckedjoin: usize,
     enabled: bool,
-    injection_manager: InjectionManager,
+    injection_manager: Option<InjectionManager>,
 }
 
 impl AutoAccept {
@@ -13,42 +14,46 @@ impl AutoAccept {
         Self {
             address_setcheckedjoin: 0,
             enabled: false,
-            injection_manager: InjectionManager::new(0), // Will be updated in init
+            injection_manager: None, // Created in init
         }
     }
 
     pub fn auto_accept_apply(&mut self, enable: bool) -> Result<(), Box<dyn Error>> {
         self.apply(enable)
     }
+
+    /// Disassembly of the `setCheckedJoin` hook, `None` while it is not applied
+    pub fn preview(&self) -> Result<Option<PatchPreview>, Box<dyn Error>> {
+        match &self.injection_manager {
+            Some(injection_manager) => injection_manager.preview("setCheckedJoin"),
+            None => Ok(None),
+        }
+    }
 }
 
 impl Command for AutoAccept {
     fn init(&mut self, ctx: &mut crate::modules::base::CommandContext) -> Result<(), Box<dyn Error>> {
-        const OFFSET_SETCHECKEDJOIN: usize = 12;
-        
-        // Update injection manager with correct PID
-        self.injection_manager = InjectionManager::new(ctx.pid);
-        self.injection_manager.add_injection("setCheckedJoin".to_string());
+        let mut injection_manager = InjectionManager::new(ctx.memory.clone(), "AutoAccept");
+        injection_manager.add_injection("setCheckedJoin".to_string());
+        self.injection_manager = Some(injection_manager);
 
         // Get function address using context helper
         self.address_setcheckedjoin = ctx.get_function_address("setCheckedJoin", Some(0))?;
-        self.address_setcheckedjoin += OFFSET_SETCHECKEDJOIN;
 
         Ok(())
     }
 
     fn apply(&mut self, enable: bool) -> Result<(), Box<dyn Error>> {
+        let injection_manager = self.injection_manager.as_ref().ok_or("AutoAccept not initialized")?;
         if enable {
-            let mut code = CodeAssembler::new(64)?;
-            code.mov(dl, 1)?;
-            
-            self.injection_manager.apply_injection(
-                "setCheckedJoin", 
-                self.address_setcheckedjoin, 
-                &mut code
-            )?;
+            // The second argument is the checked flag, every join is accepted
+            let handler = ContextHandler::register(|context| {
+                context.rdx = 1;
+                HookAction::Continue
+            });
+            injection_manager.apply_patch("setCheckedJoin", self.address_setcheckedjoin, PatchMode::Entry(handler))?;
         } else {
-            self.injection_manager.remove_injection("setCheckedJoin")?;
+            injection_manager.remove_injection("setCheckedJoin")?;
         }
 
         self.enabled = enable;
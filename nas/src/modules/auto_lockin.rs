@@ -1,8 +1,14 @@
 use crate::modules::base::InjectionManager;
+use crate::modules::libmem_injection::StubBuilder;
 use crate::modules::mem_alloc::*;
 use crate::modules::hashlink::*;
 use iced_x86::code_asm::*;
+use crate::utils::process_memory::ProcessMemory;
 use std::error::Error;
+use std::sync::Arc;
+
+/// Offset of the injection at the end of `canReady`
+pub(crate) const OFFSET_CANREADY_END: usize = 182;
 
 pub struct AutoLockin {
     pid: u32, // Keep pid for now since this struct has complex memory management
@@ -38,15 +44,15 @@ pub struct AutoLockin {
 }
 
 impl AutoLockin {
-    pub fn new(pid: u32) -> Result<Self, Box<dyn Error>> {
-        let mut memory_allocator = MemoryAllocator::new(pid, 0x1000)?;
+    pub fn new(pid: u32, memory: Arc<dyn ProcessMemory>) -> Result<Self, Box<dyn Error>> {
+        let mut memory_allocator = MemoryAllocator::new(memory.clone(), "AutoLockin", 0x1000)?;
         let var_ptr_clan_tmp = memory_allocator.allocate_var("ClanString", DataType::Pointer)?;
         let var_ptr_color_tmp = memory_allocator.allocate_var("ColorString", DataType::Pointer)?;
         let var_ptr_color_int_tmp = memory_allocator.allocate_var("ColorInt", DataType::Pointer)?;
         let var_ptr_lobbymanager_tmp = memory_allocator.allocate_var("gamesys.LobbyManager", DataType::Pointer)?;
         let var_ptr_lockedin_tmp = memory_allocator.allocate_var("gamesys.LobbyManager.LockedIn", DataType::Pointer)?;
 
-        let mut injection_manager = InjectionManager::new(pid);
+        let mut injection_manager = InjectionManager::new(memory, "AutoLockin");
         injection_manager.add_injection("canready".to_string());
         injection_manager.add_injection("canready_end".to_string());
 
@@ -85,8 +91,6 @@ impl AutoLockin {
     }
 
     pub fn init_auto_lockin(&mut self) -> Result<(), Box<dyn Error>> {
-        const OFFSET_CANREADY_END: usize = 182;
-
         let guard = Hashlink::instance(self.pid).lock().unwrap();
         if let Some(hashlink) = guard.as_ref() {
             self.address_allocstring = hashlink.get_function_address("__alloc__", Some(0))?;
@@ -197,6 +201,14 @@ impl AutoLockin {
         Ok(())
     }
 
+    /// Stop locking in the clan and the color and remove the injections
+    pub fn disable(&mut self) -> Result<(), Box<dyn Error>> {
+        self.clan_enabled = false;
+        self.color_enabled = false;
+        self.injection_manager.remove_injection("canready")?;
+        self.injection_manager.remove_injection("canready_end")
+    }
+
     pub fn auto_lockin_apply(&mut self) -> Result<(), Box<dyn Error>> {
         // Remove existing injections
         self.injection_manager.remove_injection("canready")?;
@@ -219,21 +231,24 @@ impl AutoLockin {
         self.injection_manager.apply_injection("canready", self.address_canready, &mut code)?;
 
         // Apply canready_end injection
-        let mut code_end = CodeAssembler::new(64)?;
-        let mut label_end = code_end.create_label();
-
-        code_end.pushfq()?;
-        code_end.push(rax)?;
-        code_end.push(rcx)?;
-        code_end.push(rdx)?;
-
-        // Compare `var_ptr_lockedin` and `var_ptr_lobbymanager`
-        code_end.mov(rax, self.var_ptr_lockedin as u64)?;
-        code_end.mov(rcx, qword_ptr(rax))?;
-        code_end.mov(rax, self.var_ptr_lobbymanager as u64)?;
-        code_end.mov(rdx, qword_ptr(rax))?;
-        code_end.cmp(rcx, rdx)?;
-        code_end.je(label_end)?;
+        let mut code_end = self.create_canready_end_code()?;
+        self.injection_manager.apply_injection("canready_end", self.address_canready_end, &mut code_end)?;
+
+        Ok(())
+    }
+
+    // Lock in the selected clan and color once the lobby can be readied
+    fn create_canready_end_code(&self) -> Result<CodeAssembler, Box<dyn Error>> {
+        StubBuilder::new().build(|code_end| {
+            let mut label_end = code_end.create_label();
+
+            // Compare `var_ptr_lockedin` and `var_ptr_lobbymanager`
+            code_end.mov(rax, self.var_ptr_lockedin as u64)?;
+            code_end.mov(rcx, qword_ptr(rax))?;
+            code_end.mov(rax, self.var_ptr_lobbymanager as u64)?;
+            code_end.mov(rdx, qword_ptr(rax))?;
+            code_end.cmp(rcx, rdx)?;
+            code_end.je(label_end)?;
 
             if self.clan_enabled {
                 let clan_addr = self.var_ptr_arrayclans[self.clan_current.unwrap()] as u64;
@@ -306,15 +321,9 @@ impl AutoLockin {
                 code_end.call(rax)?;
             }
 
-        code_end.set_label(&mut label_end)?;
-        code_end.pop(rdx)?;
-        code_end.pop(rcx)?;
-        code_end.pop(rax)?;
-        code_end.popfq()?;
-
-        self.injection_manager.apply_injection("canready_end", self.address_canready_end, &mut code_end)?;
-
-        Ok(())
+            code_end.set_label(&mut label_end)?;
+            Ok(())
+        })
     }
 
     pub fn get_base_clans(&self) -> Vec<&str> {
@@ -1,24 +1,30 @@
-use crate::modules::libmem_injection::LibmemInjection;
+use crate::modules::eject;
+use crate::modules::hook_monitor::{self, InjectionSlot, TamperPolicy};
+use crate::modules::libmem_injection::{LibmemInjection, PatchMode, PatchPreview};
+use crate::modules::patch_map::{ChainedHook, PatchMap};
 use crate::modules::mem_alloc::*;
 use crate::modules::hashlink::*;
-use crate::utils::libmem_ex::get_target_process;
-use libmem::Process;
+use crate::utils::process_memory::ProcessMemory;
+use iced_x86::code_asm::CodeAssembler;
 use std::error::Error;
-use std::sync::Mutex;
+use std::fmt;
+use std::sync::{Arc, Mutex};
 use std::collections::HashMap;
 
 /// Shared context for all commands to reduce duplication
 pub struct CommandContext {
     pub pid: u32,
+    pub memory: Arc<dyn ProcessMemory>,
     pub mem_allocator: MemoryAllocator,
 }
 
 impl CommandContext {
-    pub fn new(pid: u32) -> Result<Self, Box<dyn Error>> {
-        let mem_allocator = MemoryAllocator::new(pid, 0x1000)?;
+    pub fn new(pid: u32, memory: Arc<dyn ProcessMemory>) -> Result<Self, Box<dyn Error>> {
+        let mem_allocator = MemoryAllocator::new(memory.clone(), "CommandContext", 0x1000)?;
         
         Ok(Self {
             pid,
+            memory,
             mem_allocator,
         })
     }
@@ -60,48 +66,277 @@ pub trait Command {
     fn name(&self) -> &'static str;
 }
 
+/// Injections applied together by `InjectionManager::apply_transaction`
+#[derive(Default)]
+pub struct InjectionTransaction {
+    hooks: Vec<PendingInjection>,
+}
+
+struct PendingInjection {
+    name: String,
+    address: usize,
+    mode: Result<PatchMode, Box<dyn Error>>,
+}
+
+impl InjectionTransaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue the injection `name` at `address`, the payload is built right away and a failure
+    /// is reported when the transaction is applied
+    pub fn add<F>(&mut self, name: &str, address: usize, build_code: F) -> &mut Self
+    where
+        F: FnOnce() -> Result<CodeAssembler, Box<dyn Error>>,
+    {
+        self.add_patch(name, address, || build_code().map(PatchMode::Detour))
+    }
+
+    /// Queue a patch of any mode, see `add`
+    pub fn add_patch<F>(&mut self, name: &str, address: usize, build_mode: F) -> &mut Self
+    where
+        F: FnOnce() -> Result<PatchMode, Box<dyn Error>>,
+    {
+        self.hooks.push(PendingInjection { name: name.to_string(), address, mode: build_mode() });
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.hooks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+}
+
+/// Injection of a transaction that failed, everything written before it was rolled back
+#[derive(Debug)]
+pub struct TransactionError {
+    pub name: String,
+    pub address: usize,
+    /// `prepare` when building the payload or cave, `commit` when patching the hook site
+    pub stage: &'static str,
+    pub reason: String,
+    /// Hook sites that were already patched and restored
+    pub rolled_back: usize,
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Injection {} at 0x{:X} failed to {}: {} ({} rolled back)",
+            self.name, self.address, self.stage, self.reason, self.rolled_back
+        )
+    }
+}
+
+impl Error for TransactionError {}
+
 /// Injection manager to handle common injection patterns
 pub struct InjectionManager {
-    injections: HashMap<String, Mutex<Option<LibmemInjection>>>,
-    pid: u32,
-    process: Process,
+    injections: HashMap<String, InjectionSlot>,
+    /// Payloads sharing a hook site with other modules
+    chained: Mutex<HashMap<String, ChainedHook>>,
+    memory: Arc<dyn ProcessMemory>,
+    /// Module listed as the owner of the patches
+    owner: String,
 }
 
 impl InjectionManager {
-    pub fn new(pid: u32) -> Self {
-        let process = get_target_process(pid).expect("Failed to get process with libmem");
+    pub fn new(memory: Arc<dyn ProcessMemory>, owner: &str) -> Self {
         Self {
             injections: HashMap::new(),
-            pid,
-            process,
+            chained: Mutex::new(HashMap::new()),
+            memory,
+            owner: owner.to_string(),
         }
     }
 
-    /// Add a new injection point
+    /// Add a new injection point, re-applied by the hook monitor when overwritten
     pub fn add_injection(&mut self, name: String) {
-        self.injections.insert(name, Mutex::new(None));
+        self.add_injection_with_policy(name, TamperPolicy::default());
+    }
+
+    /// Add a new injection point watched by the hook monitor with the given policy
+    pub fn add_injection_with_policy(&mut self, name: String, policy: TamperPolicy) {
+        let slot: InjectionSlot = Arc::new(Mutex::new(None));
+        hook_monitor::instance().watch(&name, &slot, policy);
+        eject::instance().register(&name, &slot);
+        self.injections.insert(name, slot);
     }
 
     /// Apply injection at a specific address
     pub fn apply_injection(&self, name: &str, address: usize, code: &mut iced_x86::code_asm::CodeAssembler) -> Result<(), Box<dyn Error>> {
+        let owner = self.patch_owner(name);
+        self.replace_injection(name, address, || LibmemInjection::prepare_detour(&self.memory, &owner, address, code)?.committed())
+    }
+
+    /// Apply a patch of any mode at a specific address
+    pub fn apply_patch(&self, name: &str, address: usize, mode: PatchMode) -> Result<(), Box<dyn Error>> {
+        let owner = self.patch_owner(name);
+        self.replace_injection(name, address, || LibmemInjection::prepare_owned(&self.memory, &owner, address, mode)?.committed())
+    }
+
+    /// Run `build` at `address` in the hook chain shared with other modules, `priority` orders the
+    /// payloads. Removed with `remove_injection` like the other injections
+    pub fn apply_chained<F>(&self, name: &str, address: usize, priority: i32, build: F) -> Result<(), Box<dyn Error>>
+    where
+        F: Fn(&mut iced_x86::code_asm::CodeAssembler) -> Result<(), Box<dyn Error>> + Send + Sync + 'static,
+    {
+        // The previous payload leaves the chain first
+        self.chained.lock().unwrap().remove(name);
+        let hook = PatchMap::shared(&self.memory).chain(&self.patch_owner(name), address, priority, build)?;
+        self.chained.lock().unwrap().insert(name.to_string(), hook);
+        Ok(())
+    }
+
+    /// Disassembly of the applied injection `name`
+    pub fn preview(&self, name: &str) -> Result<Option<PatchPreview>, Box<dyn Error>> {
+        let Some(injection_mutex) = self.injections.get(name) else {
+            return Ok(None);
+        };
+        let injection = injection_mutex.lock().unwrap();
+        injection.as_ref().map(LibmemInjection::preview).transpose()
+    }
+
+    fn replace_injection<F>(&self, name: &str, address: usize, apply: F) -> Result<(), Box<dyn Error>>
+    where
+        F: FnOnce() -> Result<LibmemInjection, Box<dyn Error>>,
+    {
         if let Some(injection_mutex) = self.injections.get(name) {
             let mut injection = injection_mutex.lock().unwrap();
             
             // Remove existing injection
             if let Some(inj) = injection.as_ref() {
                 inj.undo()?;
+                // Cleared before `apply` can fail, the slot must not hold an undone injection
+                *injection = None;
                 tracing::info!("Removed injection: {} at 0x{:X}", name, address);
             }
 
             // Apply new injection
-            *injection = Some(LibmemInjection::new(self.pid, address, code, &self.process)?);
+            *injection = Some(apply()?);
             tracing::info!("Applied injection: {} at 0x{:X}", name, address);
         }
         Ok(())
     }
 
+    /// Apply every injection of `transaction` or none of them. All payloads and caves are prepared
+    /// before the first hook site is patched, a failure restores the sites already patched.
+    /// Injections of the transaction that were applied before are taken off their site while the
+    /// new ones are prepared, and applied again when the transaction fails. An injection whose
+    /// rollback fails stays in its slot, so eject keeps its cave
+    pub fn apply_transaction(&self, transaction: InjectionTransaction) -> Result<(), Box<dyn Error>> {
+        let mut slots = Vec::with_capacity(transaction.len());
+        for (index, PendingInjection { name, address, .. }) in transaction.hooks.iter().enumerate() {
+            let reason = match self.injections.get(name) {
+                None => "unknown injection",
+                Some(_) if transaction.hooks[..index].iter().any(|other| &other.name == name) => "queued twice",
+                Some(slot) => {
+                    slots.push(slot);
+                    continue;
+                }
+            };
+            let stage = "prepare";
+            return Err(TransactionError { name: name.clone(), address: *address, stage, reason: reason.to_string(), rolled_back: 0 }.into());
+        }
+        for pending in &transaction.hooks {
+            if self.chained.lock().unwrap().remove(&pending.name).is_some() {
+                tracing::info!("Removed chained injection: {}", pending.name);
+            }
+        }
+
+        // Previous injections leave their site, their caves are kept until the transaction is done
+        let mut previous = Vec::new();
+        for (PendingInjection { name, address, .. }, slot) in transaction.hooks.iter().zip(&slots) {
+            let mut injection = slot.lock().unwrap();
+            let Some(mut inj) = injection.take() else {
+                continue;
+            };
+            if let Err(e) = inj.restore() {
+                *injection = Some(inj);
+                drop(injection);
+                Self::reapply(previous);
+                return Err(TransactionError { name: name.clone(), address: *address, stage: "prepare", reason: e.to_string(), rolled_back: 0 }.into());
+            }
+            previous.push((*slot, name.clone(), inj));
+        }
+
+        // Prepare: payloads and caves, nothing is visible to the game yet
+        let mut prepared = Vec::with_capacity(transaction.len());
+        for PendingInjection { name, address, mode } in transaction.hooks {
+            let injection = mode.and_then(|mode| LibmemInjection::prepare_owned(&self.memory, &self.patch_owner(&name), address, mode));
+            match injection {
+                Ok(injection) => prepared.push((name, injection)),
+                Err(e) => {
+                    prepared.iter().for_each(|(_, injection)| injection.discard());
+                    Self::reapply(previous);
+                    return Err(TransactionError { name, address, stage: "prepare", reason: e.to_string(), rolled_back: 0 }.into());
+                }
+            }
+        }
+
+        // Commit: patch every hook site, restore the patched ones on failure
+        let failure = prepared.iter().enumerate().find_map(|(index, (name, injection))| {
+            let e = injection.commit().err()?;
+            Some((index, name.clone(), injection.address(), e.to_string()))
+        });
+        if let Some((index, name, address, reason)) = failure {
+            let mut rolled_back = 0;
+            let mut stuck = Vec::new();
+            let committed: Vec<_> = prepared.into_iter().zip(&slots).enumerate().collect();
+            for (position, ((committed_name, committed), slot)) in committed.into_iter().rev() {
+                if position >= index {
+                    committed.discard();
+                    continue;
+                }
+                match committed.undo() {
+                    Ok(()) => rolled_back += 1,
+                    Err(e) => {
+                        // Still patched, the slot keeps it and its cave for eject
+                        tracing::error!("Failed to roll back injection {}: {}", committed_name, e);
+                        *slot.lock().unwrap() = Some(committed);
+                        stuck.push(*slot);
+                    }
+                }
+            }
+            // The site of a stuck injection cannot take the previous one back
+            let (replaced, previous): (Vec<_>, Vec<_>) = previous
+                .into_iter()
+                .partition(|(slot, _, _)| stuck.iter().any(|stuck| Arc::ptr_eq(stuck, slot)));
+            replaced.into_iter().for_each(|(_, _, injection)| injection.discard());
+            Self::reapply(previous);
+            return Err(TransactionError { name, address, stage: "commit", reason, rolled_back }.into());
+        }
+
+        for (_, name, injection) in previous {
+            injection.discard();
+            tracing::info!("Removed injection: {}", name);
+        }
+        for ((name, injection), slot) in prepared.into_iter().zip(slots) {
+            tracing::info!("Applied injection: {} at 0x{:X}", name, injection.address());
+            *slot.lock().unwrap() = Some(injection);
+        }
+        Ok(())
+    }
+
+    /// Put back the injections a failed transaction took off their site
+    fn reapply(previous: Vec<(&InjectionSlot, String, LibmemInjection)>) {
+        for (slot, name, injection) in previous {
+            match injection.reapply() {
+                Ok(injection) => *slot.lock().unwrap() = Some(injection),
+                Err(e) => tracing::error!("Failed to apply injection {} again: {}", name, e),
+            }
+        }
+    }
+
     /// Remove a specific injection
     pub fn remove_injection(&self, name: &str) -> Result<(), Box<dyn Error>> {
+        if self.chained.lock().unwrap().remove(name).is_some() {
+            tracing::info!("Removed chained injection: {}", name);
+        }
         if let Some(injection_mutex) = self.injections.get(name) {
             let mut injection = injection_mutex.lock().unwrap();
             if let Some(inj) = injection.as_ref() {
@@ -115,6 +350,7 @@ impl InjectionManager {
 
     /// Remove all injections
     pub fn remove_all(&self) -> Result<(), Box<dyn Error>> {
+        self.chained.lock().unwrap().clear();
         for (name, injection_mutex) in &self.injections {
             let mut injection = injection_mutex.lock().unwrap();
             if let Some(inj) = injection.as_ref() {
@@ -125,4 +361,263 @@ impl InjectionManager {
         }
         Ok(())
     }
-}
\ No newline at end of file
+
+    fn patch_owner(&self, name: &str) -> String {
+        format!("{} {}", self.owner, name)
+    }
+}
+
+impl Drop for InjectionManager {
+    /// Restore the game code. An injection that cannot be undone keeps its slot alive so
+    /// eject still finds it
+    fn drop(&mut self) {
+        for (name, slot) in self.injections.drain() {
+            let mut injection = slot.lock().unwrap();
+            let Some(inj) = injection.as_ref() else {
+                continue;
+            };
+            match inj.undo() {
+                Ok(()) => {
+                    *injection = None;
+                    tracing::info!("Removed injection: {}", name);
+                }
+                Err(e) => {
+                    tracing::error!("Injection {} at 0x{:X} not removed: {}", name, inj.address(), e);
+                    drop(injection);
+                    std::mem::forget(slot);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::cave_pool::CavePool;
+    use crate::utils::process_memory::{AllocRegion, MemoryRegion, MockMemory, MEM_IMAGE, PAGE_EXECUTE_READ};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const SITES: [usize; 3] = [0x1_4000_0010, 0x1_4000_0020, 0x1_4000_0030];
+    // mov rax, rcx; add rax, 5; ret
+    const ORIGINAL: [u8; 8] = [0x48, 0x89, 0xC8, 0x48, 0x83, 0xC0, 0x05, 0xC3];
+
+    /// Mock memory refusing writes at `locked`, an address in `write_once` is locked after one write
+    struct LockedSite {
+        inner: MockMemory,
+        locked: AtomicUsize,
+        write_once: AtomicUsize,
+        sealed: AtomicUsize,
+    }
+
+    impl ProcessMemory for LockedSite {
+        fn read_bytes(&self, address: usize, length: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+            self.inner.read_bytes(address, length)
+        }
+
+        fn write_bytes(&self, address: usize, data: &[u8]) -> Result<(), Box<dyn Error>> {
+            if address == self.locked.load(Ordering::SeqCst) || address == self.sealed.load(Ordering::SeqCst) {
+                return Err("Access denied".into());
+            }
+            self.inner.write_bytes(address, data)?;
+            if self.write_once.compare_exchange(address, 0, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+                self.sealed.store(address, Ordering::SeqCst);
+            }
+            Ok(())
+        }
+
+        fn protect(&self, address: usize, size: usize, protection: u32) -> Result<u32, Box<dyn Error>> {
+            self.inner.protect(address, size, protection)
+        }
+
+        fn allocate(&self, size: usize, protection: u32, near: Option<usize>) -> Result<AllocRegion, Box<dyn Error>> {
+            self.inner.allocate(size, protection, near)
+        }
+
+        fn free(&self, address: usize, size: usize) -> Result<(), Box<dyn Error>> {
+            self.inner.free(address, size)
+        }
+
+        fn regions(&self) -> Result<Vec<MemoryRegion>, Box<dyn Error>> {
+            self.inner.regions()
+        }
+    }
+
+    fn locked_manager(locked: usize) -> (Arc<LockedSite>, InjectionManager) {
+        let inner = MockMemory::new();
+        inner.map(0x1_4000_0000, 0x1000, PAGE_EXECUTE_READ, MEM_IMAGE);
+        for site in SITES {
+            inner.write_bytes(site, &ORIGINAL).unwrap();
+        }
+        let memory = Arc::new(LockedSite { inner, locked: AtomicUsize::new(locked), write_once: AtomicUsize::new(0), sealed: AtomicUsize::new(0) });
+        let mut manager = InjectionManager::new(memory.clone(), "Test");
+        for name in ["first", "second", "third"] {
+            manager.add_injection(name.to_string());
+        }
+        (memory, manager)
+    }
+
+    fn nop() -> Result<CodeAssembler, Box<dyn Error>> {
+        let mut code = CodeAssembler::new(64)?;
+        code.nop()?;
+        Ok(code)
+    }
+
+    fn transaction(third: usize) -> InjectionTransaction {
+        let mut transaction = InjectionTransaction::new();
+        transaction.add("first", SITES[0], nop).add("second", SITES[1], nop).add("third", third, nop);
+        transaction
+    }
+
+    #[test]
+    fn test_transaction_applies_all() {
+        let (memory, manager) = locked_manager(0);
+        manager.apply_transaction(transaction(SITES[2])).unwrap();
+        for site in SITES {
+            assert_eq!(memory.read_u8(site).unwrap(), 0xE9);
+        }
+        // The three caves share a page
+        assert_eq!(memory.regions().unwrap().len(), 2);
+
+        manager.remove_all().unwrap();
+        for site in SITES {
+            assert_eq!(memory.read_bytes(site, 8).unwrap(), ORIGINAL);
+        }
+        assert_eq!(memory.regions().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_apply_patch_modes() {
+        let (memory, manager) = locked_manager(0);
+        let memory: Arc<dyn ProcessMemory> = memory;
+        manager.apply_patch("first", SITES[0], PatchMode::Nop { len: 3 }).unwrap();
+        let mut transaction = InjectionTransaction::new();
+        transaction.add_patch("second", SITES[1], || Ok(PatchMode::Return(0)));
+        manager.apply_transaction(transaction).unwrap();
+        assert_eq!(memory.read_bytes(SITES[0], 3).unwrap(), [0x90, 0x90, 0x90]);
+        // xor eax, eax; ret
+        assert_eq!(memory.read_bytes(SITES[1], 3).unwrap(), [0x31, 0xC0, 0xC3]);
+
+        let preview = manager.preview("second").unwrap().unwrap();
+        assert_eq!(preview.before.len(), 1);
+        assert_eq!(preview.after.len(), 2);
+        assert!(manager.preview("third").unwrap().is_none());
+
+        // Listed under the module, overlapping patches and chains are refused on a patched site
+        let owners: Vec<_> = PatchMap::shared(&memory).patches().into_iter().map(|patch| patch.owner).collect();
+        assert_eq!(owners, ["Test first", "Test second"]);
+        assert!(manager.apply_patch("third", SITES[0] + 1, PatchMode::Nop { len: 1 }).is_err());
+        assert!(manager.apply_chained("third", SITES[1], 0, |code| Ok(code.nop()?)).is_err());
+        manager.apply_chained("third", SITES[2], 0, |code| Ok(code.nop()?)).unwrap();
+        assert_eq!(memory.read_u8(SITES[2]).unwrap(), 0xE9);
+        manager.remove_injection("third").unwrap();
+        assert_eq!(memory.read_bytes(SITES[2], 8).unwrap(), ORIGINAL);
+
+        // A replacement that fails leaves the slot empty, not holding the undone patch
+        assert!(manager.apply_patch("first", SITES[0], PatchMode::Nop { len: 4 }).is_err());
+        assert!(manager.preview("first").unwrap().is_none());
+        assert_eq!(memory.read_bytes(SITES[0], 8).unwrap(), ORIGINAL);
+
+        manager.remove_all().unwrap();
+        assert_eq!(memory.read_bytes(SITES[0], 8).unwrap(), ORIGINAL);
+        assert_eq!(memory.read_bytes(SITES[1], 8).unwrap(), ORIGINAL);
+    }
+
+    #[test]
+    fn test_drop_restores_code() {
+        let (memory, manager) = locked_manager(0);
+        manager.apply_transaction(transaction(SITES[2])).unwrap();
+        drop(manager);
+        for site in SITES {
+            assert_eq!(memory.read_bytes(site, 8).unwrap(), ORIGINAL);
+        }
+        assert_eq!(memory.regions().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_drop_keeps_stuck_hooks() {
+        let (memory, manager) = locked_manager(0);
+        let allocator = MemoryAllocator::new(memory.clone(), "Test", 0x100).unwrap();
+        manager.apply_injection("first", SITES[0], &mut nop().unwrap()).unwrap();
+        manager.apply_injection("second", SITES[1], &mut nop().unwrap()).unwrap();
+
+        // The first hook cannot be undone, it keeps running with its cave and the variables
+        memory.locked.store(SITES[0], Ordering::SeqCst);
+        drop(manager);
+        drop(allocator);
+        assert_eq!(memory.read_u8(SITES[0]).unwrap(), 0xE9);
+        assert_eq!(memory.read_bytes(SITES[1], 8).unwrap(), ORIGINAL);
+        let memory: Arc<dyn ProcessMemory> = memory;
+        let mut owners: Vec<_> = CavePool::shared(&memory).blocks().into_iter().map(|block| block.owner).collect();
+        owners.sort();
+        assert_eq!(owners, ["Test", "Test first"]);
+        assert_eq!(PatchMap::shared(&memory).owned_by("Test").len(), 1);
+    }
+
+    #[test]
+    fn test_transaction_prepare_failure() {
+        let (memory, manager) = locked_manager(0);
+        let memory: Arc<dyn ProcessMemory> = memory;
+        manager.apply_injection("first", SITES[0], &mut nop().unwrap()).unwrap();
+        let applied = memory.read_bytes(SITES[0], 8).unwrap();
+        // Unmapped third site
+        let error = manager.apply_transaction(transaction(0x2000)).unwrap_err();
+        let error = error.downcast::<TransactionError>().unwrap();
+        assert_eq!((error.name.as_str(), error.stage, error.rolled_back), ("third", "prepare", 0));
+
+        // The hook applied before is still there
+        assert_eq!(memory.read_bytes(SITES[0], 8).unwrap(), applied);
+        assert!(manager.preview("first").unwrap().is_some());
+        assert_eq!(PatchMap::shared(&memory).patches().len(), 1);
+        for site in &SITES[1..] {
+            assert_eq!(memory.read_bytes(*site, 8).unwrap(), ORIGINAL);
+        }
+        // The image and the cave page of the first hook
+        assert_eq!(memory.regions().unwrap().len(), 2);
+        manager.remove_injection("first").unwrap();
+        assert_eq!(memory.read_bytes(SITES[0], 8).unwrap(), ORIGINAL);
+        assert_eq!(memory.regions().unwrap().len(), 1);
+
+        let mut unknown = InjectionTransaction::new();
+        unknown.add("fourth", SITES[0], nop);
+        let error = manager.apply_transaction(unknown).unwrap_err().to_string();
+        assert_eq!(error, "Injection fourth at 0x140000010 failed to prepare: unknown injection (0 rolled back)");
+    }
+
+    #[test]
+    fn test_transaction_commit_rollback() {
+        let (memory, manager) = locked_manager(SITES[2]);
+        let error = manager.apply_transaction(transaction(SITES[2])).unwrap_err();
+        let error = error.downcast::<TransactionError>().unwrap();
+        assert_eq!((error.name.as_str(), error.stage, error.rolled_back), ("third", "commit", 2));
+        assert_eq!(error.reason, "Cannot patch 0x140000030: Failed to write 7 bytes at 0x140000030");
+
+        for site in SITES {
+            assert_eq!(memory.read_bytes(site, 8).unwrap(), ORIGINAL);
+        }
+        assert_eq!(memory.regions().unwrap().len(), 1);
+        // Nothing is left to remove
+        manager.remove_all().unwrap();
+        assert_eq!(memory.read_bytes(SITES[0], 8).unwrap(), ORIGINAL);
+    }
+
+    #[test]
+    fn test_transaction_rollback_failure() {
+        let (memory, manager) = locked_manager(SITES[2]);
+        // The first site is locked once patched, its rollback fails
+        memory.write_once.store(SITES[0], Ordering::SeqCst);
+        let error = manager.apply_transaction(transaction(SITES[2])).unwrap_err();
+        let error = error.downcast::<TransactionError>().unwrap();
+        assert_eq!((error.name.as_str(), error.stage, error.rolled_back), ("third", "commit", 1));
+
+        // The stuck hook stays in its slot with its cave, where eject can find it
+        assert_eq!(memory.read_u8(SITES[0]).unwrap(), 0xE9);
+        assert_eq!(memory.read_bytes(SITES[1], 8).unwrap(), ORIGINAL);
+        assert!(manager.preview("first").unwrap().is_some());
+        assert!(manager.preview("second").unwrap().is_none());
+        let memory: Arc<dyn ProcessMemory> = memory;
+        let owners: Vec<_> = CavePool::shared(&memory).blocks().into_iter().map(|block| block.owner).collect();
+        assert_eq!(owners, ["Test first"]);
+        assert_eq!(PatchMap::shared(&memory).owned_by("Test").len(), 1);
+    }
+}
@@ -0,0 +1,327 @@
+/*
+    Compares the functions the assistant hooks between two `hlboot.dat` versions.
+    Hard-coded offsets into JIT code only stay valid while the function bytecode is the same,
+    so any change reported here means the corresponding module must be checked before injecting.
+*/
+
+use hlbc::fmt::EnhancedFmt;
+use hlbc::types::Function;
+use hlbc::Bytecode;
+use std::fmt::Write;
+
+/// How a module looks up a function
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FunctionRef {
+    /// `get_function_address(name, Some(index))`
+    Name(&'static str, usize),
+    /// `get_function_address_by_path(path)`
+    Path(&'static str),
+}
+
+impl std::fmt::Display for FunctionRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FunctionRef::Name(name, 0) => write!(f, "{}", name),
+            FunctionRef::Name(name, index) => write!(f, "{}#{}", name, index),
+            FunctionRef::Path(path) => write!(f, "{}", path),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FunctionDependency {
+    pub module: &'static str,
+    pub function: FunctionRef,
+    /// Hard-coded offset into the function code, if any
+    pub offset: Option<(&'static str, usize)>,
+}
+
+const fn dependency(module: &'static str, function: FunctionRef, offset: Option<(&'static str, usize)>) -> FunctionDependency {
+    FunctionDependency { module, function, offset }
+}
+
+/// Named offset from the module defining it
+macro_rules! offset {
+    ($module:ident :: $name:ident) => {
+        Some((stringify!($name), crate::modules::$module::$name))
+    };
+}
+
+/// Functions used by the modules, keep in sync when adding or changing an injection
+pub const FUNCTION_DEPENDENCIES: &[FunctionDependency] = &[
+    dependency("auto_accept", FunctionRef::Name("setCheckedJoin", 0), None),
+    dependency("auto_lockin", FunctionRef::Name("__alloc__", 0), None),
+    dependency("auto_lockin", FunctionRef::Name("parseInt", 0), None),
+    dependency("auto_lockin", FunctionRef::Name("changeMyClan", 0), None),
+    dependency("auto_lockin", FunctionRef::Name("changeMyColor", 0), None),
+    dependency("auto_lockin", FunctionRef::Name("canReady", 0), offset!(auto_lockin::OFFSET_CANREADY_END)),
+    dependency("auto_lockin", FunctionRef::Name("clanUnlockedByDLC", 0), None),
+    dependency("game_common", FunctionRef::Path("hxd.Window.get_width"), offset!(game_common::OFFSET_GETWIDTH_END)),
+    dependency("game_common", FunctionRef::Path("hxd.Window.get_height"), offset!(game_common::OFFSET_GETHEIGHT_END)),
+    dependency("lobby_members", FunctionRef::Name("logLobbyInfo", 0), offset!(lobby_members::OFFSET_LOGLOBBYINFO_BODY)),
+    dependency("lobby_members", FunctionRef::Name("logUserJoined", 0), offset!(lobby_members::OFFSET_LOGUSER_JOINED_LEFT)),
+    dependency("lobby_members", FunctionRef::Name("logUserLeft", 0), offset!(lobby_members::OFFSET_LOGUSER_JOINED_LEFT)),
+    dependency("lobby_members", FunctionRef::Name("logJoinLobby", 0), None),
+    dependency("lore_hook", FunctionRef::Name("playUnlockAnim", 0), None),
+    dependency("winrate_tracker", FunctionRef::Path("ui.win.EndGame.init"), offset!(winrate_tracker::INIT_OFFSET)),
+    dependency("winrate_tracker", FunctionRef::Name("getTeamPlayerCount", 0), None),
+    dependency("winrate_tracker", FunctionRef::Name("defeat", 0), None),
+    dependency("winrate_tracker", FunctionRef::Name("defaultVictory", 0), None),
+    dependency("winrate_tracker", FunctionRef::Name("escapeBifrostVictory", 0), None),
+    dependency("winrate_tracker", FunctionRef::Name("fameVictory", 0), None),
+    dependency("winrate_tracker", FunctionRef::Name("helheimVictory", 0), None),
+    dependency("winrate_tracker", FunctionRef::Name("faithVictory", 0), None),
+    dependency("winrate_tracker", FunctionRef::Name("loreVictory", 0), None),
+    dependency("winrate_tracker", FunctionRef::Name("mealSquirrelVictory", 0), None),
+    dependency("winrate_tracker", FunctionRef::Name("odinSwordVictory", 0), None),
+    dependency("winrate_tracker", FunctionRef::Name("moneyVictory", 0), None),
+    dependency("winrate_tracker", FunctionRef::Name("owlTitanVictory", 0), None),
+    dependency("winrate_tracker", FunctionRef::Name("yggdrasilVictory", 0), None),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionDiff {
+    pub module: &'static str,
+    pub function: FunctionRef,
+    pub offset: Option<(&'static str, usize)>,
+    pub old_findex: Option<usize>,
+    pub new_findex: Option<usize>,
+    pub signature_changed: bool,
+    pub registers_changed: bool,
+    /// Index of the first different opcode
+    pub first_opcode_change: Option<usize>,
+    pub old_opcodes: usize,
+    pub new_opcodes: usize,
+}
+
+impl FunctionDiff {
+    pub fn is_missing(&self) -> bool {
+        self.old_findex.is_none() || self.new_findex.is_none()
+    }
+
+    pub fn is_moved(&self) -> bool {
+        !self.is_missing() && self.old_findex != self.new_findex
+    }
+
+    pub fn is_changed(&self) -> bool {
+        self.signature_changed || self.registers_changed || self.first_opcode_change.is_some()
+    }
+
+    /// The module must be checked (offsets or lookups may be wrong)
+    pub fn needs_attention(&self) -> bool {
+        self.is_missing() || self.is_changed()
+    }
+
+    pub fn status(&self) -> &'static str {
+        if self.is_missing() {
+            "MISSING"
+        } else if self.is_changed() {
+            "CHANGED"
+        } else if self.is_moved() {
+            "MOVED"
+        } else {
+            "OK"
+        }
+    }
+}
+
+/// Fully-qualified path of a function, same format as `HLFunction::path`
+fn function_path(bytecode: &Bytecode, function: &Function) -> String {
+    let name = function.name(bytecode);
+    match function.parent.and_then(|parent| parent.as_obj(bytecode)) {
+        Some(obj) => format!("{}.{}", obj.name(bytecode).trim_start_matches('$'), name),
+        None => name.to_string(),
+    }
+}
+
+pub fn find_function(bytecode: &Bytecode, function: FunctionRef) -> Option<&Function> {
+    match function {
+        FunctionRef::Name(name, index) => bytecode.functions
+            .iter()
+            .filter(|f| f.name(bytecode) == name)
+            .nth(index),
+        FunctionRef::Path(path) => bytecode.functions
+            .iter()
+            .find(|f| function_path(bytecode, f) == path),
+    }
+}
+
+/// Type indices differ between versions, so types are compared by their display names
+fn type_names(bytecode: &Bytecode, function: &Function) -> Vec<String> {
+    function.regs
+        .iter()
+        .map(|reg| bytecode[*reg].display::<EnhancedFmt>(bytecode).to_string())
+        .collect()
+}
+
+/// Opcodes with their operands. Function and global indices move between versions,
+/// so their `@index` is dropped and calls are compared by function name
+fn opcode_texts(bytecode: &Bytecode, function: &Function) -> Vec<String> {
+    function.ops
+        .iter()
+        .enumerate()
+        .map(|(i, op)| {
+            let text = op.display(bytecode, function, i as i32, 0).to_string();
+            let mut normalized = String::with_capacity(text.len());
+            let mut chars = text.chars().peekable();
+            while let Some(c) = chars.next() {
+                if c == '@' && chars.peek().is_some_and(char::is_ascii_digit) {
+                    while chars.next_if(char::is_ascii_digit).is_some() {}
+                } else {
+                    normalized.push(c);
+                }
+            }
+            normalized
+        })
+        .collect()
+}
+
+pub fn diff_function(old: &Bytecode, new: &Bytecode, dependency: &FunctionDependency) -> FunctionDiff {
+    let old_function = find_function(old, dependency.function);
+    let new_function = find_function(new, dependency.function);
+
+    let mut diff = FunctionDiff {
+        module: dependency.module,
+        function: dependency.function,
+        offset: dependency.offset,
+        old_findex: old_function.map(|f| f.findex.0),
+        new_findex: new_function.map(|f| f.findex.0),
+        signature_changed: false,
+        registers_changed: false,
+        first_opcode_change: None,
+        old_opcodes: old_function.map_or(0, |f| f.ops.len()),
+        new_opcodes: new_function.map_or(0, |f| f.ops.len()),
+    };
+
+    if let (Some(old_function), Some(new_function)) = (old_function, new_function) {
+        diff.signature_changed = old[old_function.t].display::<EnhancedFmt>(old).to_string()
+            != new[new_function.t].display::<EnhancedFmt>(new).to_string();
+        diff.registers_changed = type_names(old, old_function) != type_names(new, new_function);
+        diff.first_opcode_change = opcode_texts(old, old_function)
+            .iter()
+            .zip(&opcode_texts(new, new_function))
+            .position(|(a, b)| a != b)
+            .or_else(|| (diff.old_opcodes != diff.new_opcodes).then_some(diff.old_opcodes.min(diff.new_opcodes)));
+    }
+
+    diff
+}
+
+pub fn diff_dependencies(old: &Bytecode, new: &Bytecode, dependencies: &[FunctionDependency]) -> Vec<FunctionDiff> {
+    dependencies
+        .iter()
+        .map(|dependency| diff_function(old, new, dependency))
+        .collect()
+}
+
+/// Human readable report, one line per function followed by the list of modules to check
+pub fn format_report(diffs: &[FunctionDiff]) -> String {
+    let mut report = String::new();
+    for diff in diffs {
+        let _ = write!(report, "[{:<7}] {:<16} {}", diff.status(), diff.module, diff.function);
+        match (diff.old_findex, diff.new_findex) {
+            (Some(old), Some(new)) if old != new => { let _ = write!(report, " (findex {} -> {})", old, new); }
+            (Some(findex), Some(_)) => { let _ = write!(report, " (findex {})", findex); }
+            (None, _) => report.push_str(" (not in old file)"),
+            (_, None) => report.push_str(" (not in new file)"),
+        }
+        if diff.signature_changed {
+            report.push_str(", signature changed");
+        }
+        if diff.registers_changed {
+            report.push_str(", registers changed");
+        }
+        if let Some(index) = diff.first_opcode_change {
+            let _ = write!(report, ", opcodes differ from #{} ({} -> {} opcodes)", index, diff.old_opcodes, diff.new_opcodes);
+        }
+        if let (Some((name, value)), true) = (diff.offset, diff.needs_attention()) {
+            let _ = write!(report, ", check {} = {}", name, value);
+        }
+        report.push('\n');
+    }
+
+    let mut modules: Vec<&str> = Vec::new();
+    for diff in diffs.iter().filter(|diff| diff.needs_attention()) {
+        if !modules.contains(&diff.module) {
+            modules.push(diff.module);
+        }
+    }
+    if modules.is_empty() {
+        report.push_str("\nAll modules are up to date\n");
+    } else {
+        let _ = writeln!(report, "\nModules needing attention: {}", modules.join(", "));
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hlbc::opcodes::Opcode;
+    use hlbc::types::{RefFun, RefString, RefType, Reg, Type, TypeFun};
+
+    fn function(name: usize, findex: usize, regs: Vec<RefType>, ops: Vec<Opcode>) -> Function {
+        Function {
+            name: RefString(name),
+            t: RefType(2),
+            findex: RefFun(findex),
+            regs,
+            ops,
+            debug_info: None,
+            assigns: None,
+            parent: None,
+        }
+    }
+
+    fn bytecode(functions: Vec<Function>) -> Bytecode {
+        let mut bytecode = Bytecode::default();
+        bytecode.strings = ["", "canReady", "defeat", "logJoinLobby"].iter().map(|s| (*s).into()).collect();
+        bytecode.types = vec![
+            Type::Void,
+            Type::I32,
+            Type::Fun(TypeFun { args: vec![RefType(1)], ret: RefType(0) }),
+        ];
+        bytecode.functions = functions;
+        bytecode
+    }
+
+    const DEPENDENCIES: &[FunctionDependency] = &[
+        dependency("auto_lockin", FunctionRef::Name("canReady", 0), Some(("OFFSET_CANREADY_END", 182))),
+        dependency("winrate_tracker", FunctionRef::Name("defeat", 0), None),
+        dependency("lobby_members", FunctionRef::Name("logJoinLobby", 0), None),
+    ];
+
+    #[test]
+    fn test_diff_dependencies() {
+        let ret = || Opcode::Ret { ret: Reg(0) };
+        let mov = || Opcode::Mov { dst: Reg(0), src: Reg(0) };
+        let old = bytecode(vec![
+            function(1, 10, vec![RefType(1)], vec![mov(), ret()]),
+            function(2, 11, vec![RefType(1)], vec![ret()]),
+            function(3, 12, vec![RefType(1)], vec![ret()]),
+        ]);
+        let new = bytecode(vec![
+            function(1, 10, vec![RefType(1), RefType(1)], vec![mov(), mov(), ret()]),
+            function(2, 15, vec![RefType(1)], vec![ret()]),
+        ]);
+
+        let diffs = diff_dependencies(&old, &new, DEPENDENCIES);
+        assert_eq!(diffs[0].status(), "CHANGED");
+        assert!(diffs[0].registers_changed);
+        assert_eq!(diffs[0].first_opcode_change, Some(1));
+        assert_eq!(diffs[1].status(), "MOVED");
+        assert!(!diffs[1].needs_attention());
+        assert_eq!(diffs[2].status(), "MISSING");
+
+        // Same opcode names, different operands
+        let new = bytecode(vec![
+            function(1, 10, vec![RefType(1)], vec![Opcode::Mov { dst: Reg(0), src: Reg(1) }, ret()]),
+        ]);
+        let diff = diff_function(&old, &new, &DEPENDENCIES[0]);
+        assert_eq!(diff.first_opcode_change, Some(0));
+
+        let report = format_report(&diffs);
+        assert!(report.contains("check OFFSET_CANREADY_END = 182"));
+        assert!(report.contains("Modules needing attention: auto_lockin, lobby_members"));
+    }
+}
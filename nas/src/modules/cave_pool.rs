@@ -0,0 +1,268 @@
+/*
+    Pages shared by every hook cave and variable block we allocate in the game. A pool reserves
+    a few executable and data pages with `ProcessMemory::allocate` and splits them into aligned
+    blocks, a page is released once its last block is freed. Code blocks requested near an
+    address stay within reach of a rel32 jump from it.
+    There is one pool per memory backend, `CavePool::all` lists them with their blocks.
+*/
+
+use crate::utils::process_memory::{ProcessMemory, PAGE_EXECUTE_READWRITE, PAGE_READWRITE};
+use std::collections::HashMap;
+use std::error::Error;
+use std::ops::Range;
+use std::sync::{Arc, Mutex, Weak};
+
+/// Size of the pages reserved by the pool, the allocation granularity of Windows
+const POOL_PAGE_SIZE: usize = 0x10000;
+const BLOCK_ALIGN: usize = 0x10;
+/// Distance covered by a rel32 jump, with some margin for the jump itself
+const NEAR_REACH: usize = 0x7FFF_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaveKind {
+    /// Executable, for injected code
+    Code,
+    /// Read-write, for variables
+    Data,
+}
+
+impl CaveKind {
+    fn protection(self) -> u32 {
+        match self {
+            CaveKind::Code => PAGE_EXECUTE_READWRITE,
+            CaveKind::Data => PAGE_READWRITE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaveBlock {
+    pub address: usize,
+    pub size: usize,
+    pub kind: CaveKind,
+    /// Who asked for the block, for listings
+    pub owner: String,
+}
+
+impl CaveBlock {
+    pub fn range(&self) -> Range<usize> {
+        self.address..self.address + self.size
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CavePage {
+    pub address: usize,
+    pub size: usize,
+    pub kind: CaveKind,
+    /// Sorted by address
+    pub blocks: Vec<CaveBlock>,
+}
+
+impl CavePage {
+    /// Bytes handed out to blocks
+    pub fn used(&self) -> usize {
+        self.blocks.iter().map(|block| block.size).sum()
+    }
+
+    /// First aligned gap of `size` bytes
+    fn find_gap(&self, size: usize) -> Option<usize> {
+        let mut start = self.address;
+        for block in &self.blocks {
+            if start + size <= block.address {
+                return Some(start);
+            }
+            start = (block.address + block.size).next_multiple_of(BLOCK_ALIGN);
+        }
+        (start + size <= self.address + self.size).then_some(start)
+    }
+
+    fn reaches(&self, near: Option<usize>) -> bool {
+        near.is_none_or(|near| {
+            self.address.abs_diff(near) < NEAR_REACH && (self.address + self.size).abs_diff(near) < NEAR_REACH
+        })
+    }
+}
+
+pub struct CavePool {
+    memory: Arc<dyn ProcessMemory>,
+    pages: Mutex<Vec<CavePage>>,
+}
+
+/// Live pools by memory backend
+static POOLS: once_cell::sync::Lazy<Mutex<HashMap<usize, Weak<CavePool>>>> =
+    once_cell::sync::Lazy::new(|| Mutex::new(HashMap::new()));
+
+impl CavePool {
+    pub fn new(memory: Arc<dyn ProcessMemory>) -> Self {
+        Self { memory, pages: Mutex::new(Vec::new()) }
+    }
+
+    /// Pool of `memory`, shared by everything allocating through the same backend.
+    /// Pages are not released when the pool is dropped, hooks that were never undone still use them
+    pub fn shared(memory: &Arc<dyn ProcessMemory>) -> Arc<Self> {
+        let key = Arc::as_ptr(memory) as *const () as usize;
+        let mut pools = POOLS.lock().unwrap();
+        pools.retain(|_, pool| pool.strong_count() > 0);
+        if let Some(pool) = pools.get(&key).and_then(Weak::upgrade) {
+            return pool;
+        }
+        let pool = Arc::new(Self::new(memory.clone()));
+        pools.insert(key, Arc::downgrade(&pool));
+        pool
+    }
+
+    /// Every live pool
+    pub fn all() -> Vec<Arc<Self>> {
+        POOLS.lock().unwrap().values().filter_map(Weak::upgrade).collect()
+    }
+
+    /// Reserve `size` zeroed bytes, in an existing page when one has room. Code blocks requested
+    /// `near` an address can be reached from it with a rel32 jump
+    pub fn allocate(&self, owner: &str, kind: CaveKind, size: usize, near: Option<usize>) -> Result<CaveBlock, Box<dyn Error>> {
+        let size = size.max(1).next_multiple_of(BLOCK_ALIGN);
+        let near = near.filter(|_| kind == CaveKind::Code);
+        let mut pages = self.pages.lock().unwrap();
+
+        let free = pages.iter_mut().find_map(|page| {
+            let address = (page.kind == kind && page.reaches(near)).then(|| page.find_gap(size)).flatten()?;
+            Some((page, address))
+        });
+        if let Some((page, address)) = free {
+            // New pages come zeroed, a reused gap still holds the code or variables of its last owner
+            self.memory.write_bytes(address, &vec![0; size])?;
+            let block = CaveBlock { address, size, kind, owner: owner.to_string() };
+            let position = page.blocks.partition_point(|other| other.address < address);
+            page.blocks.insert(position, block.clone());
+            return Ok(block);
+        }
+
+        let region = self.memory.allocate(size.next_multiple_of(POOL_PAGE_SIZE), kind.protection(), near)?;
+        let mut page = CavePage { address: region.base_address, size: region.region_size, kind, blocks: Vec::new() };
+        if !page.reaches(near) {
+            let _ = self.memory.free(page.address, page.size);
+            return Err(format!("Cannot allocate a cave near 0x{:X}: got 0x{:X}", near.unwrap_or(0), page.address).into());
+        }
+        tracing::debug!("New {:?} cave page at 0x{:X} ({:#x} bytes)", kind, page.address, page.size);
+
+        let block = CaveBlock { address: page.address, size, kind, owner: owner.to_string() };
+        page.blocks.push(block.clone());
+        pages.push(page);
+        Ok(block)
+    }
+
+    /// Give back the block at `address`, its page is released when it was the last one
+    pub fn free(&self, address: usize) -> Result<(), Box<dyn Error>> {
+        let mut pages = self.pages.lock().unwrap();
+        let (index, page) = pages
+            .iter_mut()
+            .enumerate()
+            .find(|(_, page)| page.blocks.iter().any(|block| block.address == address))
+            .ok_or_else(|| format!("No cave block at 0x{:X}", address))?;
+        page.blocks.retain(|block| block.address != address);
+
+        if page.blocks.is_empty() {
+            // Kept for reuse when the release fails
+            self.memory.free(page.address, page.size)?;
+            pages.remove(index);
+        }
+        Ok(())
+    }
+
+    /// Release every page, blocks still handed out included. Only for eject, once nothing
+    /// runs or reads in the caves anymore. Returns the number of pages released
+    pub fn release_all(&self) -> Result<usize, Box<dyn Error>> {
+        let mut pages = self.pages.lock().unwrap();
+        let mut released = 0;
+        while let Some(page) = pages.last() {
+            self.memory.free(page.address, page.size)?;
+            pages.pop();
+            released += 1;
+        }
+        Ok(released)
+    }
+
+    pub fn pages(&self) -> Vec<CavePage> {
+        self.pages.lock().unwrap().clone()
+    }
+
+    /// Every block handed out, by page
+    pub fn blocks(&self) -> Vec<CaveBlock> {
+        self.pages.lock().unwrap().iter().flat_map(|page| page.blocks.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::process_memory::{MockMemory, MEM_IMAGE, PAGE_EXECUTE_READ};
+
+    const SITE: usize = 0x1_4000_0010;
+
+    fn memory() -> Arc<dyn ProcessMemory> {
+        let memory = MockMemory::new();
+        memory.map(0x1_4000_0000, 0x1000, PAGE_EXECUTE_READ, MEM_IMAGE);
+        Arc::new(memory)
+    }
+
+    #[test]
+    fn test_blocks_share_pages() {
+        let memory = memory();
+        let pool = CavePool::shared(&memory);
+        assert!(Arc::ptr_eq(&pool, &CavePool::shared(&memory)));
+
+        let first = pool.allocate("hook 0x140000010", CaveKind::Code, 0x41, Some(SITE)).unwrap();
+        let second = pool.allocate("hook 0x140000020", CaveKind::Code, 0x20, Some(SITE + 0x10)).unwrap();
+        let variables = pool.allocate("GameCommon", CaveKind::Data, 0x1000, None).unwrap();
+        assert_eq!(first.size, 0x50);
+        assert_eq!(second.address, first.address + 0x50);
+        assert!(first.address.abs_diff(SITE) < NEAR_REACH);
+        assert_eq!(pool.pages().len(), 2);
+        // The image and the two pages
+        assert_eq!(memory.regions().unwrap().len(), 3);
+
+        // A site out of rel32 reach gets its own page
+        let far = pool.allocate("hook far", CaveKind::Code, 0x20, Some(0x7FF0_0000_0000)).unwrap();
+        assert!(far.address.abs_diff(0x7FF0_0000_0000) < NEAR_REACH);
+        assert_eq!(pool.pages().len(), 3);
+        pool.free(far.address).unwrap();
+
+        // Freed blocks are reused, empty pages released
+        pool.free(first.address).unwrap();
+        let third = pool.allocate("hook 0x140000030", CaveKind::Code, 0x30, Some(SITE)).unwrap();
+        assert_eq!(third.address, first.address);
+        let owners: Vec<_> = pool.blocks().into_iter().map(|block| block.owner).collect();
+        assert_eq!(owners, ["hook 0x140000030", "hook 0x140000020", "GameCommon"]);
+
+        pool.free(second.address).unwrap();
+        pool.free(third.address).unwrap();
+        pool.free(variables.address).unwrap();
+        assert!(pool.free(variables.address).is_err());
+        assert!(pool.pages().is_empty());
+        assert_eq!(memory.regions().unwrap().len(), 1);
+
+        // Eject releases the pages still in use
+        pool.allocate("hook 0x140000010", CaveKind::Code, 0x20, Some(SITE)).unwrap();
+        pool.allocate("GameCommon", CaveKind::Data, 0x20, None).unwrap();
+        assert_eq!(pool.release_all().unwrap(), 2);
+        assert!(pool.blocks().is_empty());
+        assert_eq!(memory.regions().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_reused_blocks_are_zeroed() {
+        let memory = memory();
+        let pool = CavePool::new(memory.clone());
+        let first = pool.allocate("LobbyMembers", CaveKind::Data, 0x20, None).unwrap();
+        let second = pool.allocate("GameCommon", CaveKind::Data, 0x20, None).unwrap();
+        memory.write_bytes(first.address, &[0xAB; 0x20]).unwrap();
+
+        pool.free(first.address).unwrap();
+        let reused = pool.allocate("LobbyMembers", CaveKind::Data, 0x18, None).unwrap();
+        assert_eq!(reused.address, first.address);
+        assert_eq!(memory.read_bytes(reused.address, 0x20).unwrap(), [0; 0x20]);
+
+        pool.free(second.address).unwrap();
+        pool.free(reused.address).unwrap();
+    }
+}
@@ -0,0 +1,347 @@
+/*
+    Hooks handled by a Rust closure instead of hand-written assembly. The stub saves every
+    register into a `HookContext` on the game stack, calls the closure with it and loads the
+    registers back, changes included:
+
+        let hook = ContextHook::new(&memory, address, |context| {
+            tracing::info!("joined lobby {:#x}", context.arg(0));
+            HookAction::Continue
+        })?;
+
+    The closure runs on the game thread that reached the hook, so context hooks only work in
+    the process the crate is loaded into.
+*/
+
+use crate::modules::eject;
+use crate::modules::hook_monitor::{self, InjectionSlot, TamperPolicy};
+use crate::modules::libmem_injection::{LibmemInjection, XMM_REGISTERS};
+use crate::utils::process_memory::ProcessMemory;
+use iced_x86::code_asm::*;
+use std::collections::HashMap;
+use std::error::Error;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Registers of the game thread, in the order the stub pushes them
+#[repr(C)]
+#[derive(Debug)]
+pub struct HookContext {
+    /// XMM0-15, low quadword first
+    pub xmm: [[u64; 2]; 16],
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rax: u64,
+    pub rflags: u64,
+    /// Where the stub jumps once the registers are restored
+    resume: u64,
+}
+
+const XMM_AREA: i32 = 0x100;
+const RESUME_OFFSET: i32 = 0x180;
+
+impl HookContext {
+    /// RSP of the game thread when it reached the hook, the context sits right below it
+    pub fn rsp(&self) -> usize {
+        self as *const Self as usize + std::mem::size_of::<Self>()
+    }
+
+    /// Integer argument `index` of the hooked function, only meaningful for a hook at the
+    /// function entry. Follows the Windows x64 convention, HL functions use it too
+    pub fn arg(&self, index: usize) -> u64 {
+        match index {
+            0 => self.rcx,
+            1 => self.rdx,
+            2 => self.r8,
+            3 => self.r9,
+            // Return address, shadow space, then the stack arguments
+            _ => unsafe { *((self.rsp() + 8 + index * 8) as *const u64) },
+        }
+    }
+
+    /// Floating point argument `index` (0 to 3) of the hooked function
+    pub fn float_arg(&self, index: usize) -> f64 {
+        f64::from_bits(self.xmm[index][0])
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookAction {
+    /// Run the overwritten instructions and the rest of the game code
+    Continue,
+    /// Return to the caller with RAX set to the value, only valid for a hook at the function entry
+    Return(u64),
+}
+
+type Handler = dyn Fn(&mut HookContext) -> HookAction + Send + Sync;
+
+static HANDLERS: once_cell::sync::Lazy<Mutex<HashMap<usize, Arc<Handler>>>> =
+    once_cell::sync::Lazy::new(|| Mutex::new(HashMap::new()));
+static NEXT_HANDLER_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Called by the stubs, returns 1 for an early return. Unknown ids continue, the handler may
+/// have been unregistered while a thread was already inside the stub
+extern "win64" fn dispatch(id: usize, context: *mut HookContext) -> u64 {
+    let handler = HANDLERS.lock().unwrap().get(&id).cloned();
+    let Some(handler) = handler else {
+        return 0;
+    };
+    let context = unsafe { &mut *context };
+    // Unwinding into game code is undefined behavior
+    match std::panic::catch_unwind(AssertUnwindSafe(|| handler(context))) {
+        Ok(HookAction::Continue) => 0,
+        Ok(HookAction::Return(value)) => {
+            context.rax = value;
+            1
+        }
+        Err(_) => {
+            tracing::error!("Context hook handler {} panicked", id);
+            0
+        }
+    }
+}
+
+/// Closure callable from a stub, unregistered when dropped
+pub struct ContextHandler {
+    id: usize,
+}
+
+impl ContextHandler {
+    pub fn register<F>(handler: F) -> Self
+    where
+        F: Fn(&mut HookContext) -> HookAction + Send + Sync + 'static,
+    {
+        let id = NEXT_HANDLER_ID.fetch_add(1, Ordering::Relaxed);
+        HANDLERS.lock().unwrap().insert(id, Arc::new(handler));
+        Self { id }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Payload calling the handler, for a `LibmemInjection` or an `InjectionTransaction`.
+    /// The injection must be removed before the handler is dropped
+    pub fn code(&self) -> Result<CodeAssembler, Box<dyn Error>> {
+        let mut code = CodeAssembler::new(64)?;
+        let mut label_return = code.create_label();
+        let mut label_continue = code.create_label();
+        let mut label_restore = code.create_label();
+
+        // Slot for the resume address, then the flags and registers in `HookContext` order
+        code.push(rax)?;
+        code.pushfq()?;
+        for register in [rax, rcx, rdx, rbx, rbp, rsi, rdi, r8, r9, r10, r11, r12, r13, r14, r15] {
+            code.push(register)?;
+        }
+        code.sub(rsp, XMM_AREA)?;
+        for (index, register) in XMM_REGISTERS.into_iter().enumerate() {
+            code.movups(oword_ptr(rsp + index * 0x10), register)?;
+        }
+
+        // RBX is non-volatile, it keeps the context across the call
+        code.mov(rbx, rsp)?;
+        code.lea(rax, ptr(label_continue))?;
+        code.mov(qword_ptr(rbx + RESUME_OFFSET), rax)?;
+        code.mov(rcx, self.id as u64)?;
+        code.mov(rdx, rbx)?;
+        code.and(rsp, -0x10)?;
+        code.sub(rsp, 0x20)?;
+        code.mov(rax, dispatch as *const () as usize as u64)?;
+        code.call(rax)?;
+        code.mov(rsp, rbx)?;
+
+        code.test(eax, eax)?;
+        code.jz(label_restore)?;
+        code.lea(rax, ptr(label_return))?;
+        code.mov(qword_ptr(rbx + RESUME_OFFSET), rax)?;
+
+        code.set_label(&mut label_restore)?;
+        for (index, register) in XMM_REGISTERS.into_iter().enumerate() {
+            code.movups(register, oword_ptr(rsp + index * 0x10))?;
+        }
+        code.add(rsp, XMM_AREA)?;
+        for register in [r15, r14, r13, r12, r11, r10, r9, r8, rdi, rsi, rbp, rbx, rdx, rcx, rax] {
+            code.pop(register)?;
+        }
+        code.popfq()?;
+        // Pops the resume slot
+        code.ret()?;
+
+        code.set_label(&mut label_return)?;
+        code.ret()?;
+
+        // The relocated instructions follow the payload
+        code.set_label(&mut label_continue)?;
+        code.zero_bytes()?;
+        Ok(code)
+    }
+}
+
+impl Drop for ContextHandler {
+    fn drop(&mut self) {
+        HANDLERS.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Injection running a closure, removed when dropped or on eject
+pub struct ContextHook {
+    address: usize,
+    injection: InjectionSlot,
+    // Dropped after the injection is undone, kept registered while the stub can still call it
+    handler: Option<ContextHandler>,
+}
+
+impl ContextHook {
+    pub fn new<F>(memory: &Arc<dyn ProcessMemory>, address: usize, handler: F) -> Result<Self, Box<dyn Error>>
+    where
+        F: Fn(&mut HookContext) -> HookAction + Send + Sync + 'static,
+    {
+        let handler = ContextHandler::register(handler);
+        let injection: InjectionSlot = Arc::new(Mutex::new(Some(LibmemInjection::new(memory, address, &mut handler.code()?)?)));
+        let name = format!("context hook 0x{:X}", address);
+        hook_monitor::instance().watch(&name, &injection, TamperPolicy::default());
+        eject::instance().register(&name, &injection);
+        Ok(Self { address, injection, handler: Some(handler) })
+    }
+
+    pub fn address(&self) -> usize {
+        self.address
+    }
+}
+
+impl Drop for ContextHook {
+    /// Waits until no thread runs the stub or the handler, the undo checks the instruction
+    /// pointers and the return addresses on the stacks. The calling thread is not checked, a
+    /// handler must never drop its own hook. A handler that does not return in time leaves the
+    /// hook to eject
+    fn drop(&mut self) {
+        let mut injection = self.injection.lock().unwrap();
+        let Some(inj) = injection.as_ref() else {
+            return;
+        };
+        match inj.undo() {
+            Ok(()) => *injection = None,
+            Err(e) => {
+                tracing::error!("Context hook at 0x{:X} not removed: {}", self.address, e);
+                // Left to eject, the stub keeps calling the handler until then
+                drop(injection);
+                std::mem::forget(self.injection.clone());
+                std::mem::forget(self.handler.take());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::process_memory::{MockMemory, MEM_IMAGE, PAGE_EXECUTE_READ};
+    use iced_x86::{Decoder, DecoderOptions, Mnemonic, OpKind, Register};
+
+    const SITE: usize = 0x1_4000_0010;
+
+    fn context() -> HookContext {
+        HookContext {
+            xmm: [[0; 2]; 16],
+            r15: 0,
+            r14: 0,
+            r13: 0,
+            r12: 0,
+            r11: 0,
+            r10: 0,
+            r9: 4,
+            r8: 3,
+            rdi: 0,
+            rsi: 0,
+            rbp: 0,
+            rbx: 0,
+            rdx: 2,
+            rcx: 1,
+            rax: 0,
+            rflags: 0,
+            resume: 0,
+        }
+    }
+
+    #[test]
+    fn test_context_layout() {
+        assert_eq!(std::mem::offset_of!(HookContext, r15), XMM_AREA as usize);
+        assert_eq!(std::mem::offset_of!(HookContext, rax), 0x170);
+        assert_eq!(std::mem::offset_of!(HookContext, resume), RESUME_OFFSET as usize);
+        assert_eq!(std::mem::size_of::<HookContext>(), 0x188);
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let handler = ContextHandler::register(|context| {
+            context.rbx = context.arg(0) + context.arg(3);
+            if context.float_arg(1) > 1.0 {
+                HookAction::Return(7)
+            } else {
+                HookAction::Continue
+            }
+        });
+
+        let mut context = context();
+        assert_eq!(dispatch(handler.id(), &mut context), 0);
+        assert_eq!(context.rbx, 5);
+        context.xmm[1][0] = 2.5f64.to_bits();
+        assert_eq!(dispatch(handler.id(), &mut context), 1);
+        assert_eq!(context.rax, 7);
+
+        let panicking = ContextHandler::register(|_| panic!("handler bug"));
+        assert_eq!(dispatch(panicking.id(), &mut context), 0);
+
+        let id = handler.id();
+        drop(handler);
+        context.rax = 0;
+        assert_eq!(dispatch(id, &mut context), 0);
+        assert_eq!(context.rax, 0);
+    }
+
+    #[test]
+    fn test_stub_and_hook() {
+        let mock = MockMemory::new();
+        mock.map(0x1_4000_0000, 0x1000, PAGE_EXECUTE_READ, MEM_IMAGE);
+        // mov rax, rcx; add rax, 5; ret
+        let original = [0x48, 0x89, 0xC8, 0x48, 0x83, 0xC0, 0x05, 0xC3];
+        mock.write_bytes(SITE, &original).unwrap();
+        let memory: Arc<dyn ProcessMemory> = Arc::new(mock);
+
+        let hook = ContextHook::new(&memory, SITE, |_| HookAction::Continue).unwrap();
+        assert_eq!(hook.address(), SITE);
+        assert_eq!(memory.read_u8(SITE).unwrap(), 0xE9);
+
+        let handler = ContextHandler::register(|_| HookAction::Continue);
+        let bytes = handler.code().unwrap().assemble(0x1000).unwrap();
+        let instructions: Vec<_> = Decoder::with_ip(64, &bytes, 0x1000, DecoderOptions::NONE).into_iter().collect();
+        let mnemonics: Vec<_> = instructions.iter().map(|instruction| instruction.mnemonic()).collect();
+        let pushes = mnemonics.iter().take_while(|&&mnemonic| matches!(mnemonic, Mnemonic::Push | Mnemonic::Pushfq)).count();
+        let pops = mnemonics.iter().filter(|&&mnemonic| matches!(mnemonic, Mnemonic::Pop | Mnemonic::Popfq)).count();
+        assert_eq!(pushes * 8 + XMM_AREA as usize, std::mem::size_of::<HookContext>());
+        assert_eq!(pops, pushes - 1);
+        assert!(instructions.iter().any(|instruction| {
+            instruction.mnemonic() == Mnemonic::Mov
+                && instruction.op0_register() == Register::RCX
+                && instruction.op1_kind() == OpKind::Immediate64
+                && instruction.immediate64() == handler.id() as u64
+        }));
+        assert_eq!(&mnemonics[mnemonics.len() - 2..], [Mnemonic::Ret, Mnemonic::Ret]);
+
+        drop(hook);
+        assert_eq!(memory.read_bytes(SITE, 8).unwrap(), original);
+    }
+}
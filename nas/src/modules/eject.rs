@@ -0,0 +1,154 @@
+/*
+    Unload path. Every injection slot is registered here and every remote allocation belongs to a
+    `CavePool`, so `eject` can find whatever the modules left behind: it stops the hook monitor,
+    restores the game code, then releases the pools. Once it reports a clean state the DLL can be
+    unloaded and a new build injected without restarting the game.
+*/
+
+use crate::modules::cave_pool::CavePool;
+use crate::modules::hook_monitor::{self, InjectionSlot};
+use crate::modules::libmem_injection::LibmemInjection;
+use std::sync::{Arc, Mutex, Weak};
+
+struct RegisteredInjection {
+    name: String,
+    slot: Weak<Mutex<Option<LibmemInjection>>>,
+}
+
+#[derive(Debug, Default)]
+pub struct EjectReport {
+    /// Injections still applied that were restored
+    pub undone: Vec<String>,
+    /// What could not be undone or released, with the reason
+    pub failed: Vec<(String, String)>,
+    /// Cave pages given back to the process
+    pub released_pages: usize,
+}
+
+impl EjectReport {
+    /// Nothing of ours is left in the game code, the DLL can be unloaded
+    pub fn is_clean(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+#[derive(Default)]
+pub struct EjectRegistry {
+    injections: Mutex<Vec<RegisteredInjection>>,
+}
+
+impl EjectRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Track the injection in `slot` until the slot is dropped
+    pub fn register(&self, name: &str, slot: &InjectionSlot) {
+        let mut injections = self.injections.lock().unwrap();
+        injections.retain(|injection| injection.slot.strong_count() > 0);
+        injections.push(RegisteredInjection { name: name.to_string(), slot: Arc::downgrade(slot) });
+    }
+
+    /// Name and address of every applied injection
+    pub fn applied(&self) -> Vec<(String, usize)> {
+        self.live()
+            .into_iter()
+            .filter_map(|(name, slot)| {
+                let address = slot.lock().unwrap().as_ref()?.address();
+                Some((name, address))
+            })
+            .collect()
+    }
+
+    /// Undo every applied injection, the ones that fail stay applied
+    pub fn undo_all(&self) -> EjectReport {
+        let mut report = EjectReport::default();
+        for (name, slot) in self.live() {
+            let mut injection = slot.lock().unwrap();
+            let Some(inj) = injection.as_ref() else {
+                continue;
+            };
+            match inj.undo() {
+                Ok(()) => {
+                    *injection = None;
+                    report.undone.push(name);
+                }
+                Err(e) => report.failed.push((name, e.to_string())),
+            }
+        }
+        report
+    }
+
+    fn live(&self) -> Vec<(String, InjectionSlot)> {
+        let mut injections = self.injections.lock().unwrap();
+        injections.retain(|injection| injection.slot.strong_count() > 0);
+        injections
+            .iter()
+            .filter_map(|injection| Some((injection.name.clone(), injection.slot.upgrade()?)))
+            .collect()
+    }
+}
+
+pub fn instance() -> &'static Arc<EjectRegistry> {
+    static INSTANCE: once_cell::sync::Lazy<Arc<EjectRegistry>> = once_cell::sync::Lazy::new(|| Arc::new(EjectRegistry::new()));
+    &INSTANCE
+}
+
+/// Stop the hook monitor, undo the injections left applied and release every cave pool.
+/// The pools are kept when an injection could not be undone, its cave may still be running
+pub fn eject() -> EjectReport {
+    hook_monitor::instance().stop();
+    let mut report = instance().undo_all();
+    if !report.is_clean() {
+        return report;
+    }
+
+    for pool in CavePool::all() {
+        for block in pool.blocks() {
+            tracing::info!("Releasing {} at 0x{:X} ({:#x} bytes)", block.owner, block.address, block.size);
+        }
+        match pool.release_all() {
+            Ok(pages) => report.released_pages += pages,
+            Err(e) => report.failed.push(("cave pool".to_string(), e.to_string())),
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::process_memory::{MockMemory, ProcessMemory, MEM_IMAGE, PAGE_EXECUTE_READ};
+    use iced_x86::code_asm::*;
+
+    #[test]
+    fn test_undo_all() {
+        let mock = MockMemory::new();
+        mock.map(0x1_4000_0000, 0x1000, PAGE_EXECUTE_READ, MEM_IMAGE);
+        // mov rax, rcx; add rax, 5; ret
+        let original = [0x48, 0x89, 0xC8, 0x48, 0x83, 0xC0, 0x05, 0xC3];
+        mock.write_bytes(0x1_4000_0010, &original).unwrap();
+        let memory: Arc<dyn ProcessMemory> = Arc::new(mock);
+
+        let mut code = CodeAssembler::new(64).unwrap();
+        code.nop().unwrap();
+        let slot: InjectionSlot = Arc::new(Mutex::new(Some(LibmemInjection::new(&memory, 0x1_4000_0010, &mut code).unwrap())));
+        let empty: InjectionSlot = Arc::new(Mutex::new(None));
+        let registry = EjectRegistry::new();
+        registry.register("setCheckedJoin", &slot);
+        registry.register("getwidth", &empty);
+        assert_eq!(registry.applied(), [("setCheckedJoin".to_string(), 0x1_4000_0010)]);
+
+        let report = registry.undo_all();
+        assert!(report.is_clean());
+        assert_eq!(report.undone, ["setCheckedJoin"]);
+        assert!(slot.lock().unwrap().is_none());
+        assert_eq!(memory.read_bytes(0x1_4000_0010, 8).unwrap(), original);
+        assert_eq!(memory.regions().unwrap().len(), 1);
+        assert!(registry.applied().is_empty());
+
+        drop(slot);
+        drop(empty);
+        assert!(registry.live().is_empty());
+    }
+}
@@ -1,50 +1,63 @@
-use crate::modules::basic::*;
-use crate::modules::libmem_injection::LibmemInjection;
+use crate::modules::base::InjectionManager;
 use crate::modules::mem_alloc::*;
 use crate::modules::hashlink::*;
+use crate::modules::remote_struct::RemoteStruct;
 use iced_x86::code_asm::*;
 use std::error::Error;
-use std::sync::Mutex;
-use crate::utils::libmem_ex::get_target_process;
-use libmem::Process;
+use std::sync::Arc;
+use crate::utils::process_memory::ProcessMemory;
+
+/// Offsets of the injections at the end of `get_width` and `get_height`
+pub(crate) const OFFSET_GETWIDTH_END: usize = 18;
+pub(crate) const OFFSET_GETHEIGHT_END: usize = 18;
+
+/// Window size written by the `get_width` and `get_height` hooks, allocated as one variable
+#[derive(Debug, Clone, Copy, PartialEq, RemoteStruct)]
+pub struct WindowSize {
+    #[remote(offset = 0x0)]
+    pub width: u32,
+    #[remote(offset = 0x4)]
+    pub height: u32,
+}
 
 #[allow(dead_code)]
 pub struct GameCommon {
     pid: u32,
-    process: Process,
+    memory: Arc<dyn ProcessMemory>,
 
     // fn get_width@590 (hxd.Window) -> i32 (2 regs, 2 ops)
     address_getwidth: usize,
     // fn get_height@591 (hxd.Window) -> i32 (2 regs, 2 ops)
     address_getheight: usize,
 
-    // i32
+    // i32, `WindowSize::width`
     var_ptr_winwidth: usize,
-    // i32
+    // i32, `WindowSize::height`
     var_ptr_winheight: usize,
 
-    injection_getwidth: Mutex<Option<LibmemInjection>>,
-    injection_getheight: Mutex<Option<LibmemInjection>>,
+    injection_manager: InjectionManager,
 
     mem_allocator: MemoryAllocator,
 }
 
 impl GameCommon {
-    pub fn new(pid: u32) -> Result<Self, Box<dyn Error>> {
-        let mut memory_allocator = MemoryAllocator::new(pid, 0x1000)?;
-        let var_ptr_windowwidth = memory_allocator.allocate_var("WindowWidth", DataType::U32)?;
-        let var_ptr_windowheight = memory_allocator.allocate_var("WindowHeight", DataType::U32)?;
-        let process = get_target_process(pid).ok_or("Failed to get process with libmem")?;
+    pub fn new(pid: u32, memory: Arc<dyn ProcessMemory>) -> Result<Self, Box<dyn Error>> {
+        let mut memory_allocator = MemoryAllocator::new(memory.clone(), "GameCommon", 0x1000)?;
+        let var_ptr_windowsize = memory_allocator.allocate_var_with_size("WindowSize", DataType::ByteArray, WindowSize::SIZE)?;
+
+        let mut injection_manager = InjectionManager::new(memory.clone(), "GameCommon");
+        injection_manager.add_injection("getwidth".to_string());
+        injection_manager.add_injection("getheight".to_string());
 
         let mut game_common = Self {
             pid,
-            var_ptr_winwidth: var_ptr_windowwidth,
-            var_ptr_winheight: var_ptr_windowheight,
+            var_ptr_winwidth: var_ptr_windowsize,
+            // `#[remote(offset = 0x4)]` of `WindowSize::height`
+            var_ptr_winheight: var_ptr_windowsize + 0x4,
             address_getwidth: 0,
             address_getheight: 0,
-            process,
-            injection_getwidth: Mutex::new(None),
-            injection_getheight: Mutex::new(None),
+            memory,
+            injection_manager,
             mem_allocator: memory_allocator,
         };
 
@@ -54,13 +67,10 @@ impl GameCommon {
     }
 
     pub fn init_game_common(&mut self) -> Result<(), Box<dyn Error>> {
-        const OFFSET_GETWIDTH_END: usize = 18;
-        const OFFSET_GETHEIGHT_END: usize = 18;
-
         let guard = Hashlink::instance(self.pid).lock().unwrap();
         if let Some(hashlink) = guard.as_ref() {
-            self.address_getwidth = hashlink.get_function_address("get_width", Some(2))? + OFFSET_GETWIDTH_END;
-            self.address_getheight = hashlink.get_function_address("get_height", Some(1))? + OFFSET_GETHEIGHT_END;
+            self.address_getwidth = hashlink.get_function_address_by_path("hxd.Window.get_width")? + OFFSET_GETWIDTH_END;
+            self.address_getheight = hashlink.get_function_address_by_path("hxd.Window.get_height")? + OFFSET_GETHEIGHT_END;
         } else {
             return Err("Hashlink not initialized".into());
         }
@@ -69,63 +79,37 @@ impl GameCommon {
     }
 
     pub fn game_common_apply(&mut self, enable: bool) -> Result<(), Box<dyn Error>> {
-        let mut injection_getwidth = self.injection_getwidth.lock().unwrap();
-        let mut injection_getheight = self.injection_getheight.lock().unwrap();
-
         if enable {
-            if injection_getwidth.is_none() {
-                let mut code = CodeAssembler::new(64)?;
-                code.pushfq()?;
-                code.push(rax)?;
-                code.push(rbx)?;
-                code.mov(rbx, self.var_ptr_winwidth as u64)?;
-                code.mov(dword_ptr(rbx), eax)?;
-                code.pop(rbx)?;
-                code.pop(rax)?;
-                code.popfq()?;
-
-                *injection_getwidth = Some(LibmemInjection::new(self.pid, self.address_getwidth, &mut code, &self.process)?);
-                tracing::info!("Successfully injected: getwidth at 0x{:X}", self.address_getwidth);
-            }
-
-            if injection_getheight.is_none() {
-                let mut code = CodeAssembler::new(64)?;
-                code.pushfq()?;
-                code.push(rax)?;
-                code.push(rbx)?;
-                code.mov(rbx, self.var_ptr_winheight as u64)?;
-                code.mov(dword_ptr(rbx), eax)?;
-                code.pop(rbx)?;
-                code.pop(rax)?;
-                code.popfq()?;
-
-                *injection_getheight = Some(LibmemInjection::new(self.pid, self.address_getheight, &mut code, &self.process)?);
-                tracing::info!("Successfully injected: getheight at 0x{:X}", self.address_getheight);
-            }
+            let mut code = CodeAssembler::new(64)?;
+            code.pushfq()?;
+            code.push(rax)?;
+            code.push(rbx)?;
+            code.mov(rbx, self.var_ptr_winwidth as u64)?;
+            code.mov(dword_ptr(rbx), eax)?;
+            code.pop(rbx)?;
+            code.pop(rax)?;
+            code.popfq()?;
+            self.injection_manager.apply_injection("getwidth", self.address_getwidth, &mut code)?;
+
+            let mut code = CodeAssembler::new(64)?;
+            code.pushfq()?;
+            code.push(rax)?;
+            code.push(rbx)?;
+            code.mov(rbx, self.var_ptr_winheight as u64)?;
+            code.mov(dword_ptr(rbx), eax)?;
+            code.pop(rbx)?;
+            code.pop(rax)?;
+            code.popfq()?;
+            self.injection_manager.apply_injection("getheight", self.address_getheight, &mut code)?;
         } else {
-            if let Some(inj) = injection_getwidth.as_ref() {
-                inj.undo()?;
-                *injection_getwidth = None;
-                tracing::info!("Successfully removed: getwidth at 0x{:X}", self.address_getwidth);
-            }
-
-            if let Some(inj) = injection_getheight.as_ref() {
-                inj.undo()?;
-                *injection_getheight = None;
-                tracing::info!("Successfully removed: getheight at 0x{:X}", self.address_getheight);
-            }
+            self.injection_manager.remove_injection("getwidth")?;
+            self.injection_manager.remove_injection("getheight")?;
         }
 
         Ok(())
     }
 
-    pub fn get_window_width(&self) -> Result<u32, Box<dyn Error>> {
-        let winwidth = read_dword(self.pid, self.var_ptr_winwidth)? as u32;
-        Ok(winwidth)
-    }
-
-    pub fn get_window_height(&self) -> Result<u32, Box<dyn Error>> {
-        let winheight = read_dword(self.pid, self.var_ptr_winheight)? as u32;
-        Ok(winheight)
+    pub fn get_window_size(&self) -> Result<WindowSize, Box<dyn Error>> {
+        WindowSize::read(self.memory.as_ref(), self.var_ptr_winwidth)
     }
 }
\ No newline at end of file
@@ -3,38 +3,73 @@
 */
 
 use crate::modules::basic::*;
+use crate::modules::hashlink_layout::{detect_structures, BytecodeHeader, HLStructures};
+use crate::modules::hashlink_types::{HLField, HLFieldValue, HLObjType};
+use crate::modules::hashlink_value::{HLValue, HLValueReader};
+use crate::modules::pointer_path::PointerSymbols;
+use crate::utils::process_memory::{open_process_memory, ProcessMemory, MEM_IMAGE, PAGE_EXECUTABLE, PAGE_READWRITE};
+use hlbc::types::RefType;
+use hlbc::Bytecode;
+use crate::modules::symbol_cache::{hash_bytes, SymbolCache};
+use crate::modules::symbol_export::{save_export, ExportFormat};
+use std::collections::HashMap;
 use std::error::Error;
-use std::sync::{Mutex, Once};
-use windows::Win32::System::Memory::{PAGE_EXECUTE, PAGE_EXECUTE_READ, PAGE_EXECUTE_READWRITE, PAGE_READWRITE};
+use std::sync::{Arc, Mutex, Once};
+#[cfg(windows)]
 use windows::Win32::System::Threading::{OpenProcess, PROCESS_QUERY_INFORMATION, PROCESS_VM_READ};
+#[cfg(windows)]
 use windows::Win32::System::ProcessStatus::K32GetModuleFileNameExW;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::result::Result;
 use std::fs::File;
-use std::io::Write;
+use std::io::{Cursor, Write};
 
 pub struct HLFunction {
     pub name: String,
     pub address: usize,
+    /// Function index (`findex`) in the bytecode
+    pub findex: usize,
+    /// Owning class, e.g. `ui.win.EndGame` (static classes are stored without the `$` prefix)
+    pub class_name: Option<String>,
+    /// Function type, e.g. `(ui.win.EndGame) -> void`
+    pub signature: String,
+}
+
+impl HLFunction {
+    /// Fully-qualified path of the function, e.g. `ui.win.EndGame.init`
+    pub fn path(&self) -> String {
+        match &self.class_name {
+            Some(class_name) => format!("{}.{}", class_name, self.name),
+            None => self.name.clone(),
+        }
+    }
 }
 
 pub struct Hashlink {
     pub pid: u32,
+    pub memory: Arc<dyn ProcessMemory>,
     pub address_allocstring: usize,
     pub functions: Vec<HLFunction>,
+    /// Class layouts indexed by class name
+    pub obj_types: HashMap<String, HLObjType>,
     pub hashlink_version: u32,
     pub hlbootdat_address: usize,
     pub structure_address: usize,
+    /// Runtime structures and the layout they were found with
+    pub structures: Option<HLStructures>,
 }
 
 impl Hashlink {
-    pub fn new(pid: u32) -> Result<Self, Box<dyn Error>> {
+    pub fn new(pid: u32, memory: Arc<dyn ProcessMemory>) -> Result<Self, Box<dyn Error>> {
         Ok(Self {
             pid,
+            memory,
             address_allocstring: 0,
             functions: Vec::new(),
+            obj_types: HashMap::new(),
             hlbootdat_address: 0,
             structure_address: 0,
+            structures: None,
             hashlink_version: 0,
         })
     }
@@ -44,20 +79,23 @@ impl Hashlink {
         static INIT: Once = Once::new();
         
         INIT.call_once(|| {
-            if let Ok(mut hashlink) = Hashlink::new(pid) {
-                // Log initialization attempt
-                tracing::info!("Initializing Hashlink singleton for PID: {}", pid);
-                match hashlink.init_hashlink() {
-                    Ok(_) => {
-                        tracing::info!("Successfully initialized Hashlink");
-                        *SINGLETON.lock().unwrap() = Some(hashlink);
-                    }
-                    Err(e) => {
-                        tracing::error!("Failed to initialize Hashlink: {}", e);
+            match open_process_memory(pid).and_then(|memory| Hashlink::new(pid, memory)) {
+                Ok(mut hashlink) => {
+                    // Log initialization attempt
+                    tracing::info!("Initializing Hashlink singleton for PID: {}", pid);
+                    match hashlink.init_hashlink() {
+                        Ok(_) => {
+                            tracing::info!("Successfully initialized Hashlink");
+                            *SINGLETON.lock().unwrap() = Some(hashlink);
+                        }
+                        Err(e) => {
+                            tracing::error!("Failed to initialize Hashlink: {}", e);
+                        }
                     }
                 }
-            } else {
-                tracing::error!("Failed to create new Hashlink instance");
+                Err(e) => {
+                    tracing::error!("Failed to create new Hashlink instance: {}", e);
+                }
             }
         });
         &SINGLETON
@@ -65,7 +103,6 @@ impl Hashlink {
 
     fn find_hlbootdat_address(&mut self) -> Result<(), Box<dyn Error>> {
         const EXPECTED_STRING: &str = "hlboot.dat";
-        const IMAGE_TYPE: u32 = 0x1000000;
 
         let mut pattern = String::new();
         for c in EXPECTED_STRING.encode_utf16() {
@@ -73,81 +110,44 @@ impl Hashlink {
         }
         pattern.pop();
 
-        let addrs = aob_scan_mrtype(self.pid, &pattern, IMAGE_TYPE)?;
-        self.hlbootdat_address = addrs[0];
+        let addrs = aob_scan_mrtype(self.memory.as_ref(), &pattern, MEM_IMAGE)?;
+        self.hlbootdat_address = *addrs
+            .first()
+            .ok_or("\"hlboot.dat\" path not found in the game image, is this a hashlink game?")?;
         Ok(())
     }
 
-    fn setup_hashlink_version(&mut self) -> bool {
-        let addr = match read_qword(self.pid, self.structure_address + 0x8) {
-            Ok(addr) => {
-                addr
-            },
-            Err(_) => return false
-        };
-
-        let current_value = match read_dword(self.pid, addr as usize) {
-            Ok(val) => {
-                val
-            },
-            Err(_) => return false
-        };
-
-        let possible_values = [3, 4, 5];
-        for value in possible_values {
-            if current_value == value {
-                tracing::info!("Hashlink version: {}", value);
-                self.hashlink_version = value;
-                return true;
-            }
-        }
-        false
-    }
-
-    fn get_structure_address(&mut self) -> Result<(), Box<dyn Error>> {
+    /// Find `main_context`, `hl_code` and `hl_module` by checking every pointer to the `hlboot.dat`
+    /// path against the known runtime layouts and the counters of the bytecode file
+    fn get_structure_address(&mut self, header: &BytecodeHeader) -> Result<(), Box<dyn Error>> {
         let mut pattern = String::new();
         for byte in self.hlbootdat_address.to_le_bytes() {
             pattern.push_str(&format!("{:02x} ", byte));
         }
         pattern.pop();
 
-        let executable_protection = PAGE_READWRITE.0;
-        let addrs = aob_scan_mrprotect(self.pid, &pattern, executable_protection)?;
-        for addr in addrs {
-            self.structure_address = addr;
-            if self.setup_hashlink_version() {
-                return Ok(());
-            }
-        }
-        Err("No valid structure address found".into())
-    }
-
-    fn get_nfunctions(&self) -> Result<u32, Box<dyn Error>> {
-        let mut nfunctions_offset: usize = 28;
-        if self.hashlink_version >= 4 {
-            nfunctions_offset = 32;
-        }
+        let addrs = aob_scan_mrprotect(self.memory.as_ref(), &pattern, PAGE_READWRITE)?;
+        let structures = detect_structures(self.memory.as_ref(), &addrs, header)?;
 
-        let addr = read_pointer(self.pid, self.structure_address + 0x8)?;
-        let nfunctions = read_dword(self.pid, addr + nfunctions_offset)?;
-        Ok(nfunctions)
+        tracing::info!("Hashlink layout: {}", structures.layout.name);
+        tracing::info!("Hashlink version: {}", structures.version);
+        self.structure_address = structures.context;
+        self.hashlink_version = structures.version;
+        self.structures = Some(structures);
+        Ok(())
     }
 
-    fn get_function_list(&self, nfunctions: u32) -> Result<Vec<usize>, Box<dyn Error>> {
-        let mut result = Vec::new();
-        let hl_module_pointer = read_pointer(self.pid, self.structure_address + 0x10)?;
-        let functions_pointer = read_pointer(self.pid, hl_module_pointer + 0x20)?;
-
-        let bytes = read_bytes(self.pid, functions_pointer, (nfunctions * 8) as usize)?;
+    /// JIT addresses indexed by findex (functions and natives share the index space)
+    fn get_function_list(&self) -> Result<Vec<usize>, Box<dyn Error>> {
+        let structures = self.structures.as_ref().ok_or("Hashlink structures not found")?;
+        let count = (structures.nfunctions + structures.nnatives) as usize;
+        let functions_pointer = self.memory.read_pointer(structures.module + structures.layout.module_functions_ptrs)?;
 
-        for i in 0..nfunctions as usize {
-            let func = byte_array_to_pointer(&bytes[i * 8..(i + 1) * 8]);
-            result.push(func);
-        }
-
-        Ok(result)
+        let bytes = self.memory.read_bytes(functions_pointer, count * 8)?;
+        Ok(bytes.chunks_exact(8).map(byte_array_to_pointer).collect())
     }
 
+    #[cfg(windows)]
     pub fn get_directory(pid: u32) -> Result<String, Box<dyn Error>> {
         let handle = unsafe {
             OpenProcess(
@@ -180,45 +180,89 @@ impl Hashlink {
         }
     }
 
+    #[cfg(not(windows))]
+    pub fn get_directory(pid: u32) -> Result<String, Box<dyn Error>> {
+        Err(format!("Cannot get the directory of process {} on this platform", pid).into())
+    }
+
     pub fn init_hashlink(&mut self) -> Result<usize, Box<dyn Error>> {
-        
+        let directory = Self::get_directory(self.pid)?;
+        self.init_hashlink_from(&PathBuf::from(directory).join("hlboot.dat"))
+    }
+
+    /// `init_hashlink` with an explicit `hlboot.dat`, e.g. to parse a memory dump without the game
+    pub fn init_hashlink_from(&mut self, path: &Path) -> Result<usize, Box<dyn Error>> {
         // Find allocString function
         let hex_pattern_allocstring = "55 48 8B ?? 48 83 ?? ?? 48 89 ?? ?? 89 ?? ?? 48 B9 ?? ?? ?? ?? ?? ?? ?? ?? 48 B8 ?? ?? ?? ?? ?? ?? ?? ?? 48 83 ?? ?? FF ?? 48 89 ?? ?? ?? 48 83 ?? ?? 48 89 ?? ?? 48 8B ?? ?? 48 89 ?? ?? 8B ?? ?? 89 ?? ?? 48 83 ?? ?? 5D 48 C3";
-        let executable_protection = PAGE_EXECUTE.0 | PAGE_EXECUTE_READ.0 | PAGE_EXECUTE_READWRITE.0;
-        let addrs = aob_scan_mrprotect(self.pid, hex_pattern_allocstring, executable_protection)?;
+        let addrs = aob_scan_mrprotect(self.memory.as_ref(), hex_pattern_allocstring, PAGE_EXECUTABLE)?;
         if addrs.is_empty() {
             return Err("Pattern not found: init_hashlink: hex_pattern_allocstring".into());
         }
         self.address_allocstring = addrs[0];
 
+        // Read hlboot.dat first, its header is used to validate the runtime structures
+        tracing::info!("File path: {}", path.to_string_lossy());
+        let data = std::fs::read(path)?;
+        let header = BytecodeHeader::parse(&data)?;
+        tracing::info!("Bytecode version: {}, functions: {}, natives: {}", header.version, header.nfunctions, header.nnatives);
+
         // Initialize other addresses
         self.find_hlbootdat_address()?;
         tracing::info!("HLBootdat address: {:#016x}", self.hlbootdat_address);
 
-        self.get_structure_address()?;
+        self.get_structure_address(&header)?;
         tracing::info!("Structure address: {:#016x}", self.structure_address);
 
         // Get functions
-        let nfunctions = self.get_nfunctions()?;
-        tracing::info!("Function number: {}", nfunctions);
+        let function_list = self.get_function_list()?;
+        tracing::info!("Function number: {}", function_list.len());
+
+        // Load hlboot.dat symbols (from cache when possible) and match functions
+        let symbols = self.load_symbols(data)?;
+
+        for function in symbols.functions {
+            if function.findex < function_list.len() {
+                let address = function_list[function.findex];
+                self.functions.push(HLFunction {
+                    name: function.name,
+                    address,
+                    findex: function.findex,
+                    class_name: function.class_name,
+                    signature: function.signature,
+                });
+            }
+        }
 
-        let function_list = self.get_function_list(nfunctions)?;
+        for obj_type in symbols.obj_types {
+            self.obj_types.insert(obj_type.name.clone(), obj_type);
+        }
+        tracing::info!("Class number: {}", self.obj_types.len());
 
-        // Load hlboot.dat and match functions
-        let directory = Self::get_directory(self.pid)?;
-        let path = PathBuf::from(directory).join("hlboot.dat");
-        tracing::info!("File path: {}", path.to_string_lossy());
-        let bytecode = hlbc::Bytecode::from_file(path)?;
+        Ok(self.address_allocstring)
+    }
 
-        for function in &bytecode.functions {
-            let name = function.name(&bytecode).to_string();
-            if function.findex.0 < function_list.len() {
-                let address = function_list[function.findex.0];
-                self.functions.push(HLFunction { name, address });
+    /// Load symbols from the persistent cache, re-parsing `hlboot.dat` only if it changed
+    fn load_symbols(&self, data: Vec<u8>) -> Result<SymbolCache, Box<dyn Error>> {
+        let bytecode_hash = hash_bytes(&data);
+        let cache_path = SymbolCache::default_path();
+
+        match SymbolCache::load(&cache_path) {
+            Ok(cache) if cache.is_valid_for(bytecode_hash, self.hashlink_version) => {
+                tracing::info!("Loaded symbol cache: {}", cache_path.to_string_lossy());
+                return Ok(cache);
             }
+            Ok(_) => tracing::info!("Symbol cache is outdated, rebuilding"),
+            Err(e) => tracing::info!("Symbol cache not loaded ({}), rebuilding", e),
         }
 
-        Ok(self.address_allocstring)
+        let bytecode = hlbc::Bytecode::deserialize(&mut Cursor::new(data))?;
+        let cache = SymbolCache::from_bytecode(&bytecode, bytecode_hash, self.hashlink_version);
+        match cache.save(&cache_path) {
+            Ok(_) => tracing::info!("Saved symbol cache: {}", cache_path.to_string_lossy()),
+            Err(e) => tracing::warn!("Failed to save symbol cache: {}", e),
+        }
+
+        Ok(cache)
     }
 
     pub fn save_functions(&self, path: &str) -> Result<(), Box<dyn Error>> {
@@ -229,6 +273,19 @@ impl Hashlink {
         Ok(())
     }
 
+    /// Export resolved functions for debuggers and disassemblers
+    pub fn export_functions(&self, format: ExportFormat, path: &Path) -> Result<(), Box<dyn Error>> {
+        save_export(&self.functions, format, path)?;
+        tracing::info!("Exported {} functions ({}): {}", self.functions.len(), format.name(), path.to_string_lossy());
+        Ok(())
+    }
+
+    /// Directory used by `export_functions` from the overlay
+    pub fn exports_directory() -> PathBuf {
+        let base = dirs::data_local_dir().unwrap_or_else(|| PathBuf::from("."));
+        base.join("NgAssistant").join("exports")
+    }
+
     pub fn get_function_address(&self, name: &str, idx: Option<usize>) -> Result<usize, Box<dyn Error>> {
         let mut found_count = 0;
         let target_idx = idx.unwrap_or(0);
@@ -249,4 +306,190 @@ impl Hashlink {
                 name, target_idx, found_count - 1).into())
         }
     }
-}
\ No newline at end of file
+
+    /// Find function by its fully-qualified path, e.g. `ui.win.EndGame.init` or `hxd.Window.get_width`.
+    /// Fails if the path matches several functions, use `get_function_by_findex` in that case.
+    pub fn get_function_by_path(&self, path: &str) -> Result<&HLFunction, Box<dyn Error>> {
+        let matches: Vec<&HLFunction> = self.functions
+            .iter()
+            .filter(|function| function.path() == path)
+            .collect();
+
+        match matches.as_slice() {
+            [] => Err(format!("Function '{}' not found", path).into()),
+            [function] => Ok(function),
+            _ => {
+                let findexes: Vec<String> = matches.iter().map(|f| f.findex.to_string()).collect();
+                Err(format!("Function '{}' is ambiguous (findex: {})", path, findexes.join(", ")).into())
+            }
+        }
+    }
+
+    pub fn get_function_address_by_path(&self, path: &str) -> Result<usize, Box<dyn Error>> {
+        Ok(self.get_function_by_path(path)?.address)
+    }
+
+    /// Find function by its bytecode function index (`findex`)
+    pub fn get_function_by_findex(&self, findex: usize) -> Result<&HLFunction, Box<dyn Error>> {
+        self.functions
+            .iter()
+            .find(|function| function.findex == findex)
+            .ok_or_else(|| format!("Function with findex {} not found", findex).into())
+    }
+
+    pub fn get_function_address_by_findex(&self, findex: usize) -> Result<usize, Box<dyn Error>> {
+        Ok(self.get_function_by_findex(findex)?.address)
+    }
+
+    /// Find class layout by name, e.g. `gamesys.LobbyManager` (or `$gamesys.LobbyManager` for statics)
+    pub fn get_obj_type(&self, name: &str) -> Result<&HLObjType, Box<dyn Error>> {
+        self.obj_types
+            .get(name)
+            .ok_or_else(|| format!("Class '{}' not found", name).into())
+    }
+
+    pub fn get_field(&self, class_name: &str, field_name: &str) -> Result<&HLField, Box<dyn Error>> {
+        self.get_obj_type(class_name)?.field(field_name)
+    }
+
+    /// Offset of a field from the object address, for use in injected code
+    pub fn get_field_offset(&self, class_name: &str, field_name: &str) -> Result<usize, Box<dyn Error>> {
+        Ok(self.get_field(class_name, field_name)?.offset)
+    }
+
+    /// Read a field of a remote object, e.g. `read_field::<bool>(manager, "gamesys.LobbyManager", "lockedIn")`.
+    /// Fails if `T` does not match how the field is stored.
+    pub fn read_field<T: HLFieldValue>(&self, object: usize, class_name: &str, field_name: &str) -> Result<T, Box<dyn Error>> {
+        let field = self.get_field(class_name, field_name)?;
+        if !T::accepts(field.kind) {
+            return Err(format!("Field '{}.{}' is {:?}, cannot read it as {}",
+                class_name, field_name, field.kind, std::any::type_name::<T>()).into());
+        }
+
+        let bytes = self.memory.read_bytes(object + field.offset, field.kind.size())?;
+        Ok(T::from_bytes(&bytes))
+    }
+
+    /// Hashlink globals as roots for pointer paths. Class globals are named after the
+    /// static type of the object they hold (`$gamesys.LobbyManager`), others `global@<index>`
+    pub fn pointer_symbols(&self) -> Result<PointerSymbols, Box<dyn Error>> {
+        let structures = self.structures.as_ref().ok_or("Hashlink runtime structures not found")?;
+        let class_names: HashMap<usize, String> = self.obj_types
+            .values()
+            .filter_map(|obj_type| {
+                let name = obj_type.name.trim_start_matches('$');
+                obj_type.global.map(|global| (global, format!("${}", name)))
+            })
+            .collect();
+
+        let mut symbols = PointerSymbols::new();
+        for (index, address) in structures.global_addresses(self.memory.as_ref())?.into_iter().enumerate() {
+            match class_names.get(&index) {
+                Some(name) => symbols.insert(name, address, 8),
+                None => symbols.insert(&format!("global@{}", index), address, 8),
+            }
+        }
+        Ok(symbols)
+    }
+
+    /// Parse the game `hlboot.dat`, needed to decode values with `read_value`.
+    /// Takes the pid so the parse can run without holding the `Hashlink` lock
+    pub fn load_bytecode(pid: u32) -> Result<Bytecode, Box<dyn Error>> {
+        let path = PathBuf::from(Self::get_directory(pid)?).join("hlboot.dat");
+        Ok(Bytecode::from_file(path)?)
+    }
+
+    /// Decode a remote value of type `ty`, see `HLValueReader::read_value`
+    pub fn read_value(&self, bytecode: &Bytecode, address: usize, ty: RefType) -> Result<HLValue, Box<dyn Error>> {
+        HLValueReader::new(self.memory.as_ref(), bytecode, self.hashlink_version).read_value(address, ty)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::process_memory::{MockMemory, MEM_PRIVATE, PAGE_READONLY};
+
+    const FUNCTIONS: [u64; 4] = [0x7000_0000, 0x7000_0100, 0x7000_0200, 0x7100_0000];
+
+    /// hlboot.dat path in the image, a stale pointer to it and the runtime structures:
+    /// main_context at 0x10800, hl_code at 0x11000, hl_module at 0x12000, JIT table at 0x13000,
+    /// globals indexes at 0x13800 and globals data at 0x13900
+    fn memory() -> MockMemory {
+        let memory = MockMemory::new();
+        memory.map(0x400000, 0x1000, PAGE_READONLY, MEM_IMAGE);
+        memory.map(0x10000, 0x4000, PAGE_READWRITE, MEM_PRIVATE);
+
+        let path: Vec<u8> = "hlboot.dat".encode_utf16().flat_map(u16::to_le_bytes).collect();
+        memory.write_bytes(0x400100, &path).unwrap();
+        memory.write_u64(0x10000, 0x400100).unwrap();
+
+        memory.write_u64(0x10800, 0x400100).unwrap();
+        memory.write_u64(0x10808, 0x11000).unwrap();
+        memory.write_u64(0x10810, 0x12000).unwrap();
+        for (offset, value) in [(0, 5u32), (4, 10), (12, 300), (20, 200), (28, 1), (32, 3)] {
+            memory.write_u32(0x11000 + offset, value).unwrap();
+        }
+        memory.write_u64(0x12000, 0x11000).unwrap();
+        memory.write_u64(0x12010, 0x13800).unwrap();
+        memory.write_u64(0x12018, 0x13900).unwrap();
+        memory.write_u64(0x12020, 0x13000).unwrap();
+        for (i, address) in FUNCTIONS.iter().enumerate() {
+            memory.write_u64(0x13000 + i * 8, *address).unwrap();
+        }
+        for (i, offset) in [0u32, 8, 16].iter().enumerate() {
+            memory.write_u32(0x13800 + i * 4, *offset).unwrap();
+        }
+        memory
+    }
+
+    fn header() -> BytecodeHeader {
+        BytecodeHeader {
+            version: 5,
+            nints: 10,
+            nstrings: 300,
+            ntypes: 200,
+            nnatives: 1,
+            nfunctions: 3,
+            nglobals: 3,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_find_functions() {
+        let header = header();
+        let mut hashlink = Hashlink::new(0, Arc::new(memory())).unwrap();
+
+        hashlink.find_hlbootdat_address().unwrap();
+        assert_eq!(hashlink.hlbootdat_address, 0x400100);
+        hashlink.get_structure_address(&header).unwrap();
+        assert_eq!((hashlink.structure_address, hashlink.hashlink_version), (0x10800, 5));
+
+        let functions = hashlink.get_function_list().unwrap();
+        assert_eq!(functions, FUNCTIONS.map(|address| address as usize));
+    }
+
+    #[test]
+    fn test_pointer_symbols() {
+        let mut hashlink = Hashlink::new(0, Arc::new(memory())).unwrap();
+        assert!(hashlink.pointer_symbols().is_err());
+
+        hashlink.find_hlbootdat_address().unwrap();
+        hashlink.get_structure_address(&header()).unwrap();
+        let manager = HLObjType {
+            name: "gamesys.LobbyManager".to_string(),
+            type_index: 40,
+            super_name: None,
+            global: Some(1),
+            fields: Vec::new(),
+            size: 8,
+        };
+        hashlink.obj_types.insert(manager.name.clone(), manager);
+
+        let symbols = hashlink.pointer_symbols().unwrap();
+        assert_eq!(symbols.len(), 3);
+        assert_eq!(symbols.get("$gamesys.LobbyManager"), Some(0x13908));
+        assert_eq!(symbols.find(0x13910), Some(("global@2", 0)));
+    }
+}
@@ -0,0 +1,354 @@
+/*
+    Layouts of the hashlink runtime structures (`main_context`, `hl_code`, `hl_module`)
+    for the released HL versions, and validation of a candidate `main_context` against
+    the header of the `hlboot.dat` file loaded by the game.
+*/
+
+use crate::utils::process_memory::ProcessMemory;
+use std::error::Error;
+use std::ops::RangeInclusive;
+
+/// Offsets of the fields we use, all structures are 64-bit
+#[derive(Debug)]
+pub struct HLLayout {
+    pub name: &'static str,
+    /// Bytecode versions this runtime can load
+    pub versions: RangeInclusive<u32>,
+    /// `main_context.file`, the pointer used to find the structure
+    pub context_file: usize,
+    pub context_code: usize,
+    pub context_module: usize,
+    pub code_version: usize,
+    pub code_nints: usize,
+    pub code_nstrings: usize,
+    pub code_ntypes: usize,
+    pub code_nnatives: usize,
+    pub code_nfunctions: usize,
+    pub module_code: usize,
+    pub module_globals_indexes: usize,
+    pub module_globals_data: usize,
+    pub module_functions_ptrs: usize,
+}
+
+pub const HL_LAYOUTS: &[HLLayout] = &[
+    // `hl_code.nbytes` was added for the bytes pool, shifting the following counters
+    HLLayout {
+        name: "file-first main_context, hl_code with nbytes",
+        versions: 4..=5,
+        context_file: 0x0,
+        context_code: 0x8,
+        context_module: 0x10,
+        code_version: 0,
+        code_nints: 4,
+        code_nstrings: 12,
+        code_ntypes: 20,
+        code_nnatives: 28,
+        code_nfunctions: 32,
+        module_code: 0x0,
+        module_globals_indexes: 0x10,
+        module_globals_data: 0x18,
+        module_functions_ptrs: 0x20,
+    },
+    HLLayout {
+        name: "file-first main_context, hl_code without nbytes",
+        versions: 2..=4,
+        context_file: 0x0,
+        context_code: 0x8,
+        context_module: 0x10,
+        code_version: 0,
+        code_nints: 4,
+        code_nstrings: 12,
+        code_ntypes: 16,
+        code_nnatives: 24,
+        code_nfunctions: 28,
+        module_code: 0x0,
+        module_globals_indexes: 0x10,
+        module_globals_data: 0x18,
+        module_functions_ptrs: 0x20,
+    },
+    // `main_context.file` used to be stored after `code`, `m` and `ret`
+    HLLayout {
+        name: "file-last main_context, hl_code without nbytes",
+        versions: 2..=3,
+        context_file: 0x18,
+        context_code: 0x0,
+        context_module: 0x8,
+        code_version: 0,
+        code_nints: 4,
+        code_nstrings: 12,
+        code_ntypes: 16,
+        code_nnatives: 24,
+        code_nfunctions: 28,
+        module_code: 0x0,
+        module_globals_indexes: 0x10,
+        module_globals_data: 0x18,
+        module_functions_ptrs: 0x20,
+    },
+];
+
+/// Counters from the `hlboot.dat` header, same order as `hl_read_code`
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct BytecodeHeader {
+    pub version: u32,
+    pub nints: u32,
+    pub nfloats: u32,
+    pub nstrings: u32,
+    pub nbytes: u32,
+    pub ntypes: u32,
+    pub nglobals: u32,
+    pub nnatives: u32,
+    pub nfunctions: u32,
+    pub nconstants: u32,
+}
+
+impl BytecodeHeader {
+    pub fn parse(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        if data.len() < 4 || &data[..3] != b"HLB" {
+            return Err("Invalid hlboot.dat: missing HLB header".into());
+        }
+
+        let mut reader = IndexReader { data, position: 4 };
+        let version = data[3] as u32;
+        if !(2..=5).contains(&version) {
+            return Err(format!("Unsupported bytecode version {} in hlboot.dat", version).into());
+        }
+
+        let _flags = reader.read()?;
+        let mut header = BytecodeHeader {
+            version,
+            nints: reader.read()?,
+            nfloats: reader.read()?,
+            nstrings: reader.read()?,
+            ..Default::default()
+        };
+        if version >= 5 {
+            header.nbytes = reader.read()?;
+        }
+        header.ntypes = reader.read()?;
+        header.nglobals = reader.read()?;
+        header.nnatives = reader.read()?;
+        header.nfunctions = reader.read()?;
+        if version >= 4 {
+            header.nconstants = reader.read()?;
+        }
+        Ok(header)
+    }
+}
+
+/// Variable-length unsigned integers of the bytecode format (`hl_read_uindex`)
+struct IndexReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl IndexReader<'_> {
+    fn byte(&mut self) -> Result<u32, Box<dyn Error>> {
+        let byte = *self.data.get(self.position).ok_or("Invalid hlboot.dat: truncated header")?;
+        self.position += 1;
+        Ok(byte as u32)
+    }
+
+    fn read(&mut self) -> Result<u32, Box<dyn Error>> {
+        let b = self.byte()?;
+        if b & 0x80 == 0 {
+            return Ok(b & 0x7F);
+        }
+        if b & 0x20 != 0 {
+            return Err("Invalid hlboot.dat: negative count in header".into());
+        }
+        if b & 0x40 == 0 {
+            Ok(self.byte()? | ((b & 31) << 8))
+        } else {
+            Ok(((b & 31) << 24) | (self.byte()? << 16) | (self.byte()? << 8) | self.byte()?)
+        }
+    }
+}
+
+/// Runtime structures found for the loaded `hlboot.dat`
+#[derive(Debug)]
+pub struct HLStructures {
+    pub layout: &'static HLLayout,
+    pub context: usize,
+    pub code: usize,
+    pub module: usize,
+    pub version: u32,
+    pub nfunctions: u32,
+    pub nnatives: u32,
+    pub nglobals: u32,
+}
+
+impl HLStructures {
+    /// Address of every global, `hl_module.globals_data` plus `hl_module.globals_indexes[i]`
+    pub fn global_addresses(&self, memory: &dyn ProcessMemory) -> Result<Vec<usize>, Box<dyn Error>> {
+        let indexes = memory.read_pointer(self.module + self.layout.module_globals_indexes)?;
+        let data = memory.read_pointer(self.module + self.layout.module_globals_data)?;
+        let bytes = memory.read_bytes(indexes, self.nglobals as usize * 4)?;
+        Ok(bytes
+            .chunks_exact(4)
+            .map(|index| data + u32::from_le_bytes(index.try_into().unwrap()) as usize)
+            .collect())
+    }
+}
+
+fn read_u32(memory: &dyn ProcessMemory, address: usize) -> Result<u32, String> {
+    memory.read_u32(address).map_err(|e| e.to_string())
+}
+
+fn read_pointer(memory: &dyn ProcessMemory, address: usize) -> Result<usize, String> {
+    memory.read_pointer(address).map_err(|e| e.to_string())
+}
+
+/// Check that `file_pointer` (an address holding a pointer to the `hlboot.dat` path)
+/// is the `main_context.file` of `layout`, the returned message explains the first mismatch
+pub fn validate_layout(
+    memory: &dyn ProcessMemory,
+    layout: &'static HLLayout,
+    file_pointer: usize,
+    header: &BytecodeHeader,
+) -> Result<HLStructures, String> {
+    let context = file_pointer
+        .checked_sub(layout.context_file)
+        .ok_or("address below main_context")?;
+    let code = read_pointer(memory, context + layout.context_code)?;
+    let module = read_pointer(memory, context + layout.context_module)?;
+    if code == 0 || module == 0 {
+        return Err(format!("null hl_code ({:#x}) or hl_module ({:#x})", code, module));
+    }
+
+    let version = read_u32(memory, code + layout.code_version)?;
+    if version != header.version {
+        return Err(format!("hl_code.version is {}, hlboot.dat is version {}", version, header.version));
+    }
+    if !layout.versions.contains(&version) {
+        return Err(format!("bytecode version {} not loaded by this runtime", version));
+    }
+
+    let counters = [
+        ("nints", layout.code_nints, header.nints),
+        ("nstrings", layout.code_nstrings, header.nstrings),
+        ("ntypes", layout.code_ntypes, header.ntypes),
+        ("nnatives", layout.code_nnatives, header.nnatives),
+        ("nfunctions", layout.code_nfunctions, header.nfunctions),
+    ];
+    for (name, offset, expected) in counters {
+        let value = read_u32(memory, code + offset)?;
+        if value != expected {
+            return Err(format!("hl_code.{} is {}, hlboot.dat has {}", name, value, expected));
+        }
+    }
+
+    let module_code = read_pointer(memory, module + layout.module_code)?;
+    if module_code != code {
+        return Err(format!("hl_module.code ({:#x}) does not point to hl_code ({:#x})", module_code, code));
+    }
+
+    Ok(HLStructures {
+        layout,
+        context,
+        code,
+        module,
+        version,
+        nfunctions: header.nfunctions,
+        nnatives: header.nnatives,
+        nglobals: header.nglobals,
+    })
+}
+
+/// Try every layout on every candidate, fails with the reason each attempt was rejected
+pub fn detect_structures(
+    memory: &dyn ProcessMemory,
+    file_pointers: &[usize],
+    header: &BytecodeHeader,
+) -> Result<HLStructures, Box<dyn Error>> {
+    const MAX_REPORTED: usize = 12;
+
+    let mut failures = Vec::new();
+    for &file_pointer in file_pointers {
+        for layout in HL_LAYOUTS {
+            match validate_layout(memory, layout, file_pointer, header) {
+                Ok(structures) => return Ok(structures),
+                Err(reason) => failures.push(format!("{:#x} as {}: {}", file_pointer, layout.name, reason)),
+            }
+        }
+    }
+
+    let mut message = format!(
+        "Hashlink runtime structures not found for hlboot.dat (bytecode version {}, {} functions, {} natives). ",
+        header.version, header.nfunctions, header.nnatives
+    );
+    if file_pointers.is_empty() {
+        message.push_str("No pointer to the hlboot.dat path was found in writable memory");
+    } else {
+        message.push_str(&format!("Checked {} candidate(s) against {} layouts:", file_pointers.len(), HL_LAYOUTS.len()));
+        for failure in failures.iter().take(MAX_REPORTED) {
+            message.push_str("\n  ");
+            message.push_str(failure);
+        }
+        if failures.len() > MAX_REPORTED {
+            message.push_str(&format!("\n  ... {} more", failures.len() - MAX_REPORTED));
+        }
+    }
+    Err(message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::process_memory::{MockMemory, MEM_PRIVATE, PAGE_READWRITE};
+
+    fn header() -> BytecodeHeader {
+        BytecodeHeader {
+            version: 5,
+            nints: 10,
+            nfloats: 2,
+            nstrings: 300,
+            nbytes: 1,
+            ntypes: 200,
+            nglobals: 50,
+            nnatives: 40,
+            nfunctions: 24600,
+            nconstants: 3,
+        }
+    }
+
+    #[test]
+    fn test_parse_header() {
+        // 300 and 200 use the two-byte encoding, 24600 the four-byte one, 0xE0 marks a negative value
+        let data = [
+            b'H', b'L', b'B', 5, 0, 10, 2, 0x81, 0x2C, 1, 0x80, 200, 50, 40, 0xE0, 0x00, 0x60, 0x18, 3,
+        ];
+        assert!(BytecodeHeader::parse(&data).is_err());
+
+        let data = [b'H', b'L', b'B', 5, 0, 10, 2, 0x81, 0x2C, 1, 0x80, 200, 50, 40, 0xC0, 0x00, 0x60, 0x18, 3];
+        assert_eq!(BytecodeHeader::parse(&data).unwrap(), header());
+        assert!(BytecodeHeader::parse(b"HLB\x09").is_err());
+    }
+
+    /// main_context at 0x1000 using the first layout, hl_code at 0x2000, hl_module at 0x3000
+    fn memory(nfunctions: u32) -> MockMemory {
+        let memory = MockMemory::new();
+        memory.map(0x1000, 0x3000, PAGE_READWRITE, MEM_PRIVATE);
+        memory.write_bytes(0x1008, &0x2000u64.to_le_bytes()).unwrap();
+        memory.write_bytes(0x1010, &0x3000u64.to_le_bytes()).unwrap();
+        for (offset, value) in [(0, 5u32), (4, 10), (12, 300), (20, 200), (28, 40), (32, nfunctions)] {
+            memory.write_bytes(0x2000 + offset, &value.to_le_bytes()).unwrap();
+        }
+        memory.write_bytes(0x3000, &0x2000u64.to_le_bytes()).unwrap();
+        memory
+    }
+
+    #[test]
+    fn test_detect_structures() {
+        let memory = memory(24600);
+        let structures = detect_structures(&memory, &[0x1800, 0x1000], &header()).unwrap();
+        assert_eq!(structures.layout.name, HL_LAYOUTS[0].name);
+        assert_eq!((structures.context, structures.code, structures.module), (0x1000, 0x2000, 0x3000));
+    }
+
+    #[test]
+    fn test_detect_structures_diagnostic() {
+        let memory = memory(24599);
+        let error = detect_structures(&memory, &[0x1000], &header()).unwrap_err().to_string();
+        assert!(error.contains("0x1000 as file-first main_context, hl_code with nbytes: hl_code.nfunctions is 24599, hlboot.dat has 24600"));
+    }
+}
@@ -0,0 +1,230 @@
+/*
+    Runtime layout of hashlink objects, computed from the bytecode type definitions.
+    Mirrors `hl_get_obj_rt` from the hashlink runtime, so offsets follow field reordering in game patches.
+*/
+
+use hlbc::types::{RefType, Type, TypeObj};
+use hlbc::Bytecode;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+const POINTER_SIZE: usize = 8;
+
+/// How a field is stored in memory
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum HLFieldKind {
+    Void,
+    UI8,
+    UI16,
+    I32,
+    I64,
+    F32,
+    F64,
+    Bool,
+    /// Any reference type (objects, strings, arrays, dynamic, functions...)
+    Pointer,
+    /// Inlined struct (`hl.Packed`), `size` bytes are stored in place
+    Packed { size: usize },
+}
+
+impl HLFieldKind {
+    pub fn from_type(bytecode: &Bytecode, ty: &Type, hashlink_version: u32) -> Self {
+        match ty {
+            Type::Void => HLFieldKind::Void,
+            Type::UI8 => HLFieldKind::UI8,
+            Type::UI16 => HLFieldKind::UI16,
+            Type::I32 => HLFieldKind::I32,
+            Type::I64 => HLFieldKind::I64,
+            Type::F32 => HLFieldKind::F32,
+            Type::F64 => HLFieldKind::F64,
+            Type::Bool => HLFieldKind::Bool,
+            Type::Packed(inner) => {
+                let size = bytecode[*inner]
+                    .get_type_obj()
+                    .map(|obj| object_size(bytecode, &bytecode[*inner], obj, hashlink_version))
+                    .unwrap_or(POINTER_SIZE);
+                HLFieldKind::Packed { size }
+            }
+            _ => HLFieldKind::Pointer,
+        }
+    }
+
+    /// Same as `hl_type_size`
+    pub fn size(&self) -> usize {
+        match self {
+            HLFieldKind::Void => 0,
+            HLFieldKind::UI8 | HLFieldKind::Bool => 1,
+            HLFieldKind::UI16 => 2,
+            HLFieldKind::I32 | HLFieldKind::F32 => 4,
+            HLFieldKind::I64 | HLFieldKind::F64 | HLFieldKind::Pointer => 8,
+            HLFieldKind::Packed { size } => *size,
+        }
+    }
+
+    /// Same as `hl_pad_size`, packed structs are aligned like pointers
+    pub fn padding(&self, position: usize) -> usize {
+        let align = match self {
+            HLFieldKind::Void => return 0,
+            HLFieldKind::Packed { .. } => POINTER_SIZE,
+            kind => kind.size(),
+        };
+        position.wrapping_neg() & (align - 1)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct HLField {
+    pub name: String,
+    /// Bytecode type index of the field
+    pub type_index: usize,
+    pub kind: HLFieldKind,
+    /// Offset from the object address
+    pub offset: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct HLObjType {
+    /// Class name, static parts of classes are prefixed with `$`
+    pub name: String,
+    pub type_index: usize,
+    pub super_name: Option<String>,
+    /// Index of the global holding the class object (the instance of the `$` type)
+    pub global: Option<usize>,
+    /// All fields including inherited ones, in memory order
+    pub fields: Vec<HLField>,
+    /// Size of the object in bytes
+    pub size: usize,
+}
+
+impl HLObjType {
+    pub fn field(&self, name: &str) -> Result<&HLField, Box<dyn Error>> {
+        self.fields
+            .iter()
+            .find(|field| field.name == name)
+            .ok_or_else(|| format!("Field '{}' not found in '{}'", name, self.name).into())
+    }
+}
+
+/// Size of the object header: `hl_type*` for classes, nothing for structs
+fn header_size(ty: &Type, hashlink_version: u32) -> usize {
+    match ty {
+        Type::Struct(_) if hashlink_version >= 5 => 0,
+        _ => POINTER_SIZE,
+    }
+}
+
+fn object_size(bytecode: &Bytecode, ty: &Type, obj: &TypeObj, hashlink_version: u32) -> usize {
+    let mut size = header_size(ty, hashlink_version);
+    for field in &obj.fields {
+        let kind = HLFieldKind::from_type(bytecode, &bytecode[field.t], hashlink_version);
+        size += kind.padding(size) + kind.size();
+    }
+    size
+}
+
+/// Compute the runtime layout of an `Obj` or `Struct` type
+pub fn compute_obj_layout(bytecode: &Bytecode, type_index: usize, hashlink_version: u32) -> Option<HLObjType> {
+    let ty = &bytecode.types[type_index];
+    let obj = ty.get_type_obj()?;
+
+    // `obj.fields` already contains parent fields first, so offsets accumulate like in the runtime
+    let mut size = header_size(ty, hashlink_version);
+    let mut fields = Vec::with_capacity(obj.fields.len());
+    for field in &obj.fields {
+        let kind = HLFieldKind::from_type(bytecode, &bytecode[field.t], hashlink_version);
+        size += kind.padding(size);
+        fields.push(HLField {
+            name: field.name(bytecode).to_string(),
+            type_index: field.t.0,
+            kind,
+            offset: size,
+        });
+        size += kind.size();
+    }
+
+    Some(HLObjType {
+        name: obj.name(bytecode).to_string(),
+        type_index,
+        super_name: obj.super_
+            .and_then(|RefType(index)| bytecode.types[index].get_type_obj())
+            .map(|parent| parent.name(bytecode).to_string()),
+        // Global references of types are 1-based, 0 means none
+        global: obj.global.0.checked_sub(1),
+        fields,
+        size,
+    })
+}
+
+/// Compute layouts of every class in the bytecode
+pub fn compute_obj_layouts(bytecode: &Bytecode, hashlink_version: u32) -> Vec<HLObjType> {
+    (0..bytecode.types.len())
+        .filter_map(|index| compute_obj_layout(bytecode, index, hashlink_version))
+        .collect()
+}
+
+/// Rust types that can be read from an object field
+pub trait HLFieldValue: Sized {
+    fn accepts(kind: HLFieldKind) -> bool;
+    fn from_bytes(bytes: &[u8]) -> Self;
+}
+
+macro_rules! impl_hl_field_value {
+    ($ty:ty, $($kind:pat_param)|+) => {
+        impl HLFieldValue for $ty {
+            fn accepts(kind: HLFieldKind) -> bool {
+                matches!(kind, $($kind)|+)
+            }
+
+            fn from_bytes(bytes: &[u8]) -> Self {
+                <$ty>::from_le_bytes(bytes.try_into().unwrap())
+            }
+        }
+    };
+}
+
+impl_hl_field_value!(u8, HLFieldKind::UI8);
+impl_hl_field_value!(u16, HLFieldKind::UI16);
+impl_hl_field_value!(i32, HLFieldKind::I32);
+impl_hl_field_value!(i64, HLFieldKind::I64);
+impl_hl_field_value!(f32, HLFieldKind::F32);
+impl_hl_field_value!(f64, HLFieldKind::F64);
+impl_hl_field_value!(usize, HLFieldKind::Pointer);
+
+impl HLFieldValue for bool {
+    fn accepts(kind: HLFieldKind) -> bool {
+        kind == HLFieldKind::Bool
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        bytes[0] != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_field_padding() {
+        // header (8) + bool (1) + i32 aligned to 4 + f64 aligned to 8 + pointer
+        let kinds = [HLFieldKind::Bool, HLFieldKind::I32, HLFieldKind::F64, HLFieldKind::Pointer];
+        let mut size = POINTER_SIZE;
+        let offsets: Vec<usize> = kinds.iter().map(|kind| {
+            size += kind.padding(size);
+            let offset = size;
+            size += kind.size();
+            offset
+        }).collect();
+
+        assert_eq!(offsets, vec![8, 12, 16, 24]);
+        assert_eq!(size, 32);
+    }
+
+    #[test]
+    fn test_field_value() {
+        assert!(i32::accepts(HLFieldKind::I32));
+        assert!(!i32::accepts(HLFieldKind::Pointer));
+        assert_eq!(i32::from_bytes(&[0xff, 0xff, 0xff, 0xff]), -1);
+        assert!(bool::from_bytes(&[1]));
+    }
+}
@@ -0,0 +1,505 @@
+/*
+    Decodes hashlink values from remote memory into a Rust value tree.
+    Supports strings, arrays (`hl.types.ArrayObj`, `hl.types.ArrayBytes_*`, native arrays),
+    objects, enums with their constructor parameters and dynamic values.
+*/
+
+use crate::modules::hashlink_types::{compute_obj_layout, HLFieldKind};
+use crate::utils::process_memory::ProcessMemory;
+use hlbc::types::{RefType, Type};
+use hlbc::Bytecode;
+use std::error::Error;
+
+const POINTER_SIZE: usize = 8;
+/// `hl_type.kind` of objects and enums in the runtime
+const HL_KIND_OBJ: i32 = 11;
+const HL_KIND_ARRAY: i32 = 12;
+const HL_KIND_ENUM: i32 = 18;
+const HL_KIND_STRUCT: i32 = 21;
+/// `sizeof(varray)`, elements of a native array are stored right after its header
+const VARRAY_HEADER_SIZE: usize = 24;
+/// `sizeof(hl_type*) + sizeof(int)`, first enum parameter is stored after the constructor index
+const VENUM_HEADER_SIZE: usize = 12;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HLValue {
+    Null,
+    UI8(u8),
+    UI16(u16),
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    Bool(bool),
+    String(String),
+    Array(Vec<HLValue>),
+    Object {
+        class_name: String,
+        fields: Vec<(String, HLValue)>,
+    },
+    Enum {
+        name: String,
+        construct: String,
+        index: usize,
+        params: Vec<HLValue>,
+    },
+    /// Value that is not decoded (functions, virtuals, raw bytes, maximum depth reached...)
+    Pointer(usize),
+}
+
+impl HLValue {
+    /// Field of an object value
+    pub fn field(&self, name: &str) -> Option<&HLValue> {
+        match self {
+            HLValue::Object { fields, .. } => fields
+                .iter()
+                .find(|(field_name, _)| field_name == name)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+}
+
+pub struct HLValueReader<'a> {
+    memory: &'a dyn ProcessMemory,
+    bytecode: &'a Bytecode,
+    hashlink_version: u32,
+    /// Objects nested deeper are returned as `HLValue::Pointer`, also stops reference cycles
+    pub max_depth: usize,
+}
+
+impl<'a> HLValueReader<'a> {
+    pub fn new(memory: &'a dyn ProcessMemory, bytecode: &'a Bytecode, hashlink_version: u32) -> Self {
+        Self {
+            memory,
+            bytecode,
+            hashlink_version,
+            max_depth: 4,
+        }
+    }
+
+    /// Decode a value of type `ty`.
+    /// For reference types `address` is the object pointer, for scalars it is where the value is stored.
+    pub fn read_value(&self, address: usize, ty: RefType) -> Result<HLValue, Box<dyn Error>> {
+        self.decode(address, ty, 0)
+    }
+
+    /// Decode the value stored at `slot` (object field, array element...)
+    fn read_slot(&self, slot: usize, ty: RefType, depth: usize) -> Result<HLValue, Box<dyn Error>> {
+        match self.kind(ty) {
+            HLFieldKind::Pointer => {
+                let pointer = self.read_u64(slot)? as usize;
+                self.decode(pointer, ty, depth)
+            }
+            _ => self.decode(slot, ty, depth),
+        }
+    }
+
+    fn decode(&self, address: usize, ty: RefType, depth: usize) -> Result<HLValue, Box<dyn Error>> {
+        let kind = self.kind(ty);
+        if kind != HLFieldKind::Pointer && !matches!(kind, HLFieldKind::Packed { .. }) {
+            return self.read_scalar(address, kind);
+        }
+        if address == 0 {
+            return Ok(HLValue::Null);
+        }
+        if depth > self.max_depth {
+            return Ok(HLValue::Pointer(address));
+        }
+
+        match &self.bytecode[ty] {
+            Type::Obj(obj) | Type::Struct(obj) => match &*obj.name(self.bytecode) {
+                "String" => self.read_string(address, ty),
+                "hl.types.ArrayObj" => self.read_array_obj(address, ty, depth),
+                name if name.starts_with("hl.types.ArrayBytes") => self.read_array_bytes(address, ty, name),
+                _ => self.read_object(address, ty, depth),
+            },
+            Type::Packed(inner) => self.read_object(address, *inner, depth),
+            Type::Enum { .. } => self.read_enum(address, ty, depth),
+            Type::Dyn => self.read_dynamic(address, depth),
+            Type::Array => self.read_native_array(address, depth),
+            Type::Null(inner) => match self.kind(*inner) {
+                // Boxed scalar, stored in `vdynamic.v`
+                HLFieldKind::Pointer => self.decode(address, *inner, depth),
+                _ => self.decode(address + POINTER_SIZE, *inner, depth),
+            },
+            _ => Ok(HLValue::Pointer(address)),
+        }
+    }
+
+    fn kind(&self, ty: RefType) -> HLFieldKind {
+        HLFieldKind::from_type(self.bytecode, &self.bytecode[ty], self.hashlink_version)
+    }
+
+    fn read_scalar(&self, address: usize, kind: HLFieldKind) -> Result<HLValue, Box<dyn Error>> {
+        let bytes = self.memory.read_bytes(address, kind.size())?;
+        Ok(match kind {
+            HLFieldKind::Void => HLValue::Null,
+            HLFieldKind::UI8 => HLValue::UI8(bytes[0]),
+            HLFieldKind::UI16 => HLValue::UI16(u16::from_le_bytes(bytes.try_into().unwrap())),
+            HLFieldKind::I32 => HLValue::I32(i32::from_le_bytes(bytes.try_into().unwrap())),
+            HLFieldKind::I64 => HLValue::I64(i64::from_le_bytes(bytes.try_into().unwrap())),
+            HLFieldKind::F32 => HLValue::F32(f32::from_le_bytes(bytes.try_into().unwrap())),
+            HLFieldKind::F64 => HLValue::F64(f64::from_le_bytes(bytes.try_into().unwrap())),
+            HLFieldKind::Bool => HLValue::Bool(bytes[0] != 0),
+            HLFieldKind::Pointer | HLFieldKind::Packed { .. } => {
+                HLValue::Pointer(u64::from_le_bytes(bytes[..8].try_into().unwrap()) as usize)
+            }
+        })
+    }
+
+    fn read_u64(&self, address: usize) -> Result<u64, Box<dyn Error>> {
+        let bytes = self.memory.read_bytes(address, 8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn read_i32(&self, address: usize) -> Result<i32, Box<dyn Error>> {
+        let bytes = self.memory.read_bytes(address, 4)?;
+        Ok(i32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    /// Read a null-terminated UTF-16 string, as used for runtime type names
+    fn read_utf16_nul(&self, address: usize) -> Result<String, Box<dyn Error>> {
+        const MAX_CHARS: usize = 512;
+        let mut chars = Vec::new();
+        while chars.len() < MAX_CHARS {
+            let bytes = self.memory.read_bytes(address + chars.len() * 2, 2)?;
+            let char = u16::from_le_bytes([bytes[0], bytes[1]]);
+            if char == 0 {
+                break;
+            }
+            chars.push(char);
+        }
+        Ok(String::from_utf16_lossy(&chars))
+    }
+
+    fn field_offset(&self, ty: RefType, name: &str) -> Result<usize, Box<dyn Error>> {
+        let layout = compute_obj_layout(self.bytecode, ty.0, self.hashlink_version)
+            .ok_or_else(|| format!("Type {} is not a class", ty.0))?;
+        Ok(layout.field(name)?.offset)
+    }
+
+    fn read_string(&self, address: usize, ty: RefType) -> Result<HLValue, Box<dyn Error>> {
+        let bytes = self.read_u64(address + self.field_offset(ty, "bytes")?)? as usize;
+        let length = self.read_i32(address + self.field_offset(ty, "length")?)?.max(0) as usize;
+        if bytes == 0 {
+            return Ok(HLValue::Null);
+        }
+
+        let data = self.memory.read_bytes(bytes, length * 2)?;
+        let chars: Vec<u16> = data
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        Ok(HLValue::String(String::from_utf16_lossy(&chars)))
+    }
+
+    /// `hl.types.ArrayObj`: `length` dynamic values stored in a native array
+    fn read_array_obj(&self, address: usize, ty: RefType, depth: usize) -> Result<HLValue, Box<dyn Error>> {
+        let length = self.read_i32(address + self.field_offset(ty, "length")?)?.max(0) as usize;
+        let array = self.read_u64(address + self.field_offset(ty, "array")?)? as usize;
+        if array == 0 {
+            return Ok(HLValue::Null);
+        }
+
+        let mut values = Vec::with_capacity(length);
+        for i in 0..length {
+            let element = self.read_u64(array + VARRAY_HEADER_SIZE + i * POINTER_SIZE)? as usize;
+            values.push(self.read_dynamic(element, depth + 1)?);
+        }
+        Ok(HLValue::Array(values))
+    }
+
+    /// `hl.types.ArrayBytes_*`: scalars stored in a raw buffer, element type is given by the class name
+    fn read_array_bytes(&self, address: usize, ty: RefType, name: &str) -> Result<HLValue, Box<dyn Error>> {
+        let kind = match name.rsplit('_').next() {
+            Some("Int") => HLFieldKind::I32,
+            Some("Float") => HLFieldKind::F64,
+            Some("Single") | Some("F32") => HLFieldKind::F32,
+            Some("UI8") => HLFieldKind::UI8,
+            Some("UI16") => HLFieldKind::UI16,
+            Some("I64") => HLFieldKind::I64,
+            _ => return Ok(HLValue::Pointer(address)),
+        };
+
+        let length = self.read_i32(address + self.field_offset(ty, "length")?)?.max(0) as usize;
+        let bytes = self.read_u64(address + self.field_offset(ty, "bytes")?)? as usize;
+        if bytes == 0 {
+            return Ok(HLValue::Null);
+        }
+
+        let values = (0..length)
+            .map(|i| self.read_scalar(bytes + i * kind.size(), kind))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(HLValue::Array(values))
+    }
+
+    /// Native `varray`: element type is only known at runtime
+    fn read_native_array(&self, address: usize, depth: usize) -> Result<HLValue, Box<dyn Error>> {
+        let element_type = self.read_u64(address + POINTER_SIZE)? as usize;
+        let length = self.read_i32(address + 2 * POINTER_SIZE)?.max(0) as usize;
+        let kind = runtime_kind(self.read_i32(element_type)?);
+
+        let mut values = Vec::with_capacity(length);
+        for i in 0..length {
+            let slot = address + VARRAY_HEADER_SIZE + i * kind.size();
+            values.push(match kind {
+                HLFieldKind::Pointer => {
+                    let element = self.read_u64(slot)? as usize;
+                    self.read_dynamic(element, depth + 1)?
+                }
+                _ => self.read_scalar(slot, kind)?,
+            });
+        }
+        Ok(HLValue::Array(values))
+    }
+
+    fn read_object(&self, address: usize, ty: RefType, depth: usize) -> Result<HLValue, Box<dyn Error>> {
+        let layout = compute_obj_layout(self.bytecode, ty.0, self.hashlink_version)
+            .ok_or_else(|| format!("Type {} is not a class", ty.0))?;
+
+        let mut fields = Vec::with_capacity(layout.fields.len());
+        for field in &layout.fields {
+            let value = self.read_slot(address + field.offset, RefType(field.type_index), depth + 1)?;
+            fields.push((field.name.clone(), value));
+        }
+        Ok(HLValue::Object {
+            class_name: layout.name,
+            fields,
+        })
+    }
+
+    fn read_enum(&self, address: usize, ty: RefType, depth: usize) -> Result<HLValue, Box<dyn Error>> {
+        let Type::Enum { name, constructs, .. } = &self.bytecode[ty] else {
+            return Err(format!("Type {} is not an enum", ty.0).into());
+        };
+
+        let index = self.read_i32(address + POINTER_SIZE)?;
+        let construct = usize::try_from(index)
+            .ok()
+            .and_then(|index| constructs.get(index))
+            .ok_or_else(|| format!("Invalid constructor index {} at {:#x}", index, address))?;
+
+        // Same layout as `hl_init_enum`
+        let mut offset = VENUM_HEADER_SIZE;
+        let mut params = Vec::with_capacity(construct.params.len());
+        for param in &construct.params {
+            let kind = self.kind(*param);
+            offset += kind.padding(offset);
+            params.push(self.read_slot(address + offset, *param, depth + 1)?);
+            offset += kind.size();
+        }
+
+        Ok(HLValue::Enum {
+            name: self.bytecode[*name].to_string(),
+            construct: construct.name(self.bytecode).to_string(),
+            index: index as usize,
+            params,
+        })
+    }
+
+    /// `vdynamic`: the value carries its runtime `hl_type*`
+    fn read_dynamic(&self, address: usize, depth: usize) -> Result<HLValue, Box<dyn Error>> {
+        if address == 0 {
+            return Ok(HLValue::Null);
+        }
+
+        let runtime_type = self.read_u64(address)? as usize;
+        let kind = self.read_i32(runtime_type)?;
+        match kind {
+            HL_KIND_OBJ | HL_KIND_STRUCT | HL_KIND_ENUM => {
+                // `hl_type.obj->name` for classes, `hl_type.tenum->name` for enums
+                let info = self.read_u64(runtime_type + POINTER_SIZE)? as usize;
+                let name_offset = if kind == HL_KIND_ENUM { 0 } else { 2 * POINTER_SIZE };
+                let name = self.read_utf16_nul(self.read_u64(info + name_offset)? as usize)?;
+                match self.find_type(&name) {
+                    Some(ty) => self.decode(address, ty, depth),
+                    None => Ok(HLValue::Pointer(address)),
+                }
+            }
+            HL_KIND_ARRAY => self.read_native_array(address, depth),
+            _ => match runtime_kind(kind) {
+                HLFieldKind::Pointer => Ok(HLValue::Pointer(address)),
+                scalar => self.read_scalar(address + POINTER_SIZE, scalar),
+            },
+        }
+    }
+
+    /// Find a class or enum type by name
+    fn find_type(&self, name: &str) -> Option<RefType> {
+        self.bytecode.types
+            .iter()
+            .position(|ty| match ty {
+                Type::Obj(obj) | Type::Struct(obj) => obj.name(self.bytecode) == name,
+                Type::Enum { name: enum_name, .. } => self.bytecode[*enum_name] == *name,
+                _ => false,
+            })
+            .map(RefType)
+    }
+}
+
+/// Storage of a value from its runtime `hl_type.kind`
+fn runtime_kind(kind: i32) -> HLFieldKind {
+    match kind {
+        0 => HLFieldKind::Void,
+        1 => HLFieldKind::UI8,
+        2 => HLFieldKind::UI16,
+        3 => HLFieldKind::I32,
+        4 => HLFieldKind::I64,
+        5 => HLFieldKind::F32,
+        6 => HLFieldKind::F64,
+        7 => HLFieldKind::Bool,
+        _ => HLFieldKind::Pointer,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::process_memory::{MockMemory, MEM_PRIVATE, PAGE_READWRITE};
+    use hlbc::types::{EnumConstruct, ObjField, RefGlobal, RefString, TypeObj};
+
+    const STRING: RefType = RefType(12);
+    const PLAYER: RefType = RefType(13);
+    const ARRAY_OBJ: RefType = RefType(14);
+    const STATE: RefType = RefType(15);
+    const ARRAY_INT: RefType = RefType(16);
+
+    fn obj(name: usize, fields: &[(usize, usize)]) -> Type {
+        let fields: Vec<ObjField> = fields
+            .iter()
+            .map(|&(name, t)| ObjField { name: RefString(name), t: RefType(t) })
+            .collect();
+        Type::Obj(TypeObj {
+            name: RefString(name),
+            super_: None,
+            global: RefGlobal(0),
+            own_fields: fields.clone(),
+            protos: Vec::new(),
+            bindings: Default::default(),
+            fields,
+        })
+    }
+
+    fn bytecode() -> Bytecode {
+        let mut bytecode = Bytecode::default();
+        bytecode.strings = [
+            "", "String", "bytes", "length", "game.Player", "name", "level", "alive", "items",
+            "hl.types.ArrayObj", "array", "game.State", "Idle", "Playing",
+            "hl.types.ArrayBytes_Int", "size",
+        ].iter().map(|s| (*s).into()).collect();
+        bytecode.types = vec![
+            Type::Void, Type::UI8, Type::UI16, Type::I32, Type::I64, Type::F32, Type::F64, Type::Bool,
+            Type::Type, Type::Dyn, Type::Bytes, Type::Array,
+            obj(1, &[(2, 10), (3, 3)]),
+            obj(4, &[(5, 12), (6, 3), (7, 7), (8, 14)]),
+            obj(9, &[(3, 3), (10, 11)]),
+            Type::Enum {
+                name: RefString(11),
+                global: RefGlobal(0),
+                constructs: vec![
+                    EnumConstruct { name: RefString(12), params: vec![] },
+                    EnumConstruct { name: RefString(13), params: vec![RefType(7), RefType(3), STRING] },
+                ],
+            },
+            obj(14, &[(3, 3), (2, 10), (15, 3)]),
+        ];
+        bytecode
+    }
+
+    fn utf16(text: &str) -> Vec<u8> {
+        text.encode_utf16().flat_map(|c| c.to_le_bytes()).collect()
+    }
+
+    /// Write a `String` object at `address`, its characters at `address + 0x20`
+    fn write_string(memory: &MockMemory, address: usize, text: &str) {
+        memory.write_bytes(address, &0x9000u64.to_le_bytes()).unwrap();
+        memory.write_bytes(address + 8, &((address + 0x20) as u64).to_le_bytes()).unwrap();
+        memory.write_bytes(address + 16, &(text.encode_utf16().count() as i32).to_le_bytes()).unwrap();
+        memory.write_bytes(address + 0x20, &utf16(text)).unwrap();
+    }
+
+    fn memory() -> MockMemory {
+        let memory = MockMemory::new();
+        memory.map(0x1000, 0x9000, PAGE_READWRITE, MEM_PRIVATE);
+
+        // Runtime `hl_type` of String: kind, obj -> name
+        memory.write_bytes(0x9000, &HL_KIND_OBJ.to_le_bytes()).unwrap();
+        memory.write_bytes(0x9008, &0x9100u64.to_le_bytes()).unwrap();
+        memory.write_bytes(0x9110, &0x9200u64.to_le_bytes()).unwrap();
+        memory.write_bytes(0x9200, &utf16("String\0")).unwrap();
+
+        // game.Player { name, level, alive, items }
+        memory.write_bytes(0x1008, &0x2000u64.to_le_bytes()).unwrap();
+        memory.write_bytes(0x1010, &42i32.to_le_bytes()).unwrap();
+        memory.write_bytes(0x1014, &[1]).unwrap();
+        memory.write_bytes(0x1018, &0x3000u64.to_le_bytes()).unwrap();
+        write_string(&memory, 0x2000, "Rook");
+
+        // ArrayObj { length: 2, array } with a String and a null element
+        memory.write_bytes(0x3008, &2i32.to_le_bytes()).unwrap();
+        memory.write_bytes(0x3010, &0x3100u64.to_le_bytes()).unwrap();
+        memory.write_bytes(0x3100 + VARRAY_HEADER_SIZE, &0x4000u64.to_le_bytes()).unwrap();
+        write_string(&memory, 0x4000, "sword");
+
+        // game.State.Playing(true, 7, "arena")
+        memory.write_bytes(0x5008, &1i32.to_le_bytes()).unwrap();
+        memory.write_bytes(0x500C, &[1]).unwrap();
+        memory.write_bytes(0x5010, &7i32.to_le_bytes()).unwrap();
+        memory.write_bytes(0x5018, &0x6000u64.to_le_bytes()).unwrap();
+        write_string(&memory, 0x6000, "arena");
+
+        // ArrayBytes_Int { length: 3, bytes, size }
+        memory.write_bytes(0x7008, &3i32.to_le_bytes()).unwrap();
+        memory.write_bytes(0x7010, &0x7100u64.to_le_bytes()).unwrap();
+        for (i, value) in [5i32, -1, 9].iter().enumerate() {
+            memory.write_bytes(0x7100 + i * 4, &value.to_le_bytes()).unwrap();
+        }
+        memory
+    }
+
+    #[test]
+    fn test_read_object() {
+        let (bytecode, memory) = (bytecode(), memory());
+        let reader = HLValueReader::new(&memory, &bytecode, 5);
+        let player = reader.read_value(0x1000, PLAYER).unwrap();
+
+        assert_eq!(player.field("name"), Some(&HLValue::String("Rook".into())));
+        assert_eq!(player.field("level"), Some(&HLValue::I32(42)));
+        assert_eq!(player.field("alive"), Some(&HLValue::Bool(true)));
+        assert_eq!(player.field("items"), Some(&HLValue::Array(vec![
+            HLValue::String("sword".into()),
+            HLValue::Null,
+        ])));
+    }
+
+    #[test]
+    fn test_read_enum_and_arrays() {
+        let (bytecode, memory) = (bytecode(), memory());
+        let reader = HLValueReader::new(&memory, &bytecode, 5);
+
+        assert_eq!(reader.read_value(0x5000, STATE).unwrap(), HLValue::Enum {
+            name: "game.State".into(),
+            construct: "Playing".into(),
+            index: 1,
+            params: vec![HLValue::Bool(true), HLValue::I32(7), HLValue::String("arena".into())],
+        });
+        assert_eq!(
+            reader.read_value(0x7000, ARRAY_INT).unwrap(),
+            HLValue::Array(vec![HLValue::I32(5), HLValue::I32(-1), HLValue::I32(9)]),
+        );
+        assert_eq!(reader.read_value(0, ARRAY_OBJ).unwrap(), HLValue::Null);
+        assert_eq!(reader.read_value(0x4000, RefType(9)).unwrap(), HLValue::String("sword".into()));
+        assert_eq!(reader.read_value(0x1010, RefType(3)).unwrap(), HLValue::I32(42));
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let (bytecode, memory) = (bytecode(), memory());
+        let mut reader = HLValueReader::new(&memory, &bytecode, 5);
+        reader.max_depth = 0;
+
+        let player = reader.read_value(0x1000, PLAYER).unwrap();
+        assert_eq!(player.field("name"), Some(&HLValue::Pointer(0x2000)));
+    }
+}
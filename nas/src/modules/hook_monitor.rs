@@ -0,0 +1,257 @@
+/*
+    Background check of the applied hooks: every watched site must still hold the trampoline
+    written by its `LibmemInjection`. Overwritten sites are reported with a `HookTamperEvent`
+    through the event system and handled according to the `TamperPolicy` of the hook.
+    Disabled hooks are reported with their owner, so the module can be turned off with them.
+*/
+
+use crate::modules::callback_system::{self, EventManager};
+use crate::modules::libmem_injection::LibmemInjection;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Shared slot of an injection, `None` while it is not applied
+pub type InjectionSlot = Arc<Mutex<Option<LibmemInjection>>>;
+
+/// What to do with a hook whose site was overwritten
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TamperPolicy {
+    /// Write the trampoline again
+    #[default]
+    Reapply,
+    /// Restore the original bytes and drop the injection
+    Disable,
+    /// Only report it
+    Report,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TamperAction {
+    Reapplied,
+    Disabled,
+    Reported,
+    /// The policy could not be applied
+    Failed(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct TamperedHook {
+    pub name: String,
+    /// Patch owner, `"{module} {name}"` for the injections of a module
+    pub owner: String,
+    pub address: usize,
+    pub expected: Vec<u8>,
+    pub found: Vec<u8>,
+    pub action: TamperAction,
+}
+
+/// Hooks found overwritten during one check
+#[derive(Debug, Clone)]
+pub struct HookTamperEvent {
+    pub hooks: Vec<TamperedHook>,
+}
+
+struct WatchedHook {
+    name: String,
+    slot: Weak<Mutex<Option<LibmemInjection>>>,
+    policy: TamperPolicy,
+}
+
+pub struct HookMonitor {
+    hooks: Mutex<Vec<WatchedHook>>,
+    events: Arc<EventManager>,
+    running: AtomicBool,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl TamperedHook {
+    /// Module owning the hook, first word of `owner`
+    pub fn module(&self) -> &str {
+        self.owner.split(' ').next().unwrap_or_default()
+    }
+}
+
+impl HookMonitor {
+    pub fn new(events: Arc<EventManager>) -> Self {
+        Self {
+            hooks: Mutex::new(Vec::new()),
+            events,
+            running: AtomicBool::new(false),
+            thread: Mutex::new(None),
+        }
+    }
+
+    /// Watch the injection in `slot` until the slot is dropped
+    pub fn watch(&self, name: &str, slot: &InjectionSlot, policy: TamperPolicy) {
+        let mut hooks = self.hooks.lock().unwrap();
+        let slot = Arc::downgrade(slot);
+        hooks.retain(|hook| hook.slot.strong_count() > 0 && !hook.slot.ptr_eq(&slot));
+        hooks.push(WatchedHook { name: name.to_string(), slot, policy });
+    }
+
+    /// Number of watched slots still alive
+    pub fn watched(&self) -> usize {
+        let mut hooks = self.hooks.lock().unwrap();
+        hooks.retain(|hook| hook.slot.strong_count() > 0);
+        hooks.len()
+    }
+
+    /// Compare every applied hook with its expected bytes and apply the policies.
+    /// Emits a `HookTamperEvent` when something was overwritten
+    pub fn check(&self) -> Vec<TamperedHook> {
+        let watched: Vec<(String, InjectionSlot, TamperPolicy)> = {
+            let mut hooks = self.hooks.lock().unwrap();
+            hooks.retain(|hook| hook.slot.strong_count() > 0);
+            hooks
+                .iter()
+                .filter_map(|hook| Some((hook.name.clone(), hook.slot.upgrade()?, hook.policy)))
+                .collect()
+        };
+
+        let mut tampered = Vec::new();
+        for (name, slot, policy) in watched {
+            let mut injection = slot.lock().unwrap();
+            let Some(inj) = injection.as_ref() else {
+                continue;
+            };
+            let found = match inj.site_bytes() {
+                Ok(found) => found,
+                Err(e) => {
+                    tracing::warn!("Cannot read hook {} at 0x{:X}: {}", name, inj.address(), e);
+                    continue;
+                }
+            };
+            if found == inj.patch_bytes() {
+                continue;
+            }
+
+            let result = match policy {
+                TamperPolicy::Reapply => inj.commit().map(|_| TamperAction::Reapplied),
+                TamperPolicy::Disable => inj.undo().map(|_| TamperAction::Disabled),
+                TamperPolicy::Report => Ok(TamperAction::Reported),
+            };
+            let action = result.unwrap_or_else(|e| TamperAction::Failed(e.to_string()));
+            tracing::warn!("Hook {} at 0x{:X} was overwritten: {:?}", name, inj.address(), action);
+
+            tampered.push(TamperedHook {
+                name,
+                owner: inj.owner().to_string(),
+                address: inj.address(),
+                expected: inj.patch_bytes().to_vec(),
+                found,
+                action: action.clone(),
+            });
+            if action == TamperAction::Disabled {
+                *injection = None;
+            }
+        }
+
+        if !tampered.is_empty() {
+            self.events.emit(HookTamperEvent { hooks: tampered.clone() });
+        }
+        tampered
+    }
+
+    /// Run `check` every `interval` on a background thread, until `stop` or the monitor is dropped
+    pub fn start(self: &Arc<Self>, interval: Duration) {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let monitor = Arc::downgrade(self);
+        let thread = std::thread::spawn(move || loop {
+            std::thread::park_timeout(interval);
+            let Some(monitor) = monitor.upgrade() else {
+                break;
+            };
+            if !monitor.running.load(Ordering::SeqCst) {
+                break;
+            }
+            monitor.check();
+        });
+        *self.thread.lock().unwrap() = Some(thread);
+        tracing::info!("Hook monitor started, checking every {:?}", interval);
+    }
+
+    /// Stop the background checks and wait for the thread to exit, so no check runs during eject
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::SeqCst);
+        let Some(thread) = self.thread.lock().unwrap().take() else {
+            return;
+        };
+        if thread.thread().id() != std::thread::current().id() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+pub fn instance() -> &'static Arc<HookMonitor> {
+    static INSTANCE: once_cell::sync::Lazy<Arc<HookMonitor>> =
+        once_cell::sync::Lazy::new(|| Arc::new(HookMonitor::new(callback_system::instance().clone())));
+    &INSTANCE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::process_memory::{MockMemory, ProcessMemory, MEM_IMAGE, PAGE_EXECUTE_READ};
+    use iced_x86::code_asm::*;
+
+    const SITE: usize = 0x1_4000_0010;
+
+    fn hooked_slot(memory: &Arc<dyn ProcessMemory>) -> InjectionSlot {
+        let mut code = CodeAssembler::new(64).unwrap();
+        code.nop().unwrap();
+        let injection = LibmemInjection::prepare_detour(memory, "AutoAccept setCheckedJoin", SITE, &mut code).unwrap();
+        Arc::new(Mutex::new(Some(injection.committed().unwrap())))
+    }
+
+    #[test]
+    fn test_tamper_policies() {
+        let mock = MockMemory::new();
+        mock.map(0x1_4000_0000, 0x1000, PAGE_EXECUTE_READ, MEM_IMAGE);
+        // mov rax, rcx; add rax, 5; ret
+        let original = [0x48, 0x89, 0xC8, 0x48, 0x83, 0xC0, 0x05, 0xC3];
+        mock.write_bytes(SITE, &original).unwrap();
+        let memory: Arc<dyn ProcessMemory> = Arc::new(mock);
+
+        let events = Arc::new(EventManager::new());
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let sink = reported.clone();
+        events.register(move |event: &HookTamperEvent| {
+            sink.lock().unwrap().extend(event.hooks.iter().map(|hook| hook.action.clone()));
+        });
+        let monitor = HookMonitor::new(events.clone());
+
+        let slot = hooked_slot(&memory);
+        monitor.watch("setCheckedJoin", &slot, TamperPolicy::Reapply);
+        assert!(monitor.check().is_empty());
+
+        // Another tool restores the function
+        memory.write_bytes(SITE, &original[..7]).unwrap();
+        let tampered = monitor.check();
+        assert_eq!(tampered.len(), 1);
+        assert_eq!(tampered[0].found, &original[..7]);
+        assert_eq!(tampered[0].action, TamperAction::Reapplied);
+        let expected = slot.lock().unwrap().as_ref().unwrap().patch_bytes().to_vec();
+        assert_eq!(memory.read_bytes(SITE, 7).unwrap(), expected);
+        events.update();
+        assert_eq!(*reported.lock().unwrap(), [TamperAction::Reapplied]);
+
+        // Watching the same slot again replaces its policy
+        monitor.watch("setCheckedJoin", &slot, TamperPolicy::Disable);
+        assert_eq!(monitor.watched(), 1);
+        memory.write_bytes(SITE, &[0xCC]).unwrap();
+        let tampered = monitor.check();
+        assert_eq!(tampered[0].action, TamperAction::Disabled);
+        assert_eq!(tampered[0].module(), "AutoAccept");
+        assert!(slot.lock().unwrap().is_none());
+        assert_eq!(memory.read_bytes(SITE, 8).unwrap(), original);
+        assert!(monitor.check().is_empty());
+
+        drop(slot);
+        assert_eq!(monitor.watched(), 0);
+    }
+}
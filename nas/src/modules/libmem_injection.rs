@@ -1,9 +1,37 @@
-use crate::utils::libmem_ex::{read_bytes_ex, write_bytes_ex, free, allocate_region_near};
+use crate::modules::cave_pool::{CaveBlock, CaveKind, CavePool};
+use crate::modules::context_hook::ContextHandler;
+use crate::modules::patch_map::PatchMap;
+use crate::utils::process_memory::ProcessMemory;
 use iced_x86::code_asm::*;
-use iced_x86::{Decoder, DecoderOptions};
-use libmem::Process;
-use windows::Win32::System::Memory::PAGE_EXECUTE_READWRITE;
+use iced_x86::{BlockEncoder, BlockEncoderOptions, Code, Decoder, DecoderOptions, Instruction, InstructionBlock, OpKind};
 use std::error::Error;
+use std::fmt;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Tries to catch every other thread outside the bytes being patched
+const PATCH_ATTEMPTS: usize = 100;
+const PATCH_RETRY_DELAY: Duration = Duration::from_millis(5);
+/// Room left in the cave for relocated instructions that grow at its final address
+const CAVE_SLACK: usize = 0x20;
+
+/// How a `LibmemInjection` changes the code at its address
+pub enum PatchMode {
+    /// Run the payload, then the overwritten instructions, then continue after them
+    Detour(CodeAssembler),
+    /// Replace the first `count` instructions with `code`, in place when it fits and from a cave otherwise
+    Replace { count: usize, code: CodeAssembler },
+    /// Overwrite `len` bytes of whole instructions with NOPs
+    Nop { len: usize },
+    /// Return the value in RAX right away, for a function entry
+    Return(u64),
+    /// Send the call at the address to another function
+    CallTarget(usize),
+    /// Run a context handler at a function entry, it reads the arguments from its `HookContext`
+    /// and can return early
+    Entry(ContextHandler),
+}
 
 #[allow(dead_code)]
 pub struct CodeEntry {
@@ -14,92 +42,986 @@ pub struct CodeEntry {
 
 #[allow(dead_code)]
 pub struct LibmemInjection {
-    pid: u32,
     address: usize,
     original_bytes: Vec<u8>,
-    allocated_addr: usize,
-    allocated_size: usize,
-    next_free_addr: usize,
-    next_free_size: usize,
+    /// Trampoline and NOP padding written over the hook site
+    patch_bytes: Vec<u8>,
+    /// Patches written in place have no cave
+    cave: Option<CaveBlock>,
+    /// Bytes written to the cave
+    cave_len: usize,
     entries: Vec<CodeEntry>,
-    process: Process,
+    memory: Arc<dyn ProcessMemory>,
+    pool: Arc<CavePool>,
+    patches: Arc<PatchMap>,
+    /// Reservation of the overwritten bytes in `patches`
+    patch_id: usize,
+    /// Owner of the reservation
+    owner: String,
     overwritten_len: usize,
     resume_address: usize,
+    /// Called by an `Entry` patch, unregistered once the injection is dropped
+    handler: Option<ContextHandler>,
 }
 
 impl LibmemInjection {
-    pub fn new(pid: u32, address: usize, code: &mut CodeAssembler, process: &Process) -> Result<Self, Box<dyn Error>> {
-        // Allocate memory near the target address (payload + room for stolen bytes + tail jmp)
-        // We will assemble the payload using the allocated base address to ensure correct relative offsets
-        let alloc = allocate_region_near(pid, address, 0x1000, PAGE_EXECUTE_READWRITE)?;
-        let payload_bytes = code.assemble(alloc.base_address as u64)?;
+    pub fn new(memory: &Arc<dyn ProcessMemory>, address: usize, code: &mut CodeAssembler) -> Result<Self, Box<dyn Error>> {
+        Self::prepare(memory, address, code)?.committed()
+    }
+
+    pub fn with_mode(memory: &Arc<dyn ProcessMemory>, address: usize, mode: PatchMode) -> Result<Self, Box<dyn Error>> {
+        Self::prepare_mode(memory, address, mode)?.committed()
+    }
+
+    /// Allocate and write the cave without touching the hook site, `commit` patches it
+    pub fn prepare(memory: &Arc<dyn ProcessMemory>, address: usize, code: &mut CodeAssembler) -> Result<Self, Box<dyn Error>> {
+        Self::prepare_detour(memory, &format!("hook 0x{:X}", address), address, code)
+    }
+
+    /// `prepare` listed under `owner` in the patch map and the cave pool
+    pub fn prepare_detour(memory: &Arc<dyn ProcessMemory>, owner: &str, address: usize, code: &mut CodeAssembler) -> Result<Self, Box<dyn Error>> {
+        // Refused before the bytes of another patch are decoded
+        PatchMap::shared(memory).check(owner, address, 1)?;
+        Self::detour(memory, owner, address, code)?.reserved(owner)
+    }
+
+    /// `prepare` for any patch mode, `preview` shows the result before it is committed
+    pub fn prepare_mode(memory: &Arc<dyn ProcessMemory>, address: usize, mode: PatchMode) -> Result<Self, Box<dyn Error>> {
+        Self::prepare_owned(memory, &format!("hook 0x{:X}", address), address, mode)
+    }
+
+    /// `prepare_mode` listed under `owner`. The overwritten bytes are reserved in the patch map,
+    /// a patch overlapping another one is refused
+    pub fn prepare_owned(memory: &Arc<dyn ProcessMemory>, owner: &str, address: usize, mode: PatchMode) -> Result<Self, Box<dyn Error>> {
+        PatchMap::shared(memory).check(owner, address, 1)?;
+        Self::build(memory, owner, address, mode)?.reserved(owner)
+    }
+
+    fn reserved(mut self, owner: &str) -> Result<Self, Box<dyn Error>> {
+        match self.patches.reserve(owner, self.address, self.overwritten_len) {
+            Ok(id) => {
+                self.patch_id = id;
+                self.owner = owner.to_string();
+            }
+            Err(e) => {
+                self.discard();
+                return Err(e);
+            }
+        }
+        Ok(self)
+    }
+
+    fn detour(memory: &Arc<dyn ProcessMemory>, owner: &str, address: usize, code: &mut CodeAssembler) -> Result<Self, Box<dyn Error>> {
+        // Determine stolen instructions (>= near JMP size), refused early if they cannot be moved
+        let stolen = steal_instructions(memory.as_ref(), address, 5)?;
+        let stolen_len: usize = stolen.iter().map(Instruction::len).sum();
+        check_relocatable(&stolen)?;
+
+        // Payload, the stolen instructions moved after it and a jump back
+        let resume = (address + stolen_len) as u64;
+        let original = memory.read_bytes(address, stolen_len)?;
+        Self::with_cave(memory, owner, address, original, |base, size| build_cave(base, size, code, &stolen, resume))
+    }
+
+    fn build(memory: &Arc<dyn ProcessMemory>, owner: &str, address: usize, mode: PatchMode) -> Result<Self, Box<dyn Error>> {
+        match mode {
+            PatchMode::Detour(mut code) => Self::detour(memory, owner, address, &mut code),
+            PatchMode::Replace { count, mut code } => {
+                let replaced = decode_instructions(memory.as_ref(), address, count)?;
+                let replaced_len: usize = replaced.iter().map(Instruction::len).sum();
+                let replacement = code.assemble(address as u64)?;
+                if replacement.len() <= replaced_len {
+                    return Self::in_place(memory, address, replaced_len, replacement);
+                }
+                if replaced_len < 5 {
+                    return Err(format!(
+                        "Cannot replace {} bytes at 0x{:X} with {} bytes of code: no room for a jump",
+                        replaced_len,
+                        address,
+                        replacement.len()
+                    )
+                    .into());
+                }
+                let resume = (address + replaced_len) as u64;
+                let original = memory.read_bytes(address, replaced_len)?;
+                Self::with_cave(memory, owner, address, original, |base, size| build_cave(base, size, &mut code, &[], resume))
+            }
+            PatchMode::Nop { len } => {
+                let replaced = steal_instructions(memory.as_ref(), address, len)?;
+                if let Some(last) = replaced.last().filter(|last| last.next_ip() != (address + len) as u64) {
+                    return Err(format!("Cannot NOP {:#x} bytes at 0x{:X}: the range ends inside `{}`", len, address, last).into());
+                }
+                Self::in_place(memory, address, len, vec![0x90; len])
+            }
+            PatchMode::Return(value) => {
+                let mut code = CodeAssembler::new(64)?;
+                match value {
+                    0 => code.xor(eax, eax)?,
+                    // Writing EAX clears the upper half of RAX
+                    value if value <= u32::MAX as u64 => code.mov(eax, value as u32)?,
+                    value => code.mov(rax, value)?,
+                }
+                code.ret()?;
+                let bytes = code.assemble(address as u64)?;
+                let replaced = steal_instructions(memory.as_ref(), address, bytes.len())?;
+                Self::in_place(memory, address, replaced.iter().map(Instruction::len).sum(), bytes)
+            }
+            PatchMode::CallTarget(target) => {
+                let call = steal_instructions(memory.as_ref(), address, 1)?[0];
+                let replaceable = call.code() == Code::Call_rel32_64 || (call.code() == Code::Call_rm64 && call.is_ip_rel_memory_operand());
+                if !replaceable {
+                    return Err(format!("Cannot replace the call target at 0x{:X}: `{}` is not a direct call", address, call).into());
+                }
+                if i32::try_from(target as i64 - (address + 5) as i64).is_ok() {
+                    return Self::in_place(memory, address, call.len(), near_call(address, target)?);
+                }
+                // Out of rel32 reach: call a cave jumping to the target
+                let original = memory.read_bytes(address, call.len())?;
+                let mut injection = Self::with_cave(memory, owner, address, original, |_, _| Ok(far_jump(target)))?;
+                let mut patch_bytes = near_call(address, injection.cave.as_ref().unwrap().address)?;
+                patch_bytes.resize(call.len(), 0x90);
+                injection.patch_bytes = patch_bytes;
+                Ok(injection)
+            }
+            PatchMode::Entry(handler) => {
+                let mut injection = Self::detour(memory, owner, address, &mut handler.code()?)?;
+                injection.handler = Some(handler);
+                Ok(injection)
+            }
+        }
+    }
+
+    /// Commit a prepared injection, it is discarded when the hook site cannot be patched
+    pub fn committed(self) -> Result<Self, Box<dyn Error>> {
+        if let Err(e) = self.commit() {
+            self.discard();
+            return Err(e);
+        }
+        Ok(self)
+    }
 
-        // Build trampoline bytes targeting our allocated payload
+    /// Patch of `len` bytes of whole instructions, padded with NOPs
+    fn in_place(memory: &Arc<dyn ProcessMemory>, address: usize, len: usize, mut patch_bytes: Vec<u8>) -> Result<Self, Box<dyn Error>> {
+        let original = memory.read_bytes(address, len)?;
+        patch_bytes.resize(len, 0x90);
+        Ok(Self::from_parts(memory, address, original, patch_bytes, None, 0))
+    }
+
+    /// Cave written by `build` for its base address and size, reached by a jump replacing the
+    /// `original` bytes at `address`, kept for undo
+    fn with_cave<F>(memory: &Arc<dyn ProcessMemory>, owner: &str, address: usize, original: Vec<u8>, mut build: F) -> Result<Self, Box<dyn Error>>
+    where
+        F: FnMut(usize, usize) -> Result<Vec<u8>, Box<dyn Error>>,
+    {
+        let len = original.len();
+        // Reserve a block near the target address, sized from the cave assembled at the hook site
+        let pool = CavePool::shared(memory);
+        let estimate = build(address, usize::MAX)?.len() + CAVE_SLACK;
+        let alloc = pool.allocate(owner, CaveKind::Code, estimate, Some(address))?;
+        // We assemble the cave again at the block address to ensure correct relative offsets
+        let written = build(alloc.address, alloc.size)
+            .and_then(|cave_bytes| memory.write_bytes(alloc.address, &cave_bytes).map(|_| cave_bytes.len()));
+        let cave_len = match written {
+            Ok(cave_len) => cave_len,
+            Err(e) => {
+                let _ = pool.free(alloc.address);
+                return Err(e);
+            }
+        };
+
+        // Trampoline to our allocated payload, the remainder of stolen bytes is NOPed
         let mut trampoline_final = CodeAssembler::new(64)?;
-        trampoline_final.jmp(alloc.base_address as u64)?;
-        let trampoline_bytes = trampoline_final.assemble(address as u64)?;
-
-        // Determine stolen instruction length (>= near JMP size)
-        let probe = read_bytes_ex(process, address, 32).ok_or("libmem read_bytes_ex failed")?;
-        let mut decoder = Decoder::with_ip(64, &probe, address as u64, DecoderOptions::NONE);
-        let mut stolen_len = 0usize;
-        while stolen_len < trampoline_bytes.len() {
-            let instr = decoder.decode();
-            let ilen = instr.len();
-            if ilen == 0 { break; }
-            stolen_len += ilen;
-        }
-
-        // Read stolen bytes exactly for undo and for re-emitting in the stub
-        let original = read_bytes_ex(process, address, stolen_len).ok_or("libmem read_bytes_ex failed")?;
-
-        // Write the payload to allocated memory
-        write_bytes_ex(process, alloc.base_address, &payload_bytes).ok_or("libmem write_bytes_ex failed")?;
-
-        // Emit stolen bytes into the allocated region after our payload
-        let stolen_dst = alloc.base_address + payload_bytes.len();
-        write_bytes_ex(process, stolen_dst, &original).ok_or("libmem write_bytes_ex failed")?;
-
-        // Append a tail jump back to the original function after the stolen bytes
-        let resume_address = address + stolen_len;
-        let mut tail = CodeAssembler::new(64)?;
-        tail.jmp(resume_address as u64)?;
-        let tail_bytes = tail.assemble((stolen_dst + original.len()) as u64)?;
-        write_bytes_ex(process, stolen_dst + original.len(), &tail_bytes).ok_or("libmem write_bytes_ex failed")?;
-
-        // Patch the hook site with the trampoline and NOP the remainder of stolen bytes
-        write_bytes_ex(process, address, &trampoline_bytes).ok_or("libmem write_bytes_ex failed")?;
-        if stolen_len > trampoline_bytes.len() {
-            let nops = vec![0x90u8; stolen_len - trampoline_bytes.len()];
-            write_bytes_ex(process, address + trampoline_bytes.len(), &nops).ok_or("libmem write_bytes_ex failed")?;
-        }
-
-        let original_len = original.len();
-        Ok(Self {
-            pid,
+        trampoline_final.jmp(alloc.address as u64)?;
+        let mut patch_bytes = trampoline_final.assemble(address as u64)?;
+        patch_bytes.resize(len, 0x90);
+
+        Ok(Self::from_parts(memory, address, original, patch_bytes, Some(alloc), cave_len))
+    }
+
+    fn from_parts(
+        memory: &Arc<dyn ProcessMemory>,
+        address: usize,
+        original_bytes: Vec<u8>,
+        patch_bytes: Vec<u8>,
+        cave: Option<CaveBlock>,
+        cave_len: usize,
+    ) -> Self {
+        Self {
             address,
-            original_bytes: original.clone(),
-            allocated_addr: alloc.base_address,
-            allocated_size: alloc.region_size,
-            next_free_addr: alloc.base_address + payload_bytes.len() + original_len + tail_bytes.len(),
-            next_free_size: alloc.region_size - (payload_bytes.len() + original_len + tail_bytes.len()),
+            overwritten_len: original_bytes.len(),
+            resume_address: address + original_bytes.len(),
+            original_bytes,
+            patch_bytes,
+            cave,
+            cave_len,
             entries: Vec::new(),
-            process: process.clone(),
-            overwritten_len: stolen_len,
-            resume_address,
+            memory: memory.clone(),
+            pool: CavePool::shared(memory),
+            patches: PatchMap::shared(memory),
+            patch_id: 0,
+            owner: String::new(),
+            handler: None,
+        }
+    }
+
+    pub fn address(&self) -> usize {
+        self.address
+    }
+
+    /// Owner listed in the patch map, empty while the site is not reserved
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+
+    /// Bytes the hook site should contain while the injection is applied
+    pub fn patch_bytes(&self) -> &[u8] {
+        &self.patch_bytes
+    }
+
+    /// Block holding the injected code, if the patch needed one
+    pub fn cave(&self) -> Option<Range<usize>> {
+        self.cave.as_ref().map(CaveBlock::range)
+    }
+
+    /// Current bytes at the hook site, as long as the patch
+    pub fn site_bytes(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.memory.read_bytes(self.address, self.patch_bytes.len())
+    }
+
+    /// Disassembly of the hook site before and after the patch, and of the cave
+    pub fn preview(&self) -> Result<PatchPreview, Box<dyn Error>> {
+        let cave = match &self.cave {
+            Some(cave) => disassemble(&self.memory.read_bytes(cave.address, self.cave_len)?, cave.address),
+            None => Vec::new(),
+        };
+        Ok(PatchPreview {
+            address: self.address,
+            before: disassemble(&self.original_bytes, self.address),
+            after: disassemble(&self.patch_bytes, self.address),
+            cave,
         })
     }
 
-    /// Undo the injection and free allocated memory
+    /// Patch the hook site with the trampoline, also restores it after it was overwritten.
+    /// Waits until no thread is in the middle of the replaced instructions or returns into them
+    pub fn commit(&self) -> Result<(), Box<dyn Error>> {
+        patch_suspended(self.memory.as_ref(), &[self.site_interior()], self.address, &self.patch_bytes)
+            .map_err(|e| format!("Cannot patch 0x{:X}: {}", self.address, e).into())
+    }
+
+    /// Free the cave and the reservation of an injection that was never committed or was undone.
+    /// Takes the pool lock, never call it while the other threads are suspended
+    pub fn discard(&self) {
+        self.patches.release(self.patch_id);
+        self.free_cave();
+    }
+
+    fn free_cave(&self) {
+        if let Some(cave) = &self.cave {
+            if let Err(e) = self.pool.free(cave.address) {
+                tracing::warn!("Failed to free injection cave at 0x{:X}: {}", cave.address, e);
+            }
+        }
+    }
+
+    /// Undo the injection and free allocated memory, once no thread executes or returns into the hook site or the cave
     pub fn undo(&self) -> Result<(), Box<dyn Error>> {
-        // Restore original bytes using libmem
-        write_bytes_ex(&self.process, self.address, &self.original_bytes)
-            .ok_or("libmem write_bytes_ex failed")?;
+        self.write_original()?;
+        // Freed once the threads run again, one of them may have held the pool lock
+        self.discard();
+        Ok(())
+    }
+
+    /// Put the original bytes back but keep the cave, so the site can be prepared again and the
+    /// injection applied with `reapply` if its replacement fails. `discard` frees it otherwise
+    pub fn restore(&mut self) -> Result<(), Box<dyn Error>> {
+        self.write_original()?;
+        self.patches.release(self.patch_id);
+        Ok(())
+    }
+
+    /// Reserve the site again and commit an injection taken off with `restore`
+    pub fn reapply(mut self) -> Result<Self, Box<dyn Error>> {
+        let owner = std::mem::take(&mut self.owner);
+        self.reserved(&owner)?.committed()
+    }
+
+    /// Detour running `code` at the site of this one, with the same stolen instructions. Only its
+    /// cave is written, `replace_with` patches the site
+    pub fn prepare_replacement(&self, code: &mut CodeAssembler) -> Result<Self, Box<dyn Error>> {
+        if self.cave.is_none() {
+            return Err(format!("Cannot replace the patch at 0x{:X}: it is not a detour", self.address).into());
+        }
+        // The site holds our trampoline, the stolen instructions are decoded from the saved bytes
+        let stolen: Vec<Instruction> =
+            Decoder::with_ip(64, &self.original_bytes, self.address as u64, DecoderOptions::NONE).into_iter().collect();
+        let resume = self.resume_address as u64;
+        let mut replacement = Self::with_cave(&self.memory, &self.owner, self.address, self.original_bytes.clone(), |base, size| {
+            build_cave(base, size, code, &stolen, resume)
+        })?;
+        replacement.owner = self.owner.clone();
+        Ok(replacement)
+    }
+
+    /// Patch the site with a `prepare_replacement` detour in one write, once no thread runs this
+    /// injection. The replacement takes over the reservation and this cave is freed
+    pub fn replace_with(&self, mut replacement: Self) -> Result<Self, Box<dyn Error>> {
+        let mut busy = vec![self.site_interior()];
+        busy.extend(self.cave());
+        if let Err(e) = patch_suspended(self.memory.as_ref(), &busy, self.address, &replacement.patch_bytes) {
+            replacement.discard();
+            return Err(format!("Cannot replace injection at 0x{:X}: {}", self.address, e).into());
+        }
+        replacement.patch_id = self.patch_id;
+        self.free_cave();
+        Ok(replacement)
+    }
+
+    fn write_original(&self) -> Result<(), Box<dyn Error>> {
+        let mut busy = vec![self.site_interior()];
+        busy.extend(self.cave());
+        patch_suspended(self.memory.as_ref(), &busy, self.address, &self.original_bytes)
+            .map_err(|e| format!("Cannot undo injection at 0x{:X}: {}", self.address, e).into())
+    }
 
-        // Free allocated memory via libmem
-        free(&self.process, self.allocated_addr, self.allocated_size)
-            .ok_or("libmem free failed")?;
+    /// A thread stopped on the first replaced instruction is safe, it runs the new bytes from
+    /// their start. Anywhere after that it would resume in the middle of an instruction
+    fn site_interior(&self) -> Range<usize> {
+        self.address + 1..self.address + self.patch_bytes.len()
+    }
+}
+
+/// Disassembly of a patch, one line per instruction
+#[derive(Debug, Clone)]
+pub struct PatchPreview {
+    pub address: usize,
+    pub before: Vec<String>,
+    pub after: Vec<String>,
+    /// Code of the cave, empty for patches written in place
+    pub cave: Vec<String>,
+}
 
+impl fmt::Display for PatchPreview {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Patch at 0x{:X}", self.address)?;
+        for (title, lines) in [("before", &self.before), ("after", &self.after), ("cave", &self.cave)] {
+            if lines.is_empty() {
+                continue;
+            }
+            writeln!(f, "  {}:", title)?;
+            for line in lines {
+                writeln!(f, "    {}", line)?;
+            }
+        }
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Address, bytes and text of every instruction in `bytes`
+pub fn disassemble(bytes: &[u8], ip: usize) -> Vec<String> {
+    Decoder::with_ip(64, bytes, ip as u64, DecoderOptions::NONE)
+        .into_iter()
+        .map(|instr| {
+            let start = instr.ip() as usize - ip;
+            let hex: Vec<String> = bytes[start..start + instr.len()].iter().map(|byte| format!("{:02X}", byte)).collect();
+            format!("{:X}  {:<30} {}", instr.ip(), hex.join(" "), instr)
+        })
+        .collect()
+}
+
+fn near_call(address: usize, target: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut code = CodeAssembler::new(64)?;
+    code.call(target as u64)?;
+    Ok(code.assemble(address as u64)?)
+}
+
+/// `jmp [rip]` followed by the absolute target, reaches any address
+fn far_jump(target: usize) -> Vec<u8> {
+    let mut bytes = vec![0xFF, 0x25, 0, 0, 0, 0];
+    bytes.extend((target as u64).to_le_bytes());
+    bytes
+}
+
+/// Held while the threads are suspended, so two of our threads never suspend each other
+static PATCH_LOCK: Mutex<()> = Mutex::new(());
+
+/// Write `bytes` at `address` while no other thread executes in `busy` or has a value pointing
+/// into it on its stack, such as the return address of a call made from a cave.
+/// Retried a few times while a thread is in the way. Nothing is allocated while the threads
+/// are suspended, one of them may hold the heap lock
+fn patch_suspended(memory: &dyn ProcessMemory, busy: &[Range<usize>], address: usize, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+    let _lock = PATCH_LOCK.lock().unwrap();
+    let mut blocking = None;
+    for _ in 0..PATCH_ATTEMPTS {
+        let threads = memory.suspend_threads()?;
+        blocking = match threads.executing_in(busy) {
+            Some(thread) => Some((thread, thread.instruction_pointer, false)),
+            None => threads
+                .returning_into(busy, |address, buffer| memory.read_raw(address, buffer))
+                .map(|(thread, value)| (thread, value, true)),
+        };
+        if blocking.is_none() {
+            let written = memory.write_raw(address, bytes);
+            drop(threads);
+            if !written {
+                return Err(format!("Failed to write {} bytes at {:#x}", bytes.len(), address).into());
+            }
+            return Ok(());
+        }
+        drop(threads);
+        std::thread::sleep(PATCH_RETRY_DELAY);
+    }
+    match blocking.unwrap() {
+        (thread, value, true) => Err(format!("thread {} returns into 0x{:X}", thread.id, value).into()),
+        (thread, value, false) => Err(format!("thread {} keeps executing at 0x{:X}", thread.id, value).into()),
+    }
+}
+
+/// Payload assembled at `base`, followed by the relocated stolen instructions and a jump to `resume`
+fn build_cave(base: usize, size: usize, code: &mut CodeAssembler, stolen: &[Instruction], resume: u64) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut cave_bytes = code.assemble(base as u64)?;
+
+    // Stolen instructions are re-encoded after our payload so relative targets still match
+    if !stolen.is_empty() {
+        let stolen_dst = base + cave_bytes.len();
+        cave_bytes.extend(relocate_instructions(stolen, stolen_dst as u64)?);
+    }
+
+    let mut tail = CodeAssembler::new(64)?;
+    tail.jmp(resume)?;
+    cave_bytes.extend(tail.assemble((base + cave_bytes.len()) as u64)?);
+
+    if cave_bytes.len() > size {
+        return Err(format!("Injection code does not fit in {:#x} bytes", size).into());
+    }
+    Ok(cave_bytes)
+}
+
+/// Decode `count` instructions at `address`
+fn decode_instructions(memory: &dyn ProcessMemory, address: usize, count: usize) -> Result<Vec<Instruction>, Box<dyn Error>> {
+    let bytes = memory.read_bytes(address, count * 15)?;
+    let instructions: Vec<Instruction> = Decoder::with_ip(64, &bytes, address as u64, DecoderOptions::NONE).into_iter().take(count).collect();
+    if let Some(invalid) = instructions.iter().find(|instr| instr.code() == Code::INVALID) {
+        return Err(format!("Cannot patch {:#x}: invalid instruction at {:#x}", address, invalid.ip()).into());
+    }
+    Ok(instructions)
+}
+
+/// Decode the whole instructions covering at least `min_len` bytes at `address`
+fn steal_instructions(memory: &dyn ProcessMemory, address: usize, min_len: usize) -> Result<Vec<Instruction>, Box<dyn Error>> {
+    let probe = memory.read_bytes(address, min_len + 15)?;
+    let mut decoder = Decoder::with_ip(64, &probe, address as u64, DecoderOptions::NONE);
+    let mut stolen = Vec::new();
+    let mut stolen_len = 0usize;
+    while stolen_len < min_len {
+        let instr = decoder.decode();
+        if instr.code() == Code::INVALID {
+            return Err(format!("Cannot hook {:#x}: invalid instruction at {:#x}", address, instr.ip()).into());
+        }
+        stolen_len += instr.len();
+        stolen.push(instr);
+    }
+    Ok(stolen)
+}
+
+/// Refuse instructions that cannot run from another address: branches back into the stolen
+/// bytes would land on the trampoline
+fn check_relocatable(instructions: &[Instruction]) -> Result<(), Box<dyn Error>> {
+    let (Some(first), Some(last)) = (instructions.first(), instructions.last()) else {
+        return Ok(());
+    };
+    let stolen = first.ip()..last.next_ip();
+    for instr in instructions {
+        if instr.code() == Code::INVALID {
+            return Err(format!("Cannot relocate invalid instruction at {:#x}", instr.ip()).into());
+        }
+        let branches_inside = (0..instr.op_count()).any(|operand| {
+            matches!(instr.op_kind(operand), OpKind::NearBranch16 | OpKind::NearBranch32 | OpKind::NearBranch64)
+                && stolen.contains(&instr.near_branch_target())
+        });
+        let reads_inside = instr.is_ip_rel_memory_operand() && stolen.contains(&instr.ip_rel_memory_address());
+        if branches_inside || reads_inside {
+            return Err(format!("Cannot relocate `{}` at {:#x}: it targets the overwritten bytes", instr, instr.ip()).into());
+        }
+    }
+    Ok(())
+}
+
+/// Re-encode `instructions` to run at `new_ip`. Relative branches and RIP-relative operands keep
+/// their targets, short branches are widened when the new location is out of their range
+pub fn relocate_instructions(instructions: &[Instruction], new_ip: u64) -> Result<Vec<u8>, Box<dyn Error>> {
+    check_relocatable(instructions)?;
+    let from = instructions.first().map_or(0, Instruction::ip);
+    let block = InstructionBlock::new(instructions, new_ip);
+    let result = BlockEncoder::encode(64, block, BlockEncoderOptions::NONE)
+        .map_err(|e| format!("Cannot relocate the instructions at {:#x} to {:#x}: {}", from, new_ip, e))?;
+    Ok(result.code_buffer)
+}
+
+/// General purpose registers a call may change under the Windows x64 ABI
+pub const VOLATILE_GPRS: [AsmRegister64; 7] = [rax, rcx, rdx, r8, r9, r10, r11];
+pub const XMM_REGISTERS: [AsmRegisterXmm; 16] = [
+    xmm0, xmm1, xmm2, xmm3, xmm4, xmm5, xmm6, xmm7, xmm8, xmm9, xmm10, xmm11, xmm12, xmm13, xmm14, xmm15,
+];
+
+/// Wraps a hook payload so it can call Rust or HL functions from the middle of game code:
+/// saves the flags and registers, aligns the stack to 16 bytes and reserves the shadow space,
+/// then restores everything. The payload starts with the general purpose registers of the game,
+/// only RSP differs
+#[derive(Debug, Clone)]
+pub struct StubBuilder {
+    flags: bool,
+    registers: Vec<AsmRegister64>,
+    xmm_count: usize,
+    shadow_space: u32,
+}
+
+impl Default for StubBuilder {
+    /// Flags, volatile registers, XMM0-5 and 0x20 bytes of shadow space
+    fn default() -> Self {
+        Self { flags: true, registers: VOLATILE_GPRS.to_vec(), xmm_count: 6, shadow_space: 0x20 }
+    }
+}
+
+impl StubBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn flags(mut self, save: bool) -> Self {
+        self.flags = save;
+        self
+    }
+
+    /// Replace the saved general purpose registers
+    pub fn registers(mut self, registers: &[AsmRegister64]) -> Self {
+        self.registers = registers.to_vec();
+        self
+    }
+
+    /// Also save `register`, for non-volatile registers the payload uses
+    pub fn save(mut self, register: AsmRegister64) -> Self {
+        if !self.registers.contains(&register) {
+            self.registers.push(register);
+        }
+        self
+    }
+
+    /// Save XMM0 to XMM`count - 1`
+    pub fn xmm(mut self, count: usize) -> Self {
+        self.xmm_count = count;
+        self
+    }
+
+    /// Stack reserved below the saved state for the callee, rounded up to keep the alignment
+    pub fn shadow_space(mut self, bytes: u32) -> Self {
+        self.shadow_space = bytes;
+        self
+    }
+
+    pub fn build<F>(&self, payload: F) -> Result<CodeAssembler, Box<dyn Error>>
+    where
+        F: FnOnce(&mut CodeAssembler) -> Result<(), Box<dyn Error>>,
+    {
+        if self.xmm_count > XMM_REGISTERS.len() {
+            return Err(format!("Cannot save {} XMM registers", self.xmm_count).into());
+        }
+        if self.registers.contains(&rsp) {
+            return Err("RSP cannot be saved by the stub".into());
+        }
+        let xmm_area = self.xmm_count as i32 * 0x10;
+        let shadow_space = self.shadow_space.next_multiple_of(0x10) as i32;
+
+        let mut code = CodeAssembler::new(64)?;
+        if self.flags {
+            code.pushfq()?;
+        }
+        for &register in &self.registers {
+            code.push(register)?;
+        }
+
+        // Align without touching a register: the original RSP ends up at [rsp+8] either way
+        code.push(rsp)?;
+        code.push(qword_ptr(rsp))?;
+        code.and(rsp, -0x10)?;
+
+        if xmm_area > 0 {
+            code.sub(rsp, xmm_area)?;
+            for (index, &register) in XMM_REGISTERS[..self.xmm_count].iter().enumerate() {
+                code.movups(oword_ptr(rsp + index * 0x10), register)?;
+            }
+        }
+        if shadow_space > 0 {
+            code.sub(rsp, shadow_space)?;
+        }
+
+        payload(&mut code)?;
+
+        if shadow_space > 0 {
+            code.add(rsp, shadow_space)?;
+        }
+        if xmm_area > 0 {
+            for (index, &register) in XMM_REGISTERS[..self.xmm_count].iter().enumerate() {
+                code.movups(register, oword_ptr(rsp + index * 0x10))?;
+            }
+            code.add(rsp, xmm_area)?;
+        }
+        code.mov(rsp, qword_ptr(rsp + 8))?;
+
+        for &register in self.registers.iter().rev() {
+            code.pop(register)?;
+        }
+        if self.flags {
+            code.popfq()?;
+        }
+        Ok(code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::process_memory::{MockMemory, MEM_IMAGE, MEM_PRIVATE, PAGE_EXECUTE_READ, PAGE_READWRITE};
+
+    use iced_x86::{Mnemonic, Register};
+
+    const SITE: usize = 0x1_4000_0010;
+
+    #[test]
+    fn test_inject_and_undo() {
+        let mock = MockMemory::new();
+        mock.map(0x1_4000_0000, 0x1000, PAGE_EXECUTE_READ, MEM_IMAGE);
+        // mov rax, rcx; add rax, 5; ret
+        let original = [0x48, 0x89, 0xC8, 0x48, 0x83, 0xC0, 0x05, 0xC3];
+        mock.write_bytes(SITE, &original).unwrap();
+        let memory: Arc<dyn ProcessMemory> = Arc::new(mock);
+
+        let mut code = CodeAssembler::new(64).unwrap();
+        code.nop().unwrap();
+        let injection = LibmemInjection::new(&memory, SITE, &mut code).unwrap();
+
+        // Both instructions are stolen, the byte after the jump is padded
+        let site = memory.read_bytes(SITE, 8).unwrap();
+        assert_eq!(site[0], 0xE9);
+        assert_eq!(&site[5..], &[0x90, 0x90, 0xC3]);
+        let stub = memory.read_bytes(injection.cave().unwrap().start, 9).unwrap();
+        assert_eq!(&stub[..8], &[0x90, 0x48, 0x89, 0xC8, 0x48, 0x83, 0xC0, 0x05]);
+        assert_eq!(stub[8], 0xE9);
+
+        injection.undo().unwrap();
+        assert_eq!(memory.read_bytes(SITE, 8).unwrap(), original);
+        assert_eq!(memory.regions().unwrap().len(), 1);
+    }
+
+    /// Assemble `build` at `ip` and decode the instructions back
+    fn assemble(ip: u64, build: impl FnOnce(&mut CodeAssembler)) -> Vec<Instruction> {
+        let mut code = CodeAssembler::new(64).unwrap();
+        build(&mut code);
+        let bytes = code.assemble(ip).unwrap();
+        Decoder::with_ip(64, &bytes, ip, DecoderOptions::NONE).into_iter().collect()
+    }
+
+    fn decode(bytes: &[u8], ip: u64) -> Vec<Instruction> {
+        Decoder::with_ip(64, bytes, ip, DecoderOptions::NONE).into_iter().collect()
+    }
+
+    #[test]
+    fn test_relocate_rip_relative() {
+        // sub rsp, 0x28; mov rax, [rip+global]; cmp byte [rip+flag], 0
+        let prologue = assemble(SITE as u64, |code| {
+            let mut global = code.create_label();
+            let mut flag = code.create_label();
+            code.sub(rsp, 0x28).unwrap();
+            code.mov(rax, qword_ptr(global)).unwrap();
+            code.cmp(byte_ptr(flag), 0).unwrap();
+            code.ret().unwrap();
+            code.set_label(&mut global).unwrap();
+            code.dq(&[0]).unwrap();
+            code.set_label(&mut flag).unwrap();
+            code.db(&[0]).unwrap();
+        });
+        let stolen = &prologue[..3];
+
+        let cave = 0x1_3FF0_0000u64;
+        let relocated = decode(&relocate_instructions(stolen, cave).unwrap(), cave);
+        assert_eq!(relocated.len(), 3);
+        assert_eq!(relocated[0].ip(), cave);
+        for (moved, original) in relocated.iter().zip(stolen).skip(1) {
+            assert_eq!(moved.code(), original.code());
+            assert!(moved.is_ip_rel_memory_operand());
+            assert_eq!(moved.ip_rel_memory_address(), original.ip_rel_memory_address());
+        }
+
+        // Out of reach of a 32-bit displacement
+        let error = relocate_instructions(stolen, 0x7FF0_0000_0000).unwrap_err().to_string();
+        assert!(error.starts_with("Cannot relocate the instructions at 0x140000010"), "{}", error);
+    }
+
+    #[test]
+    fn test_relocate_branches() {
+        // test rcx, rcx; je short skip; call helper; skip: ret
+        let prologue = assemble(SITE as u64, |code| {
+            let mut skip = code.create_label();
+            code.test(rcx, rcx).unwrap();
+            code.je(skip).unwrap();
+            code.call(0x1_4000_2000).unwrap();
+            code.set_label(&mut skip).unwrap();
+            code.ret().unwrap();
+        });
+        assert_eq!(prologue[1].code(), Code::Je_rel8_64);
+        let stolen = &prologue[..3];
+
+        let cave = 0x1_3FF0_0000u64;
+        let relocated = decode(&relocate_instructions(stolen, cave).unwrap(), cave);
+        assert_eq!(relocated.len(), 3);
+        assert_eq!(relocated[1].code(), Code::Je_rel32_64);
+        assert_eq!(relocated[1].near_branch_target(), prologue[3].ip());
+        assert_eq!(relocated[2].code(), Code::Call_rel32_64);
+        assert_eq!(relocated[2].near_branch_target(), 0x1_4000_2000);
+    }
+
+    #[test]
+    fn test_refuse_branch_into_stolen_bytes() {
+        // retry: dec ecx; jne short retry; ret
+        let prologue = assemble(SITE as u64, |code| {
+            let mut retry = code.create_label();
+            code.set_label(&mut retry).unwrap();
+            code.dec(ecx).unwrap();
+            code.jne(retry).unwrap();
+            code.ret().unwrap();
+        });
+        let error = relocate_instructions(&prologue[..2], 0x1_3FF0_0000).unwrap_err().to_string();
+        assert!(error.contains("targets the overwritten bytes"), "{}", error);
+
+        let mock = MockMemory::new();
+        mock.map(0x1_4000_0000, 0x1000, PAGE_EXECUTE_READ, MEM_IMAGE);
+        mock.write_bytes(SITE, &[0xFF, 0xC9, 0x75, 0xFC, 0xC3]).unwrap();
+        let memory: Arc<dyn ProcessMemory> = Arc::new(mock);
+        let mut code = CodeAssembler::new(64).unwrap();
+        code.nop().unwrap();
+        assert!(LibmemInjection::new(&memory, SITE, &mut code).is_err());
+        assert_eq!(memory.read_bytes(SITE, 5).unwrap(), [0xFF, 0xC9, 0x75, 0xFC, 0xC3]);
+        assert_eq!(memory.regions().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_inject_rip_relative_prologue() {
+        let mock = MockMemory::new();
+        mock.map(0x1_4000_0000, 0x1000, PAGE_EXECUTE_READ, MEM_IMAGE);
+        // mov rax, [rip+0x100]; ret
+        mock.write_bytes(SITE, &[0x48, 0x8B, 0x05, 0x00, 0x01, 0x00, 0x00, 0xC3]).unwrap();
+        let memory: Arc<dyn ProcessMemory> = Arc::new(mock);
+
+        let mut code = CodeAssembler::new(64).unwrap();
+        code.nop().unwrap();
+        let injection = LibmemInjection::new(&memory, SITE, &mut code).unwrap();
+        assert_eq!(injection.overwritten_len, 7);
+
+        let cave = injection.cave().unwrap().start;
+        let stub = decode(&memory.read_bytes(cave, 13).unwrap(), cave as u64);
+        assert_eq!(stub[1].ip_rel_memory_address(), (SITE + 7 + 0x100) as u64);
+        assert_eq!(stub[2].near_branch_target(), (SITE + 7) as u64);
+    }
+
+    #[test]
+    fn test_patch_waits_for_threads() {
+        let mock = MockMemory::new();
+        mock.map(0x1_4000_0000, 0x1000, PAGE_EXECUTE_READ, MEM_IMAGE);
+        let original = [0x48, 0x89, 0xC8, 0x48, 0x83, 0xC0, 0x05, 0xC3];
+        mock.write_bytes(SITE, &original).unwrap();
+        // A thread on `add rax, 5` twice, then on the first instruction which is safe
+        mock.set_threads(vec![vec![SITE + 3], vec![0x1000, SITE + 3], vec![SITE, 0x1000]]);
+        let mock = Arc::new(mock);
+        let memory: Arc<dyn ProcessMemory> = mock.clone();
+
+        let mut code = CodeAssembler::new(64).unwrap();
+        code.nop().unwrap();
+        let injection = LibmemInjection::new(&memory, SITE, &mut code).unwrap();
+        assert_eq!(memory.read_u8(SITE).unwrap(), 0xE9);
+
+        // Still running the cave: undo gives up and leaves everything in place
+        let cave = injection.cave().unwrap().start;
+        mock.set_threads(vec![vec![cave + 1]]);
+        let error = injection.undo().unwrap_err().to_string();
+        assert_eq!(error, format!("Cannot undo injection at 0x{:X}: thread 1 keeps executing at 0x{:X}", SITE, cave + 1));
+        assert_eq!(memory.read_u8(SITE).unwrap(), 0xE9);
+        assert_eq!(memory.regions().unwrap().len(), 2);
+
+        // Called out of the cave: its return address is on the stack
+        mock.map(0x10_0000, 0x1000, PAGE_READWRITE, MEM_PRIVATE);
+        mock.write_u64(0x10_0F80, (cave + 6) as u64).unwrap();
+        let stack = 0x10_0F00..0x10_1000;
+        mock.set_stacks(vec![stack]);
+        mock.set_threads(vec![vec![0x1000]]);
+        let error = injection.undo().unwrap_err().to_string();
+        assert_eq!(error, format!("Cannot undo injection at 0x{:X}: thread 1 returns into 0x{:X}", SITE, cave + 6));
+        assert_eq!(memory.read_u8(SITE).unwrap(), 0xE9);
+
+        mock.write_u64(0x10_0F80, 0).unwrap();
+        mock.set_threads(vec![vec![cave + 1], vec![SITE + 0x100]]);
+        injection.undo().unwrap();
+        assert_eq!(memory.read_bytes(SITE, 8).unwrap(), original);
+        assert_eq!(memory.regions().unwrap().len(), 2);
+    }
+
+    /// Image with `bytes` at `SITE`
+    fn image(bytes: &[u8]) -> Arc<dyn ProcessMemory> {
+        let mock = MockMemory::new();
+        mock.map(0x1_4000_0000, 0x1000, PAGE_EXECUTE_READ, MEM_IMAGE);
+        mock.write_bytes(SITE, bytes).unwrap();
+        Arc::new(mock)
+    }
+
+    #[test]
+    fn test_patch_modes() {
+        // mov rax, rcx; add rax, 5; ret
+        let original = [0x48, 0x89, 0xC8, 0x48, 0x83, 0xC0, 0x05, 0xC3];
+        let memory = image(&original);
+
+        let nop = LibmemInjection::with_mode(&memory, SITE, PatchMode::Nop { len: 3 }).unwrap();
+        assert!(nop.cave().is_none());
+        assert_eq!(memory.read_bytes(SITE, 4).unwrap(), [0x90, 0x90, 0x90, 0x48]);
+        nop.undo().unwrap();
+        let error = LibmemInjection::prepare_mode(&memory, SITE, PatchMode::Nop { len: 4 }).err().unwrap();
+        assert!(error.to_string().starts_with("Cannot NOP 0x4 bytes at 0x140000010: the range ends inside"));
+
+        // mov eax, 1; ret, over both instructions
+        let early_return = LibmemInjection::with_mode(&memory, SITE, PatchMode::Return(1)).unwrap();
+        assert_eq!(memory.read_bytes(SITE, 8).unwrap(), [0xB8, 0x01, 0x00, 0x00, 0x00, 0xC3, 0x90, 0xC3]);
+        let preview = early_return.preview().unwrap();
+        assert_eq!(preview.before.len(), 2);
+        assert!(preview.after[1].ends_with("ret"));
+        assert!(preview.cave.is_empty());
+        early_return.undo().unwrap();
+        assert_eq!(memory.read_bytes(SITE, 8).unwrap(), original);
+
+        // Fits over `add rax, 5`
+        let mut code = CodeAssembler::new(64).unwrap();
+        code.inc(rax).unwrap();
+        let replace = LibmemInjection::with_mode(&memory, SITE + 3, PatchMode::Replace { count: 1, code }).unwrap();
+        assert_eq!(memory.read_bytes(SITE + 3, 4).unwrap(), [0x48, 0xFF, 0xC0, 0x90]);
+        replace.undo().unwrap();
+
+        // Too long for the two instructions, runs from a cave and jumps back to the `ret`
+        let mut code = CodeAssembler::new(64).unwrap();
+        code.mov(rax, 0x1122_3344_5566_7788u64).unwrap();
+        let replace = LibmemInjection::prepare_mode(&memory, SITE, PatchMode::Replace { count: 2, code }).unwrap();
+        let preview = replace.preview().unwrap();
+        assert_eq!(preview.cave.len(), 2);
+        assert!(preview.cave[1].ends_with(&format!("jmp near ptr {:016X}h", SITE + 7)), "{}", preview);
+        replace.commit().unwrap();
+        assert_eq!(memory.read_u8(SITE).unwrap(), 0xE9);
+        replace.undo().unwrap();
+        assert_eq!(memory.read_bytes(SITE, 8).unwrap(), original);
+        assert_eq!(memory.regions().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_replace_call_target() {
+        // call SITE+0x100; ret
+        let original = [0xE8, 0xFB, 0x00, 0x00, 0x00, 0xC3];
+        let memory = image(&original);
+        let call_target = |memory: &Arc<dyn ProcessMemory>| {
+            decode(&memory.read_bytes(SITE, 5).unwrap(), SITE as u64)[0].near_branch_target() as usize
+        };
+
+        let near = LibmemInjection::with_mode(&memory, SITE, PatchMode::CallTarget(SITE + 0x200)).unwrap();
+        assert!(near.cave().is_none());
+        assert_eq!(call_target(&memory), SITE + 0x200);
+        near.undo().unwrap();
+        assert_eq!(call_target(&memory), SITE + 0x100);
+
+        // Out of rel32 reach: the call goes to a jump in a cave
+        let far = LibmemInjection::with_mode(&memory, SITE, PatchMode::CallTarget(0x7FF0_0000_0000)).unwrap();
+        let cave = far.cave().unwrap().start;
+        assert_eq!(call_target(&memory), cave);
+        assert_eq!(memory.read_bytes(cave, 14).unwrap(), far_jump(0x7FF0_0000_0000));
+        far.undo().unwrap();
+        assert_eq!(memory.read_bytes(SITE, 6).unwrap(), original);
+        assert_eq!(memory.regions().unwrap().len(), 1);
+
+        assert!(LibmemInjection::prepare_mode(&memory, SITE + 5, PatchMode::CallTarget(SITE)).is_err());
+    }
+
+    /// Follow RSP through `bytes` from `start`: returns the final RSP, the RSP at each `call`
+    /// and the registers pushed and popped, in order
+    fn trace_stack(bytes: &[u8], start: u64) -> (u64, Vec<u64>, Vec<Register>, Vec<Register>) {
+        let mut stack = std::collections::HashMap::new();
+        let (mut stack_pointer, mut calls, mut pushed, mut popped) = (start, Vec::new(), Vec::new(), Vec::new());
+        for instr in decode(bytes, 0) {
+            match instr.mnemonic() {
+                Mnemonic::Pushfq | Mnemonic::Push => {
+                    let value = match instr.op0_kind() {
+                        OpKind::Register if instr.op0_register() == Register::RSP => stack_pointer,
+                        OpKind::Memory => stack[&(stack_pointer + instr.memory_displacement64())],
+                        _ => 0,
+                    };
+                    pushed.push(instr.op0_register());
+                    stack_pointer -= 8;
+                    stack.insert(stack_pointer, value);
+                }
+                Mnemonic::Popfq | Mnemonic::Pop => {
+                    popped.push(instr.op0_register());
+                    stack_pointer += 8;
+                }
+                _ if instr.op0_register() != Register::RSP => {
+                    if instr.mnemonic() == Mnemonic::Call {
+                        calls.push(stack_pointer);
+                    }
+                }
+                Mnemonic::Sub => stack_pointer -= instr.immediate(1),
+                Mnemonic::Add => stack_pointer += instr.immediate(1),
+                Mnemonic::And => stack_pointer &= instr.immediate(1),
+                Mnemonic::Mov => stack_pointer = stack[&(stack_pointer + instr.memory_displacement64())],
+                mnemonic => panic!("Unexpected {:?} on RSP", mnemonic),
+            }
+        }
+        (stack_pointer, calls, pushed, popped)
+    }
+
+    #[test]
+    fn test_stub_builder_balance() {
+        let stub = StubBuilder::new().save(rbx).shadow_space(0x18);
+        let mut code = stub
+            .build(|code| {
+                code.mov(rax, 0x1234_5678u64)?;
+                code.call(rax)?;
+                Ok(())
+            })
+            .unwrap();
+        let bytes = code.assemble(0).unwrap();
+
+        // Called with RSP aligned or not, like a hook in the middle of a function
+        for start in [0x10_0000u64, 0x10_0008] {
+            let (end, calls, pushed, popped) = trace_stack(&bytes, start);
+            assert_eq!(end, start);
+            assert_eq!(calls.len(), 1);
+            assert_eq!(calls[0] % 0x10, 0);
+            // Shadow space rounded up, above it 6 XMM registers
+            assert!(calls[0] + 0x20 + 0x60 <= start - 10 * 8);
+
+            let saved = [Register::None, Register::RAX, Register::RCX, Register::RDX, Register::R8, Register::R9, Register::R10, Register::R11, Register::RBX];
+            assert_eq!(pushed[..9], saved);
+            let mut restored = popped.clone();
+            restored.reverse();
+            assert_eq!(restored, saved);
+        }
+
+        let instructions = decode(&bytes, 0);
+        let saved_xmm: Vec<_> = instructions.iter().filter(|i| i.op0_kind() == OpKind::Memory && i.op1_register().is_xmm()).map(|i| i.op1_register()).collect();
+        let restored_xmm: Vec<_> = instructions.iter().filter(|i| i.op0_register().is_xmm()).map(|i| i.op0_register()).collect();
+        assert_eq!(saved_xmm, [Register::XMM0, Register::XMM1, Register::XMM2, Register::XMM3, Register::XMM4, Register::XMM5]);
+        assert_eq!(saved_xmm, restored_xmm);
+    }
+
+    #[test]
+    fn test_stub_builder_minimal() {
+        let mut code = StubBuilder::new().flags(false).registers(&[]).xmm(0).shadow_space(0).build(|_| Ok(())).unwrap();
+        let bytes = code.assemble(0).unwrap();
+        let mnemonics: Vec<_> = decode(&bytes, 0).iter().map(|i| format!("{}", i)).collect();
+        assert_eq!(mnemonics, ["push rsp", "push qword ptr [rsp]", "and rsp,0FFFFFFFFFFFFFFF0h", "mov rsp,[rsp+8]"]);
+        assert_eq!(trace_stack(&bytes, 0x1008).0, 0x1008);
+
+        assert!(StubBuilder::new().xmm(17).build(|_| Ok(())).is_err());
+    }
+}
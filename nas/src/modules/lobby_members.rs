@@ -2,16 +2,32 @@
     Allows to see players in queue.
 */
 
-use crate::modules::libmem_injection::LibmemInjection;
+use crate::modules::base::{InjectionManager, InjectionTransaction};
+use crate::modules::libmem_injection::StubBuilder;
 use crate::modules::hashlink::*;
-use libmem::Process;
-use crate::utils::libmem_ex::{get_target_process, free, read_qword_ex, read_utf16_string_ex, allocate_region_mrprotect};
-use windows::Win32::System::Memory::PAGE_READWRITE;
+use crate::modules::mem_alloc::{DataType, MemoryAllocator};
+use crate::modules::remote_struct::RemoteStruct;
+use crate::utils::process_memory::ProcessMemory;
 use iced_x86::code_asm::*;
 use std::error::Error;
 use std::sync::Mutex;
 use std::sync::Arc;
 
+/// Offset of the injection into the body of `logLobbyInfo`
+pub(crate) const OFFSET_LOGLOBBYINFO_BODY: usize = 2035;
+/// Offset of the injections into `logUserJoined` and `logUserLeft`
+pub(crate) const OFFSET_LOGUSER_JOINED_LEFT: usize = 28;
+
+/// `bytes` field of a `String` object, read at the offset Hashlink gives for it
+#[derive(Debug, RemoteStruct)]
+struct StringBytes {
+    #[remote(offset = 0x0, utf16)]
+    text: String,
+}
+
+/// Injection points, all applied together
+const INJECTIONS: [&str; 4] = ["loglobbyinfo", "loguserjoined", "loguserleft", "logjoinlobby"];
+
 pub struct LobbyMembers {
     pid: u32,
     address_loglobbyinfo_body: usize,
@@ -21,14 +37,13 @@ pub struct LobbyMembers {
     address_logjoinlobby: usize,
     var_ptr_logs: usize,
     var_ptr_lobby: usize,
-    injection_loglobbyinfo: Mutex<Option<LibmemInjection>>,
-    injection_loguserjoined: Mutex<Option<LibmemInjection>>,
-    injection_loguserleft: Mutex<Option<LibmemInjection>>,
-    injection_logjoinlobby: Mutex<Option<LibmemInjection>>,
+    /// Offset of `String.bytes`
+    offset_string_bytes: usize,
+    injection_manager: InjectionManager,
     members: Arc<Mutex<Vec<String>>>,
-    lm_process: Process,
-    lm_alloc_addr: usize,
-    lm_alloc_size: usize,
+    memory: Arc<dyn ProcessMemory>,
+    // Freed on drop, after the injections using its variables
+    _mem_allocator: MemoryAllocator,
 }
 
 impl LobbyMembers {
@@ -42,16 +57,26 @@ impl LobbyMembers {
         self.lobby_members_apply(false)
     }
 
-    pub fn new(pid: u32) -> Result<Self, Box<dyn Error>> {
+    pub fn new(pid: u32, memory: Arc<dyn ProcessMemory>) -> Result<Self, Box<dyn Error>> {
+        let mut lobby = Self::allocate(pid, memory)?;
+        lobby.lobby_members_init()?;
+        
+        Ok(lobby)
+    }
+
+    /// Allocate the variables written by the injected code, addresses are resolved by `lobby_members_init`
+    fn allocate(pid: u32, memory: Arc<dyn ProcessMemory>) -> Result<Self, Box<dyn Error>> {
         let members = Arc::new(Mutex::new(Vec::new()));
-        let lm_process = get_target_process(pid).ok_or("Failed to get process with libmem")?;
-        let lm_alloc_size = 0x1000usize;
-        let allocated = allocate_region_mrprotect(pid, lm_alloc_size, PAGE_READWRITE)?;
-        let lm_alloc_addr = allocated.base_address as usize;
-        let var_ptr_logs_tmp = lm_alloc_addr;
-        let var_ptr_lobby_tmp = lm_alloc_addr + 8;
+        let mut memory_allocator = MemoryAllocator::new(memory.clone(), "LobbyMembers", 0x1000)?;
+        let var_ptr_logs_tmp = memory_allocator.allocate_var("Logs", DataType::Pointer)?;
+        let var_ptr_lobby_tmp = memory_allocator.allocate_var("Lobby", DataType::Pointer)?;
 
-        let mut lobby = Self {
+        let mut injection_manager = InjectionManager::new(memory.clone(), "LobbyMembers");
+        for name in INJECTIONS {
+            injection_manager.add_injection(name.to_string());
+        }
+
+        Ok(Self {
             pid,
             address_loglobbyinfo_body: 0,
             address_loguserjoined: 0,
@@ -60,22 +85,16 @@ impl LobbyMembers {
             address_loglobbyinfo: 0,
             var_ptr_logs: var_ptr_logs_tmp,
             var_ptr_lobby: var_ptr_lobby_tmp,
-            injection_loglobbyinfo: Mutex::new(None),
-            injection_loguserjoined: Mutex::new(None),
-            injection_loguserleft: Mutex::new(None),
-            injection_logjoinlobby: Mutex::new(None),
+            offset_string_bytes: 0,
+            injection_manager,
             members,
-            lm_process,
-            lm_alloc_addr,
-            lm_alloc_size,
-        };
-        lobby.lobby_members_init()?;
-        
-        Ok(lobby)
+            memory,
+            _mem_allocator: memory_allocator,
+        })
     }
 
     pub fn update_members(&self) {
-        if let Some(var_ptr_logs) = read_qword_ex(&self.lm_process, self.var_ptr_logs) {
+        if let Ok(var_ptr_logs) = self.memory.read_u64(self.var_ptr_logs) {
             if var_ptr_logs == 0 { return; }
         }
 
@@ -90,9 +109,6 @@ impl LobbyMembers {
 
     /// Initializes `LobbyMembers` by finding the target addresses
     pub fn lobby_members_init(&mut self) -> Result<(), Box<dyn Error>> {
-        const OFFSET_LOGLOBBYINFO_BODY: usize = 2035;
-        const OFFSET_LOGUSER_JOINED_LEFT: usize = 28;
-
         let guard = Hashlink::instance(self.pid).lock().unwrap();
         if let Some(hashlink) = guard.as_ref() {
             self.address_loglobbyinfo = hashlink.get_function_address("logLobbyInfo", Some(0))?;
@@ -102,6 +118,7 @@ impl LobbyMembers {
             self.address_loguserleft = hashlink.get_function_address("logUserLeft", Some(0))?;
             self.address_loguserleft += OFFSET_LOGUSER_JOINED_LEFT;
             self.address_logjoinlobby = hashlink.get_function_address("logJoinLobby", Some(0))?;
+            self.offset_string_bytes = hashlink.get_field_offset("String", "bytes")?;
         } else {
             return Err("Hashlink instance not found".into());
         }
@@ -111,44 +128,18 @@ impl LobbyMembers {
 
     /// Apply or remove lobby members at the specified address
     pub fn lobby_members_apply(&self, enable: bool) -> Result<(), Box<dyn Error>> {
-        let mut injection_loglobbyinfo = self.injection_loglobbyinfo.lock().unwrap();
-        let mut injection_loguserjoined = self.injection_loguserjoined.lock().unwrap();
-        let mut injection_loguserleft = self.injection_loguserleft.lock().unwrap();
-        let mut injection_logjoinlobby = self.injection_logjoinlobby.lock().unwrap();
-
         if enable {
-            self.ensure_injection(
-                &mut injection_loglobbyinfo,
-                self.address_loglobbyinfo_body,
-                || self.asm_save_log_to_var(),
-                "loglobbyinfo",
-            )?;
-
-            self.ensure_injection(
-                &mut injection_loguserjoined,
-                self.address_loguserjoined,
-                || self.asm_call_loglobbyinfo_with_lobby_from_var(),
-                "loguserjoined",
-            )?;
-
-            self.ensure_injection(
-                &mut injection_loguserleft,
-                self.address_loguserleft,
-                || self.asm_call_loglobbyinfo_with_lobby_from_var(),
-                "loguserleft",
-            )?;
-
-            self.ensure_injection(
-                &mut injection_logjoinlobby,
-                self.address_logjoinlobby,
-                || self.asm_save_lobby_arg_to_var(),
-                "logjoinlobby",
-            )?;
+            let mut transaction = InjectionTransaction::new();
+            transaction
+                .add("loglobbyinfo", self.address_loglobbyinfo_body, || self.asm_save_log_to_var())
+                .add("loguserjoined", self.address_loguserjoined, || self.asm_call_loglobbyinfo_with_lobby_from_var())
+                .add("loguserleft", self.address_loguserleft, || self.asm_call_loglobbyinfo_with_lobby_from_var())
+                .add("logjoinlobby", self.address_logjoinlobby, || self.asm_save_lobby_arg_to_var());
+            self.injection_manager.apply_transaction(transaction)?;
         } else {
-            self.remove_injection(&mut injection_loglobbyinfo, self.address_loglobbyinfo_body, "loglobbyinfo")?;
-            self.remove_injection(&mut injection_loguserjoined, self.address_loguserjoined, "loguserjoined")?;
-            self.remove_injection(&mut injection_loguserleft, self.address_loguserleft, "loguserleft")?;
-            self.remove_injection(&mut injection_logjoinlobby, self.address_logjoinlobby, "logjoinlobby")?;
+            for name in INJECTIONS {
+                self.injection_manager.remove_injection(name)?;
+            }
         }
         
         Ok(())
@@ -156,12 +147,12 @@ impl LobbyMembers {
 
     /// Extracts users from `var_ptr_logs`
     pub fn lobby_members_extract(&self) -> Result<String, Box<dyn Error>> {
-        let log_addr_ptr = read_qword_ex(&self.lm_process, self.var_ptr_logs)
-            .ok_or("libmem read_qword_ex failed")? as usize;
-        let log_addr = read_qword_ex(&self.lm_process, log_addr_ptr + 8)
-            .ok_or("libmem read_qword_ex failed")? as usize;
-        let log_data = read_utf16_string_ex(&self.lm_process, log_addr, 0x1000)
-            .ok_or("libmem read_utf16_string_ex failed")?;
+        // `String` object of the last lobby log message, its layout comes from the bytecode
+        let log_string = self.memory.read_pointer(self.var_ptr_logs)?;
+        if log_string == 0 {
+            return Err("No lobby log yet".into());
+        }
+        let log_data = StringBytes::read(self.memory.as_ref(), log_string + self.offset_string_bytes)?.text;
         
         tracing::debug!("log_data: {}", log_data);
 
@@ -253,45 +244,8 @@ impl LobbyMembers {
     }
 }
 
-impl Drop for LobbyMembers {
-    fn drop(&mut self) {
-        let _ = free(&self.lm_process, self.lm_alloc_addr, self.lm_alloc_size);
-    }
-}
-
 // ---- Internal helpers to assemble and manage injections ----
 impl LobbyMembers {
-    fn ensure_injection<F>(&self,
-        slot: &mut Option<LibmemInjection>,
-        target_addr: usize,
-        build_code: F,
-        label: &str,
-    ) -> Result<(), Box<dyn Error>>
-    where
-        F: FnOnce() -> Result<CodeAssembler, Box<dyn Error>>,
-    {
-        if slot.is_none() {
-            let mut code = build_code()?;
-            *slot = Some(LibmemInjection::new(self.pid, target_addr, &mut code, &self.lm_process)?);
-            tracing::info!("Successfully injected: {} at 0x{:X}", label, target_addr);
-        }
-        Ok(())
-    }
-
-    fn remove_injection(
-        &self,
-        slot: &mut Option<LibmemInjection>,
-        target_addr: usize,
-        label: &str,
-    ) -> Result<(), Box<dyn Error>> {
-        if let Some(inj) = slot.as_ref() {
-            inj.undo()?;
-            *slot = None;
-            tracing::info!("Successfully removed: {} at 0x{:X}", label, target_addr);
-        }
-        Ok(())
-    }
-
     // Save `logLobbyInfo` result (RAX) into `var_ptr_logs`
     fn asm_save_log_to_var(&self) -> Result<CodeAssembler, Box<dyn Error>> {
         let mut code = CodeAssembler::new(64)?;
@@ -304,16 +258,13 @@ impl LobbyMembers {
 
     // Take mpman.Lobby from `var_ptr_lobby` and call `logLobbyInfo`
     fn asm_call_loglobbyinfo_with_lobby_from_var(&self) -> Result<CodeAssembler, Box<dyn Error>> {
-        let mut code = CodeAssembler::new(64)?;
-        code.push(rax)?;
-        code.push(rcx)?;
-        code.mov(rax, self.var_ptr_lobby as u64)?;
-        code.mov(rcx, qword_ptr(rax))?;
-        code.mov(rax, self.address_loglobbyinfo as u64)?;
-        code.call(rax)?;
-        code.pop(rcx)?;
-        code.pop(rax)?;
-        Ok(code)
+        StubBuilder::new().build(|code| {
+            code.mov(rax, self.var_ptr_lobby as u64)?;
+            code.mov(rcx, qword_ptr(rax))?;
+            code.mov(rax, self.address_loglobbyinfo as u64)?;
+            code.call(rax)?;
+            Ok(())
+        })
     }
 
     // Save `mpman.Lobby` argument (RCX) into `var_ptr_lobby`
@@ -328,3 +279,31 @@ impl LobbyMembers {
         Ok(code)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::process_memory::{MockMemory, MEM_PRIVATE, PAGE_READWRITE};
+
+    #[test]
+    fn test_members_from_log() {
+        let mock = MockMemory::new();
+        mock.map(0x1000, 0x1000, PAGE_READWRITE, MEM_PRIVATE);
+        let memory: Arc<dyn ProcessMemory> = Arc::new(mock);
+        let mut lobby = LobbyMembers::allocate(0, memory.clone()).unwrap();
+        lobby.offset_string_bytes = 0x10;
+
+        // Nothing logged yet
+        assert!(lobby.get_members().is_empty());
+
+        // `String` object at 0x1000 with its characters at 0x1100
+        let log = "Members:\nPlayer1(S7a801dc1) (Team 0)\nPlayer2(Sa3d12ca) (Team 1)";
+        let chars: Vec<u8> = log.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        memory.write_bytes(0x1100, &chars).unwrap();
+        memory.write_u64(0x1010, 0x1100).unwrap();
+        memory.write_u64(lobby.var_ptr_logs, 0x1000).unwrap();
+
+        assert_eq!(lobby.lobby_members_extract().unwrap(), log);
+        assert_eq!(lobby.get_members_cleaned(), vec!["Player1(Team 0)", "Player2(Team 1)"]);
+    }
+}
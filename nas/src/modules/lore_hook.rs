@@ -1,28 +1,25 @@
 /*
-playUnlockAnim@24502 - count lores
-
-fn <none>@24509 (enum<String>) -> ui.Window (10 regs, 22 ops)
-Appear on:
-55 48 8B ?? 48 83 ?? ?? 48 89 ?? ?? 48 8B ?? ?? 48 89 ?? ?? 48 85 ?? 0F ?? ?? ?? ?? ?? 48 8B ?? ?? 48 89 ?? ?? 48 85 ?? 75 ?? 48 B8 ?? ?? ?? ?? ?? ?? ?? ?? 48 83 ?? ?? FF ?? 48 89 ?? ?? ?? 48 8B ?? 48 83 ?? ?? E8 ?? ?? ?? ?? 48 89 ?? ?? ?? 48 83 ?? ?? 48 89 ?? ?? E9 ?? ?? ?? ?? 48 33 ?? 48 89 ?? ?? 48 B9 ?? ?? ?? ?? ?? ?? ?? ?? 48 B8 ?? ?? ?? ?? ?? ?? ?? ?? 48 83 ?? ?? FF ?? 48 89 ?? ?? ?? 48 83 ?? ?? 48 89 ?? ?? 48 B9 ?? ?? ?? ?? ?? ?? ?? ?? 48 B8 ?? ?? ?? ?? ?? ?? ?? ?? 48 83 ?? ?? FF ?? 48 89 ?? ?? ?? 48 83 ?? ?? 48 89 ?? ?? 4C ?? ?? ?? 4D ?? ?? ?? 4C ?? ?? ?? 4C ?? ?? ?? 4D ?? ?? 75 ?? 49 ?? ?? ?? ?? ?? ?? ?? ?? ?? 48 BA ?? ?? ?? ?? ?? ?? ?? ?? 48 8B ?? 48 B8 ?? ?? ?? ?? ?? ?? ?? ?? 48 83 ?? ?? FF ?? 48 89 ?? ?? ?? 48 83 ?? ?? EB ?? 4C ?? ?? ?? 4D ?? ?? 48 8B ?? ?? 48 8B ?? ?? 48 85 ?? 75 ?? 4C ?? ?? ?? 49 ?? ?? ?? ?? ?? ?? ?? ?? ?? 48 BA ?? ?? ?? ?? ?? ?? ?? ?? 48 8B ?? 48 B8 ?? ?? ?? ?? ?? ?? ?? ?? 48 83 ?? ?? FF ?? 48 89 ?? ?? ?? 48 83 ?? ?? EB ?? 48 8B ?? ?? 48 89 ?? 4C ?? ?? ?? 4D ?? ?? ?? 4C ?? ?? ?? 4D ?? ?? 74 ?? 4D ?? ?? ?? EB ?? 4D ?? ?? 44 ?? ?? ?? 4D ?? ?? ?? 4C ?? ?? ?? 48 8B ?? ?? 48 8B ?? ?? 4D ?? ?? 45 ?? ?? 4D ?? ?? 48 83 ?? ?? E8 ?? ?? ?? ?? 48 89 ?? ?? ?? 48 83 ?? ?? 4C ?? ?? ?? 49 ?? ?? ?? 48 89 ?? ?? 48 85 ?? 0F ?? ?? ?? ?? ?? 49 ?? ?? ?? 48 89 ?? ?? 48 85 ?? 75 ?? 48 B8 ?? ?? ?? ?? ?? ?? ?? ?? 48 83 ?? ?? FF ?? 48 89 ?? ?? ?? 8B ?? ?? 85 ?? 75 ?? 48 8B ?? ?? 4C ?? ?? ?? 48 83 ?? ?? 41 ?? ?? ?? 48 89 ?? ?? ?? 48 83 ?? ?? EB ?? 48 8B ?? ?? 48 8B ?? ?? 4C ?? ?? ?? 48 83 ?? ?? 41 ?? ?? ?? 48 89 ?? ?? ?? 48 83 ?? ?? 48 8B ?? ?? 48 83 ?? ?? 5D 48 C3
-
-fn remove@24527 (ui.win.tech.TechTree) -> void (4 regs, 10 ops)
-Disappear on:
-55 48 8B ?? 48 83 ?? ?? 48 89 ?? ?? 48 33 ?? 8A ?? ?? ?? ?? ?? 88 ?? ?? 48 84 ?? 0F ?? ?? ?? ?? ?? 48 8B ?? ?? 48 83 ?? ?? 5D 48 C3 48 8B ?? ?? 4C ?? ?? ?? ?? ?? ?? 4C ?? ?? ?? 4D ?? ?? 0F ?? ?? ?? ?? ?? 4C ?? ?? ?? ?? ?? ?? 4C ?? ?? ?? 4D ?? ?? 75 ?? 48 B8 ?? ?? ?? ?? ?? ?? ?? ?? 48 83 ?? ?? FF ?? 48 89 ?? ?? ?? 49 ?? ?? 48 83 ?? ?? E8 ?? ?? ?? ?? 48 89 ?? ?? ?? 48 83 ?? ?? 48 8B ?? ?? 48 83 ?? ?? E8 ?? ?? ?? ?? 48 89 ?? ?? ?? 48 83 ?? ?? 48 8B ?? ?? 48 83 ?? ?? 5D 48 C3 90 55 48 8B ?? 48 83 ?? ?? 48 89 ?? ?? 48 89 ?? ?? 4C ?? ?? ?? B8 ?? ?? ?? ?? 88 ?? ?? 88 ?? ?? ?? ?? ?? 48 89 ?? ?? ?? ?? ?? 48 85 ?? 75 ?? 48 83 ?? ?? 68 ?? ?? ?? ?? 48 B8 ?? ?? ?? ?? ?? ?? ?? ?? 48 83 ?? ?? FF ?? 48 89 ?? ?? ?? 4C ?? ?? ?? ?? ?? ?? 4C ?? ?? ?? 4C ?? ?? ?? ?? ?? ?? 48 8B ?? 48 83 ?? ?? E8 ?? ?? ?? ?? 48 89 ?? ?? ?? 48 83 ?? ?? 88 ?? ?? 48 84 ?? 0F ?? ?? ?? ?? ?? 48 8B ?? ?? 4C ?? ?? ?? ?? ?? ?? 4C ?? ?? ?? 4C ?? ?? ?? 4D ?? ?? ?? ?? ?? ?? 4C ?? ?? ?? 4D ?? ?? ?? ?? ?? ?? 4C ?? ?? ?? 48 8B ?? ?? 4C ?? ?? ?? ?? ?? ?? 48 33 ?? 48 89 ?? ?? 48 8B ?? 48 8B ?? ?? 4C ?? ?? ?? 48 83 ?? ?? E8 ?? ?? ?? ?? 48 89 ?? ?? ?? 48 83 ?? ?? 48 8B ?? ?? 48 83 ?? ?? 5D 48 C3
+    Hooks of the lore window:
+    playUnlockAnim@24502 - count lores
+    fn <none>@24509 (enum<String>) -> ui.Window - lore window appears
+    fn remove@24527 (ui.win.tech.TechTree) -> void - lore window disappears
 
+    Use the function viewer (Ctrl+Shift+D) to see their decompiled source and bytecode.
 */
 
 use crate::modules::libmem_injection::LibmemInjection;
 use crate::modules::mem_alloc::*;
 use crate::modules::hashlink::*;
-use crate::modules::basic::*;
+use crate::modules::scanner::PatternScanner;
+use crate::utils::process_memory::{ProcessMemory, PAGE_EXECUTABLE};
 use crate::utils::signals::*;
 use std::error::Error;
-use std::sync::Mutex;
-use windows::Win32::System::Memory::{PAGE_EXECUTE, PAGE_EXECUTE_READ, PAGE_EXECUTE_READWRITE};
+use std::sync::{Arc, Mutex};
 
 
 pub struct LoreHook {
     pid: u32,
+    memory: Arc<dyn ProcessMemory>,
 
     // fn playUnlockAnim@24502
     address_playunlockanim: usize,
@@ -46,11 +43,12 @@ pub struct LoreHook {
 
 
 impl LoreHook {
-    pub fn new(pid: u32) -> Result<Self, Box<dyn Error>> {
-        let memory_allocator = MemoryAllocator::new(pid, 0x1000)?;
+    pub fn new(pid: u32, memory: Arc<dyn ProcessMemory>) -> Result<Self, Box<dyn Error>> {
+        let memory_allocator = MemoryAllocator::new(memory.clone(), "LoreHook", 0x1000)?;
         
         let mut lore_hook = Self {
             pid,
+            memory,
             address_playunlockanim: 0,
             address_none_appear: 0,
             address_remove: 0,
@@ -74,20 +72,18 @@ impl LoreHook {
         // fn remove@24527
         let hex_pattern_remove = "55 48 8B ?? 48 83 ?? ?? 48 89 ?? ?? 48 33 ?? 8A ?? ?? ?? ?? ?? 88 ?? ?? 48 84 ?? 0F ?? ?? ?? ?? ?? 48 8B ?? ?? 48 83 ?? ?? 5D 48 C3 48 8B ?? ?? 4C ?? ?? ?? ?? ?? ?? 4C ?? ?? ?? 4D ?? ?? 0F ?? ?? ?? ?? ?? 4C ?? ?? ?? ?? ?? ?? 4C ?? ?? ?? 4D ?? ?? 75 ?? 48 B8 ?? ?? ?? ?? ?? ?? ?? ?? 48 83 ?? ?? FF ?? 48 89 ?? ?? ?? 49 ?? ?? 48 83 ?? ?? E8 ?? ?? ?? ?? 48 89 ?? ?? ?? 48 83 ?? ?? 48 8B ?? ?? 48 83 ?? ?? E8 ?? ?? ?? ?? 48 89 ?? ?? ?? 48 83 ?? ?? 48 8B ?? ?? 48 83 ?? ?? 5D 48 C3 90 55 48 8B ?? 48 83 ?? ?? 48 89 ?? ?? 48 89 ?? ?? 4C ?? ?? ?? B8 ?? ?? ?? ?? 88 ?? ?? 88 ?? ?? ?? ?? ?? 48 89 ?? ?? ?? ?? ?? 48 85 ?? 75 ?? 48 83 ?? ?? 68 ?? ?? ?? ?? 48 B8 ?? ?? ?? ?? ?? ?? ?? ?? 48 83 ?? ?? FF ?? 48 89 ?? ?? ?? 4C ?? ?? ?? ?? ?? ?? 4C ?? ?? ?? 4C ?? ?? ?? ?? ?? ?? 48 8B ?? 48 83 ?? ?? E8 ?? ?? ?? ?? 48 89 ?? ?? ?? 48 83 ?? ?? 88 ?? ?? 48 84 ?? 0F ?? ?? ?? ?? ?? 48 8B ?? ?? 4C ?? ?? ?? ?? ?? ?? 4C ?? ?? ?? 4C ?? ?? ?? 4D ?? ?? ?? ?? ?? ?? 4C ?? ?? ?? 4D ?? ?? ?? ?? ?? ?? 4C ?? ?? ?? 48 8B ?? ?? 4C ?? ?? ?? ?? ?? ?? 48 33 ?? 48 89 ?? ?? 48 8B ?? 48 8B ?? ?? 4C ?? ?? ?? 48 83 ?? ?? E8 ?? ?? ?? ?? 48 89 ?? ?? ?? 48 83 ?? ?? 48 8B ?? ?? 48 83 ?? ?? 5D 48 C3";
 
-        let executable_protection = PAGE_EXECUTE.0 | PAGE_EXECUTE_READ.0 | PAGE_EXECUTE_READWRITE.0;
-        let mut addrs = aob_scan_mrprotect(self.pid, hex_pattern_none_appear, executable_protection)?;
-        if addrs.is_empty() {
-            tracing::error!("Pattern not found: init_lore_hook: hex_pattern_none_appear");
-            return Err("Pattern not found: init_lore_hook: hex_pattern_none_appear".into());
-        }
-        self.address_none_appear = addrs[0];
+        // Both functions are found in one pass over executable memory
+        let mut scanner = PatternScanner::new();
+        let id_none_appear = scanner.add(hex_pattern_none_appear)?;
+        let id_remove = scanner.add(hex_pattern_remove)?;
+        let results = scanner.scan(self.memory.as_ref(), |region| region.protect & PAGE_EXECUTABLE != 0)?;
 
-        addrs = aob_scan_mrprotect(self.pid, hex_pattern_remove, executable_protection)?;
-        if addrs.is_empty() {
+        self.address_none_appear = results.first(id_none_appear).inspect_err(|_| {
+            tracing::error!("Pattern not found: init_lore_hook: hex_pattern_none_appear");
+        })?;
+        self.address_remove = results.first(id_remove).inspect_err(|_| {
             tracing::error!("Pattern not found: init_lore_hook: hex_pattern_remove");
-            return Err("Pattern not found: init_lore_hook: hex_pattern_remove".into());
-        }
-        self.address_remove = addrs[0];
+        })?;
 
         let guard = Hashlink::instance(self.pid).lock().unwrap();
         if let Some(hashlink) = guard.as_ref() {
@@ -1,8 +1,10 @@
-use crate::utils::libmem_ex::{allocate_region_mrprotect, write_bytes_ex, read_bytes_ex, free, get_target_process};
-use windows::Win32::System::Memory::PAGE_READWRITE;
+use crate::modules::cave_pool::{CaveKind, CavePool};
+use crate::modules::patch_map::PatchMap;
+use crate::utils::process_memory::ProcessMemory;
 use std::error::Error;
-use libmem::Process;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 pub struct Variable {
     pub name: String,
@@ -42,28 +44,32 @@ impl DataType {
 }
 
 pub struct MemoryAllocator {
-    pid: u32,
+    memory: Arc<dyn ProcessMemory>,
+    pool: Arc<CavePool>,
+    /// Module whose hooks use the variables
+    owner: String,
     allocated_addr: usize,
-    allocated_size: usize,
     next_free_addr: usize,
     next_free_size: usize,
     variables: HashMap<String, Variable>,
-    process: Process,
+    freed: AtomicBool,
 }
 
 impl MemoryAllocator {
-    pub fn new(pid: u32, total_size: usize) -> Result<Self, Box<dyn Error>> {
-        let process = get_target_process(pid).ok_or("Failed to get process with libmem")?;
-        let allocated = allocate_region_mrprotect(pid, total_size, PAGE_READWRITE)?;
+    /// Reserve `total_size` bytes from the shared cave pool, `owner` names the block in its listing
+    pub fn new(memory: Arc<dyn ProcessMemory>, owner: &str, total_size: usize) -> Result<Self, Box<dyn Error>> {
+        let pool = CavePool::shared(&memory);
+        let allocated = pool.allocate(owner, CaveKind::Data, total_size, None)?;
         
         Ok(Self {
-            pid,
-            allocated_addr: allocated.base_address,
-            allocated_size: allocated.region_size,
-            next_free_addr: allocated.base_address,
-            next_free_size: allocated.region_size,
+            memory,
+            pool,
+            owner: owner.to_string(),
+            allocated_addr: allocated.address,
+            next_free_addr: allocated.address,
+            next_free_size: allocated.size,
             variables: HashMap::new(),
-            process,
+            freed: AtomicBool::new(false),
         })
     }
 
@@ -127,10 +133,10 @@ impl MemoryAllocator {
 
         // Write the string bytes
         if !str.is_empty() {
-            write_bytes_ex(&self.process, addr, str.as_bytes()).ok_or("libmem write_bytes_ex failed")?;
+            self.memory.write_bytes(addr, str.as_bytes())?;
         }
         // Null terminator
-        write_bytes_ex(&self.process, addr + size - 1, &[0u8]).ok_or("libmem write_bytes_ex failed")?;
+        self.memory.write_bytes(addr + size - 1, &[0u8])?;
 
         Ok(addr)
     }
@@ -143,10 +149,10 @@ impl MemoryAllocator {
         // Write UTF-16LE bytes
         for (i, ch) in str.encode_utf16().enumerate() {
             let bytes = ch.to_le_bytes();
-            write_bytes_ex(&self.process, addr + i * 2, &bytes).ok_or("libmem write_bytes_ex failed")?;
+            self.memory.write_bytes(addr + i * 2, &bytes)?;
         }
         // Null terminator (2 bytes)
-        write_bytes_ex(&self.process, addr + size - 2, &[0u8, 0u8]).ok_or("libmem write_bytes_ex failed")?;
+        self.memory.write_bytes(addr + size - 2, &[0u8, 0u8])?;
 
         Ok(addr)
     }
@@ -163,7 +169,7 @@ impl MemoryAllocator {
 
         let size = std::mem::size_of::<T>();
         let bytes = unsafe { std::slice::from_raw_parts(&value as *const T as *const u8, size) };
-        write_bytes_ex(&self.process, var.address, bytes).ok_or("libmem write_bytes_ex failed")?;
+        self.memory.write_bytes(var.address, bytes)?;
 
         Ok(())
     }
@@ -177,7 +183,7 @@ impl MemoryAllocator {
             return Err("Byte array too large for allocated variable".into());
         }
 
-        write_bytes_ex(&self.process, var.address, bytes).ok_or("libmem write_bytes_ex failed")?;
+        self.memory.write_bytes(var.address, bytes)?;
 
         Ok(())
     }
@@ -192,7 +198,7 @@ impl MemoryAllocator {
             return Err("Type size mismatch".into());
         }
 
-        let bytes = read_bytes_ex(&self.process, var.address, var.size).ok_or("libmem read_bytes_ex failed")?;
+        let bytes = self.memory.read_bytes(var.address, var.size)?;
         if bytes.len() != var.size { return Err("Incomplete read".into()); }
 
         let mut out = std::mem::MaybeUninit::<T>::uninit();
@@ -207,10 +213,62 @@ impl MemoryAllocator {
         self.variables.get(name).map(|var| var.address)
     }
 
-    /// Free all allocated memory
+    /// Free all allocated memory, done once. Also done on drop
     pub fn free(&self) -> Result<(), Box<dyn Error>> {
-        free(&self.process, self.allocated_addr, self.allocated_size).ok_or("libmem free failed")?;
+        if self.freed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.pool.free(self.allocated_addr)?;
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+impl Drop for MemoryAllocator {
+    /// Kept while a hook of the owner could not be undone, it may still use the variables.
+    /// Eject releases the block with the rest of the pool
+    fn drop(&mut self) {
+        if self.freed.load(Ordering::SeqCst) {
+            return;
+        }
+        let stuck = PatchMap::shared(&self.memory).owned_by(&self.owner);
+        if let Some(patch) = stuck.first() {
+            tracing::warn!("Variables at 0x{:X} kept for {} at 0x{:X}", self.allocated_addr, patch.owner, patch.address);
+            return;
+        }
+        if let Err(e) = self.free() {
+            tracing::warn!("Variables at 0x{:X} not freed: {}", self.allocated_addr, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::process_memory::MockMemory;
+
+    #[test]
+    fn test_allocate_variables() {
+        let memory: Arc<dyn ProcessMemory> = Arc::new(MockMemory::new());
+        let mut allocator = MemoryAllocator::new(memory.clone(), "Test", 0x100).unwrap();
+
+        let counter = allocator.allocate_var("Counter", DataType::U32).unwrap();
+        let name = allocator.allocate_wide_string("Name", "Rook").unwrap();
+        assert_eq!(name, counter + 4);
+        assert!(allocator.allocate_var("Counter", DataType::U8).is_err());
+
+        allocator.write_var("Counter", 7u32).unwrap();
+        assert_eq!(allocator.read_var::<u32>("Counter").unwrap(), 7);
+        assert!(allocator.write_var("Counter", 7u64).is_err());
+        assert_eq!(memory.read_utf16_string(name, 16).unwrap(), "Rook");
+
+        allocator.free().unwrap();
+        assert!(memory.read_u32(counter).is_err());
+        allocator.free().unwrap();
+
+        let mut allocator = MemoryAllocator::new(memory.clone(), "Test", 0x100).unwrap();
+        let address = allocator.allocate_var("Counter", DataType::U32).unwrap();
+        drop(allocator);
+        assert!(memory.read_u8(address).is_err());
+    }
+}
@@ -4,12 +4,27 @@ pub mod base;
 pub mod basic;
 pub mod game_common;
 pub mod hashlink;
+pub mod hashlink_layout;
+pub mod hashlink_types;
+pub mod hashlink_value;
+pub mod hook_monitor;
 pub mod callback_system;
+pub mod cave_pool;
+pub mod context_hook;
+pub mod eject;
 pub mod libmem_injection;
 pub mod lobby_members;
 pub mod lore_hook;
 pub mod mem_alloc;
+pub mod patch_map;
+pub mod pointer_path;
+pub mod remote_struct;
+pub mod scanner;
+pub mod value_scanner;
 pub mod build_guide;
+pub mod bytecode_diff;
+pub mod symbol_cache;
+pub mod symbol_export;
 pub mod winrate_tracker;
 pub mod winrate_store;
 
@@ -18,11 +33,24 @@ pub use auto_lockin::*;
 pub use base::*;
 pub use game_common::*;
 pub use hashlink::*;
+pub use hashlink_layout::*;
+pub use hashlink_types::*;
+pub use hashlink_value::*;
 pub use callback_system::*;
+pub use cave_pool::*;
+pub use context_hook::*;
 pub use libmem_injection::*;
 pub use lobby_members::*;
 pub use lore_hook::*;
 pub use mem_alloc::*;
+pub use patch_map::*;
+pub use pointer_path::*;
+pub use remote_struct::*;
+pub use scanner::*;
+pub use value_scanner::*;
 pub use build_guide::*;
+pub use bytecode_diff::*;
+pub use symbol_cache::*;
+pub use symbol_export::*;
 pub use winrate_tracker::*;
 pub use winrate_store::*;
\ No newline at end of file
@@ -0,0 +1,397 @@
+/*
+    Map of the game code we patch, one per memory backend like the cave pools. Every
+    `LibmemInjection` reserves the bytes it overwrites when it is prepared, a patch overlapping
+    another one is refused with a `PatchConflict` naming both owners.
+    Payloads that need the same site go through a `HookChain` instead: they share one detour and
+    run in priority order, the site is rebuilt whenever a payload joins or leaves.
+    `PatchMap::all` lists the active patches with their owner.
+*/
+
+use crate::modules::eject;
+use crate::modules::hook_monitor::{self, InjectionSlot, TamperPolicy};
+use crate::modules::libmem_injection::LibmemInjection;
+use crate::utils::process_memory::ProcessMemory;
+use iced_x86::code_asm::CodeAssembler;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+/// Owner of the detour shared by a chain
+const CHAIN_OWNER: &str = "hook chain";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchEntry {
+    pub address: usize,
+    /// Bytes overwritten at `address`
+    pub len: usize,
+    pub owner: String,
+    /// Owners of the chained payloads in run order, empty for a single patch
+    pub chain: Vec<String>,
+}
+
+impl PatchEntry {
+    pub fn range(&self) -> Range<usize> {
+        self.address..self.address + self.len
+    }
+}
+
+/// A patch refused because it overlaps one already applied
+#[derive(Debug, Clone)]
+pub struct PatchConflict {
+    pub owner: String,
+    pub address: usize,
+    pub existing: PatchEntry,
+}
+
+impl fmt::Display for PatchConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} at 0x{:X} overlaps the patch of {} at 0x{:X} ({} bytes)",
+            self.owner, self.address, self.existing.owner, self.existing.address, self.existing.len
+        )
+    }
+}
+
+impl Error for PatchConflict {}
+
+pub struct PatchMap {
+    memory: Arc<dyn ProcessMemory>,
+    patches: Mutex<HashMap<usize, PatchEntry>>,
+    chains: Mutex<HashMap<usize, Weak<HookChain>>>,
+}
+
+/// Live maps by memory backend
+static MAPS: once_cell::sync::Lazy<Mutex<HashMap<usize, Weak<PatchMap>>>> =
+    once_cell::sync::Lazy::new(|| Mutex::new(HashMap::new()));
+static NEXT_PATCH_ID: AtomicUsize = AtomicUsize::new(1);
+
+impl PatchMap {
+    pub fn new(memory: Arc<dyn ProcessMemory>) -> Self {
+        Self { memory, patches: Mutex::new(HashMap::new()), chains: Mutex::new(HashMap::new()) }
+    }
+
+    /// Map of `memory`, shared by every injection patching through the same backend
+    pub fn shared(memory: &Arc<dyn ProcessMemory>) -> Arc<Self> {
+        let key = Arc::as_ptr(memory) as *const () as usize;
+        let mut maps = MAPS.lock().unwrap();
+        maps.retain(|_, map| map.strong_count() > 0);
+        if let Some(map) = maps.get(&key).and_then(Weak::upgrade) {
+            return map;
+        }
+        let map = Arc::new(Self::new(memory.clone()));
+        maps.insert(key, Arc::downgrade(&map));
+        map
+    }
+
+    /// Every live map
+    pub fn all() -> Vec<Arc<Self>> {
+        MAPS.lock().unwrap().values().filter_map(Weak::upgrade).collect()
+    }
+
+    /// Fails with a `PatchConflict` when `len` bytes at `address` are already patched
+    pub fn check(&self, owner: &str, address: usize, len: usize) -> Result<(), Box<dyn Error>> {
+        Self::find_conflict(&self.patches.lock().unwrap(), owner, address, len)
+    }
+
+    /// Reserve `len` bytes at `address` for `owner`, returns the id to release them with
+    pub fn reserve(&self, owner: &str, address: usize, len: usize) -> Result<usize, Box<dyn Error>> {
+        let mut patches = self.patches.lock().unwrap();
+        Self::find_conflict(&patches, owner, address, len)?;
+        let id = NEXT_PATCH_ID.fetch_add(1, Ordering::Relaxed);
+        patches.insert(id, PatchEntry { address, len, owner: owner.to_string(), chain: Vec::new() });
+        Ok(id)
+    }
+
+    /// Unknown ids are ignored, a patch may be released by `discard` and `undo`
+    pub fn release(&self, id: usize) {
+        self.patches.lock().unwrap().remove(&id);
+    }
+
+    /// Active patches by address
+    pub fn patches(&self) -> Vec<PatchEntry> {
+        let chains: HashMap<usize, Arc<HookChain>> = {
+            let mut chains = self.chains.lock().unwrap();
+            chains.retain(|_, chain| chain.strong_count() > 0);
+            chains.iter().filter_map(|(&address, chain)| Some((address, chain.upgrade()?))).collect()
+        };
+        let mut patches: Vec<PatchEntry> = self.patches.lock().unwrap().values().cloned().collect();
+        for patch in &mut patches {
+            if let Some(chain) = chains.get(&patch.address).filter(|_| patch.owner == CHAIN_OWNER) {
+                patch.chain = chain.owners();
+            }
+        }
+        patches.sort_by_key(|patch| patch.address);
+        patches
+    }
+
+    /// Patches of module `owner`, alone or in a chain. Owners are the module name or start with it
+    pub fn owned_by(&self, owner: &str) -> Vec<PatchEntry> {
+        let owns = |name: &str| name.strip_prefix(owner).is_some_and(|rest| rest.is_empty() || rest.starts_with(' '));
+        self.patches()
+            .into_iter()
+            .filter(|patch| owns(&patch.owner) || patch.chain.iter().any(|link| owns(link)))
+            .collect()
+    }
+
+    /// Add `build` to the chain of payloads run at `address`, higher priorities run first.
+    /// Payloads must fall through to the next one, the overwritten instructions run after the last.
+    /// The payload leaves the chain when the returned hook is dropped
+    pub fn chain<F>(&self, owner: &str, address: usize, priority: i32, build: F) -> Result<ChainedHook, Box<dyn Error>>
+    where
+        F: Fn(&mut CodeAssembler) -> Result<(), Box<dyn Error>> + Send + Sync + 'static,
+    {
+        let chain = {
+            let mut chains = self.chains.lock().unwrap();
+            chains.retain(|_, chain| chain.strong_count() > 0);
+            match chains.get(&address).and_then(Weak::upgrade) {
+                Some(chain) => chain,
+                None => {
+                    let chain = Arc::new(HookChain::new(self.memory.clone(), address));
+                    chains.insert(address, Arc::downgrade(&chain));
+                    chain
+                }
+            }
+        };
+        chain.add(owner, priority, Box::new(build))
+    }
+
+    fn find_conflict(patches: &HashMap<usize, PatchEntry>, owner: &str, address: usize, len: usize) -> Result<(), Box<dyn Error>> {
+        let range = address..address + len;
+        match patches.values().find(|patch| patch.address < range.end && range.start < patch.address + patch.len) {
+            Some(existing) => Err(PatchConflict { owner: owner.to_string(), address, existing: existing.clone() }.into()),
+            None => Ok(()),
+        }
+    }
+}
+
+type ChainPayload = dyn Fn(&mut CodeAssembler) -> Result<(), Box<dyn Error>> + Send + Sync;
+
+struct ChainLink {
+    id: usize,
+    owner: String,
+    priority: i32,
+    build: Box<ChainPayload>,
+}
+
+/// Payloads sharing the detour at one address
+pub struct HookChain {
+    memory: Arc<dyn ProcessMemory>,
+    address: usize,
+    /// By descending priority
+    links: Mutex<Vec<ChainLink>>,
+    injection: InjectionSlot,
+    /// Set while the detour is applied, an empty slot then means the monitor or eject removed it
+    applied: AtomicBool,
+}
+
+static NEXT_LINK_ID: AtomicUsize = AtomicUsize::new(1);
+
+impl HookChain {
+    fn new(memory: Arc<dyn ProcessMemory>, address: usize) -> Self {
+        let injection: InjectionSlot = Arc::new(Mutex::new(None));
+        let name = format!("hook chain 0x{:X}", address);
+        hook_monitor::instance().watch(&name, &injection, TamperPolicy::default());
+        eject::instance().register(&name, &injection);
+        Self { memory, address, links: Mutex::new(Vec::new()), injection, applied: AtomicBool::new(false) }
+    }
+
+    fn owners(&self) -> Vec<String> {
+        self.links.lock().unwrap().iter().map(|link| link.owner.clone()).collect()
+    }
+
+    fn add(self: &Arc<Self>, owner: &str, priority: i32, build: Box<ChainPayload>) -> Result<ChainedHook, Box<dyn Error>> {
+        let id = NEXT_LINK_ID.fetch_add(1, Ordering::Relaxed);
+        let mut links = self.links.lock().unwrap();
+        let position = links.partition_point(|link| link.priority >= priority);
+        links.insert(position, ChainLink { id, owner: owner.to_string(), priority, build });
+
+        if let Err(e) = self.rebuild(&links) {
+            // The site still runs the previous detour
+            links.remove(position);
+            return Err(format!("{} cannot join the hook chain at 0x{:X}: {}", owner, self.address, e).into());
+        }
+        tracing::info!("{} joined the hook chain at 0x{:X} with priority {}", owner, self.address, priority);
+        Ok(ChainedHook { chain: self.clone(), id })
+    }
+
+    /// Apply the detour running `links`, or restore the site when there are none. The new cave
+    /// is prepared first and swapped in with one write, a failure leaves the current detour in place
+    fn rebuild(&self, links: &[ChainLink]) -> Result<(), Box<dyn Error>> {
+        let mut code = CodeAssembler::new(64)?;
+        for link in links {
+            (link.build)(&mut code)?;
+        }
+
+        let mut injection = self.injection.lock().unwrap();
+        match injection.take() {
+            None if self.applied.load(Ordering::SeqCst) => {
+                return Err(format!("the hook chain at 0x{:X} was disabled", self.address).into());
+            }
+            None if links.is_empty() => {}
+            None => {
+                *injection = Some(LibmemInjection::prepare_detour(&self.memory, CHAIN_OWNER, self.address, &mut code)?.committed()?);
+                self.applied.store(true, Ordering::SeqCst);
+            }
+            Some(current) if links.is_empty() => {
+                if let Err(e) = current.undo() {
+                    *injection = Some(current);
+                    return Err(e);
+                }
+                self.applied.store(false, Ordering::SeqCst);
+            }
+            Some(current) => {
+                let replaced = current.prepare_replacement(&mut code).and_then(|replacement| current.replace_with(replacement));
+                match replaced {
+                    Ok(replacement) => *injection = Some(replacement),
+                    Err(e) => {
+                        *injection = Some(current);
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Payload of a `HookChain`, leaves the chain when dropped
+pub struct ChainedHook {
+    chain: Arc<HookChain>,
+    id: usize,
+}
+
+impl ChainedHook {
+    pub fn address(&self) -> usize {
+        self.chain.address
+    }
+}
+
+impl Drop for ChainedHook {
+    fn drop(&mut self) {
+        let mut links = self.chain.links.lock().unwrap();
+        links.retain(|link| link.id != self.id);
+        if let Err(e) = self.chain.rebuild(&links) {
+            tracing::error!("Hook chain at 0x{:X} not rebuilt: {}", self.chain.address, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::libmem_injection::PatchMode;
+    use crate::utils::process_memory::{MockMemory, MEM_IMAGE, PAGE_EXECUTE_READ};
+    use iced_x86::code_asm::*;
+
+    const SITE: usize = 0x1_4000_0010;
+    // mov rax, rcx; add rax, 5; ret; mov rax, rcx; add rax, 5; ret
+    const ORIGINAL: [u8; 16] = [
+        0x48, 0x89, 0xC8, 0x48, 0x83, 0xC0, 0x05, 0xC3, 0x48, 0x89, 0xC8, 0x48, 0x83, 0xC0, 0x05, 0xC3,
+    ];
+
+    fn memory() -> Arc<dyn ProcessMemory> {
+        let memory = MockMemory::new();
+        memory.map(0x1_4000_0000, 0x1000, PAGE_EXECUTE_READ, MEM_IMAGE);
+        memory.write_bytes(SITE, &ORIGINAL).unwrap();
+        Arc::new(memory)
+    }
+
+    fn nop() -> CodeAssembler {
+        let mut code = CodeAssembler::new(64).unwrap();
+        code.nop().unwrap();
+        code
+    }
+
+    #[test]
+    fn test_overlapping_patches() {
+        let memory = memory();
+        let map = PatchMap::shared(&memory);
+        let hook = LibmemInjection::prepare_detour(&memory, "AutoAccept setCheckedJoin", SITE, &mut nop()).unwrap().committed().unwrap();
+
+        // Inside the 7 bytes of the detour, and overlapping its end
+        let error = LibmemInjection::with_mode(&memory, SITE + 3, PatchMode::Nop { len: 4 }).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "hook 0x140000013 at 0x140000013 overlaps the patch of AutoAccept setCheckedJoin at 0x140000010 (7 bytes)"
+        );
+        assert!(error.downcast_ref::<PatchConflict>().is_some());
+        assert!(map.reserve("LobbyMembers", SITE - 2, 3).is_err());
+
+        // The `ret` right after is free, the early return also covers the next `mov`
+        let ret = LibmemInjection::with_mode(&memory, SITE + 7, PatchMode::Return(0)).unwrap();
+        let patches = map.patches();
+        let listed: Vec<_> = patches.iter().map(|patch| (patch.address, patch.len, patch.owner.as_str())).collect();
+        assert_eq!(listed, [(SITE, 7, "AutoAccept setCheckedJoin"), (SITE + 7, 4, "hook 0x140000017")]);
+        assert!(PatchMap::all().iter().any(|other| Arc::ptr_eq(other, &map)));
+
+        ret.undo().unwrap();
+        hook.undo().unwrap();
+        assert!(map.patches().is_empty());
+        LibmemInjection::with_mode(&memory, SITE + 3, PatchMode::Nop { len: 4 }).unwrap().undo().unwrap();
+    }
+
+    #[test]
+    fn test_hook_chain() {
+        let memory = memory();
+        let map = PatchMap::shared(&memory);
+        let low = map.chain("WinrateTracker", SITE, 0, |code| Ok(code.inc(rax)?)).unwrap();
+        let high = map.chain("LobbyMembers", SITE, 10, |code| Ok(code.inc(rcx)?)).unwrap();
+        assert_eq!(low.address(), SITE);
+
+        let chain = high.chain.clone();
+        let cave = chain.injection.lock().unwrap().as_ref().unwrap().cave().unwrap().start;
+        // inc rcx; inc rax, then the stolen instructions
+        assert_eq!(memory.read_bytes(cave, 9).unwrap(), [0x48, 0xFF, 0xC1, 0x48, 0xFF, 0xC0, 0x48, 0x89, 0xC8]);
+        let patches = map.patches();
+        assert_eq!(patches.len(), 1);
+        assert_eq!(patches[0].owner, CHAIN_OWNER);
+        assert_eq!(patches[0].chain, ["LobbyMembers", "WinrateTracker"]);
+
+        // The chain owns the site, a single patch cannot take it
+        assert!(LibmemInjection::new(&memory, SITE, &mut nop()).is_err());
+        // A failing payload is refused and the chain kept
+        assert!(map.chain("GameCommon", SITE, 5, |_| Err("no payload".into())).is_err());
+        assert_eq!(map.patches()[0].chain, ["LobbyMembers", "WinrateTracker"]);
+
+        // The site jumps straight to the new cave
+        drop(high);
+        let cave = chain.injection.lock().unwrap().as_ref().unwrap().cave().unwrap().start;
+        assert_eq!(memory.read_bytes(cave, 3).unwrap(), [0x48, 0xFF, 0xC0]);
+        let jump = i32::from_le_bytes(memory.read_bytes(SITE + 1, 4).unwrap().try_into().unwrap());
+        assert_eq!(SITE.wrapping_add_signed(5 + jump as isize), cave);
+        assert_eq!(map.patches().len(), 1);
+        drop(low);
+        assert!(chain.injection.lock().unwrap().is_none());
+        assert_eq!(memory.read_bytes(SITE, 16).unwrap(), ORIGINAL);
+        assert!(map.patches().is_empty());
+        assert_eq!(memory.regions().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_disabled_chain() {
+        let memory = memory();
+        let map = PatchMap::shared(&memory);
+        let low = map.chain("WinrateTracker", SITE, 0, |code| Ok(code.inc(rax)?)).unwrap();
+        let high = map.chain("LobbyMembers", SITE, 10, |code| Ok(code.inc(rcx)?)).unwrap();
+
+        // Disabled like the hook monitor does
+        let chain = high.chain.clone();
+        {
+            let mut injection = chain.injection.lock().unwrap();
+            injection.as_ref().unwrap().undo().unwrap();
+            *injection = None;
+        }
+
+        // Leaving the chain does not apply it again
+        drop(high);
+        assert!(chain.injection.lock().unwrap().is_none());
+        assert_eq!(memory.read_bytes(SITE, 16).unwrap(), ORIGINAL);
+        assert!(map.chain("GameCommon", SITE, 5, |code| Ok(code.nop()?)).is_err());
+        drop(low);
+        assert_eq!(memory.read_bytes(SITE, 16).unwrap(), ORIGINAL);
+    }
+}
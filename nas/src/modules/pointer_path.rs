@@ -0,0 +1,412 @@
+/*
+    Multi-level pointer paths like `$LobbyManager+0x8 -> 0x10 -> 0x28`, and a pointer scanner
+    finding such paths from static roots (the Hashlink globals, loaded modules) to an address.
+*/
+
+use crate::utils::process_memory::{is_readable, is_writable, ProcessMemory, MEM_COMMIT};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Bytes read per call when collecting pointers
+const CHUNK_SIZE: usize = 0x10000;
+
+/// Named static addresses that paths start from
+#[derive(Debug, Clone, Default)]
+pub struct PointerSymbols {
+    /// Start address to name and size
+    by_address: BTreeMap<usize, (String, usize)>,
+    by_name: HashMap<String, usize>,
+}
+
+impl PointerSymbols {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, name: &str, address: usize, size: usize) {
+        self.by_address.insert(address, (name.to_string(), size));
+        self.by_name.insert(name.to_string(), address);
+    }
+
+    pub fn get(&self, name: &str) -> Option<usize> {
+        self.by_name.get(name).copied()
+    }
+
+    /// Symbol containing `address`, with the offset of `address` in it
+    pub fn find(&self, address: usize) -> Option<(&str, usize)> {
+        let (start, (name, size)) = self.by_address.range(..=address).next_back()?;
+        (address < start + size).then(|| (name.as_str(), address - start))
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_address.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_address.is_empty()
+    }
+
+    /// Add the modules loaded in the process, by file name when the backend knows it
+    pub fn insert_modules(&mut self, memory: &dyn ProcessMemory) -> Result<(), Box<dyn Error>> {
+        for module in memory.modules()? {
+            self.insert(&module.name, module.base, module.size);
+        }
+        Ok(())
+    }
+}
+
+/// Start address plus offsets, each offset is added to the pointer read at the previous address
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PointerPath {
+    /// Symbol the path starts from, `None` when `base_offset` is an absolute address
+    pub base: Option<String>,
+    pub base_offset: usize,
+    pub offsets: Vec<usize>,
+}
+
+/// Why a path could not be resolved, `level` is the number of pointers read successfully
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PointerError {
+    pub path: String,
+    pub level: usize,
+    pub address: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for PointerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to resolve {} at level {} ({:#x}): {}", self.path, self.level, self.address, self.reason)
+    }
+}
+
+impl Error for PointerError {}
+
+impl PointerPath {
+    pub fn from_symbol(name: &str, offsets: &[usize]) -> Self {
+        Self { base: Some(name.to_string()), base_offset: 0, offsets: offsets.to_vec() }
+    }
+
+    pub fn from_address(address: usize, offsets: &[usize]) -> Self {
+        Self { base: None, base_offset: address, offsets: offsets.to_vec() }
+    }
+
+    /// Same path with one more level, e.g. the element of an array found by the path
+    pub fn with_offset(mut self, offset: usize) -> Self {
+        self.offsets.push(offset);
+        self
+    }
+
+    /// Address of the first pointer of the path
+    pub fn base_address(&self, symbols: &PointerSymbols) -> Result<usize, PointerError> {
+        match &self.base {
+            Some(name) => symbols
+                .get(name)
+                .map(|address| address + self.base_offset)
+                .ok_or_else(|| self.error(0, 0, format!("unknown symbol {}", name))),
+            None => Ok(self.base_offset),
+        }
+    }
+
+    /// Follow the path, returns the final address (not the value stored there)
+    pub fn resolve(&self, memory: &dyn ProcessMemory, symbols: &PointerSymbols) -> Result<usize, PointerError> {
+        let mut address = self.base_address(symbols)?;
+        for (level, offset) in self.offsets.iter().enumerate() {
+            let pointer = memory
+                .read_pointer(address)
+                .map_err(|e| self.error(level, address, e.to_string()))?;
+            if pointer == 0 {
+                return Err(self.error(level, address, "null pointer".to_string()));
+            }
+            address = pointer.wrapping_add(*offset);
+        }
+        Ok(address)
+    }
+
+    fn error(&self, level: usize, address: usize, reason: String) -> PointerError {
+        PointerError { path: self.to_string(), level, address, reason }
+    }
+}
+
+impl fmt::Display for PointerPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.base {
+            Some(name) if self.base_offset == 0 => write!(f, "{}", name)?,
+            Some(name) => write!(f, "{}+{:#x}", name, self.base_offset)?,
+            None => write!(f, "{:#x}", self.base_offset)?,
+        }
+        for offset in &self.offsets {
+            write!(f, " -> {:#x}", offset)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_hex(text: &str) -> Option<usize> {
+    let text = text.trim();
+    usize::from_str_radix(text.strip_prefix("0x").unwrap_or(text), 16).ok()
+}
+
+impl FromStr for PointerPath {
+    type Err = Box<dyn Error>;
+
+    /// Parse the `Display` format, e.g. `libhl.dll+0x4A0 -> 0x10 -> 0x28`
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut parts = text.split("->");
+        let base = parts.next().unwrap_or_default().trim();
+        if base.is_empty() {
+            return Err(format!("Invalid pointer path '{}': missing base", text).into());
+        }
+
+        let (base, base_offset) = if base.starts_with("0x") {
+            (None, parse_hex(base).ok_or_else(|| format!("Invalid base address in pointer path '{}'", text))?)
+        } else {
+            match base.rsplit_once('+').and_then(|(name, offset)| Some((name, parse_hex(offset)?))) {
+                Some((name, offset)) => (Some(name.trim().to_string()), offset),
+                None => (Some(base.to_string()), 0),
+            }
+        };
+
+        let offsets = parts
+            .map(|part| parse_hex(part).ok_or_else(|| format!("Invalid offset '{}' in pointer path '{}'", part.trim(), text)))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { base, base_offset, offsets })
+    }
+}
+
+/// Pointer path with its last resolved address, resolved again once `max_age` elapsed
+#[derive(Debug, Clone)]
+pub struct CachedPointer {
+    pub path: PointerPath,
+    max_age: Duration,
+    resolved: Option<(usize, Instant)>,
+}
+
+impl CachedPointer {
+    pub fn new(path: PointerPath, max_age: Duration) -> Self {
+        Self { path, max_age, resolved: None }
+    }
+
+    pub fn get(&mut self, memory: &dyn ProcessMemory, symbols: &PointerSymbols) -> Result<usize, PointerError> {
+        if let Some((address, resolved_at)) = self.resolved {
+            if resolved_at.elapsed() < self.max_age {
+                return Ok(address);
+            }
+        }
+
+        self.resolved = None;
+        let address = self.path.resolve(memory, symbols)?;
+        self.resolved = Some((address, Instant::now()));
+        Ok(address)
+    }
+
+    /// Forget the cached address, e.g. after the object it pointed to was freed
+    pub fn invalidate(&mut self) {
+        self.resolved = None;
+    }
+}
+
+/// Finds paths from the symbols to a target address, shortest paths first
+#[derive(Debug, Clone)]
+pub struct PointerScanner {
+    /// Maximum number of pointers read by a path
+    pub max_depth: usize,
+    /// Maximum offset added to a pointer, should cover the largest object on the path
+    pub max_offset: usize,
+    pub max_results: usize,
+}
+
+impl Default for PointerScanner {
+    fn default() -> Self {
+        Self { max_depth: 5, max_offset: 0x400, max_results: 100 }
+    }
+}
+
+impl PointerScanner {
+    /// Every aligned pointer stored in writable memory that points to readable memory,
+    /// as (value, location) pairs sorted by value
+    fn collect_pointers(&self, memory: &dyn ProcessMemory) -> Result<Vec<(usize, usize)>, Box<dyn Error>> {
+        let regions: Vec<_> = memory
+            .regions()?
+            .into_iter()
+            .filter(|region| region.state == MEM_COMMIT && is_readable(region.protect))
+            .collect();
+        let is_valid = |value: usize| {
+            let index = regions.partition_point(|region| region.end_address() <= value);
+            regions.get(index).is_some_and(|region| region.contains(value))
+        };
+
+        let mut pointers = Vec::new();
+        for region in regions.iter().filter(|region| is_writable(region.protect)) {
+            let mut chunk_start = region.base_address;
+            while chunk_start < region.end_address() {
+                let length = CHUNK_SIZE.min(region.end_address() - chunk_start);
+                if let Ok(data) = memory.read_bytes(chunk_start, length) {
+                    for (index, bytes) in data.chunks_exact(8).enumerate() {
+                        let value = usize::from_le_bytes(bytes.try_into().unwrap());
+                        if is_valid(value) {
+                            pointers.push((value, chunk_start + index * 8));
+                        }
+                    }
+                }
+                chunk_start += length;
+            }
+        }
+        pointers.sort_unstable();
+        Ok(pointers)
+    }
+
+    /// Search backwards from `target`: at each level, find the pointers to an address at most
+    /// `max_offset` bytes before the current one, until a symbol is reached
+    pub fn scan(
+        &self,
+        memory: &dyn ProcessMemory,
+        symbols: &PointerSymbols,
+        target: usize,
+    ) -> Result<Vec<PointerPath>, Box<dyn Error>> {
+        let started = Instant::now();
+        let pointers = self.collect_pointers(memory)?;
+
+        let mut paths = Vec::new();
+        let mut visited = HashSet::from([target]);
+        // Address reached and the offsets leading from it to the target, last offset first
+        let mut queue = VecDeque::from([(target, Vec::new())]);
+        while let Some((address, offsets)) = queue.pop_front() {
+            if let Some((name, base_offset)) = symbols.find(address) {
+                let offsets: Vec<usize> = offsets.iter().rev().copied().collect();
+                paths.push(PointerPath { base: Some(name.to_string()), base_offset, offsets });
+                if paths.len() >= self.max_results {
+                    break;
+                }
+                continue;
+            }
+            if offsets.len() >= self.max_depth {
+                continue;
+            }
+
+            let first = pointers.partition_point(|&(value, _)| value < address.saturating_sub(self.max_offset));
+            for &(value, location) in pointers[first..].iter().take_while(|&&(value, _)| value <= address) {
+                if visited.insert(location) {
+                    let mut offsets = offsets.clone();
+                    offsets.push(address - value);
+                    queue.push_back((location, offsets));
+                }
+            }
+        }
+
+        tracing::debug!(
+            "Pointer scan for {:#x}: {} paths from {} pointers in {:?}",
+            target,
+            paths.len(),
+            pointers.len(),
+            started.elapsed()
+        );
+        Ok(paths)
+    }
+
+    /// Keep the paths that still lead to `target`, to rescan after the game moved the object
+    pub fn retain_valid(
+        paths: &mut Vec<PointerPath>,
+        memory: &dyn ProcessMemory,
+        symbols: &PointerSymbols,
+        target: usize,
+    ) {
+        paths.retain(|path| path.resolve(memory, symbols).is_ok_and(|address| address == target));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::process_memory::{MockMemory, MEM_IMAGE, MEM_PRIVATE, PAGE_EXECUTE_READ, PAGE_READONLY, PAGE_READWRITE};
+
+    /// Globals at 0x10000, `$LobbyManager` -> manager (0x20100) -> lobby (0x21200)
+    /// -> members array (0x22300) -> member 1 (0x23400) -> name (0x24480)
+    fn memory() -> (MockMemory, PointerSymbols) {
+        let memory = MockMemory::new();
+        memory.map(0x10000, 0x1000, PAGE_READWRITE, MEM_PRIVATE);
+        memory.map(0x20000, 0x5000, PAGE_READWRITE, MEM_PRIVATE);
+        memory.write_u64(0x10008, 0x20100).unwrap();
+        memory.write_u64(0x20110, 0x21200).unwrap();
+        memory.write_u64(0x21228, 0x22300).unwrap();
+        memory.write_u64(0x22318, 0x23400).unwrap();
+        memory.write_u64(0x23430, 0x24480).unwrap();
+
+        let mut symbols = PointerSymbols::new();
+        symbols.insert("$Game", 0x10000, 8);
+        symbols.insert("$LobbyManager", 0x10008, 8);
+        (memory, symbols)
+    }
+
+    #[test]
+    fn test_resolve_path() {
+        let (memory, symbols) = memory();
+        let members: PointerPath = "$LobbyManager -> 0x10 -> 0x28".parse().unwrap();
+        assert_eq!(members.resolve(&memory, &symbols).unwrap(), 0x21228);
+        let member = members.with_offset(0x10 + 8);
+        assert_eq!(member.to_string(), "$LobbyManager -> 0x10 -> 0x28 -> 0x18");
+        assert_eq!(member.resolve(&memory, &symbols).unwrap(), 0x22318);
+        assert_eq!(member.with_offset(0x30).with_offset(0).resolve(&memory, &symbols).unwrap(), 0x24480);
+
+        let error = PointerPath::from_symbol("$LobbyManager", &[0x10, 0x20, 0]).resolve(&memory, &symbols).unwrap_err();
+        assert_eq!((error.level, error.address, error.reason.as_str()), (2, 0x21220, "null pointer"));
+        assert!(PointerPath::from_symbol("$Missing", &[0]).resolve(&memory, &symbols).is_err());
+
+        let absolute: PointerPath = "0x10008 -> 0x10".parse().unwrap();
+        assert_eq!(absolute, PointerPath::from_address(0x10008, &[0x10]));
+        let module: PointerPath = "game.exe+0x4A0".parse().unwrap();
+        assert_eq!((module.base.as_deref(), module.base_offset), (Some("game.exe"), 0x4A0));
+        assert_eq!(module.to_string(), "game.exe+0x4a0");
+        assert!("$Game -> zz".parse::<PointerPath>().is_err());
+    }
+
+    #[test]
+    fn test_cached_pointer() {
+        let (memory, symbols) = memory();
+        let mut pointer = CachedPointer::new(PointerPath::from_symbol("$LobbyManager", &[0x10]), Duration::from_secs(60));
+        assert_eq!(pointer.get(&memory, &symbols).unwrap(), 0x20110);
+
+        memory.write_u64(0x10008, 0x20500).unwrap();
+        assert_eq!(pointer.get(&memory, &symbols).unwrap(), 0x20110);
+        pointer.invalidate();
+        assert_eq!(pointer.get(&memory, &symbols).unwrap(), 0x20510);
+    }
+
+    #[test]
+    fn test_pointer_scan() {
+        let (memory, symbols) = memory();
+        let mut paths = PointerScanner::default().scan(&memory, &symbols, 0x24480).unwrap();
+        assert_eq!(
+            paths.iter().map(PointerPath::to_string).collect::<Vec<_>>(),
+            ["$LobbyManager -> 0x10 -> 0x28 -> 0x18 -> 0x30 -> 0x0"]
+        );
+
+        let shallow = PointerScanner { max_depth: 4, ..Default::default() };
+        assert!(shallow.scan(&memory, &symbols, 0x24480).unwrap().is_empty());
+
+        // The member object moved, the path through the members array still leads to the name
+        memory.write_u64(0x22318, 0x23800).unwrap();
+        memory.write_u64(0x23830, 0x24480).unwrap();
+        memory.write_u64(0x23430, 0).unwrap();
+        paths.push(PointerPath::from_address(0x23430, &[0]));
+        PointerScanner::retain_valid(&mut paths, &memory, &symbols, 0x24480);
+        assert_eq!(paths.len(), 1);
+        assert_eq!(paths[0].offsets, [0x10, 0x28, 0x18, 0x30, 0]);
+    }
+
+    #[test]
+    fn test_module_symbols() {
+        let memory = MockMemory::new();
+        memory.map(0x400000, 0x1000, PAGE_EXECUTE_READ, MEM_IMAGE);
+        memory.map(0x500000, 0x1000, PAGE_READWRITE, MEM_PRIVATE);
+        memory.map(0x600000, 0x2000, PAGE_READONLY, MEM_IMAGE);
+
+        let mut symbols = PointerSymbols::new();
+        symbols.insert_modules(&memory).unwrap();
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols.find(0x601234), Some(("image@0x600000", 0x1234)));
+        assert_eq!(symbols.find(0x500010), None);
+    }
+}
@@ -0,0 +1,158 @@
+/*
+    Structures read from the game process in one go. Declare the layout with
+    `#[derive(RemoteStruct)]` and a `#[remote(offset = ...)]` attribute on every field:
+
+        #[derive(RemoteStruct)]
+        struct Lobby {
+            #[remote(offset = 0x10)]
+            size: i32,
+            #[remote(offset = 0x18, pointer)]
+            owner: Option<Player>,
+            #[remote(offset = 0x20, utf16)]
+            name: String,
+        }
+
+    Plain fields are decoded from the bytes of the structure, `pointer` fields are read from the
+    address stored at their offset and `utf16` fields from the characters it points to.
+*/
+
+use crate::utils::process_memory::ProcessMemory;
+use std::error::Error;
+
+pub use nas_derive::RemoteStruct;
+
+/// Value with a fixed layout in the game process
+pub trait RemoteStruct: Sized {
+    /// Bytes read for the value, up to the end of its last field
+    const SIZE: usize;
+
+    /// Decode from `bytes`, which hold `SIZE` bytes. Pointer fields are followed through `memory`
+    fn from_remote_bytes(memory: &dyn ProcessMemory, bytes: &[u8]) -> Result<Self, Box<dyn Error>>;
+
+    fn read(memory: &dyn ProcessMemory, address: usize) -> Result<Self, Box<dyn Error>> {
+        let bytes = memory.read_bytes(address, Self::SIZE)?;
+        Self::from_remote_bytes(memory, &bytes)
+    }
+}
+
+macro_rules! impl_remote_struct {
+    ($($ty:ty),+) => {
+        $(
+            impl RemoteStruct for $ty {
+                const SIZE: usize = std::mem::size_of::<$ty>();
+
+                fn from_remote_bytes(_memory: &dyn ProcessMemory, bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+                    Ok(<$ty>::from_le_bytes(bytes.try_into()?))
+                }
+            }
+        )+
+    };
+}
+
+impl_remote_struct!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64, usize);
+
+impl RemoteStruct for bool {
+    const SIZE: usize = 1;
+
+    fn from_remote_bytes(_memory: &dyn ProcessMemory, bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        Ok(bytes[0] != 0)
+    }
+}
+
+impl<T: RemoteStruct, const N: usize> RemoteStruct for [T; N] {
+    const SIZE: usize = T::SIZE * N;
+
+    fn from_remote_bytes(memory: &dyn ProcessMemory, bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let values = bytes
+            .chunks_exact(T::SIZE)
+            .map(|chunk| T::from_remote_bytes(memory, chunk))
+            .collect::<Result<Vec<_>, _>>()?;
+        values.try_into().map_err(|_| "Invalid array length".into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::process_memory::{MockMemory, MEM_PRIVATE, PAGE_READWRITE};
+
+    #[derive(Debug, PartialEq, RemoteStruct)]
+    struct Name {
+        #[remote(offset = 0x8, utf16)]
+        text: String,
+    }
+
+    #[derive(Debug, PartialEq, RemoteStruct)]
+    struct Player {
+        #[remote(offset = 0x8)]
+        id: u32,
+        #[remote(offset = 0x10, pointer)]
+        name: Name,
+    }
+
+    #[derive(Debug, PartialEq, RemoteStruct)]
+    struct Lobby {
+        #[remote(offset = 0x4)]
+        locked: bool,
+        #[remote(offset = 0x8)]
+        size: i32,
+        #[remote(offset = 0x10)]
+        slots: [u16; 2],
+        #[remote(offset = 0x18, pointer)]
+        owner: Option<Player>,
+        #[remote(offset = 0x20, pointer)]
+        guest: Option<Player>,
+        #[remote(offset = 0x28, utf16 = 4)]
+        name: Option<String>,
+    }
+
+    fn utf16(text: &str) -> Vec<u8> {
+        text.encode_utf16().flat_map(u16::to_le_bytes).collect()
+    }
+
+    /// Lobby at 0x1000, its owner at 0x1100 named by the `Name` at 0x1200
+    fn memory() -> MockMemory {
+        let memory = MockMemory::new();
+        memory.map(0x1000, 0x1000, PAGE_READWRITE, MEM_PRIVATE);
+        memory.write_bytes(0x1004, &[1]).unwrap();
+        memory.write_u32(0x1008, (-3i32) as u32).unwrap();
+        memory.write_bytes(0x1010, &[2, 0, 4, 0]).unwrap();
+        memory.write_u64(0x1018, 0x1100).unwrap();
+        memory.write_u64(0x1028, 0x1300).unwrap();
+        memory.write_bytes(0x1300, &utf16("Ranked lobby")).unwrap();
+
+        memory.write_u32(0x1108, 42).unwrap();
+        memory.write_u64(0x1110, 0x1200).unwrap();
+        memory.write_u64(0x1208, 0x1280).unwrap();
+        memory.write_bytes(0x1280, &utf16("Player1")).unwrap();
+        memory
+    }
+
+    #[test]
+    fn test_read_remote_struct() {
+        let memory = memory();
+        assert_eq!(Lobby::SIZE, 0x30);
+        assert_eq!(Player::SIZE, 0x18);
+
+        let lobby = Lobby::read(&memory, 0x1000).unwrap();
+        assert_eq!(
+            lobby,
+            Lobby {
+                locked: true,
+                size: -3,
+                slots: [2, 4],
+                owner: Some(Player { id: 42, name: Name { text: "Player1".to_string() } }),
+                guest: None,
+                name: Some("Rank".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_null_pointer_field() {
+        let memory = memory();
+        memory.write_u64(0x1110, 0).unwrap();
+        let error = Lobby::read(&memory, 0x1000).unwrap_err().to_string();
+        assert_eq!(error, "Player.name is a null pointer");
+    }
+}
@@ -0,0 +1,455 @@
+/*
+    Multi-pattern AOB scanner: every pattern is looked up in a single pass over memory.
+    Each pattern is indexed by its rarest fixed byte (the anchor), so a position is only
+    compared against the patterns whose anchor matches the byte found there.
+*/
+
+use crate::utils::process_memory::{MemoryRegion, ProcessMemory};
+use std::error::Error;
+use std::time::Instant;
+
+/// Bytes read per call, consecutive chunks overlap by the longest pattern
+const CHUNK_SIZE: usize = 0x10000;
+
+/// Bytes frequent in x64 code and data, never picked as anchor when the pattern has another fixed byte
+const COMMON_BYTES: &[u8] = &[
+    0x00, 0xFF, 0x48, 0x8B, 0x89, 0x83, 0x4C, 0x49, 0x4D, 0x85, 0x0F, 0x24, 0x44, 0x8D, 0xC3, 0xCC,
+    0x90, 0x01, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x55, 0x5D, 0xE8, 0xE9, 0xEB, 0x74, 0x75, 0x33,
+];
+
+/// Value read from the matched bytes, written as `[kind]` or `[kind:name]` in a pattern
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureKind {
+    U8,
+    U16,
+    U32,
+    /// Sign-extended 32-bit value, e.g. a stack or struct offset
+    I32,
+    U64,
+    /// Branch target of a `call`/`jmp` rel32, relative to the end of the displacement
+    Rel32,
+    /// RIP-relative address, `[rip32+N]` when N immediate bytes follow the displacement
+    Rip32 { trailing: usize },
+}
+
+impl CaptureKind {
+    fn parse(text: &str) -> Option<Self> {
+        let (kind, trailing) = match text.split_once('+') {
+            Some((kind, trailing)) => (kind, Some(trailing.parse().ok()?)),
+            None => (text, None),
+        };
+        match (kind, trailing) {
+            ("u8", None) => Some(Self::U8),
+            ("u16", None) => Some(Self::U16),
+            ("u32", None) => Some(Self::U32),
+            ("i32", None) => Some(Self::I32),
+            ("u64", None) => Some(Self::U64),
+            ("rel32", None) => Some(Self::Rel32),
+            ("rip32", trailing) => Some(Self::Rip32 { trailing: trailing.unwrap_or(0) }),
+            _ => None,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        match self {
+            Self::U8 => 1,
+            Self::U16 => 2,
+            Self::U64 => 8,
+            Self::U32 | Self::I32 | Self::Rel32 | Self::Rip32 { .. } => 4,
+        }
+    }
+
+    /// Value of the capture from its bytes, found at `address`
+    fn resolve(&self, bytes: &[u8], address: usize) -> usize {
+        let mut raw = [0u8; 8];
+        raw[..bytes.len()].copy_from_slice(bytes);
+        let value = u64::from_le_bytes(raw) as usize;
+        let displacement = value as u32 as i32 as isize;
+        match self {
+            Self::U8 | Self::U16 | Self::U32 | Self::U64 => value,
+            Self::I32 => displacement as usize,
+            Self::Rel32 => (address + 4).wrapping_add_signed(displacement),
+            Self::Rip32 { trailing } => (address + 4 + trailing).wrapping_add_signed(displacement),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capture {
+    pub kind: CaptureKind,
+    pub name: Option<String>,
+    /// Position of the first captured byte in the pattern
+    pub offset: usize,
+}
+
+/// Byte pattern, a position matches when `byte & mask == value` for every byte
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    pub text: String,
+    pub value: Vec<u8>,
+    pub mask: Vec<u8>,
+    pub captures: Vec<Capture>,
+    /// Index of the byte used to find candidates
+    anchor: usize,
+}
+
+impl Pattern {
+    /// Parse a space-separated hex pattern, e.g. `48 8B 05 [rip32:players] 4? ?? ?`.
+    /// `??` and `?` are wildcards, `4?` and `?8` only match one nibble and
+    /// `[kind]` captures the bytes of a `CaptureKind` (`u8`, `u32`, `rel32`, `rip32+1`, ...)
+    pub fn parse(text: &str) -> Result<Self, Box<dyn Error>> {
+        let mut value = Vec::new();
+        let mut mask = Vec::new();
+        let mut captures = Vec::new();
+        for part in text.split_whitespace() {
+            if let Some(capture) = part.strip_prefix('[').and_then(|part| part.strip_suffix(']')) {
+                let (kind, name) = match capture.split_once(':') {
+                    Some((kind, name)) => (kind, Some(name.to_string())),
+                    None => (capture, None),
+                };
+                let kind = CaptureKind::parse(kind)
+                    .ok_or_else(|| format!("Invalid capture '{}' in pattern {}", part, text))?;
+                captures.push(Capture { kind, name, offset: value.len() });
+                value.resize(value.len() + kind.size(), 0);
+                mask.resize(mask.len() + kind.size(), 0);
+            } else if part == "?" {
+                value.push(0);
+                mask.push(0);
+            } else if part.len() == 2 {
+                let mut byte = (0, 0);
+                for digit in part.chars() {
+                    let (digit_value, digit_mask) = match digit {
+                        '?' => (0, 0),
+                        _ => (
+                            digit.to_digit(16).ok_or_else(|| format!("Invalid byte '{}' in pattern {}", part, text))?
+                                as u8,
+                            0xF,
+                        ),
+                    };
+                    byte = (byte.0 << 4 | digit_value, byte.1 << 4 | digit_mask);
+                }
+                value.push(byte.0);
+                mask.push(byte.1);
+            } else {
+                return Err(format!("Invalid byte '{}' in pattern {}", part, text).into());
+            }
+        }
+        let mut pattern = Self::from_masked(text, value, mask)?;
+        pattern.captures = captures;
+        Ok(pattern)
+    }
+
+    /// Pattern in the `"\x48\x8B\x05\x00\x00\x00\x00", "xxx????"` style of C signatures,
+    /// `x` is a fixed byte and `?` a wildcard
+    pub fn from_code(bytes: &[u8], mask: &str) -> Result<Self, Box<dyn Error>> {
+        let text = bytes
+            .iter()
+            .zip(mask.chars())
+            .map(|(byte, m)| if m == 'x' { format!("{:02X}", byte) } else { "??".to_string() })
+            .collect::<Vec<_>>()
+            .join(" ");
+        if bytes.len() != mask.len() || mask.chars().any(|m| m != 'x' && m != '?') {
+            return Err(format!("Invalid mask '{}' for pattern {}", mask, text).into());
+        }
+        let mask = mask.chars().map(|m| if m == 'x' { 0xFF } else { 0 }).collect();
+        Self::from_masked(&text, bytes.to_vec(), mask)
+    }
+
+    /// Pattern from explicit value and mask bytes, the mask must have at least one full byte
+    pub fn from_masked(text: &str, value: Vec<u8>, mask: Vec<u8>) -> Result<Self, Box<dyn Error>> {
+        if value.len() != mask.len() {
+            return Err(format!("Pattern {} has {} bytes but {} mask bytes", text, value.len(), mask.len()).into());
+        }
+        let value: Vec<u8> = value.iter().zip(&mask).map(|(v, m)| v & m).collect();
+        let anchor = (0..value.len())
+            .filter(|&i| mask[i] == 0xFF)
+            .min_by_key(|&i| COMMON_BYTES.contains(&value[i]))
+            .ok_or_else(|| format!("Invalid pattern {}: no fixed byte", text))?;
+
+        Ok(Self { text: text.to_string(), value, mask, captures: Vec::new(), anchor })
+    }
+
+    pub fn len(&self) -> usize {
+        self.value.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.value.is_empty()
+    }
+
+    /// Check the pattern against `data`, which must hold at least `len()` bytes
+    pub fn matches(&self, data: &[u8]) -> bool {
+        self.value
+            .iter()
+            .zip(&self.mask)
+            .zip(data)
+            .all(|((value, mask), byte)| byte & mask == *value)
+    }
+
+    /// Resolve the captures of a match of `data` found at `address`
+    pub fn capture(&self, data: &[u8], address: usize) -> ScanMatch {
+        let captures = self
+            .captures
+            .iter()
+            .map(|capture| {
+                let bytes = &data[capture.offset..capture.offset + capture.kind.size()];
+                capture.kind.resolve(bytes, address + capture.offset)
+            })
+            .collect();
+        ScanMatch { address, captures }
+    }
+}
+
+/// Address of a match and the values of the pattern captures, in pattern order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanMatch {
+    pub address: usize,
+    pub captures: Vec<usize>,
+}
+
+/// Matches of each pattern, in the order the patterns were added
+#[derive(Debug, Default)]
+pub struct ScanResults {
+    matches: Vec<Vec<ScanMatch>>,
+    patterns: Vec<Pattern>,
+}
+
+impl ScanResults {
+    /// Matches of pattern `id`, in address order
+    pub fn matches(&self, id: usize) -> &[ScanMatch] {
+        &self.matches[id]
+    }
+
+    /// Addresses of the matches of pattern `id`, in address order
+    pub fn hits(&self, id: usize) -> Vec<usize> {
+        self.matches[id].iter().map(|m| m.address).collect()
+    }
+
+    pub fn hit_count(&self, id: usize) -> usize {
+        self.matches[id].len()
+    }
+
+    /// First match of pattern `id`, fails with the pattern text when it was not found
+    pub fn first_match(&self, id: usize) -> Result<&ScanMatch, Box<dyn Error>> {
+        self.matches[id]
+            .first()
+            .ok_or_else(|| format!("Pattern {} not found", self.patterns[id].text).into())
+    }
+
+    pub fn first(&self, id: usize) -> Result<usize, Box<dyn Error>> {
+        Ok(self.first_match(id)?.address)
+    }
+
+    /// Capture `name` of the first match of pattern `id`
+    pub fn first_capture(&self, id: usize, name: &str) -> Result<usize, Box<dyn Error>> {
+        let pattern = &self.patterns[id];
+        let index = pattern
+            .captures
+            .iter()
+            .position(|capture| capture.name.as_deref() == Some(name))
+            .ok_or_else(|| format!("Pattern {} has no capture named {}", pattern.text, name))?;
+        Ok(self.first_match(id)?.captures[index])
+    }
+}
+
+pub struct PatternScanner {
+    patterns: Vec<Pattern>,
+    /// Patterns indexed by their anchor byte
+    by_anchor: Vec<Vec<usize>>,
+}
+
+impl Default for PatternScanner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PatternScanner {
+    pub fn new() -> Self {
+        Self { patterns: Vec::new(), by_anchor: vec![Vec::new(); 256] }
+    }
+
+    /// Add a pattern, returns its id in the scan results
+    pub fn add(&mut self, pattern: &str) -> Result<usize, Box<dyn Error>> {
+        Ok(self.add_pattern(Pattern::parse(pattern)?))
+    }
+
+    pub fn add_pattern(&mut self, pattern: Pattern) -> usize {
+        let id = self.patterns.len();
+        self.by_anchor[pattern.value[pattern.anchor] as usize].push(id);
+        self.patterns.push(pattern);
+        id
+    }
+
+    pub fn pattern(&self, id: usize) -> &Pattern {
+        &self.patterns[id]
+    }
+
+    /// Find every pattern in `data`, a buffer read at `base`. Only matches starting before
+    /// `base + scan_length` are reported, the bytes after it are the overlap with the next chunk
+    fn scan_buffer(&self, data: &[u8], base: usize, scan_length: usize, matches: &mut [Vec<ScanMatch>]) {
+        for (position, byte) in data.iter().enumerate() {
+            for &id in &self.by_anchor[*byte as usize] {
+                let pattern = &self.patterns[id];
+                let Some(start) = position.checked_sub(pattern.anchor) else {
+                    continue;
+                };
+                if start < scan_length && start + pattern.len() <= data.len() && pattern.matches(&data[start..]) {
+                    matches[id].push(pattern.capture(&data[start..], base + start));
+                }
+            }
+        }
+    }
+
+    /// Scan the regions accepted by `filter` for all patterns at once
+    pub fn scan(
+        &self,
+        memory: &dyn ProcessMemory,
+        filter: impl Fn(&MemoryRegion) -> bool,
+    ) -> Result<ScanResults, Box<dyn Error>> {
+        let started = Instant::now();
+        let mut matches = vec![Vec::new(); self.patterns.len()];
+        let overlap = self.patterns.iter().map(Pattern::len).max().unwrap_or(1) - 1;
+        let mut scanned = 0;
+
+        for region in memory.regions()?.into_iter().filter(|region| filter(region)) {
+            let mut chunk_start = region.base_address;
+            while chunk_start < region.end_address() {
+                let scan_length = CHUNK_SIZE.min(region.end_address() - chunk_start);
+                let read_length = (scan_length + overlap).min(region.end_address() - chunk_start);
+                if let Ok(data) = memory.read_bytes(chunk_start, read_length) {
+                    self.scan_buffer(&data, chunk_start, scan_length, &mut matches);
+                    scanned += scan_length;
+                }
+                chunk_start += scan_length;
+            }
+        }
+
+        for (pattern, pattern_matches) in self.patterns.iter().zip(&matches) {
+            tracing::debug!("Pattern hits: {} ({} bytes): {}", pattern_matches.len(), pattern.len(), pattern.text);
+        }
+        tracing::debug!(
+            "Scanned {:#x} bytes for {} patterns in {:?}",
+            scanned,
+            self.patterns.len(),
+            started.elapsed()
+        );
+
+        Ok(ScanResults { matches, patterns: self.patterns.clone() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::process_memory::*;
+
+    #[test]
+    fn test_parse_pattern() {
+        let pattern = Pattern::parse("48 8B ?? 7C").unwrap();
+        assert_eq!(pattern.value, vec![0x48, 0x8B, 0x00, 0x7C]);
+        assert_eq!(pattern.mask, vec![0xFF, 0xFF, 0x00, 0xFF]);
+        // 0x48 and 0x8B are too common to be used as anchor
+        assert_eq!(pattern.anchor, 3);
+        assert!(pattern.matches(&[0x48, 0x8B, 0x12, 0x7C]));
+        assert!(!pattern.matches(&[0x48, 0x8B, 0x12, 0x7D]));
+
+        assert!(Pattern::parse("?? ??").is_err());
+        assert!(Pattern::parse("").is_err());
+        assert!(Pattern::parse("48 GG").is_err());
+    }
+
+    #[test]
+    fn test_parse_extended_syntax() {
+        let pattern = Pattern::parse("4? ?8 ? 7C [u32] C3").unwrap();
+        assert_eq!(pattern.value, vec![0x40, 0x08, 0x00, 0x7C, 0, 0, 0, 0, 0xC3]);
+        assert_eq!(pattern.mask, vec![0xF0, 0x0F, 0x00, 0xFF, 0, 0, 0, 0, 0xFF]);
+        assert_eq!(pattern.captures, vec![Capture { kind: CaptureKind::U32, name: None, offset: 4 }]);
+        assert!(pattern.matches(&[0x4C, 0x38, 0x99, 0x7C, 1, 2, 3, 4, 0xC3]));
+        assert!(!pattern.matches(&[0x5C, 0x38, 0x99, 0x7C, 1, 2, 3, 4, 0xC3]));
+
+        let pattern = Pattern::parse("C7 05 [rip32+4:flag] 01").unwrap();
+        assert_eq!(
+            pattern.captures[0],
+            Capture { kind: CaptureKind::Rip32 { trailing: 4 }, name: Some("flag".to_string()), offset: 2 }
+        );
+
+        let pattern = Pattern::from_code(b"\x48\x8B\x05\x00\x00\x00\x00", "xxx????").unwrap();
+        assert_eq!(pattern.text, "48 8B 05 ?? ?? ?? ??");
+        assert_eq!(pattern.mask, vec![0xFF, 0xFF, 0xFF, 0, 0, 0, 0]);
+        assert!(Pattern::from_code(b"\x48\x8B", "x").is_err());
+
+        assert!(Pattern::parse("E8 [rel16]").is_err());
+        assert!(Pattern::parse("E8 [rel32+4]").is_err());
+        assert!(Pattern::parse("4G").is_err());
+        assert!(Pattern::parse("[u32]").is_err());
+    }
+
+    #[test]
+    fn test_scan_captures() {
+        let memory = MockMemory::new();
+        memory.map(0x100000, 0x2000, PAGE_EXECUTE_READWRITE, MEM_IMAGE);
+        // call 0x100500
+        memory.write_bytes(0x100100, &[0xE8, 0xFB, 0x03, 0x00, 0x00]).unwrap();
+        // call 0x100000, backwards
+        memory.write_bytes(0x100300, &[0xE8, 0xFB, 0xFC, 0xFF, 0xFF]).unwrap();
+        // mov rax, [0x101000]
+        memory.write_bytes(0x100200, &[0x48, 0x8B, 0x05, 0xF9, 0x0D, 0x00, 0x00]).unwrap();
+        // mov dword ptr [0x101008], 1
+        memory.write_bytes(0x100400, &[0xC7, 0x05, 0xFE, 0x0B, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00]).unwrap();
+
+        let mut scanner = PatternScanner::new();
+        let call = scanner.add("E8 [rel32]").unwrap();
+        let load = scanner.add("48 8B 05 [rip32:global]").unwrap();
+        let store = scanner.add("C7 05 [rip32+4:flag] [u32]").unwrap();
+
+        let results = scanner.scan(&memory, |_| true).unwrap();
+        assert_eq!(
+            results.matches(call),
+            [
+                ScanMatch { address: 0x100100, captures: vec![0x100500] },
+                ScanMatch { address: 0x100300, captures: vec![0x100000] },
+            ]
+        );
+        assert_eq!(results.first_capture(load, "global").unwrap(), 0x101000);
+        assert_eq!(results.first_match(store).unwrap().captures, [0x101008, 1]);
+        assert!(results.first_capture(call, "target").is_err());
+    }
+
+    #[test]
+    fn test_scan_many_patterns() {
+        let memory = MockMemory::new();
+        memory.map(0x100000, 0x30000, PAGE_EXECUTE_READ, MEM_IMAGE);
+        memory.map(0x200000, 0x1000, PAGE_READWRITE, MEM_PRIVATE);
+        // Crosses the boundary between the first two chunks
+        memory.write_bytes(0x10FFFE, &[0x55, 0x48, 0x8B, 0xEC]).unwrap();
+        memory.write_bytes(0x120000, &[0x55, 0x48, 0x8B, 0xEC]).unwrap();
+        memory.write_bytes(0x12FFF0, &[0xB8, 0x2A, 0x00, 0x00, 0x00, 0xC3]).unwrap();
+        memory.write_bytes(0x200010, &[0x55, 0x48, 0x8B, 0xEC]).unwrap();
+
+        let mut scanner = PatternScanner::new();
+        let prologue = scanner.add("55 48 8B EC").unwrap();
+        let ret_const = scanner.add("B8 ?? 00 00 00 C3").unwrap();
+        let missing = scanner.add("0F 0B CC CC").unwrap();
+
+        let results = scanner.scan(&memory, |region| region.protect & PAGE_EXECUTABLE != 0).unwrap();
+        assert_eq!(results.hits(prologue), [0x10FFFE, 0x120000]);
+        assert_eq!(results.hits(ret_const), [0x12FFF0]);
+        assert_eq!(results.hit_count(missing), 0);
+        assert!(results.first(missing).unwrap_err().to_string().contains("0F 0B CC CC"));
+
+        let results = scanner.scan(&memory, |_| true).unwrap();
+        assert_eq!(results.hit_count(prologue), 3);
+    }
+
+    #[test]
+    fn test_scan_overlapping_matches() {
+        let memory = MockMemory::new();
+        memory.map(0x1000, 0x1000, PAGE_READWRITE, MEM_PRIVATE);
+        memory.write_bytes(0x1000, &[0xAA; 5]).unwrap();
+
+        let mut scanner = PatternScanner::new();
+        let id = scanner.add("AA AA").unwrap();
+        assert_eq!(scanner.scan(&memory, |_| true).unwrap().hits(id), [0x1000, 0x1001, 0x1002, 0x1003]);
+    }
+}
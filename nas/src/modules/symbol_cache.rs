@@ -0,0 +1,150 @@
+/*
+    Persistent cache of the parsed `hlboot.dat` symbols.
+    Parsing the whole bytecode on every injection is slow, so we keep the parts we need
+    on disk and rebuild them only when the game (or its hashlink runtime) changes.
+*/
+
+use crate::modules::hashlink_types::{compute_obj_layouts, HLObjType};
+use hlbc::fmt::EnhancedFmt;
+use hlbc::Bytecode;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Bump when the layout of `SymbolCache` changes, so old cache files get rebuilt
+pub const SYMBOL_CACHE_FORMAT: u32 = 3;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CachedFunction {
+    pub findex: usize,
+    pub name: String,
+    pub class_name: Option<String>,
+    /// Function type, e.g. `(ui.win.EndGame) -> void`
+    pub signature: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SymbolCache {
+    pub format: u32,
+    /// FNV-1a hash of `hlboot.dat` content
+    pub bytecode_hash: u64,
+    pub hashlink_version: u32,
+    pub functions: Vec<CachedFunction>,
+    /// Type names indexed by their bytecode type index
+    pub types: Vec<String>,
+    /// Runtime layouts of every class
+    pub obj_types: Vec<HLObjType>,
+}
+
+impl SymbolCache {
+    pub fn default_path() -> PathBuf {
+        let base = dirs::data_local_dir().unwrap_or_else(|| PathBuf::from("."));
+        base.join("NgAssistant").join("cache").join("symbols.json")
+    }
+
+    pub fn from_bytecode(bytecode: &Bytecode, bytecode_hash: u64, hashlink_version: u32) -> Self {
+        let functions = bytecode.functions
+            .iter()
+            .map(|function| CachedFunction {
+                findex: function.findex.0,
+                name: function.name(bytecode).to_string(),
+                class_name: function.parent
+                    .and_then(|parent| parent.as_obj(bytecode))
+                    .map(|obj| obj.name(bytecode).trim_start_matches('$').to_string()),
+                signature: bytecode[function.t].display::<EnhancedFmt>(bytecode).to_string(),
+            })
+            .collect();
+
+        let types = bytecode.types
+            .iter()
+            .map(|ty| ty.display::<EnhancedFmt>(bytecode).to_string())
+            .collect();
+
+        Self {
+            format: SYMBOL_CACHE_FORMAT,
+            bytecode_hash,
+            hashlink_version,
+            functions,
+            types,
+            obj_types: compute_obj_layouts(bytecode, hashlink_version),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// Cache is only usable for the exact same `hlboot.dat` and hashlink version
+    pub fn is_valid_for(&self, bytecode_hash: u64, hashlink_version: u32) -> bool {
+        self.format == SYMBOL_CACHE_FORMAT
+            && self.bytecode_hash == bytecode_hash
+            && self.hashlink_version == hashlink_version
+    }
+}
+
+/// 64-bit FNV-1a hash, stable across builds unlike `DefaultHasher`
+pub fn hash_bytes(data: &[u8]) -> u64 {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+
+    data.iter().fold(FNV_OFFSET_BASIS, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture_path() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/symbol_cache.json")
+    }
+
+    #[test]
+    fn test_hash_bytes() {
+        assert_eq!(hash_bytes(b""), 0xcbf29ce484222325);
+        assert_eq!(hash_bytes(b"a"), 0xaf63dc4c8601ec8c);
+    }
+
+    #[test]
+    fn test_load_fixture() {
+        let cache = SymbolCache::load(&fixture_path()).unwrap();
+        assert!(cache.is_valid_for(0x1234_5678_9abc_def0, 5));
+        assert!(!cache.is_valid_for(0x1234_5678_9abc_def0, 4));
+        assert!(!cache.is_valid_for(0, 5));
+
+        let get_width = cache.functions.iter().find(|f| f.findex == 590).unwrap();
+        assert_eq!(get_width.name, "get_width");
+        assert_eq!(get_width.class_name.as_deref(), Some("hxd.Window"));
+        assert_eq!(cache.types[3], "i32");
+
+        let string = cache.obj_types.iter().find(|t| t.name == "String").unwrap();
+        assert_eq!(string.field("bytes").unwrap().offset, 8);
+        assert_eq!(string.field("length").unwrap().offset, 16);
+        assert_eq!(string.global, None);
+    }
+
+    #[test]
+    fn test_save_and_reload() {
+        let cache = SymbolCache::load(&fixture_path()).unwrap();
+        let path = std::env::temp_dir().join("nas_symbol_cache_test.json");
+        cache.save(&path).unwrap();
+
+        let reloaded = SymbolCache::load(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!(reloaded.functions, cache.functions);
+        assert_eq!(reloaded.types, cache.types);
+        assert_eq!(reloaded.obj_types, cache.obj_types);
+        assert!(reloaded.is_valid_for(cache.bytecode_hash, cache.hashlink_version));
+    }
+}
@@ -0,0 +1,236 @@
+/*
+    Exports resolved hashlink functions to formats understood by reverse-engineering tools,
+    so debuggers and disassemblers show the same names as the overlay.
+*/
+
+use crate::modules::hashlink::HLFunction;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// Array of objects with findex, qualified name, signature and address
+    Json,
+    /// x64dbg script setting a label for each function (`labelset`)
+    X64dbgScript,
+    /// x64dbg database file, can be merged into the `.dd64` of the session
+    X64dbgDatabase,
+    /// Python script renaming functions, runs in both IDA and Ghidra
+    PythonScript,
+    /// Cheat Engine table with user-defined symbols
+    CheatEngineTable,
+}
+
+impl ExportFormat {
+    pub const ALL: [ExportFormat; 5] = [
+        ExportFormat::Json,
+        ExportFormat::X64dbgScript,
+        ExportFormat::X64dbgDatabase,
+        ExportFormat::PythonScript,
+        ExportFormat::CheatEngineTable,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "JSON",
+            ExportFormat::X64dbgScript => "x64dbg script",
+            ExportFormat::X64dbgDatabase => "x64dbg database",
+            ExportFormat::PythonScript => "IDA/Ghidra script",
+            ExportFormat::CheatEngineTable => "Cheat Engine table",
+        }
+    }
+
+    /// Default file in the exports directory
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "functions.json",
+            ExportFormat::X64dbgScript => "functions_x64dbg.txt",
+            ExportFormat::X64dbgDatabase => "functions.dd64",
+            ExportFormat::PythonScript => "functions_rename.py",
+            ExportFormat::CheatEngineTable => "functions.CT",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonFunction<'a> {
+    findex: usize,
+    name: &'a str,
+    class_name: Option<&'a str>,
+    path: String,
+    signature: &'a str,
+    address: String,
+}
+
+#[derive(Serialize)]
+struct X64dbgLabel {
+    module: &'static str,
+    address: String,
+    manual: bool,
+    text: String,
+}
+
+#[derive(Serialize)]
+struct X64dbgDatabase {
+    labels: Vec<X64dbgLabel>,
+}
+
+/// Unique symbol-safe names: `ui.win.EndGame.init`, duplicates get their findex appended
+fn labels(functions: &[HLFunction]) -> Vec<String> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for function in functions {
+        *counts.entry(function.path()).or_default() += 1;
+    }
+
+    functions
+        .iter()
+        .map(|function| {
+            let path = function.path();
+            let label: String = path
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '.' { c } else { '_' })
+                .collect();
+            if counts[&path] > 1 {
+                format!("{}_{}", label, function.findex)
+            } else {
+                label
+            }
+        })
+        .collect()
+}
+
+pub fn export_json(functions: &[HLFunction]) -> Result<String, Box<dyn Error>> {
+    let entries: Vec<JsonFunction> = functions
+        .iter()
+        .map(|function| JsonFunction {
+            findex: function.findex,
+            name: &function.name,
+            class_name: function.class_name.as_deref(),
+            path: function.path(),
+            signature: &function.signature,
+            address: format!("{:#x}", function.address),
+        })
+        .collect();
+    Ok(serde_json::to_string_pretty(&entries)?)
+}
+
+pub fn export_x64dbg_script(functions: &[HLFunction]) -> String {
+    let mut script = String::new();
+    for (function, label) in functions.iter().zip(labels(functions)) {
+        let _ = writeln!(script, "labelset {:#x}, \"{}\"", function.address, label);
+    }
+    script
+}
+
+pub fn export_x64dbg_database(functions: &[HLFunction]) -> Result<String, Box<dyn Error>> {
+    // JIT code is not part of any module, so addresses are absolute
+    let labels = functions
+        .iter()
+        .zip(labels(functions))
+        .map(|(function, text)| X64dbgLabel {
+            module: "",
+            address: format!("{:#x}", function.address),
+            manual: true,
+            text,
+        })
+        .collect();
+    Ok(serde_json::to_string_pretty(&X64dbgDatabase { labels })?)
+}
+
+pub fn export_python_script(functions: &[HLFunction]) -> String {
+    let mut script = String::from("# Generated by Northgard Assistant, run from IDA (File > Script file) or Ghidra (Script Manager)\n");
+    script.push_str("FUNCTIONS = [\n");
+    for (function, label) in functions.iter().zip(labels(functions)) {
+        let _ = writeln!(script, "    ({:#x}, \"{}\"),", function.address, label);
+    }
+    script.push_str("]\n\n");
+    script.push_str(concat!(
+        "try:\n",
+        "    import idc\n",
+        "    for address, name in FUNCTIONS:\n",
+        "        idc.set_name(address, name, idc.SN_NOWARN | idc.SN_NOCHECK)\n",
+        "except ImportError:\n",
+        "    from ghidra.program.model.symbol import SourceType\n",
+        "    for address, name in FUNCTIONS:\n",
+        "        createLabel(toAddr(address), name, True, SourceType.USER_DEFINED)\n",
+    ));
+    script
+}
+
+pub fn export_cheat_engine_table(functions: &[HLFunction]) -> String {
+    let mut table = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    table.push_str("<CheatTable CheatEngineTableVersion=\"45\">\n  <UserdefinedSymbols>\n");
+    // Labels only contain `[A-Za-z0-9_.]`, no XML escaping needed
+    for (function, label) in functions.iter().zip(labels(functions)) {
+        let _ = writeln!(
+            table,
+            "    <SymbolEntry>\n      <Name>{}</Name>\n      <Address>{:X}</Address>\n    </SymbolEntry>",
+            label,
+            function.address
+        );
+    }
+    table.push_str("  </UserdefinedSymbols>\n</CheatTable>\n");
+    table
+}
+
+pub fn export_functions(functions: &[HLFunction], format: ExportFormat) -> Result<String, Box<dyn Error>> {
+    Ok(match format {
+        ExportFormat::Json => export_json(functions)?,
+        ExportFormat::X64dbgScript => export_x64dbg_script(functions),
+        ExportFormat::X64dbgDatabase => export_x64dbg_database(functions)?,
+        ExportFormat::PythonScript => export_python_script(functions),
+        ExportFormat::CheatEngineTable => export_cheat_engine_table(functions),
+    })
+}
+
+pub fn save_export(functions: &[HLFunction], format: ExportFormat, path: &Path) -> Result<(), Box<dyn Error>> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, export_functions(functions, format)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn functions() -> Vec<HLFunction> {
+        let function = |name: &str, findex, class_name: Option<&str>, address| HLFunction {
+            name: name.to_string(),
+            address,
+            findex,
+            class_name: class_name.map(str::to_string),
+            signature: "(ui.win.EndGame) -> void".to_string(),
+        };
+        vec![
+            function("init", 24500, Some("ui.win.EndGame"), 0x1a2b0000),
+            function("<none>", 24509, None, 0x1a2b1000),
+            function("<none>", 24510, None, 0x1a2b2000),
+        ]
+    }
+
+    #[test]
+    fn test_labels() {
+        assert_eq!(labels(&functions()), vec!["ui.win.EndGame.init", "_none__24509", "_none__24510"]);
+    }
+
+    #[test]
+    fn test_exports() {
+        let functions = functions();
+
+        let json: serde_json::Value = serde_json::from_str(&export_json(&functions).unwrap()).unwrap();
+        assert_eq!(json[0]["path"], "ui.win.EndGame.init");
+        assert_eq!(json[0]["address"], "0x1a2b0000");
+        assert_eq!(json[1]["class_name"], serde_json::Value::Null);
+
+        assert!(export_x64dbg_script(&functions).starts_with("labelset 0x1a2b0000, \"ui.win.EndGame.init\"\n"));
+        assert!(export_x64dbg_database(&functions).unwrap().contains("\"text\": \"_none__24510\""));
+        assert!(export_python_script(&functions).contains("    (0x1a2b1000, \"_none__24509\"),\n"));
+        assert!(export_cheat_engine_table(&functions).contains("<Name>ui.win.EndGame.init</Name>\n      <Address>1A2B0000</Address>"));
+    }
+}
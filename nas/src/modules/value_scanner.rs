@@ -0,0 +1,519 @@
+/*
+    Cheat Engine style value scanner: a first scan by exact value, range or unknown initial value,
+    then next scans comparing the candidates with their previous values.
+    Memory is scanned in blocks, each block keeps its candidates either as packed offsets and values,
+    or as a bitmap over a copy of the block when most positions are still candidates.
+*/
+
+use crate::utils::process_memory::{is_readable, MemoryRegion, ProcessMemory, MEM_COMMIT};
+use std::cmp::Ordering;
+use std::error::Error;
+use std::time::Instant;
+
+/// Largest block read at once, offsets in a block fit in a u32
+const BLOCK_SIZE: usize = 0x100_0000;
+
+/// Test applied to the values found by a first scan
+type ValueFilter<'a> = Box<dyn Fn(&[u8]) -> bool + 'a>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    U8,
+    U16,
+    U32,
+    U64,
+    I8,
+    I16,
+    I32,
+    I64,
+    F32,
+    F64,
+    /// UTF-16 string of the given number of characters
+    Utf16(usize),
+}
+
+impl ValueType {
+    pub fn size(&self) -> usize {
+        match self {
+            Self::U8 | Self::I8 => 1,
+            Self::U16 | Self::I16 => 2,
+            Self::U32 | Self::I32 | Self::F32 => 4,
+            Self::U64 | Self::I64 | Self::F64 => 8,
+            Self::Utf16(length) => length * 2,
+        }
+    }
+
+    /// Step between candidates, values are naturally aligned in Hashlink objects
+    pub fn alignment(&self) -> usize {
+        match self {
+            Self::Utf16(_) => 2,
+            _ => self.size(),
+        }
+    }
+
+    /// Encode a value typed by the user, only the signed types take negative integers.
+    /// Hexadecimal gives the raw bits, `0xFF` is -1 for I8. Values that do not fit the type are an error
+    pub fn parse(&self, text: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        let text = text.trim();
+        let hex = text.strip_prefix("0x");
+        let integer = || -> Result<i128, Box<dyn Error>> {
+            match hex {
+                Some(hex) => Ok(u64::from_str_radix(hex, 16)?.into()),
+                None => Ok(text.parse::<i128>()?),
+            }
+        };
+        macro_rules! integer {
+            ($type:ty, $unsigned:ty) => {{
+                let value = integer()?;
+                let value = match hex {
+                    Some(_) => <$unsigned>::try_from(value).map(|value| value as $type),
+                    None => <$type>::try_from(value),
+                };
+                value
+                    .map_err(|_| format!("'{}' is out of range for {:?}", text, self))?
+                    .to_le_bytes()
+                    .to_vec()
+            }};
+        }
+        let bytes = match self {
+            Self::U8 => integer!(u8, u8),
+            Self::U16 => integer!(u16, u16),
+            Self::U32 => integer!(u32, u32),
+            Self::U64 => integer!(u64, u64),
+            Self::I8 => integer!(i8, u8),
+            Self::I16 => integer!(i16, u16),
+            Self::I32 => integer!(i32, u32),
+            Self::I64 => integer!(i64, u64),
+            Self::F32 => text.parse::<f32>()?.to_le_bytes().to_vec(),
+            Self::F64 => text.parse::<f64>()?.to_le_bytes().to_vec(),
+            Self::Utf16(_) => text.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+        };
+        if bytes.len() != self.size() {
+            return Err(format!("'{}' is not a {:?} value", text, self).into());
+        }
+        Ok(bytes)
+    }
+
+    pub fn format(&self, bytes: &[u8]) -> String {
+        match self {
+            Self::F32 => bytes.try_into().map(f32::from_le_bytes).map_or_else(|_| format!("{:02X?}", bytes), |value| value.to_string()),
+            Self::F64 => bytes.try_into().map(f64::from_le_bytes).map_or_else(|_| format!("{:02X?}", bytes), |value| value.to_string()),
+            Self::Utf16(_) => {
+                let units: Vec<u16> = bytes.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+                String::from_utf16_lossy(&units)
+            }
+            _ => self.integer(bytes).map_or_else(|| format!("{:02X?}", bytes), |value| value.to_string()),
+        }
+    }
+
+    /// Value of an integer type, signed types are sign-extended
+    fn integer(&self, bytes: &[u8]) -> Option<i128> {
+        Some(match self {
+            Self::U8 => u8::from_le_bytes(bytes.try_into().ok()?).into(),
+            Self::U16 => u16::from_le_bytes(bytes.try_into().ok()?).into(),
+            Self::U32 => u32::from_le_bytes(bytes.try_into().ok()?).into(),
+            Self::U64 => u64::from_le_bytes(bytes.try_into().ok()?).into(),
+            Self::I8 => i8::from_le_bytes(bytes.try_into().ok()?).into(),
+            Self::I16 => i16::from_le_bytes(bytes.try_into().ok()?).into(),
+            Self::I32 => i32::from_le_bytes(bytes.try_into().ok()?).into(),
+            Self::I64 => i64::from_le_bytes(bytes.try_into().ok()?).into(),
+            _ => return None,
+        })
+    }
+
+    /// Numeric order of two values, `None` for strings and NaN
+    fn compare(&self, a: &[u8], b: &[u8]) -> Option<Ordering> {
+        match self {
+            Self::F32 => f32::from_le_bytes(a.try_into().ok()?).partial_cmp(&f32::from_le_bytes(b.try_into().ok()?)),
+            Self::F64 => f64::from_le_bytes(a.try_into().ok()?).partial_cmp(&f64::from_le_bytes(b.try_into().ok()?)),
+            Self::Utf16(_) => None,
+            _ => Some(self.integer(a)?.cmp(&self.integer(b)?)),
+        }
+    }
+}
+
+/// Values are encoded with `ValueType::parse`
+#[derive(Debug, Clone, PartialEq)]
+pub enum FirstScan {
+    Exact(Vec<u8>),
+    /// Inclusive range
+    Range(Vec<u8>, Vec<u8>),
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NextScan {
+    Exact(Vec<u8>),
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+}
+
+#[derive(Debug)]
+enum Candidates {
+    /// Offsets of the candidates in the block, and their values packed in the same order
+    Sparse { offsets: Vec<u32>, values: Vec<u8> },
+    /// One bit per aligned position, over a copy of the block
+    Dense { bits: Vec<u64>, snapshot: Vec<u8> },
+}
+
+#[derive(Debug)]
+struct ResultBlock {
+    base: usize,
+    length: usize,
+    count: usize,
+    candidates: Candidates,
+}
+
+impl ResultBlock {
+    /// Store the candidates at `offsets`, their values are read from `data`, which starts at `data_start` in the block
+    fn new(base: usize, length: usize, offsets: Vec<u32>, data: &[u8], data_start: usize, value_type: ValueType) -> Self {
+        let size = value_type.size();
+        let slots = length / value_type.alignment();
+        let count = offsets.len();
+        let value = |offset: u32| &data[offset as usize - data_start..offset as usize - data_start + size];
+
+        let candidates = if count * (4 + size) <= length + slots / 8 {
+            let mut values = Vec::with_capacity(count * size);
+            for &offset in &offsets {
+                values.extend_from_slice(value(offset));
+            }
+            Candidates::Sparse { offsets, values }
+        } else {
+            let mut bits = vec![0u64; slots.div_ceil(64)];
+            for &offset in &offsets {
+                let slot = offset as usize / value_type.alignment();
+                bits[slot / 64] |= 1 << (slot % 64);
+            }
+            // Only the bytes of candidates matter, the rest of the copy can stay zero
+            let snapshot = if data_start == 0 && data.len() == length {
+                data.to_vec()
+            } else {
+                let mut snapshot = vec![0u8; length];
+                snapshot[data_start..data_start + data.len()].copy_from_slice(data);
+                snapshot
+            };
+            Candidates::Dense { bits, snapshot }
+        };
+        Self { base, length, count, candidates }
+    }
+
+    /// Every position of the block, for unknown initial value scans
+    fn all(base: usize, data: Vec<u8>, value_type: ValueType) -> Self {
+        let alignment = value_type.alignment();
+        let count = data.len().saturating_sub(value_type.size()) / alignment + usize::from(data.len() >= value_type.size());
+        let mut bits = vec![0u64; (data.len() / alignment).div_ceil(64)];
+        for slot in 0..count {
+            bits[slot / 64] |= 1 << (slot % 64);
+        }
+        Self { base, length: data.len(), count, candidates: Candidates::Dense { bits, snapshot: data } }
+    }
+
+    /// Offsets and previous values of the candidates
+    fn iter(&self, value_type: ValueType) -> Box<dyn Iterator<Item = (u32, &[u8])> + '_> {
+        let size = value_type.size();
+        match &self.candidates {
+            Candidates::Sparse { offsets, values } => {
+                Box::new(offsets.iter().copied().zip(values.chunks_exact(size)))
+            }
+            Candidates::Dense { bits, snapshot } => {
+                let alignment = value_type.alignment();
+                Box::new(bits.iter().enumerate().flat_map(move |(index, &word)| {
+                    (0..64).filter(move |bit| word & (1u64 << bit) != 0).map(move |bit| {
+                        let offset = (index * 64 + bit) * alignment;
+                        (offset as u32, &snapshot[offset..offset + size])
+                    })
+                }))
+            }
+        }
+    }
+
+    /// Range of the block holding candidates
+    fn span(&self, value_type: ValueType) -> Option<(usize, usize)> {
+        match &self.candidates {
+            Candidates::Sparse { offsets, .. } => {
+                Some((*offsets.first()? as usize, *offsets.last()? as usize + value_type.size()))
+            }
+            Candidates::Dense { .. } => (self.count > 0).then_some((0, self.length)),
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        match &self.candidates {
+            Candidates::Sparse { offsets, values } => offsets.len() * 4 + values.len(),
+            Candidates::Dense { bits, snapshot } => bits.len() * 8 + snapshot.len(),
+        }
+    }
+}
+
+/// Candidates of a scan, refined with `next`
+#[derive(Debug)]
+pub struct ValueScan {
+    pub value_type: ValueType,
+    blocks: Vec<ResultBlock>,
+}
+
+impl ValueScan {
+    /// Scan the readable regions accepted by `filter`
+    pub fn first(
+        memory: &dyn ProcessMemory,
+        value_type: ValueType,
+        scan: &FirstScan,
+        filter: impl Fn(&MemoryRegion) -> bool,
+    ) -> Result<Self, Box<dyn Error>> {
+        let size = value_type.size();
+        let alignment = value_type.alignment();
+        if size == 0 {
+            return Err("Cannot scan for empty values".into());
+        }
+        let matches: ValueFilter = match scan {
+            FirstScan::Exact(value) if value.len() == size => Box::new(move |bytes| bytes == value.as_slice()),
+            FirstScan::Range(low, high) if low.len() == size && high.len() == size => {
+                match value_type.compare(low, high) {
+                    None => return Err(format!("Cannot scan a range of {:?} values", value_type).into()),
+                    Some(Ordering::Greater) => return Err("The low bound of the range is above the high bound".into()),
+                    _ => {}
+                }
+                Box::new(move |bytes| {
+                    value_type.compare(bytes, low).is_some_and(Ordering::is_ge)
+                        && value_type.compare(bytes, high).is_some_and(Ordering::is_le)
+                })
+            }
+            FirstScan::Unknown if matches!(value_type, ValueType::Utf16(_)) => {
+                return Err("Unknown initial value scans need a numeric type".into());
+            }
+            FirstScan::Unknown => Box::new(|_| true),
+            _ => return Err(format!("Scan values must be {} bytes for {:?}", size, value_type).into()),
+        };
+
+        let started = Instant::now();
+        let mut blocks = Vec::new();
+        let regions = memory.regions()?;
+        for region in regions.iter().filter(|region| region.state == MEM_COMMIT && is_readable(region.protect) && filter(region)) {
+            let mut base = region.base_address;
+            while base < region.end_address() {
+                let length = BLOCK_SIZE.min(region.end_address() - base);
+                if let Ok(data) = memory.read_bytes(base, length) {
+                    let block = if matches!(scan, FirstScan::Unknown) {
+                        ResultBlock::all(base, data, value_type)
+                    } else {
+                        let offsets: Vec<u32> = (0..length.saturating_sub(size - 1))
+                            .step_by(alignment)
+                            .filter(|&offset| matches(&data[offset..offset + size]))
+                            .map(|offset| offset as u32)
+                            .collect();
+                        ResultBlock::new(base, length, offsets, &data, 0, value_type)
+                    };
+                    if block.count > 0 {
+                        blocks.push(block);
+                    }
+                }
+                base += length;
+            }
+        }
+
+        let result = Self { value_type, blocks };
+        tracing::debug!("First scan: {} candidates in {:?}", result.count(), started.elapsed());
+        Ok(result)
+    }
+
+    /// Keep the candidates whose current value passes `scan`, and remember their new values
+    pub fn next(&mut self, memory: &dyn ProcessMemory, scan: &NextScan) -> Result<(), Box<dyn Error>> {
+        let value_type = self.value_type;
+        let size = value_type.size();
+        match scan {
+            NextScan::Exact(value) if value.len() != size => {
+                return Err(format!("Scan values must be {} bytes for {:?}", size, value_type).into());
+            }
+            NextScan::Increased | NextScan::Decreased if matches!(value_type, ValueType::Utf16(_)) => {
+                return Err(format!("Cannot compare {:?} values", value_type).into());
+            }
+            _ => {}
+        }
+        let keep = |old: &[u8], new: &[u8]| match scan {
+            NextScan::Exact(value) => new == value.as_slice(),
+            NextScan::Changed => new != old,
+            NextScan::Unchanged => new == old,
+            NextScan::Increased => value_type.compare(new, old) == Some(Ordering::Greater),
+            NextScan::Decreased => value_type.compare(new, old) == Some(Ordering::Less),
+        };
+
+        let started = Instant::now();
+        let mut blocks = Vec::new();
+        for block in &self.blocks {
+            let Some((start, end)) = block.span(value_type) else {
+                continue;
+            };
+            // Candidates that can no longer be read are dropped with their block
+            let Ok(data) = memory.read_bytes(block.base + start, end - start) else {
+                continue;
+            };
+            let offsets: Vec<u32> = block
+                .iter(value_type)
+                .filter(|&(offset, old)| {
+                    let offset = offset as usize - start;
+                    keep(old, &data[offset..offset + size])
+                })
+                .map(|(offset, _)| offset)
+                .collect();
+            if !offsets.is_empty() {
+                blocks.push(ResultBlock::new(block.base, block.length, offsets, &data, start, value_type));
+            }
+        }
+
+        self.blocks = blocks;
+        tracing::debug!("Next scan: {} candidates in {:?}", self.count(), started.elapsed());
+        Ok(())
+    }
+
+    pub fn count(&self) -> usize {
+        self.blocks.iter().map(|block| block.count).sum()
+    }
+
+    /// Address and last scanned value of the first `limit` candidates
+    pub fn results(&self, limit: usize) -> Vec<(usize, Vec<u8>)> {
+        self.blocks
+            .iter()
+            .flat_map(|block| block.iter(self.value_type).map(|(offset, value)| (block.base + offset as usize, value.to_vec())))
+            .take(limit)
+            .collect()
+    }
+
+    /// Bytes used to store the candidates
+    pub fn memory_usage(&self) -> usize {
+        self.blocks.iter().map(ResultBlock::memory_usage).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::process_memory::{MockMemory, MEM_IMAGE, MEM_PRIVATE, PAGE_READONLY, PAGE_READWRITE};
+
+    fn addresses(scan: &ValueScan) -> Vec<usize> {
+        scan.results(usize::MAX).into_iter().map(|(address, _)| address).collect()
+    }
+
+    #[test]
+    fn test_exact_and_next_scans() {
+        let memory = MockMemory::new();
+        memory.map(0x10000, 0x2000, PAGE_READWRITE, MEM_PRIVATE);
+        memory.map(0x400000, 0x1000, PAGE_READONLY, MEM_IMAGE);
+        for address in [0x10010, 0x10100, 0x11ffc, 0x400000] {
+            memory.write_u32(address, 1500).unwrap();
+        }
+        // Unaligned, never a candidate
+        memory.write_bytes(0x10201, &1500u32.to_le_bytes()).unwrap();
+
+        let gold = ValueType::U32.parse("1500").unwrap();
+        let mut scan = ValueScan::first(&memory, ValueType::U32, &FirstScan::Exact(gold), |region| {
+            region.type_ == MEM_PRIVATE
+        }).unwrap();
+        assert_eq!(addresses(&scan), [0x10010, 0x10100, 0x11ffc]);
+
+        memory.write_u32(0x10010, 1450).unwrap();
+        memory.write_u32(0x10100, 1600).unwrap();
+        scan.next(&memory, &NextScan::Changed).unwrap();
+        assert_eq!(addresses(&scan), [0x10010, 0x10100]);
+
+        scan.next(&memory, &NextScan::Unchanged).unwrap();
+        assert_eq!(scan.count(), 2);
+        memory.write_u32(0x10010, 1400).unwrap();
+        scan.next(&memory, &NextScan::Decreased).unwrap();
+        assert_eq!(scan.results(10), [(0x10010, 1400u32.to_le_bytes().to_vec())]);
+        assert_eq!(ValueType::U32.format(&scan.results(1)[0].1), "1400");
+
+        scan.next(&memory, &NextScan::Exact(ValueType::U32.parse("1").unwrap())).unwrap();
+        assert_eq!(scan.count(), 0);
+    }
+
+    #[test]
+    fn test_parse_integers() {
+        assert_eq!(ValueType::U8.parse("255").unwrap(), [0xFF]);
+        assert_eq!(ValueType::I8.parse("-1").unwrap(), [0xFF]);
+        assert_eq!(ValueType::I8.parse("0xFF").unwrap(), [0xFF]);
+        assert_eq!(ValueType::U16.parse("0x1234").unwrap(), [0x34, 0x12]);
+        assert_eq!(ValueType::I64.parse("-2").unwrap(), (-2i64).to_le_bytes());
+        assert_eq!(ValueType::U64.parse("0xFFFFFFFFFFFFFFFF").unwrap(), [0xFF; 8]);
+        assert_eq!(ValueType::I32.format(&(-7i32).to_le_bytes()), "-7");
+        assert!(ValueType::U8.parse("-1").is_err());
+        assert!(ValueType::U8.parse("256").is_err());
+        assert!(ValueType::I8.parse("128").is_err());
+        assert!(ValueType::I8.parse("-129").is_err());
+        assert!(ValueType::I8.parse("0x100").is_err());
+        assert!(ValueType::U16.parse("0x10000").is_err());
+        assert!(ValueType::U32.parse("4294967296").is_err());
+        assert!(ValueType::U64.parse("18446744073709551616").is_err());
+    }
+
+    #[test]
+    fn test_signed_scans() {
+        let memory = MockMemory::new();
+        memory.map(0x10000, 0x1000, PAGE_READWRITE, MEM_PRIVATE);
+        memory.write_bytes(0x10010, &(-1i32).to_le_bytes()).unwrap();
+        memory.write_bytes(0x10020, &(-9i32).to_le_bytes()).unwrap();
+        memory.write_bytes(0x10030, &5i32.to_le_bytes()).unwrap();
+        memory.write_bytes(0x10040, &6i32.to_le_bytes()).unwrap();
+
+        let value_type = ValueType::I32;
+        let range = FirstScan::Range(value_type.parse("-5").unwrap(), value_type.parse("5").unwrap());
+        let mut scan = ValueScan::first(&memory, value_type, &range, |_| true).unwrap();
+        // Every zero in the block is in the range too
+        assert!(addresses(&scan).contains(&0x10010));
+        assert!(addresses(&scan).contains(&0x10030));
+        assert!(!addresses(&scan).contains(&0x10020));
+        assert!(!addresses(&scan).contains(&0x10040));
+
+        memory.write_bytes(0x10010, &0i32.to_le_bytes()).unwrap();
+        scan.next(&memory, &NextScan::Increased).unwrap();
+        assert_eq!(addresses(&scan), [0x10010]);
+        assert_eq!(value_type.format(&scan.results(1)[0].1), "0");
+
+        let reversed = FirstScan::Range(value_type.parse("5").unwrap(), value_type.parse("-5").unwrap());
+        assert!(ValueScan::first(&memory, value_type, &reversed, |_| true).is_err());
+    }
+
+    #[test]
+    fn test_unknown_value_scan() {
+        let memory = MockMemory::new();
+        memory.map(0x10000, 0x1000, PAGE_READWRITE, MEM_PRIVATE);
+        memory.write_bytes(0x10008, &2.5f64.to_le_bytes()).unwrap();
+        memory.write_bytes(0x10100, &(-1.0f64).to_le_bytes()).unwrap();
+
+        let mut scan = ValueScan::first(&memory, ValueType::F64, &FirstScan::Unknown, |_| true).unwrap();
+        assert_eq!(scan.count(), 0x1000 / 8);
+        // A copy of the block and its bitmap
+        assert_eq!(scan.memory_usage(), 0x1000 + 8 * 8);
+
+        memory.write_bytes(0x10008, &3.0f64.to_le_bytes()).unwrap();
+        memory.write_bytes(0x10100, &(-2.0f64).to_le_bytes()).unwrap();
+        scan.next(&memory, &NextScan::Increased).unwrap();
+        assert_eq!(addresses(&scan), [0x10008]);
+        assert_eq!(scan.memory_usage(), 4 + 8);
+
+        let range = FirstScan::Range(ValueType::F64.parse("-5").unwrap(), ValueType::F64.parse("-1.5").unwrap());
+        let scan = ValueScan::first(&memory, ValueType::F64, &range, |_| true).unwrap();
+        assert_eq!(addresses(&scan), [0x10100]);
+    }
+
+    #[test]
+    fn test_string_scan() {
+        let memory = MockMemory::new();
+        memory.map(0x10000, 0x1000, PAGE_READWRITE, MEM_PRIVATE);
+        let name: Vec<u8> = "Spring".encode_utf16().flat_map(u16::to_le_bytes).collect();
+        memory.write_bytes(0x10202, &name).unwrap();
+
+        let value_type = ValueType::Utf16(6);
+        let mut scan = ValueScan::first(&memory, value_type, &FirstScan::Exact(value_type.parse("Spring").unwrap()), |_| true).unwrap();
+        assert_eq!(addresses(&scan), [0x10202]);
+        assert!(scan.next(&memory, &NextScan::Increased).is_err());
+        assert!(ValueScan::first(&memory, value_type, &FirstScan::Unknown, |_| true).is_err());
+        assert!(value_type.parse("Summer!").is_err());
+
+        let summer: Vec<u8> = "Summer".encode_utf16().flat_map(u16::to_le_bytes).collect();
+        memory.write_bytes(0x10202, &summer).unwrap();
+        scan.next(&memory, &NextScan::Changed).unwrap();
+        assert_eq!(value_type.format(&scan.results(1)[0].1), "Summer");
+    }
+}
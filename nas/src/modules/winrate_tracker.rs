@@ -1,12 +1,18 @@
-use crate::modules::base::InjectionManager;
+use crate::modules::base::{InjectionManager, InjectionTransaction};
+use crate::modules::context_hook::{ContextHandler, HookAction};
+use crate::modules::libmem_injection::StubBuilder;
 use crate::modules::mem_alloc::{MemoryAllocator, DataType};
 use crate::modules::hashlink::*;
 use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use iced_x86::code_asm::*;
+use crate::utils::process_memory::ProcessMemory;
 use std::error::Error;
+use std::sync::Arc;
 use std::path::PathBuf;
 
 const MAX_WINRATE_MEMORY_REGION_SIZE: usize = 0x2000;
+/// Offset of the injection into `ui.win.EndGame.init`
+pub(crate) const INIT_OFFSET: usize = 1017;
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
 #[repr(i32)]
@@ -71,23 +77,20 @@ pub struct WinrateTracker {
     address_owltitanvictory: usize,
     address_yggdrasilvictory: usize,
 
+    memory: Arc<dyn ProcessMemory>,
     injection_manager: InjectionManager,
     memory_allocator: MemoryAllocator,
+    // Kept while the endgame hooks are applied
+    endgame_handlers: Vec<ContextHandler>,
 
     var_ptr_gamestate: usize,
     var_ptr_teamplayercount: usize,
     var_ptr_endgamekind: usize,
-    var_ptr_callback: usize, // `update_winrate_callback` address
 }
 
 static ENDGAME_PENDING: AtomicBool = AtomicBool::new(false);
 static ENDGAME_KIND_ATOMIC: AtomicU32 = AtomicU32::new(0);
 
-extern "C" fn update_winrate_callback(endgame_kind: u32) {
-    ENDGAME_KIND_ATOMIC.store(endgame_kind, Ordering::Relaxed);
-    ENDGAME_PENDING.store(true, Ordering::Release);
-}
-
 pub fn take_pending_endgame() -> Option<EndGameEvent> {
     if ENDGAME_PENDING.swap(false, Ordering::Acquire) {
         let val = ENDGAME_KIND_ATOMIC.load(Ordering::Relaxed);
@@ -116,22 +119,18 @@ impl WinrateTracker {
         (true, reason)
     }
 
-    pub fn new(pid: u32) -> Result<Self, Box<dyn Error>> {
-        let mut memory_allocator = MemoryAllocator::new(pid, MAX_WINRATE_MEMORY_REGION_SIZE)?;
+    pub fn new(pid: u32, memory: Arc<dyn ProcessMemory>) -> Result<Self, Box<dyn Error>> {
+        let mut memory_allocator = MemoryAllocator::new(memory.clone(), "WinrateTracker", MAX_WINRATE_MEMORY_REGION_SIZE)?;
 
         let var_ptr_gamestate = memory_allocator.allocate_var("GameState", DataType::Pointer)?;
         let var_ptr_teamplayercount = memory_allocator.allocate_var("TeamPlayerCount", DataType::I32)?;
         let var_ptr_endgamekind = memory_allocator.allocate_var("EndGameKind", DataType::I32)?;
-
-        let var_ptr_callback = memory_allocator.allocate_var("UpdateWinrateCallback", DataType::Pointer)?;
         
         memory_allocator.write_var("GameState", 0usize)?;
         memory_allocator.write_var("TeamPlayerCount", 0i32)?;
         memory_allocator.write_var("EndGameKind", 0i32)?;
 
-        memory_allocator.write_var("UpdateWinrateCallback", update_winrate_callback as usize)?;
-
-        let mut injection_manager = InjectionManager::new(pid);
+        let mut injection_manager = InjectionManager::new(memory.clone(), "WinrateTracker");
         injection_manager.add_injection("ui_win_EndGame_init".to_string());
         injection_manager.add_injection("get_teamplayercount".to_string());
         injection_manager.add_injection("defeat".to_string());
@@ -175,13 +174,14 @@ impl WinrateTracker {
             address_owltitanvictory: 0,
             address_yggdrasilvictory: 0,
 
+            memory,
             injection_manager,
             memory_allocator,
+            endgame_handlers: Vec::new(),
 
             var_ptr_gamestate,
             var_ptr_teamplayercount,
             var_ptr_endgamekind,
-            var_ptr_callback,
         };
         
         winrate_tracker.init()?;
@@ -198,9 +198,7 @@ impl WinrateTracker {
             // mov rdx,r11
             // sub rsp,20
             // call 76CA9F329430
-            const INIT_OFFSET: usize = 1017;
-            const INIT_INDEX: usize = 450; // magic!
-            self.address_ui_win_EndGame_init = hashlink.get_function_address("init", Some(INIT_INDEX))? + INIT_OFFSET;
+            self.address_ui_win_EndGame_init = hashlink.get_function_address_by_path("ui.win.EndGame.init")? + INIT_OFFSET;
 
             self.address_getteamplayercount = hashlink.get_function_address("getTeamPlayerCount", Some(0))?;
             self.address_defeat = hashlink.get_function_address("defeat", Some(0))?;
@@ -220,179 +218,65 @@ impl WinrateTracker {
         Ok(())
     }
 
-    fn create_endgame_code(&mut self, kind: EndGameKind) -> Result<CodeAssembler, Box<dyn Error>> {
-        let mut code = CodeAssembler::new(64)?;
-        let mut label_exit = code.create_label();
-
-        code.pushfq()?;
-        code.push(rax)?;
-        code.push(rbx)?;
-        code.push(rcx)?;
-        code.push(rdx)?;
-        code.push(r8)?;
-        code.push(r9)?;
-        code.push(r10)?;
-        code.push(r11)?;
-
-        // Dynamically align stack to 16 bytes and persist the adjustment amount
-        code.mov(rax, rsp)?;
-        code.and(rax, 8)?;            // rax = 8 if misaligned (rsp % 16 == 8), else 0
-        code.sub(rsp, rax)?;          // apply alignment fix
-        code.sub(rsp, 8)?;            // reserve 8 bytes to store the adjustment
-        code.mov(qword_ptr(rsp), rax)?; // store the adjustment value on stack
-
-        code.sub(rsp, 0x60)?;
-        code.movups(oword_ptr(rsp), xmm0)?;
-        code.movups(oword_ptr(rsp + 0x10), xmm1)?;
-        code.movups(oword_ptr(rsp + 0x20), xmm2)?;
-        code.movups(oword_ptr(rsp + 0x30), xmm3)?;
-        code.movups(oword_ptr(rsp + 0x40), xmm4)?;
-        code.movups(oword_ptr(rsp + 0x50), xmm5)?;
-
-        code.mov(rbx, self.var_ptr_teamplayercount as u64)?;
-        code.mov(eax, dword_ptr(rbx))?;
-
-        code.cmp(eax, 3)?; // We need to track only 3v3 games
-        code.jne(label_exit)?;
-
-        code.mov(rbx, self.var_ptr_endgamekind as u64)?;
-        code.mov(dword_ptr(rbx), kind as u32)?;
-
-        code.mov(ecx, kind as i32)?;
-        code.mov(rbx, self.var_ptr_callback as u64)?;
-        code.mov(rax, qword_ptr(rbx))?;
-        code.sub(rsp, 0x120)?;
-        code.call(rax)?;
-        code.add(rsp, 0x120)?;
-
-        code.set_label(&mut label_exit)?;
-
-        code.movups(xmm0, oword_ptr(rsp))?;
-        code.movups(xmm1, oword_ptr(rsp + 0x10))?;
-        code.movups(xmm2, oword_ptr(rsp + 0x20))?;
-        code.movups(xmm3, oword_ptr(rsp + 0x30))?;
-        code.movups(xmm4, oword_ptr(rsp + 0x40))?;
-        code.movups(xmm5, oword_ptr(rsp + 0x50))?;
-        code.add(rsp, 0x60)?;
-
-        // Restore dynamic alignment
-        code.mov(rax, qword_ptr(rsp))?; // load the previously stored adjustment
-        code.add(rsp, 8)?;              // remove the storage slot
-        code.add(rsp, rax)?;            // undo the alignment fix
-
-        code.pop(r11)?;
-        code.pop(r10)?;
-        code.pop(r9)?;
-        code.pop(r8)?;
-        code.pop(rdx)?;
-        code.pop(rcx)?;
-        code.pop(rbx)?;
-        code.pop(rax)?;
-        code.popfq()?;
-        
-        Ok(code)
-    }
-
-    pub fn apply(&mut self, enable: bool) -> Result<(), Box<dyn Error>> {
-        if enable {
-            // Injection: ui_win_EndGame_init
-            let mut code = CodeAssembler::new(64)?;
-            
-            code.pushfq()?;
-            code.push(rax)?;
-            code.push(rbx)?;
-            code.push(rcx)?;
-            code.push(rdx)?;
-            code.push(r8)?;
-            code.push(r9)?;
-            code.push(r10)?;
-            code.push(r11)?;
-
-            // Dynamically align stack to 16 bytes and persist the adjustment amount
-            code.mov(rax, rsp)?;
-            code.and(rax, 8)?;            // rax = 8 if misaligned (rsp % 16 == 8), else 0
-            code.sub(rsp, rax)?;          // apply alignment fix
-            code.sub(rsp, 8)?;            // reserve 8 bytes to store the adjustment
-            code.mov(qword_ptr(rsp), rax)?; // store the adjustment value on stack
-
-            code.sub(rsp, 0x60)?;
-            code.movups(oword_ptr(rsp), xmm0)?;
-            code.movups(oword_ptr(rsp + 0x10), xmm1)?;
-            code.movups(oword_ptr(rsp + 0x20), xmm2)?;
-            code.movups(oword_ptr(rsp + 0x30), xmm3)?;
-            code.movups(oword_ptr(rsp + 0x40), xmm4)?;
-            code.movups(oword_ptr(rsp + 0x50), xmm5)?;
-
+    // Injection: ui_win_EndGame_init
+    fn create_endgame_init_code(&self) -> Result<CodeAssembler, Box<dyn Error>> {
+        StubBuilder::new().save(rbx).build(|code| {
             code.mov(rcx, r10)?; // we are at center of the function, r10 is the first argument
 
             code.mov(rbx, self.var_ptr_gamestate as u64)?;
             code.mov(qword_ptr(rbx), rcx)?;
             code.mov(rax, self.address_getteamplayercount as u64)?;
-            code.sub(rsp, 0x20)?;
             code.call(rax)?;
-            code.add(rsp, 0x20)?;
-            
+
             code.mov(rbx, self.var_ptr_teamplayercount as u64)?;
             code.mov(dword_ptr(rbx), eax)?;
+            Ok(())
+        })
+    }
 
-            code.movups(xmm0, oword_ptr(rsp))?;
-            code.movups(xmm1, oword_ptr(rsp + 0x10))?;
-            code.movups(xmm2, oword_ptr(rsp + 0x20))?;
-            code.movups(xmm3, oword_ptr(rsp + 0x30))?;
-            code.movups(xmm4, oword_ptr(rsp + 0x40))?;
-            code.movups(xmm5, oword_ptr(rsp + 0x50))?;
-            code.add(rsp, 0x60)?;
-
-            // Restore dynamic alignment
-            code.mov(rax, qword_ptr(rsp))?; // load the previously stored adjustment
-            code.add(rsp, 8)?;              // remove the storage slot
-            code.add(rsp, rax)?;            // undo the alignment fix
-
-            code.pop(r11)?;
-            code.pop(r10)?;
-            code.pop(r9)?;
-            code.pop(r8)?;
-            code.pop(rdx)?;
-            code.pop(rcx)?;
-            code.pop(rbx)?;
-            code.pop(rax)?;
-            code.popfq()?;
-
-            self.injection_manager.apply_injection("ui_win_EndGame_init", self.address_ui_win_EndGame_init, &mut code)?;
-
-            // Injection: EndGameScene
-            let mut code = self.create_endgame_code(EndGameKind::Defeat)?;
-            self.injection_manager.apply_injection("defeat", self.address_defeat, &mut code)?;
-
-            let mut code = self.create_endgame_code(EndGameKind::Victory)?;
-            self.injection_manager.apply_injection("default_victory", self.address_defaultvictory, &mut code)?;
-
-            let mut code = self.create_endgame_code(EndGameKind::Fame)?;
-            self.injection_manager.apply_injection("fame_victory", self.address_famevictory, &mut code)?;
-
-            let mut code = self.create_endgame_code(EndGameKind::Helheim)?;
-            self.injection_manager.apply_injection("helheim_victory", self.address_helheimvictory, &mut code)?;
-
-            let mut code = self.create_endgame_code(EndGameKind::Faith)?;
-            self.injection_manager.apply_injection("faith_victory", self.address_faithvictory, &mut code)?;
-
-            let mut code = self.create_endgame_code(EndGameKind::Lore)?;
-            self.injection_manager.apply_injection("lore_victory", self.address_lorevictory, &mut code)?;
-
-            let mut code = self.create_endgame_code(EndGameKind::Mealsquirrel)?;
-            self.injection_manager.apply_injection("mealsquirrel_victory", self.address_mealsquirrelvictory, &mut code)?;
-
-            let mut code = self.create_endgame_code(EndGameKind::Odinsword)?;
-            self.injection_manager.apply_injection("odinsword_victory", self.address_odinswordvictory, &mut code)?;
-
-            let mut code = self.create_endgame_code(EndGameKind::Money)?;
-            self.injection_manager.apply_injection("money_victory", self.address_moneyvictory, &mut code)?;
+    fn create_endgame_handler(&self, kind: EndGameKind) -> ContextHandler {
+        let memory = self.memory.clone();
+        let var_ptr_teamplayercount = self.var_ptr_teamplayercount;
+        let var_ptr_endgamekind = self.var_ptr_endgamekind;
+        ContextHandler::register(move |_context| {
+            // We need to track only 3v3 games
+            if memory.read_u32(var_ptr_teamplayercount).is_ok_and(|count| count == 3) {
+                let _ = memory.write_u32(var_ptr_endgamekind, kind as u32);
+                ENDGAME_KIND_ATOMIC.store(kind as u32, Ordering::Relaxed);
+                ENDGAME_PENDING.store(true, Ordering::Release);
+            }
+            HookAction::Continue
+        })
+    }
 
-            let mut code = self.create_endgame_code(EndGameKind::Owltitan)?;
-            self.injection_manager.apply_injection("owltitan_victory", self.address_owltitanvictory, &mut code)?;
+    pub fn apply(&mut self, enable: bool) -> Result<(), Box<dyn Error>> {
+        if enable {
+            // Every hook is installed or none, a half-installed tracker would miss results
+            let mut transaction = InjectionTransaction::new();
+            transaction.add("ui_win_EndGame_init", self.address_ui_win_EndGame_init, || self.create_endgame_init_code());
 
-            let mut code = self.create_endgame_code(EndGameKind::Yggdrasil)?;
-            self.injection_manager.apply_injection("yggdrasil_victory", self.address_yggdrasilvictory, &mut code)?;
+            // Injection: EndGameScene
+            let endgame_hooks = [
+                ("defeat", self.address_defeat, EndGameKind::Defeat),
+                ("default_victory", self.address_defaultvictory, EndGameKind::Victory),
+                ("fame_victory", self.address_famevictory, EndGameKind::Fame),
+                ("helheim_victory", self.address_helheimvictory, EndGameKind::Helheim),
+                ("faith_victory", self.address_faithvictory, EndGameKind::Faith),
+                ("lore_victory", self.address_lorevictory, EndGameKind::Lore),
+                ("mealsquirrel_victory", self.address_mealsquirrelvictory, EndGameKind::Mealsquirrel),
+                ("odinsword_victory", self.address_odinswordvictory, EndGameKind::Odinsword),
+                ("money_victory", self.address_moneyvictory, EndGameKind::Money),
+                ("owltitan_victory", self.address_owltitanvictory, EndGameKind::Owltitan),
+                ("yggdrasil_victory", self.address_yggdrasilvictory, EndGameKind::Yggdrasil),
+            ];
+            let handlers: Vec<ContextHandler> = endgame_hooks.iter().map(|&(_, _, kind)| self.create_endgame_handler(kind)).collect();
+            for (&(name, address, _), handler) in endgame_hooks.iter().zip(&handlers) {
+                transaction.add(name, address, || handler.code());
+            }
+
+            self.injection_manager.apply_transaction(transaction)?;
+            // The previous hooks were removed by the transaction
+            self.endgame_handlers = handlers;
         } else {
             self.injection_manager.remove_injection("ui_win_EndGame_init")?;
             self.injection_manager.remove_injection("get_teamplayercount")?;
@@ -408,6 +292,7 @@ impl WinrateTracker {
             self.injection_manager.remove_injection("money_victory")?;
             self.injection_manager.remove_injection("owltitan_victory")?;
             self.injection_manager.remove_injection("yggdrasil_victory")?;
+            self.endgame_handlers.clear();
         }
 
         self.enabled = enable;
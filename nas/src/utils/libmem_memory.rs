@@ -0,0 +1,129 @@
+/*
+    `ProcessMemory` backend using libmem. libmem has no placement hint for allocations
+    and does not report page types, segments inside loaded modules are reported as MEM_IMAGE.
+*/
+
+use crate::utils::libmem_ex::get_target_process;
+use crate::utils::process_memory::*;
+use crate::utils::thread_suspend::{suspend_process_threads, SuspendedThreads};
+use libmem::{
+    alloc_memory_ex, enum_modules_ex, enum_segments_ex, free_memory_ex, prot_memory_ex,
+    read_memory_buf_ex, write_memory_buf_ex, Process, Prot,
+};
+use std::error::Error;
+
+pub struct LibmemMemory {
+    process: Process,
+}
+
+impl LibmemMemory {
+    pub fn open(pid: u32) -> Result<Self, Box<dyn Error>> {
+        let process = get_target_process(pid).ok_or("Failed to get process with libmem")?;
+        Ok(Self { process })
+    }
+
+    pub fn process(&self) -> &Process {
+        &self.process
+    }
+}
+
+fn to_prot(protection: u32) -> Prot {
+    let mut prot = Prot::None;
+    if is_readable(protection) {
+        prot |= Prot::R;
+    }
+    if is_writable(protection) {
+        prot |= Prot::W;
+    }
+    if is_executable(protection) {
+        prot |= Prot::X;
+    }
+    prot
+}
+
+fn from_prot(prot: Prot) -> u32 {
+    match (prot.contains(Prot::R), prot.contains(Prot::W), prot.contains(Prot::X)) {
+        (_, true, true) => PAGE_EXECUTE_READWRITE,
+        (true, false, true) => PAGE_EXECUTE_READ,
+        (false, false, true) => PAGE_EXECUTE,
+        (_, true, false) => PAGE_READWRITE,
+        (true, false, false) => PAGE_READONLY,
+        (false, false, false) => PAGE_NOACCESS,
+    }
+}
+
+impl ProcessMemory for LibmemMemory {
+    fn read_bytes(&self, address: usize, length: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut buffer = vec![0u8; length];
+        match read_memory_buf_ex(&self.process, address, &mut buffer) {
+            Some(read) if read == length => Ok(buffer),
+            _ => Err(format!("libmem failed to read {} bytes at {:#x}", length, address).into()),
+        }
+    }
+
+    fn write_bytes(&self, address: usize, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        match write_memory_buf_ex(&self.process, address, data) {
+            Some(written) if written == data.len() => Ok(()),
+            _ => Err(format!("libmem failed to write {} bytes at {:#x}", data.len(), address).into()),
+        }
+    }
+
+    fn read_raw(&self, address: usize, buffer: &mut [u8]) -> bool {
+        read_memory_buf_ex(&self.process, address, buffer) == Some(buffer.len())
+    }
+
+    fn write_raw(&self, address: usize, data: &[u8]) -> bool {
+        write_memory_buf_ex(&self.process, address, data) == Some(data.len())
+    }
+
+    fn protect(&self, address: usize, size: usize, protection: u32) -> Result<u32, Box<dyn Error>> {
+        prot_memory_ex(&self.process, address, size, to_prot(protection))
+            .map(from_prot)
+            .ok_or_else(|| format!("libmem failed to change protection at {:#x}", address).into())
+    }
+
+    fn allocate(&self, size: usize, protection: u32, _near: Option<usize>) -> Result<AllocRegion, Box<dyn Error>> {
+        let base_address = alloc_memory_ex(&self.process, size, to_prot(protection))
+            .ok_or("libmem alloc_memory_ex failed")?;
+        Ok(AllocRegion { base_address, region_size: size })
+    }
+
+    fn free(&self, address: usize, size: usize) -> Result<(), Box<dyn Error>> {
+        free_memory_ex(&self.process, address, size).ok_or("libmem free failed")?;
+        Ok(())
+    }
+
+    fn suspend_threads(&self) -> Result<SuspendedThreads, Box<dyn Error>> {
+        suspend_process_threads(self.process.pid)
+    }
+
+    fn regions(&self) -> Result<Vec<MemoryRegion>, Box<dyn Error>> {
+        let segments = enum_segments_ex(&self.process).ok_or("libmem enum_segments_ex failed")?;
+        let modules = enum_modules_ex(&self.process).unwrap_or_default();
+
+        Ok(segments
+            .into_iter()
+            .map(|segment| {
+                let protect = from_prot(segment.prot);
+                let in_module = modules.iter().any(|m| segment.base >= m.base && segment.base < m.end);
+                MemoryRegion {
+                    base_address: segment.base,
+                    allocation_base: segment.base,
+                    allocation_protect: protect,
+                    region_size: segment.size,
+                    state: MEM_COMMIT,
+                    protect,
+                    type_: if in_module { MEM_IMAGE } else { MEM_PRIVATE },
+                }
+            })
+            .collect())
+    }
+
+    fn modules(&self) -> Result<Vec<ModuleInfo>, Box<dyn Error>> {
+        let modules = enum_modules_ex(&self.process).ok_or("libmem enum_modules_ex failed")?;
+        Ok(modules
+            .into_iter()
+            .map(|module| ModuleInfo { name: module.name, base: module.base, size: module.size })
+            .collect())
+    }
+}
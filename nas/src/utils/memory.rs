@@ -1,72 +1,23 @@
+use crate::utils::process_memory::{
+    PAGE_EXECUTE, PAGE_EXECUTE_READ, PAGE_EXECUTE_READWRITE, PAGE_EXECUTE_WRITECOPY,
+    PAGE_READONLY, PAGE_READWRITE, PAGE_WRITECOPY,
+};
+pub use crate::utils::process_memory::MemoryRegion;
+#[cfg(windows)]
 use std::error::Error;
+#[cfg(windows)]
 use windows::{
-    Win32::System::Memory::{
-        MEMORY_BASIC_INFORMATION, VirtualQueryEx, MEM_COMMIT, PAGE_EXECUTE,
-        PAGE_EXECUTE_READ, PAGE_EXECUTE_READWRITE, PAGE_EXECUTE_WRITECOPY,
-        PAGE_READONLY, PAGE_READWRITE, PAGE_WRITECOPY,
-    },
+    Win32::System::Memory::{MEMORY_BASIC_INFORMATION, VirtualQueryEx, MEM_COMMIT},
     Win32::Foundation::{HANDLE, BOOL, CloseHandle},
     Win32::System::Threading::{
         OpenProcess, PROCESS_VM_READ, PROCESS_QUERY_INFORMATION,
     },
 };
 
-/// Represents information about a memory region in a process.
-/// 
-/// This structure contains details about a memory region's location,
-/// size, and protection attributes as returned by Windows memory management functions.
-#[derive(Debug)]
-pub struct MemoryRegion {
-    /// The base address of the region of pages.
-    pub base_address: usize,
-
-    /// The base address of the allocated region of pages when the region was initially allocated.
-    pub allocation_base: usize,
-
-    /// The memory protection option when the region was initially allocated.
-    /// 
-    /// Can be a combination of:
-    /// - PAGE_EXECUTE
-    /// - PAGE_EXECUTE_READ
-    /// - PAGE_EXECUTE_READWRITE
-    /// - PAGE_READONLY
-    /// - PAGE_READWRITE
-    pub allocation_protect: u32,
-
-    /// The size of the region in bytes.
-    pub region_size: usize,
-
-    /// The state of the pages in the region.
-    /// 
-    /// Can be one of:
-    /// - MEM_COMMIT
-    /// - MEM_FREE
-    /// - MEM_RESERVE
-    pub state: u32,
-
-    /// The access protection of the pages in the region.
-    /// 
-    /// Can be a combination of:
-    /// - PAGE_EXECUTE
-    /// - PAGE_EXECUTE_READ
-    /// - PAGE_EXECUTE_READWRITE
-    /// - PAGE_READONLY
-    /// - PAGE_READWRITE
-    pub protect: u32,
-
-    /// The type of pages in the region.
-    /// 
-    /// Can be one of:
-    /// - MEM_IMAGE (Memory mapped EXE/DLL)
-    /// - MEM_MAPPED (Memory mapped file)
-    /// - MEM_PRIVATE (Private memory)
-    pub type_: u32,
-}
-
-
 /// Enumerate the memory regions of the given process.
 /// 
 /// Returns a list of memory regions.
+#[cfg(windows)]
 pub fn enum_memory_regions(pid: u32) -> Result<Vec<MemoryRegion>, Box<dyn Error>> {
     let process_handle = unsafe {
         OpenProcess(
@@ -87,7 +38,8 @@ pub fn enum_memory_regions(pid: u32) -> Result<Vec<MemoryRegion>, Box<dyn Error>
     Ok(regions)
 }
 
-fn enum_memory_regions_handle(process_handle: HANDLE) -> Vec<MemoryRegion> {
+#[cfg(windows)]
+pub(crate) fn enum_memory_regions_handle(process_handle: HANDLE) -> Vec<MemoryRegion> {
     let mut regions = Vec::new();
     let mut address: usize = 0;
 
@@ -133,19 +85,20 @@ pub fn get_protection_string(protect: u32) -> String {
     let mut rights = Vec::new();
 
     match protect {
-        p if p & PAGE_EXECUTE.0 != 0 => rights.push("X"),
-        p if p & PAGE_EXECUTE_READ.0 != 0 => rights.extend_from_slice(&["R", "X"]),
-        p if p & PAGE_EXECUTE_READWRITE.0 != 0 => rights.extend_from_slice(&["R", "W", "X"]),
-        p if p & PAGE_EXECUTE_WRITECOPY.0 != 0 => rights.extend_from_slice(&["R", "W", "X", "C"]),
-        p if p & PAGE_READONLY.0 != 0 => rights.push("R"),
-        p if p & PAGE_READWRITE.0 != 0 => rights.extend_from_slice(&["R", "W"]),
-        p if p & PAGE_WRITECOPY.0 != 0 => rights.extend_from_slice(&["R", "W", "C"]),
+        p if p & PAGE_EXECUTE != 0 => rights.push("X"),
+        p if p & PAGE_EXECUTE_READ != 0 => rights.extend_from_slice(&["R", "X"]),
+        p if p & PAGE_EXECUTE_READWRITE != 0 => rights.extend_from_slice(&["R", "W", "X"]),
+        p if p & PAGE_EXECUTE_WRITECOPY != 0 => rights.extend_from_slice(&["R", "W", "X", "C"]),
+        p if p & PAGE_READONLY != 0 => rights.push("R"),
+        p if p & PAGE_READWRITE != 0 => rights.extend_from_slice(&["R", "W"]),
+        p if p & PAGE_WRITECOPY != 0 => rights.extend_from_slice(&["R", "W", "C"]),
         _ => rights.push("---"),
     }
 
     rights.join("")
 }
 
+#[cfg(windows)]
 pub fn print_memory_regions(pid: u32) -> Result<(), Box<dyn Error>> {
     let regions = enum_memory_regions(pid)?;
     
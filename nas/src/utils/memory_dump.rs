@@ -0,0 +1,231 @@
+/*
+    Archive of memory regions captured from the game, loaded back as a read-only `ProcessMemory`
+    so scans and parsers can run offline. Layout: magic, offset of the index, the bytes of every
+    region one after the other, then the JSON index with the region metadata.
+*/
+
+use crate::utils::process_memory::{is_readable, AllocRegion, MemoryRegion, ProcessMemory, MEM_COMMIT};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MAGIC: &[u8; 8] = b"NASDUMP1";
+/// Bytes read from the process per call while dumping
+const CHUNK_SIZE: usize = 0x10_0000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DumpedRegion {
+    #[serde(flatten)]
+    pub region: MemoryRegion,
+    /// Position of the region bytes in the archive
+    pub data_offset: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DumpIndex {
+    pub pid: u32,
+    /// Unix time of the capture
+    pub created: u64,
+    /// Sorted by address
+    pub regions: Vec<DumpedRegion>,
+}
+
+impl DumpIndex {
+    pub fn total_size(&self) -> usize {
+        self.regions.iter().map(|dumped| dumped.region.region_size).sum()
+    }
+}
+
+/// Write the committed, readable regions accepted by `filter` to `path`.
+/// A region that stops being readable is kept up to the last chunk read
+pub fn dump_regions(
+    memory: &dyn ProcessMemory,
+    pid: u32,
+    path: &Path,
+    filter: impl Fn(&MemoryRegion) -> bool,
+) -> Result<DumpIndex, Box<dyn Error>> {
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(MAGIC)?;
+    file.write_all(&0u64.to_le_bytes())?;
+    let mut offset = (MAGIC.len() + 8) as u64;
+
+    let mut regions = Vec::new();
+    let mut candidates = memory.regions()?;
+    candidates.sort_by_key(|region| region.base_address);
+    for region in candidates
+        .into_iter()
+        .filter(|region| region.state == MEM_COMMIT && is_readable(region.protect) && filter(region))
+    {
+        let mut dumped = 0;
+        while dumped < region.region_size {
+            let length = CHUNK_SIZE.min(region.region_size - dumped);
+            match memory.read_bytes(region.base_address + dumped, length) {
+                Ok(data) => {
+                    file.write_all(&data)?;
+                    dumped += length;
+                }
+                Err(e) => {
+                    tracing::warn!("Dump of region {:#x} stopped at {:#x}: {}", region.base_address, region.base_address + dumped, e);
+                    break;
+                }
+            }
+        }
+        if dumped > 0 {
+            regions.push(DumpedRegion { region: MemoryRegion { region_size: dumped, ..region }, data_offset: offset });
+            offset += dumped as u64;
+        }
+    }
+
+    let created = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs());
+    let index = DumpIndex { pid, created, regions };
+    serde_json::to_writer(&mut file, &index)?;
+
+    let mut file = file.into_inner().map_err(|e| e.into_error())?;
+    file.seek(SeekFrom::Start(MAGIC.len() as u64))?;
+    file.write_all(&offset.to_le_bytes())?;
+
+    tracing::info!(
+        "Dumped {} regions ({:#x} bytes) to {}",
+        index.regions.len(),
+        index.total_size(),
+        path.to_string_lossy()
+    );
+    Ok(index)
+}
+
+/// Read-only memory backed by a dump archive, region bytes are read from the file on demand
+pub struct DumpMemory {
+    index: DumpIndex,
+    file: Mutex<File>,
+}
+
+impl DumpMemory {
+    pub fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut file = File::open(path)?;
+        let mut header = [0u8; 16];
+        file.read_exact(&mut header)
+            .map_err(|e| format!("Invalid memory dump {}: {}", path.to_string_lossy(), e))?;
+        if &header[..8] != MAGIC {
+            return Err(format!("Invalid memory dump {}: bad magic", path.to_string_lossy()).into());
+        }
+
+        let index_offset = u64::from_le_bytes(header[8..].try_into().unwrap());
+        file.seek(SeekFrom::Start(index_offset))?;
+        let mut index: DumpIndex = serde_json::from_reader(BufReader::new(&mut file))?;
+        index.regions.sort_by_key(|dumped| dumped.region.base_address);
+
+        Ok(Self { index, file: Mutex::new(file) })
+    }
+
+    pub fn index(&self) -> &DumpIndex {
+        &self.index
+    }
+
+    fn find(&self, address: usize) -> Option<&DumpedRegion> {
+        let position = self.index.regions.partition_point(|dumped| dumped.region.end_address() <= address);
+        self.index.regions.get(position).filter(|dumped| dumped.region.contains(address))
+    }
+}
+
+impl ProcessMemory for DumpMemory {
+    fn read_bytes(&self, address: usize, length: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut buffer = vec![0u8; length];
+        let mut file = self.file.lock().unwrap();
+        let mut done = 0;
+        // Reads may span contiguous regions
+        while done < length {
+            let current = address + done;
+            let dumped = self.find(current)
+                .ok_or_else(|| format!("Address {:#x} is not in the memory dump", current))?;
+            let count = (dumped.region.end_address() - current).min(length - done);
+            file.seek(SeekFrom::Start(dumped.data_offset + (current - dumped.region.base_address) as u64))?;
+            file.read_exact(&mut buffer[done..done + count])?;
+            done += count;
+        }
+        Ok(buffer)
+    }
+
+    fn write_bytes(&self, address: usize, _data: &[u8]) -> Result<(), Box<dyn Error>> {
+        Err(format!("Cannot write at {:#x}: memory dumps are read-only", address).into())
+    }
+
+    fn protect(&self, address: usize, _size: usize, _protection: u32) -> Result<u32, Box<dyn Error>> {
+        Err(format!("Cannot change protection at {:#x}: memory dumps are read-only", address).into())
+    }
+
+    fn allocate(&self, _size: usize, _protection: u32, _near: Option<usize>) -> Result<AllocRegion, Box<dyn Error>> {
+        Err("Cannot allocate: memory dumps are read-only".into())
+    }
+
+    fn free(&self, address: usize, _size: usize) -> Result<(), Box<dyn Error>> {
+        Err(format!("Cannot free {:#x}: memory dumps are read-only", address).into())
+    }
+
+    fn regions(&self) -> Result<Vec<MemoryRegion>, Box<dyn Error>> {
+        Ok(self.index.regions.iter().map(|dumped| dumped.region.clone()).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::basic::aob_scan_mrtype;
+    use crate::utils::process_memory::*;
+
+    fn memory() -> MockMemory {
+        let memory = MockMemory::new();
+        memory.map(0x400000, 0x2000, PAGE_EXECUTE_READ, MEM_IMAGE);
+        memory.map(0x402000, 0x1000, PAGE_READONLY, MEM_IMAGE);
+        memory.map(0x10000, 0x1000, PAGE_READWRITE, MEM_PRIVATE);
+        memory.map(0x20000, 0x1000, PAGE_NOACCESS, MEM_PRIVATE);
+        memory.write_bytes(0x400100, &[0x55, 0x48, 0x8B, 0xEC]).unwrap();
+        memory.write_bytes(0x401FFE, &[0x55, 0x48, 0x8B, 0xEC]).unwrap();
+        memory.write_u64(0x10010, 0x401FFE).unwrap();
+        memory
+    }
+
+    #[test]
+    fn test_dump_and_reload() {
+        let path = std::env::temp_dir().join("nas_memory_dump_test.bin");
+        let index = dump_regions(&memory(), 1234, &path, |_| true).unwrap();
+        assert_eq!(index.regions.len(), 3);
+        assert_eq!(index.total_size(), 0x4000);
+
+        let dump = DumpMemory::open(&path).unwrap();
+        assert_eq!(dump.index().pid, 1234);
+        let regions = dump.regions().unwrap();
+        assert_eq!((regions[0].base_address, regions[0].protect), (0x10000, PAGE_READWRITE));
+        assert_eq!(regions[1].type_, MEM_IMAGE);
+
+        // Crosses the two image regions
+        assert_eq!(dump.read_bytes(0x401FFE, 4).unwrap(), [0x55, 0x48, 0x8B, 0xEC]);
+        assert_eq!(dump.read_pointer(0x10010).unwrap(), 0x401FFE);
+        assert_eq!(aob_scan_mrtype(&dump, "55 48 8B EC", MEM_IMAGE).unwrap(), [0x400100]);
+        assert!(dump.read_bytes(0x20000, 1).is_err());
+        assert!(dump.read_bytes(0x10FFF, 2).is_err());
+        assert!(dump.write_u32(0x10000, 1).is_err());
+        assert!(dump.allocate(0x1000, PAGE_READWRITE, None).is_err());
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_dump_filter() {
+        let path = std::env::temp_dir().join("nas_memory_dump_filter_test.bin");
+        let index = dump_regions(&memory(), 1, &path, |region| region.protect & PAGE_EXECUTABLE != 0).unwrap();
+        assert_eq!(index.regions.len(), 1);
+
+        let dump = DumpMemory::open(&path).unwrap();
+        assert!(dump.read_u8(0x10000).is_err());
+        assert_eq!(dump.read_bytes(0x401FFE, 2).unwrap(), [0x55, 0x48]);
+        assert!(dump.read_bytes(0x401FFE, 4).is_err());
+
+        std::fs::write(&path, b"not a dump").unwrap();
+        assert!(DumpMemory::open(&path).is_err());
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -1,10 +1,23 @@
 pub mod memory;
+pub mod memory_dump;
+#[cfg(windows)]
 pub mod libmem_ex;
+#[cfg(windows)]
+pub mod libmem_memory;
+pub mod process_memory;
 pub mod signals;
+pub mod thread_suspend;
+#[cfg(windows)]
+pub mod win32_memory;
 // Re-export commonly used items from memory module
 pub use memory::{
     MemoryRegion,
-    enum_memory_regions,
     get_protection_string,
+};
+#[cfg(windows)]
+pub use memory::{
+    enum_memory_regions,
     print_memory_regions,
 };
+pub use process_memory::{ProcessMemory, MockMemory, open_process_memory};
+pub use memory_dump::{DumpMemory, dump_regions};
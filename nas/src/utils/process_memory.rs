@@ -0,0 +1,430 @@
+/*
+    Access to the memory of the game process behind a single trait, so scanning and parsing code
+    runs the same against Win32, libmem or an in-memory mock in unit tests.
+    Protection, state and type values use the Win32 constants on every platform.
+*/
+
+use crate::utils::thread_suspend::{SuspendedThread, SuspendedThreads};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+pub const PAGE_NOACCESS: u32 = 0x01;
+pub const PAGE_READONLY: u32 = 0x02;
+pub const PAGE_READWRITE: u32 = 0x04;
+pub const PAGE_WRITECOPY: u32 = 0x08;
+pub const PAGE_EXECUTE: u32 = 0x10;
+pub const PAGE_EXECUTE_READ: u32 = 0x20;
+pub const PAGE_EXECUTE_READWRITE: u32 = 0x40;
+pub const PAGE_EXECUTE_WRITECOPY: u32 = 0x80;
+pub const PAGE_GUARD: u32 = 0x100;
+/// Mask matching the pages JIT code can live in
+pub const PAGE_EXECUTABLE: u32 = PAGE_EXECUTE | PAGE_EXECUTE_READ | PAGE_EXECUTE_READWRITE;
+
+pub const MEM_COMMIT: u32 = 0x1000;
+pub const MEM_RESERVE: u32 = 0x2000;
+pub const MEM_FREE: u32 = 0x10000;
+pub const MEM_PRIVATE: u32 = 0x20000;
+pub const MEM_MAPPED: u32 = 0x40000;
+pub const MEM_IMAGE: u32 = 0x1000000;
+
+const PAGE_SIZE: usize = 0x1000;
+
+/// Represents information about a memory region in a process.
+///
+/// This structure contains details about a memory region's location,
+/// size, and protection attributes as returned by Windows memory management functions.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemoryRegion {
+    /// The base address of the region of pages.
+    pub base_address: usize,
+
+    /// The base address of the allocated region of pages when the region was initially allocated.
+    pub allocation_base: usize,
+
+    /// The memory protection option when the region was initially allocated.
+    ///
+    /// Can be a combination of:
+    /// - PAGE_EXECUTE
+    /// - PAGE_EXECUTE_READ
+    /// - PAGE_EXECUTE_READWRITE
+    /// - PAGE_READONLY
+    /// - PAGE_READWRITE
+    pub allocation_protect: u32,
+
+    /// The size of the region in bytes.
+    pub region_size: usize,
+
+    /// The state of the pages in the region.
+    ///
+    /// Can be one of:
+    /// - MEM_COMMIT
+    /// - MEM_FREE
+    /// - MEM_RESERVE
+    pub state: u32,
+
+    /// The access protection of the pages in the region.
+    ///
+    /// Can be a combination of:
+    /// - PAGE_EXECUTE
+    /// - PAGE_EXECUTE_READ
+    /// - PAGE_EXECUTE_READWRITE
+    /// - PAGE_READONLY
+    /// - PAGE_READWRITE
+    pub protect: u32,
+
+    /// The type of pages in the region.
+    ///
+    /// Can be one of:
+    /// - MEM_IMAGE (Memory mapped EXE/DLL)
+    /// - MEM_MAPPED (Memory mapped file)
+    /// - MEM_PRIVATE (Private memory)
+    pub type_: u32,
+}
+
+impl MemoryRegion {
+    pub fn end_address(&self) -> usize {
+        self.base_address + self.region_size
+    }
+
+    pub fn contains(&self, address: usize) -> bool {
+        address >= self.base_address && address < self.end_address()
+    }
+}
+
+// Simple allocation descriptor used by libmem-based helpers.
+#[derive(Clone, Copy, Debug)]
+pub struct AllocRegion {
+    pub base_address: usize,
+    pub region_size: usize,
+}
+
+/// Module loaded in the process
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleInfo {
+    pub name: String,
+    pub base: usize,
+    pub size: usize,
+}
+
+/// MEM_IMAGE allocations of `regions` as modules named `image@<base>`, for backends without module names
+pub fn image_modules(regions: &[MemoryRegion]) -> Vec<ModuleInfo> {
+    let mut modules: Vec<ModuleInfo> = Vec::new();
+    for region in regions.iter().filter(|region| region.type_ == MEM_IMAGE) {
+        match modules.last_mut() {
+            Some(module) if module.base == region.allocation_base => module.size = region.end_address() - module.base,
+            _ => modules.push(ModuleInfo {
+                name: format!("image@{:#x}", region.allocation_base),
+                base: region.allocation_base,
+                size: region.end_address() - region.allocation_base,
+            }),
+        }
+    }
+    modules
+}
+
+pub fn is_readable(protect: u32) -> bool {
+    protect & PAGE_GUARD == 0
+        && protect & (PAGE_READONLY | PAGE_READWRITE | PAGE_WRITECOPY | PAGE_EXECUTE_READ | PAGE_EXECUTE_READWRITE | PAGE_EXECUTE_WRITECOPY) != 0
+}
+
+pub fn is_writable(protect: u32) -> bool {
+    protect & (PAGE_READWRITE | PAGE_WRITECOPY | PAGE_EXECUTE_READWRITE | PAGE_EXECUTE_WRITECOPY) != 0
+}
+
+pub fn is_executable(protect: u32) -> bool {
+    protect & (PAGE_EXECUTE | PAGE_EXECUTE_READ | PAGE_EXECUTE_READWRITE | PAGE_EXECUTE_WRITECOPY) != 0
+}
+
+/// Memory of the target process
+pub trait ProcessMemory: Send + Sync {
+    /// Read exactly `length` bytes, fails if any of them cannot be read
+    fn read_bytes(&self, address: usize, length: usize) -> Result<Vec<u8>, Box<dyn Error>>;
+
+    fn write_bytes(&self, address: usize, data: &[u8]) -> Result<(), Box<dyn Error>>;
+
+    /// Fill `buffer` from `address`, false if any byte cannot be read. Used while the other
+    /// threads are suspended, backends override it to read without allocating
+    fn read_raw(&self, address: usize, buffer: &mut [u8]) -> bool {
+        self.read_bytes(address, buffer.len()).map(|bytes| buffer.copy_from_slice(&bytes)).is_ok()
+    }
+
+    /// Write `data` at `address`, false if it fails. Used like `read_raw`
+    fn write_raw(&self, address: usize, data: &[u8]) -> bool {
+        self.write_bytes(address, data).is_ok()
+    }
+
+    /// Change the protection of the pages covering the range, returns the previous protection
+    fn protect(&self, address: usize, size: usize, protection: u32) -> Result<u32, Box<dyn Error>>;
+
+    /// Allocate committed memory. `near` is a hint to allocate within ±2GB of an address,
+    /// so the region can be reached with rel32 jumps
+    fn allocate(&self, size: usize, protection: u32, near: Option<usize>) -> Result<AllocRegion, Box<dyn Error>>;
+
+    /// Release a region returned by `allocate`
+    fn free(&self, address: usize, size: usize) -> Result<(), Box<dyn Error>>;
+
+    /// Committed regions, sorted by address
+    fn regions(&self) -> Result<Vec<MemoryRegion>, Box<dyn Error>>;
+
+    /// Loaded modules, by default the MEM_IMAGE allocations of `regions`, see `image_modules`
+    fn modules(&self) -> Result<Vec<ModuleInfo>, Box<dyn Error>> {
+        Ok(image_modules(&self.regions()?))
+    }
+
+    /// Suspend the other threads of the process until the result is dropped, used to patch code
+    /// that may be running. Backends without threads suspend nothing
+    fn suspend_threads(&self) -> Result<SuspendedThreads, Box<dyn Error>> {
+        Ok(SuspendedThreads::default())
+    }
+
+    fn read_u8(&self, address: usize) -> Result<u8, Box<dyn Error>> {
+        Ok(self.read_bytes(address, 1)?[0])
+    }
+
+    fn read_u16(&self, address: usize) -> Result<u16, Box<dyn Error>> {
+        Ok(u16::from_le_bytes(self.read_bytes(address, 2)?.try_into().unwrap()))
+    }
+
+    fn read_u32(&self, address: usize) -> Result<u32, Box<dyn Error>> {
+        Ok(u32::from_le_bytes(self.read_bytes(address, 4)?.try_into().unwrap()))
+    }
+
+    fn read_u64(&self, address: usize) -> Result<u64, Box<dyn Error>> {
+        Ok(u64::from_le_bytes(self.read_bytes(address, 8)?.try_into().unwrap()))
+    }
+
+    fn read_pointer(&self, address: usize) -> Result<usize, Box<dyn Error>> {
+        Ok(self.read_u64(address)? as usize)
+    }
+
+    /// Read a null-terminated UTF-16 string of at most `max_chars` characters
+    fn read_utf16_string(&self, address: usize, max_chars: usize) -> Result<String, Box<dyn Error>> {
+        let mut chars = Vec::new();
+        while chars.len() < max_chars {
+            let c = self.read_u16(address + chars.len() * 2)?;
+            if c == 0 {
+                break;
+            }
+            chars.push(c);
+        }
+        Ok(String::from_utf16_lossy(&chars))
+    }
+
+    fn write_u32(&self, address: usize, value: u32) -> Result<(), Box<dyn Error>> {
+        self.write_bytes(address, &value.to_le_bytes())
+    }
+
+    fn write_u64(&self, address: usize, value: u64) -> Result<(), Box<dyn Error>> {
+        self.write_bytes(address, &value.to_le_bytes())
+    }
+}
+
+/// Backend used for the game process
+#[cfg(windows)]
+pub fn open_process_memory(pid: u32) -> Result<Arc<dyn ProcessMemory>, Box<dyn Error>> {
+    Ok(Arc::new(crate::utils::win32_memory::Win32Memory::open(pid)?))
+}
+
+#[cfg(not(windows))]
+pub fn open_process_memory(pid: u32) -> Result<Arc<dyn ProcessMemory>, Box<dyn Error>> {
+    Err(format!("Cannot open process {}: process memory is only available on Windows", pid).into())
+}
+
+struct MockRegion {
+    info: MemoryRegion,
+    data: Vec<u8>,
+}
+
+/// Process memory backed by buffers, regions are mapped explicitly and start zeroed.
+/// Writes ignore the protection like `WriteProcessMemory` does on code pages,
+/// protection changes apply to whole regions
+#[derive(Default)]
+pub struct MockMemory {
+    regions: Mutex<Vec<MockRegion>>,
+    threads: Mutex<VecDeque<Vec<usize>>>,
+    stacks: Mutex<Vec<Range<usize>>>,
+}
+
+impl MockMemory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Map `size` zeroed bytes at `base`, panics if it overlaps an existing region
+    pub fn map(&self, base: usize, size: usize, protection: u32, type_: u32) {
+        let mut regions = self.regions.lock().unwrap();
+        assert!(
+            !regions.iter().any(|r| base < r.info.end_address() && r.info.base_address < base + size),
+            "Mock region {:#x}..{:#x} overlaps an existing region",
+            base,
+            base + size
+        );
+        regions.push(MockRegion {
+            info: MemoryRegion {
+                base_address: base,
+                allocation_base: base,
+                allocation_protect: protection,
+                region_size: size,
+                state: MEM_COMMIT,
+                protect: protection,
+                type_,
+            },
+            data: vec![0; size],
+        });
+        regions.sort_by_key(|r| r.info.base_address);
+    }
+
+    /// Instruction pointers reported by successive `suspend_threads` calls, the last one repeats
+    pub fn set_threads(&self, snapshots: Vec<Vec<usize>>) {
+        *self.threads.lock().unwrap() = snapshots.into();
+    }
+
+    /// Stack of each thread in the `set_threads` snapshots, by position
+    pub fn set_stacks(&self, stacks: Vec<Range<usize>>) {
+        *self.stacks.lock().unwrap() = stacks;
+    }
+
+    /// Copy bytes spanning one or more contiguous regions
+    fn access(&self, address: usize, length: usize, mut copy: impl FnMut(&mut [u8], usize)) -> Result<(), Box<dyn Error>> {
+        let mut regions = self.regions.lock().unwrap();
+        let mut done = 0;
+        while done < length {
+            let current = address + done;
+            let region = regions
+                .iter_mut()
+                .find(|r| r.info.contains(current))
+                .filter(|r| r.info.protect & (PAGE_NOACCESS | PAGE_GUARD) == 0)
+                .ok_or_else(|| format!("Address {:#x} is not accessible", current))?;
+            let start = current - region.info.base_address;
+            let count = (region.data.len() - start).min(length - done);
+            copy(&mut region.data[start..start + count], done);
+            done += count;
+        }
+        Ok(())
+    }
+}
+
+impl ProcessMemory for MockMemory {
+    fn read_bytes(&self, address: usize, length: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut buffer = vec![0; length];
+        self.access(address, length, |data, offset| {
+            buffer[offset..offset + data.len()].copy_from_slice(data);
+        })?;
+        Ok(buffer)
+    }
+
+    fn write_bytes(&self, address: usize, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.access(address, data.len(), |region, offset| {
+            region.copy_from_slice(&data[offset..offset + region.len()]);
+        })
+    }
+
+    fn protect(&self, address: usize, size: usize, protection: u32) -> Result<u32, Box<dyn Error>> {
+        let mut regions = self.regions.lock().unwrap();
+        let mut previous = None;
+        for region in regions
+            .iter_mut()
+            .filter(|r| address < r.info.end_address() && r.info.base_address < address + size.max(1))
+        {
+            previous.get_or_insert(region.info.protect);
+            region.info.protect = protection;
+        }
+        previous.ok_or_else(|| format!("Address {:#x} is not mapped", address).into())
+    }
+
+    fn allocate(&self, size: usize, protection: u32, near: Option<usize>) -> Result<AllocRegion, Box<dyn Error>> {
+        let size = size.div_ceil(PAGE_SIZE).max(1) * PAGE_SIZE;
+        let mut base = near.map_or(0x10000, |address| address & !(PAGE_SIZE - 1));
+        for region in self.regions.lock().unwrap().iter() {
+            if base < region.info.end_address() && region.info.base_address < base + size {
+                base = region.info.end_address().div_ceil(PAGE_SIZE) * PAGE_SIZE;
+            }
+        }
+        self.map(base, size, protection, MEM_PRIVATE);
+        Ok(AllocRegion { base_address: base, region_size: size })
+    }
+
+    fn free(&self, address: usize, _size: usize) -> Result<(), Box<dyn Error>> {
+        let mut regions = self.regions.lock().unwrap();
+        let index = regions
+            .iter()
+            .position(|r| r.info.base_address == address)
+            .ok_or_else(|| format!("No region allocated at {:#x}", address))?;
+        regions.remove(index);
+        Ok(())
+    }
+
+    fn regions(&self) -> Result<Vec<MemoryRegion>, Box<dyn Error>> {
+        Ok(self.regions.lock().unwrap().iter().map(|r| r.info.clone()).collect())
+    }
+
+    fn suspend_threads(&self) -> Result<SuspendedThreads, Box<dyn Error>> {
+        let mut snapshots = self.threads.lock().unwrap();
+        let snapshot = match snapshots.len() {
+            0 => Vec::new(),
+            1 => snapshots[0].clone(),
+            _ => snapshots.pop_front().unwrap(),
+        };
+        let stacks = self.stacks.lock().unwrap();
+        let threads = snapshot
+            .into_iter()
+            .enumerate()
+            .map(|(id, instruction_pointer)| {
+                let stack = stacks.get(id).cloned().unwrap_or_default();
+                SuspendedThread { id: id as u32 + 1, instruction_pointer, stack_pointer: stack.start, stack_end: stack.end }
+            })
+            .collect();
+        Ok(SuspendedThreads::new(threads, Box::new(|| {})))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_read_write() {
+        let memory = MockMemory::new();
+        memory.map(0x1000, 0x1000, PAGE_READWRITE, MEM_PRIVATE);
+        memory.map(0x2000, 0x1000, PAGE_READONLY, MEM_IMAGE);
+
+        // Accesses can span contiguous regions
+        memory.write_u64(0x1ffc, 0x1122334455667788).unwrap();
+        assert_eq!(memory.read_u64(0x1ffc).unwrap(), 0x1122334455667788);
+        assert_eq!(memory.read_u32(0x2000).unwrap(), 0x11223344);
+
+        assert!(memory.read_bytes(0x2ffc, 8).is_err());
+        assert_eq!(memory.protect(0x2000, 1, PAGE_NOACCESS).unwrap(), PAGE_READONLY);
+        assert!(memory.read_u8(0x2000).is_err());
+
+        memory.write_bytes(0x1100, &[b'a', 0, b'b', 0, 0, 0]).unwrap();
+        assert_eq!(memory.read_utf16_string(0x1100, 16).unwrap(), "ab");
+        assert_eq!(memory.read_utf16_string(0x1100, 1).unwrap(), "a");
+    }
+
+    #[test]
+    fn test_mock_allocate() {
+        let memory = MockMemory::new();
+        memory.map(0x10000, 0x1000, PAGE_EXECUTE_READ, MEM_IMAGE);
+
+        let region = memory.allocate(0x10, PAGE_READWRITE, Some(0x10800)).unwrap();
+        assert_eq!((region.base_address, region.region_size), (0x11000, 0x1000));
+        let regions = memory.regions().unwrap();
+        assert_eq!(regions.len(), 2);
+        assert_eq!(regions[1].type_, MEM_PRIVATE);
+
+        memory.free(region.base_address, region.region_size).unwrap();
+        assert!(memory.read_u8(0x11000).is_err());
+        assert!(memory.free(region.base_address, region.region_size).is_err());
+    }
+
+    #[test]
+    fn test_protection_flags() {
+        assert!(is_readable(PAGE_EXECUTE_READ) && !is_writable(PAGE_EXECUTE_READ) && is_executable(PAGE_EXECUTE_READ));
+        assert!(!is_readable(PAGE_EXECUTE) && !is_readable(PAGE_NOACCESS) && !is_readable(PAGE_READWRITE | PAGE_GUARD));
+        assert!(is_writable(PAGE_WRITECOPY) && !is_executable(PAGE_READWRITE));
+    }
+}
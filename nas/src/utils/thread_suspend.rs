@@ -0,0 +1,221 @@
+/*
+    Threads of the game process suspended while code is patched, with the instruction pointer
+    and the stack of each one so patches can avoid bytes that are being executed or that a
+    thread will return into. The threads resume when `SuspendedThreads` is dropped.
+    While they are suspended one of them may hold the heap or a logging lock, keep the work
+    done in that window to reads and writes of process memory.
+*/
+
+use std::ops::Range;
+#[cfg(windows)]
+use std::sync::{Arc, Mutex};
+
+/// Bytes of stack read at once while looking for return addresses
+const STACK_CHUNK: usize = 0x1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SuspendedThread {
+    pub id: u32,
+    pub instruction_pointer: usize,
+    /// Used part of the stack is `stack_pointer..stack_end`, empty when it could not be found
+    pub stack_pointer: usize,
+    pub stack_end: usize,
+}
+
+#[derive(Default)]
+pub struct SuspendedThreads {
+    threads: Vec<SuspendedThread>,
+    resume: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl SuspendedThreads {
+    /// `resume` is called once when the suspension is dropped. It is boxed by the caller so it
+    /// can be allocated before the threads are suspended
+    pub fn new(threads: Vec<SuspendedThread>, resume: Box<dyn FnOnce() + Send>) -> Self {
+        Self { threads, resume: Some(resume) }
+    }
+
+    pub fn threads(&self) -> &[SuspendedThread] {
+        &self.threads
+    }
+
+    /// First thread whose instruction pointer lies in one of `ranges`
+    pub fn executing_in(&self, ranges: &[Range<usize>]) -> Option<SuspendedThread> {
+        self.threads
+            .iter()
+            .find(|thread| ranges.iter().any(|range| range.contains(&thread.instruction_pointer)))
+            .copied()
+    }
+
+    /// First thread holding a value inside one of `ranges` on its stack, with that value.
+    /// Return addresses are not told apart from other values, a stale one only delays the patch.
+    /// `read` fills a buffer from process memory without allocating, unreadable chunks are skipped
+    pub fn returning_into(
+        &self,
+        ranges: &[Range<usize>],
+        mut read: impl FnMut(usize, &mut [u8]) -> bool,
+    ) -> Option<(SuspendedThread, usize)> {
+        let mut buffer = [0u8; STACK_CHUNK];
+        for thread in &self.threads {
+            let mut address = thread.stack_pointer & !7;
+            while address < thread.stack_end {
+                let len = STACK_CHUNK.min(thread.stack_end - address) & !7;
+                if len == 0 {
+                    break;
+                }
+                if read(address, &mut buffer[..len]) {
+                    let found = buffer[..len]
+                        .chunks_exact(8)
+                        .map(|word| u64::from_le_bytes(word.try_into().unwrap()) as usize)
+                        .find(|value| ranges.iter().any(|range| range.contains(value)));
+                    if let Some(value) = found {
+                        return Some((*thread, value));
+                    }
+                }
+                address += len;
+            }
+        }
+        None
+    }
+}
+
+impl Drop for SuspendedThreads {
+    fn drop(&mut self) {
+        if let Some(resume) = self.resume.take() {
+            resume();
+        }
+    }
+}
+
+/// Suspend every thread of `pid` except the calling one and read their instruction and stack pointers.
+/// Threads that cannot be opened or suspended are skipped
+#[cfg(windows)]
+pub fn suspend_process_threads(pid: u32) -> Result<SuspendedThreads, Box<dyn std::error::Error>> {
+    use windows::Win32::Foundation::{CloseHandle, HANDLE};
+    use windows::Win32::System::Diagnostics::Debug::{GetThreadContext, CONTEXT, CONTEXT_CONTROL_AMD64};
+    use windows::Win32::System::Diagnostics::ToolHelp::{
+        CreateToolhelp32Snapshot, Thread32First, Thread32Next, TH32CS_SNAPTHREAD, THREADENTRY32,
+    };
+    use windows::Win32::System::Memory::{VirtualQueryEx, MEMORY_BASIC_INFORMATION};
+    use windows::Win32::System::Threading::{
+        GetCurrentThreadId, OpenProcess, OpenThread, ResumeThread, SuspendThread, PROCESS_QUERY_INFORMATION,
+        THREAD_GET_CONTEXT, THREAD_QUERY_INFORMATION, THREAD_SUSPEND_RESUME,
+    };
+
+    // GetThreadContext needs a 16-byte aligned CONTEXT
+    #[repr(C, align(16))]
+    struct AlignedContext(CONTEXT);
+
+    let mut ids = Vec::new();
+    unsafe {
+        let snapshot = CreateToolhelp32Snapshot(TH32CS_SNAPTHREAD, 0)?;
+        let mut entry = THREADENTRY32 { dwSize: std::mem::size_of::<THREADENTRY32>() as u32, ..Default::default() };
+        let mut next = Thread32First(snapshot, &mut entry);
+        while next.is_ok() {
+            if entry.th32OwnerProcessID == pid && entry.th32ThreadID != GetCurrentThreadId() {
+                ids.push(entry.th32ThreadID);
+            }
+            next = Thread32Next(snapshot, &mut entry);
+        }
+        let _ = CloseHandle(snapshot);
+    }
+
+    // Stacks end with the committed region holding the stack pointer
+    let process = unsafe { OpenProcess(PROCESS_QUERY_INFORMATION, false, pid) }
+        .map_err(|e| format!("Failed to open process {}: {:?}", pid, e))?;
+
+    // Allocated up front, nothing is allocated once the first thread is suspended
+    let mut threads = Vec::with_capacity(ids.len());
+    let handles: Arc<Mutex<Vec<usize>>> = Arc::new(Mutex::new(Vec::with_capacity(ids.len())));
+    let mut context = Box::new(AlignedContext(CONTEXT { ContextFlags: CONTEXT_CONTROL_AMD64, ..Default::default() }));
+    let suspended = handles.clone();
+    let resume: Box<dyn FnOnce() + Send> = Box::new(move || {
+        for handle in suspended.lock().unwrap().drain(..) {
+            let handle = HANDLE(handle as *mut std::ffi::c_void);
+            unsafe {
+                ResumeThread(handle);
+                let _ = CloseHandle(handle);
+            }
+        }
+    });
+
+    let mut opened = handles.lock().unwrap();
+    for id in ids {
+        unsafe {
+            let Ok(handle) = OpenThread(THREAD_SUSPEND_RESUME | THREAD_GET_CONTEXT | THREAD_QUERY_INFORMATION, false, id) else {
+                continue;
+            };
+            if SuspendThread(handle) == u32::MAX {
+                let _ = CloseHandle(handle);
+                continue;
+            }
+            opened.push(handle.0 as usize);
+            // GetThreadContext returns once the thread actually stopped
+            if GetThreadContext(handle, &mut context.0).is_ok() {
+                let stack_pointer = context.0.Rsp as usize;
+                let mut region = MEMORY_BASIC_INFORMATION::default();
+                let queried = VirtualQueryEx(
+                    process,
+                    Some(stack_pointer as *const std::ffi::c_void),
+                    &mut region,
+                    std::mem::size_of::<MEMORY_BASIC_INFORMATION>(),
+                );
+                let stack_end = match queried {
+                    0 => stack_pointer,
+                    _ => region.BaseAddress as usize + region.RegionSize,
+                };
+                threads.push(SuspendedThread { id, instruction_pointer: context.0.Rip as usize, stack_pointer, stack_end });
+            }
+        }
+    }
+    drop(opened);
+    unsafe {
+        let _ = CloseHandle(process);
+    }
+
+    Ok(SuspendedThreads::new(threads, resume))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_resume_on_drop() {
+        let resumed = Arc::new(AtomicBool::new(false));
+        let flag = resumed.clone();
+        let threads = vec![
+            SuspendedThread { id: 1, instruction_pointer: 0x1000, stack_pointer: 0, stack_end: 0 },
+            SuspendedThread { id: 2, instruction_pointer: 0x2004, stack_pointer: 0, stack_end: 0 },
+        ];
+        let suspended = SuspendedThreads::new(threads, Box::new(move || flag.store(true, Ordering::SeqCst)));
+
+        assert_eq!(suspended.executing_in(&[0x1001..0x1008, 0x2001..0x2008]).map(|thread| thread.id), Some(2));
+        assert_eq!(suspended.executing_in(&[0x1001..0x1008, 0x3000..0x4000]), None);
+        assert!(!resumed.load(Ordering::SeqCst));
+        drop(suspended);
+        assert!(resumed.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_returning_into() {
+        // Two chunks of stack, the second one holds an address inside the range
+        let mut stack = vec![0u8; STACK_CHUNK + 0x100];
+        stack[STACK_CHUNK + 0x18..STACK_CHUNK + 0x20].copy_from_slice(&0x2004u64.to_le_bytes());
+        let base = 0x10_0000;
+        let thread = SuspendedThread { id: 7, instruction_pointer: 0x1000, stack_pointer: base + 4, stack_end: base + stack.len() };
+        let suspended = SuspendedThreads::new(vec![thread], Box::new(|| {}));
+
+        let read = |address: usize, buffer: &mut [u8]| {
+            let start = address - base;
+            buffer.copy_from_slice(&stack[start..start + buffer.len()]);
+            true
+        };
+        assert_eq!(suspended.returning_into(&[0x2001..0x2008, 0x3001..0x3008], read), Some((thread, 0x2004)));
+        assert_eq!(suspended.returning_into(&[0x2005..0x2008, 0x3001..0x3008], read), None);
+        // Unreadable stacks are skipped
+        assert_eq!(suspended.returning_into(&[0x2001..0x2008, 0x3001..0x3008], |_, _| false), None);
+    }
+}
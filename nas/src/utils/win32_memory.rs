@@ -0,0 +1,231 @@
+/*
+    `ProcessMemory` backend using the Win32 API on a process handle opened once.
+*/
+
+use crate::utils::memory::enum_memory_regions_handle;
+use crate::utils::process_memory::{AllocRegion, MemoryRegion, ModuleInfo, ProcessMemory};
+use crate::utils::thread_suspend::{suspend_process_threads, SuspendedThreads};
+use std::error::Error;
+use std::ffi::c_void;
+use windows::Win32::Foundation::{CloseHandle, BOOL, HANDLE, HMODULE};
+use windows::Win32::System::Diagnostics::Debug::{ReadProcessMemory, WriteProcessMemory};
+use windows::Win32::System::Memory::{
+    VirtualAllocEx, VirtualFreeEx, VirtualProtectEx, VirtualQueryEx, MEMORY_BASIC_INFORMATION,
+    MEM_COMMIT, MEM_RELEASE, MEM_RESERVE, PAGE_PROTECTION_FLAGS,
+};
+use windows::Win32::System::ProcessStatus::{
+    K32EnumProcessModules, K32GetModuleBaseNameW, K32GetModuleInformation, MODULEINFO,
+};
+use windows::Win32::System::Threading::{
+    OpenProcess, PROCESS_QUERY_INFORMATION, PROCESS_VM_OPERATION, PROCESS_VM_READ, PROCESS_VM_WRITE,
+};
+
+/// Largest distance reachable with a rel32 jump, with some margin
+const MAX_NEAR_DISTANCE: usize = 0x7FFF_0000;
+
+pub struct Win32Memory {
+    pid: u32,
+    handle: HANDLE,
+}
+
+// Process handles can be used from any thread
+unsafe impl Send for Win32Memory {}
+unsafe impl Sync for Win32Memory {}
+
+impl Win32Memory {
+    pub fn open(pid: u32) -> Result<Self, Box<dyn Error>> {
+        let handle = unsafe {
+            OpenProcess(
+                PROCESS_QUERY_INFORMATION | PROCESS_VM_READ | PROCESS_VM_WRITE | PROCESS_VM_OPERATION,
+                BOOL::from(false),
+                pid,
+            )
+        }.map_err(|e| format!("Failed to open process {}: {:?}", pid, e))?;
+
+        Ok(Self { pid, handle })
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    fn allocate_at(&self, address: Option<usize>, size: usize, protection: u32) -> Option<AllocRegion> {
+        let pointer = unsafe {
+            VirtualAllocEx(
+                self.handle,
+                address.map(|address| address as *const c_void),
+                size,
+                MEM_COMMIT | MEM_RESERVE,
+                PAGE_PROTECTION_FLAGS(protection),
+            )
+        };
+        if pointer.is_null() {
+            return None;
+        }
+
+        // Query for the actual region size
+        let mut mbi = MEMORY_BASIC_INFORMATION::default();
+        let result = unsafe {
+            VirtualQueryEx(self.handle, Some(pointer), &mut mbi, std::mem::size_of::<MEMORY_BASIC_INFORMATION>())
+        };
+        let region_size = if result == 0 { size } else { mbi.RegionSize };
+        Some(AllocRegion { base_address: pointer as usize, region_size })
+    }
+}
+
+impl Drop for Win32Memory {
+    fn drop(&mut self) {
+        unsafe { let _ = CloseHandle(self.handle); }
+    }
+}
+
+impl ProcessMemory for Win32Memory {
+    fn read_bytes(&self, address: usize, length: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut buffer = vec![0u8; length];
+        let mut bytes_read = 0;
+        let success = unsafe {
+            ReadProcessMemory(
+                self.handle,
+                address as *const c_void,
+                buffer.as_mut_ptr() as *mut c_void,
+                buffer.len(),
+                Some(&mut bytes_read),
+            )
+        };
+
+        if success.is_err() || bytes_read != buffer.len() {
+            Err(format!("Failed to read {} bytes at {:#x}", length, address).into())
+        } else {
+            Ok(buffer)
+        }
+    }
+
+    fn write_bytes(&self, address: usize, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        let mut bytes_written = 0;
+        let success = unsafe {
+            WriteProcessMemory(
+                self.handle,
+                address as *const c_void,
+                data.as_ptr() as *const c_void,
+                data.len(),
+                Some(&mut bytes_written),
+            )
+        };
+
+        if success.is_err() || bytes_written != data.len() {
+            Err(format!("Failed to write {} bytes at {:#x}", data.len(), address).into())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn read_raw(&self, address: usize, buffer: &mut [u8]) -> bool {
+        let mut bytes_read = 0;
+        let success = unsafe {
+            ReadProcessMemory(
+                self.handle,
+                address as *const c_void,
+                buffer.as_mut_ptr() as *mut c_void,
+                buffer.len(),
+                Some(&mut bytes_read),
+            )
+        };
+        success.is_ok() && bytes_read == buffer.len()
+    }
+
+    fn write_raw(&self, address: usize, data: &[u8]) -> bool {
+        let mut bytes_written = 0;
+        let success = unsafe {
+            WriteProcessMemory(
+                self.handle,
+                address as *const c_void,
+                data.as_ptr() as *const c_void,
+                data.len(),
+                Some(&mut bytes_written),
+            )
+        };
+        success.is_ok() && bytes_written == data.len()
+    }
+
+    fn protect(&self, address: usize, size: usize, protection: u32) -> Result<u32, Box<dyn Error>> {
+        let mut previous = PAGE_PROTECTION_FLAGS::default();
+        unsafe {
+            VirtualProtectEx(
+                self.handle,
+                address as *const c_void,
+                size,
+                PAGE_PROTECTION_FLAGS(protection),
+                &mut previous,
+            )
+        }.map_err(|e| format!("Failed to change protection at {:#x}: {:?}", address, e))?;
+        Ok(previous.0)
+    }
+
+    fn allocate(&self, size: usize, protection: u32, near: Option<usize>) -> Result<AllocRegion, Box<dyn Error>> {
+        if let Some(target_address) = near {
+            // Probe free pages on both sides of the target, closest first
+            let mut offset = 0;
+            while offset < MAX_NEAR_DISTANCE {
+                for address in [target_address.wrapping_add(offset), target_address.wrapping_sub(offset)] {
+                    let Some(region) = self.allocate_at(Some(address), size, protection) else {
+                        continue;
+                    };
+                    if region.base_address.abs_diff(target_address) < MAX_NEAR_DISTANCE {
+                        return Ok(region);
+                    }
+
+                    // Not within acceptable range; free and continue
+                    let _ = self.free(region.base_address, region.region_size);
+                }
+                offset += 0x1000;
+            }
+            tracing::warn!("Could not allocate memory near {:#x}, falling back to regular allocation", target_address);
+        }
+
+        self.allocate_at(None, size, protection)
+            .ok_or_else(|| format!("Failed to allocate {:#x} bytes", size).into())
+    }
+
+    fn free(&self, address: usize, _size: usize) -> Result<(), Box<dyn Error>> {
+        unsafe { VirtualFreeEx(self.handle, address as *mut c_void, 0, MEM_RELEASE) }
+            .map_err(|e| format!("Failed to free memory at {:#x}: {:?}", address, e))?;
+        Ok(())
+    }
+
+    fn regions(&self) -> Result<Vec<MemoryRegion>, Box<dyn Error>> {
+        Ok(enum_memory_regions_handle(self.handle))
+    }
+
+    fn modules(&self) -> Result<Vec<ModuleInfo>, Box<dyn Error>> {
+        let mut handles = vec![HMODULE::default(); 1024];
+        let mut needed = 0u32;
+        let size = (handles.len() * std::mem::size_of::<HMODULE>()) as u32;
+        if !unsafe { K32EnumProcessModules(self.handle, handles.as_mut_ptr(), size, &mut needed) }.as_bool() {
+            return Err(format!("Failed to list the modules of process {}", self.pid).into());
+        }
+        handles.truncate(needed as usize / std::mem::size_of::<HMODULE>());
+
+        let mut modules = Vec::with_capacity(handles.len());
+        for module in handles {
+            let mut name = [0u16; 260];
+            let length = unsafe { K32GetModuleBaseNameW(self.handle, module, &mut name) } as usize;
+            let mut info = MODULEINFO::default();
+            let found = unsafe {
+                K32GetModuleInformation(self.handle, module, &mut info, std::mem::size_of::<MODULEINFO>() as u32)
+            };
+            if length == 0 || !found.as_bool() {
+                continue;
+            }
+            modules.push(ModuleInfo {
+                name: String::from_utf16_lossy(&name[..length]),
+                base: info.lpBaseOfDll as usize,
+                size: info.SizeOfImage as usize,
+            });
+        }
+        Ok(modules)
+    }
+
+    fn suspend_threads(&self) -> Result<SuspendedThreads, Box<dyn Error>> {
+        suspend_process_threads(self.pid)
+    }
+}